@@ -2,10 +2,13 @@ use clap::{Arg, App};
 
 use bit_field::BitField;
 
-use std::fs::{File};
+use std::collections::HashMap;
+use std::fs::{self,File};
 use std::io::{Cursor,Read,Write,BufRead,BufReader,LineWriter};
 
-use crate::instructions::{InstructionT,ALUOp,MemoryOp,ControlOp,ConditionCodes};
+use crate::instructions::{InstructionT,ALUOp,MemoryOp,ControlOp,ConditionCodes,disassemble};
+use crate::encoding::{immediate_field,operand_fields,set_control_op,SIGNED_IMMEDIATE,
+    EXTENDED_CONTROL};
 
 pub struct Assembler {
     mnemonics: Vec<InstructionTemplate>,
@@ -298,6 +301,22 @@ impl Assembler {
                     operationRD: ALUOp::DivSIRD.value(),
                     immediate_idx: 3,
                 },
+                InstructionTemplate{
+                    mnemonic: "MODU".to_string(),
+                    itype: InstructionT::ALU.value(),
+                    num_operation_bits: NUM_ALU_OP_BITS,
+                    operationI: ALUOp::ModUII.value(),
+                    operationRD: ALUOp::ModUIRD.value(),
+                    immediate_idx: 3,
+                },
+                InstructionTemplate{
+                    mnemonic: "MODS".to_string(),
+                    itype: InstructionT::ALU.value(),
+                    num_operation_bits: NUM_ALU_OP_BITS,
+                    operationI: ALUOp::ModSII.value(),
+                    operationRD: ALUOp::ModSIRD.value(),
+                    immediate_idx: 3,
+                },
                 InstructionTemplate{
                     mnemonic: "MOV".to_string(),
                     itype: InstructionT::ALU.value(),
@@ -338,6 +357,22 @@ impl Assembler {
                     operationRD: ALUOp::LSRRD.value(),
                     immediate_idx: 2,
                 },
+                InstructionTemplate{
+                    mnemonic: "ROL".to_string(),
+                    itype: InstructionT::ALU.value(),
+                    num_operation_bits: NUM_ALU_OP_BITS,
+                    operationI: ALUOp::ROLI.value(),
+                    operationRD: ALUOp::ROLRD.value(),
+                    immediate_idx: 2,
+                },
+                InstructionTemplate{
+                    mnemonic: "ROR".to_string(),
+                    itype: InstructionT::ALU.value(),
+                    num_operation_bits: NUM_ALU_OP_BITS,
+                    operationI: ALUOp::RORI.value(),
+                    operationRD: ALUOp::RORRD.value(),
+                    immediate_idx: 2,
+                },
                 InstructionTemplate{
                     mnemonic: "AND".to_string(),
                     itype: InstructionT::ALU.value(),
@@ -624,6 +659,248 @@ impl Assembler {
     }
 }
 
+// ---------------------------------- Text assembly ----------------------------------
+
+/// An instruction assemble() can produce, described by its disassembly with
+/// every operand 0.
+struct Template {
+    /// Instruction type and operation code bits.
+    word: u32,
+
+    /// Tokens of the disassembly, see tokenize().
+    tokens: Vec<String>,
+
+    /// True if the mnemonic can end with a condition code suffix.
+    conditional: bool,
+}
+
+/// Splits a line of assembly into tokens. Commas, brackets and plus signs are
+/// tokens of their own, other tokens are separated by whitespace.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+
+    for c in line.chars() {
+        if c.is_whitespace() || ",[]+".contains(c) {
+            if !token.is_empty() {
+                tokens.push(std::mem::take(&mut token));
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        } else {
+            token.push(c);
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+/// Returns a template for every instruction disassemble() can show, so the
+/// assembler accepts exactly what the disassembler writes.
+fn templates() -> Vec<Template> {
+    let mut words = Vec::new();
+    for op in 0..(1 << 3) {
+        let mut word: u32 = 0;
+        word.set_bits(5..=6, InstructionT::Memory.value());
+        words.push(*word.set_bits(7..=9, op));
+    }
+    for op in 0..(1 << 6) {
+        let mut word: u32 = 0;
+        word.set_bits(5..=6, InstructionT::ALU.value());
+        words.push(*word.set_bits(7..=12, op));
+    }
+    // Control operation codes skip EXTENDED_CONTROL, see ControlOp::value()
+    for op in (0..EXTENDED_CONTROL).chain(EXTENDED_CONTROL + 1..EXTENDED_CONTROL + 9) {
+        let mut word: u32 = 0;
+        word.set_bits(5..=6, InstructionT::Control.value());
+        set_control_op(&mut word, op);
+        words.push(word);
+    }
+
+    let mut templates = Vec::new();
+    for word in words {
+        if let Ok(text) = disassemble(word) {
+            templates.push(Template{
+                word,
+                tokens: tokenize(&text),
+                conditional: operand_fields(word).iter().any(|(name, _bits)| *name == "cond"),
+            });
+        }
+    }
+
+    templates
+}
+
+/// Parses a register token like "R12".
+fn parse_register(token: &str) -> Option<u32> {
+    let reg: u32 = token.strip_prefix('R')?.parse().ok()?;
+
+    match reg < 32 {
+        true => Some(reg),
+        false => None,
+    }
+}
+
+/// Parses a decimal number, or a hexadecimal number prefixed with 0x. Either
+/// may be negative.
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+
+    match negative {
+        true => Some(-value),
+        false => Some(value),
+    }
+}
+
+/// Returns the register and immediate values of a line's operands if they
+/// have the shape of a template's operands. Immediates are written as "#5" or
+/// as the name of a label, which stands for the label's address. Returns None
+/// if the shapes differ.
+fn match_operands(template: &[String], tokens: &[String],
+                  labels: &HashMap<String, u32>) -> Result<Option<Vec<i64>>, String> {
+    if template.len() != tokens.len() {
+        return Ok(None);
+    }
+
+    let mut values = Vec::new();
+    for (expected, token) in template.iter().zip(tokens.iter()) {
+        if parse_register(expected).is_some() {
+            match parse_register(token) {
+                Some(reg) => values.push(reg as i64),
+                None => return Ok(None),
+            };
+        } else if expected.starts_with('#') {
+            if let Some(number) = token.strip_prefix('#') {
+                values.push(parse_number(number)
+                            .ok_or_else(|| format!("invalid immediate {}", token))?);
+            } else if token.starts_with(|c: char| c.is_alphabetic() || c == '_') &&
+                parse_register(token).is_none() {
+                let addr = labels.get(token)
+                    .ok_or_else(|| format!("undefined label {}", token))?;
+                values.push(*addr as i64);
+            } else {
+                return Ok(None);
+            }
+        } else if expected != token {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(values))
+}
+
+/// Encodes one instruction from its tokens.
+fn encode(tokens: &[String], templates: &[Template],
+          labels: &HashMap<String, u32>) -> Result<u32, String> {
+    let mnemonic = &tokens[0];
+
+    for template in templates {
+        // Conditional mnemonics are written with a condition code suffix
+        let cond = match mnemonic.strip_prefix(template.tokens[0].as_str()) {
+            Some("") => Some(0),
+            Some(suffix) if template.conditional =>
+                (1..32).find(|code| ConditionCodes::suffix(*code) == Some(suffix)),
+            _ => None,
+        };
+        let cond = match cond {
+            Some(cond) => cond,
+            None => continue,
+        };
+        let values = match match_operands(&template.tokens[1..], &tokens[1..], labels)? {
+            Some(values) => values,
+            None => continue,
+        };
+
+        let mut word = template.word;
+        word.set_bits(0..=4, cond);
+
+        let fields: Vec<_> = operand_fields(word).into_iter()
+            .filter(|(name, _bits)| *name != "cond")
+            .collect();
+        if fields.len() != values.len() {
+            return Err(format!("{} has {} operand fields but {} operands",
+                               mnemonic, fields.len(), values.len()));
+        }
+
+        for ((name, bits), value) in fields.into_iter().zip(values) {
+            let width = bits.end() - bits.start() + 1;
+            let signed = bits == SIGNED_IMMEDIATE && immediate_field(word) == Some(SIGNED_IMMEDIATE);
+            let (min, max) = match signed {
+                true => (-(1i64 << (width - 1)), (1i64 << (width - 1)) - 1),
+                false => (0, (1i64 << width) - 1),
+            };
+            if value < min || value > max {
+                return Err(format!("{} does not fit in the {} bit {} field of {}",
+                                   value, width, name, mnemonic));
+            }
+
+            word.set_bits(bits, (value as u32).get_bits(0..width));
+        }
+
+        return Ok(word);
+    }
+
+    Err(format!("no instruction matches \"{}\"", tokens.join(" ")))
+}
+
+/// Assembles lines of assembly into instruction words, one word per
+/// instruction starting at address 0. Instructions are written the way
+/// disassemble() shows them, like "LOAD R20, [R6]", "ADDUI R2, R10, #2" or
+/// "JMPLT #4". A line may start with a label like "loop:", which names the
+/// address of the line's instruction, or of the next instruction if the line
+/// has none. Immediates may be written as a label, which can be defined before
+/// or after it is used. Text after a ';' is a comment.
+pub fn assemble(source: &str) -> Result<Vec<u32>, String> {
+    let templates = templates();
+
+    // Find every label first, so labels can be used before they are defined
+    let mut labels: HashMap<String, u32> = HashMap::new();
+    let mut lines: Vec<(usize, Vec<String>)> = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let code = line.split(';').next().unwrap_or("");
+        let mut tokens = tokenize(code);
+
+        if let Some(label) = tokens.first().and_then(|t| t.strip_suffix(':')) {
+            if label.is_empty() || parse_register(label).is_some() {
+                return Err(format!("Line {}: invalid label name \"{}\"", i + 1, label));
+            }
+            if labels.insert(label.to_string(), lines.len() as u32).is_some() {
+                return Err(format!("Line {}: label {} is already defined", i + 1, label));
+            }
+            tokens.remove(0);
+        }
+
+        if !tokens.is_empty() {
+            lines.push((i + 1, tokens));
+        }
+    }
+
+    lines.iter()
+        .map(|(line, tokens)| encode(tokens, &templates, &labels)
+             .map_err(|e| format!("Line {}: {}", line, e)))
+        .collect()
+}
+
+/// Writes instruction words to a file as big endian 32 bit words, the format
+/// DRAM::load_from_reader() reads.
+pub fn write_program(words: &[u32], path: &str) -> Result<(), String> {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+
+    fs::write(path, bytes)
+        .map_err(|e| format!("Failed to write program file {}: {}", path, e))
+}
+
 /*
 fn main() {
     // Parse command line arguments
@@ -680,6 +957,8 @@ fn main() {
 mod tests {
     use super::*;
     use mockers::Scenario;
+    use crate::encoding::program;
+    use crate::memory::{DRAM,Memory};
     
     #[test]
     fn test_assembler() {
@@ -687,4 +966,80 @@ mod tests {
         let data = "LDR R6 R4\nADDU R4 R18 R2".as_bytes();
         assem.assemble(data);
     }
+
+    /// Tests that jumps to labels defined before and after them are resolved,
+    /// and that words match the instructions' encoding.
+    #[test]
+    fn test_assemble_labels() {
+        let source = "\
+start:  ADDUI R1, R1, #1   ; count
+        CMP R1, R2
+        JMPLT start
+        JMP end
+        NOOP
+end:
+        LOAD R20, [R6]
+        ADDSI R3, R4, #-2
+        HALT
+";
+        let words = assemble(source).expect("failed to assemble");
+
+        let expected = program![
+            add_ui_i(1, 1, 1),
+            cmp(1, 2),
+            jmp_lt_i(0),
+            jmp_i(5),
+            noop(),
+            load_rd(20, 6),
+        ];
+        assert_eq!(words[..expected.len()], expected[..]);
+        assert_eq!(disassemble(words[6]), Ok("ADDSI R3, R4, #-2".to_string()));
+        assert_eq!(words[7], crate::encoding::asm::halt());
+        assert_eq!(words.len(), 8);
+    }
+
+    /// Tests that assembling the disassembly of every instruction gives back
+    /// the instruction.
+    #[test]
+    fn test_assemble_disassembly() {
+        for template in templates() {
+            let mut word = template.word;
+            for (i, (_name, bits)) in operand_fields(word).into_iter().enumerate() {
+                let width = bits.end() - bits.start() + 1;
+                word.set_bits(bits, (i as u32 + 1).get_bits(0..width.min(3)));
+            }
+
+            let text = disassemble(word).expect("failed to disassemble");
+            assert_eq!(assemble(&text), Ok(vec![word]), "text={}", text);
+        }
+    }
+
+    /// Tests that lines which cannot be assembled are reported with their line
+    /// number.
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(assemble("NOOP\nJMP nowhere"), Err("Line 2: undefined label nowhere".to_string()));
+        assert!(assemble("ADDUI R1, R2, #512").unwrap_err().starts_with("Line 1: 512 does not fit"));
+        assert!(assemble("ADDSI R1, R2, #-129").is_err());
+        assert!(assemble("FROB R1").unwrap_err().starts_with("Line 1: no instruction matches"));
+        assert!(assemble("a: NOOP\na: HALT").is_err());
+    }
+
+    /// Tests that a written program loads into DRAM as the same words.
+    #[test]
+    fn test_write_program() {
+        let words = assemble("ADDUI R1, R0, #7\nHALT").expect("failed to assemble");
+        let path = std::env::temp_dir().join(format!("leg-program-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_program(&words, path).expect("failed to write program");
+
+        let mut dram = DRAM::new(0);
+        dram.load_from_file(path).expect("failed to load program");
+        fs::remove_file(path).expect("failed to remove program");
+
+        for (addr, word) in words.iter().enumerate() {
+            let (_wait, loaded) = dram.get(addr as u32).unwrap("get word");
+            assert_eq!(loaded, *word);
+        }
+    }
 }
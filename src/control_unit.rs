@@ -1,22 +1,404 @@
 use bit_field::BitField;
-
-use web_sys::console;
-use wasm_bindgen::JsValue;
+use serde_derive::{Serialize,Deserialize};
 
 use std::boxed::Box;
+use std::collections::{BTreeMap,HashMap,HashSet,VecDeque};
+use std::fs;
 use std::fmt;
 use std::cell::RefCell;
 use std::rc::Rc;
 
 use crate::result::SimResult;
-use crate::memory::{Memory,DRAM,DMCache,Registers,PC};
-use crate::instructions::{Instruction,InstructionT,
-    MemoryOp,AddrMode,Load,Store,Push,Pop,
-    ArithMode,ALUOp,Move,ArithSign,ArithUnsign,
-    Comp,AS,LS,LogicType,ThreeOpLogic,Not,
-    ControlOp,Jump,SIH,INT,RFI,Halt,Noop
+use crate::output::{Output,WebConsoleOutput};
+use crate::memory::{Memory,InspectableMemory,MemoryCheckpoint,DRAM,DMCache,Registers,RegisterLayout,
+                    SpecialRegister};
+use crate::encoding::{immediate_field,operand_fields,control_op,JUMP_IMMEDIATE};
+use crate::instructions::{Instruction,InstructionT,InterruptCodes,INTERRUPT_CODE_ADDR,
+    MemoryOp,AddrMode,LoadFaultPolicy,Load,Store,Push,Pop,LoadByte,StoreByte,
+    ArithMode,ALUOp,Move,CMov,ArithSign,ArithUnsign,
+    Comp,AS,LS,Rotate,LogicType,ThreeOpLogic,Not,
+    ControlOp,Jump,SIH,INT,RFI,Halt,Noop,Enter,Leave,CacheFlush,CacheInvalidate,
+    ideal_ipc,disassemble,register_defs_uses
 };
 
+/// Sources of interrupts queued with ControlUnit::queue_interrupt(). When
+/// interrupts from more than one source are pending the source listed first is
+/// taken first: Timer, then Keyboard. Interrupts from the same source are taken
+/// in the order they were queued.
+#[derive(Serialize,Deserialize,Copy,Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum InterruptSource {
+    Timer,
+    Keyboard,
+}
+
+/// Status of the program after ControlUnit::step() or run_to_cycle().
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Status {
+    /// The program is still running.
+    Continue,
+
+    /// The program finished running.
+    Halted,
+
+    /// The step stopped before fetching the instruction at a breakpoint, whose
+    /// address is embedded. Nothing ran in the step. The next step runs the
+    /// instruction instead of stopping at the breakpoint again.
+    BreakpointHit(u32),
+}
+
+/// A bit flipped by ControlUnit::schedule_bit_flip(), to simulate a single
+/// event upset.
+#[derive(Serialize,Deserialize,Copy,Clone,Debug,PartialEq)]
+pub enum FlipTarget {
+    /// Flips a bit of a register, given as the register index and the bit.
+    Register(usize, usize),
+
+    /// Flips a bit of the value at a memory address, given as the address and
+    /// the bit. The copy in the top memory level is flipped, without counting
+    /// an access or taking cycles.
+    Memory(u32, usize),
+}
+
+/// Pipeline stage where taken branches change the program counter. Younger
+/// instructions already in the pipeline are flushed, so resolving a branch in
+/// an earlier stage flushes fewer instructions.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum BranchResolveStage {
+    /// Branches which report a target with Instruction::branch_target() are
+    /// resolved after execute, flushing the fetched instruction. Conditions
+    /// read STS when the branch is executed. Other instructions which change
+    /// PC are resolved at write back.
+    Execute,
+
+    /// Taken branches and other instructions which change PC are resolved
+    /// after write back, flushing the access memory, execute and decode
+    /// stages.
+    WriteBack,
+}
+
+/// Determines what the pipeline does when an instruction fails to decode,
+/// including words whose type or operation code is not valid.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum DecodeErrorPolicy {
+    /// The decode error is returned, which stops the program.
+    Abort,
+
+    /// The instruction is replaced by a bubble and the decode error is written
+    /// to output as a warning. The program continues with the next
+    /// instruction.
+    Bubble,
+}
+
+/// Stands in for a fetched word which is not a valid instruction, so the
+/// error is reported by the decode stage.
+#[derive(Debug)]
+struct Undecodable {
+    error: String,
+}
+
+impl fmt::Display for Undecodable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Undecodable")
+    }
+}
+
+impl Instruction for Undecodable {
+    fn decode(&mut self, _instruction: u32, _registers: &Registers) -> SimResult<(), String> {
+        SimResult::Err(self.error.clone())
+    }
+
+    fn execute(&mut self) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    fn access_memory(&mut self, _memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    fn write_back(&mut self, _registers: &mut Registers) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+}
+
+/// Cycles each stage takes to process an instruction. Without the pipeline an
+/// instruction runs through the stages one after another, so each stage's
+/// latency is added as the stage runs. With the pipeline the stages run at
+/// the same time, so each cycle takes as long as the slowest stage, see
+/// cycle_time(). The fetch and access memory latencies are added on top of
+/// the cycles the memory takes.
+#[derive(Serialize,Deserialize,Copy,Clone,Debug,PartialEq)]
+pub struct StageLatencies {
+    pub fetch: u16,
+    pub decode: u16,
+    pub execute: u16,
+    pub access_mem: u16,
+    pub write_back: u16,
+}
+
+impl StageLatencies {
+    /// Returns the cycles one pipeline cycle takes, the latency of the slowest
+    /// stage.
+    pub fn cycle_time(&self) -> u32 {
+        [self.fetch, self.decode, self.execute, self.access_mem, self.write_back]
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(0) as u32
+    }
+}
+
+impl Default for StageLatencies {
+    /// Every stage takes one cycle.
+    fn default() -> StageLatencies {
+        StageLatencies{
+            fetch: 1,
+            decode: 1,
+            execute: 1,
+            access_mem: 1,
+            write_back: 1,
+        }
+    }
+}
+
+/// Predicts whether conditional branches are taken with a table of 2-bit
+/// saturating counters, indexed by the low bits of the branch's instruction
+/// number, its address divided by the instruction size. Counters of 2 and 3
+/// predict taken. Every counter starts weakly not taken.
+#[derive(Clone,Debug,PartialEq)]
+pub struct BranchPredictor {
+    counters: Vec<u8>,
+    index_bits: usize,
+
+    /// Size of an instruction, should be the control unit's instruction_size.
+    instruction_size: u32,
+}
+
+impl BranchPredictor {
+    /// Largest number of index bits a predictor can have, which keeps the
+    /// counter table to 64K entries.
+    pub const MAX_INDEX_BITS: usize = 16;
+
+    /// Creates a predictor with 2^index_bits counters. Fails if index_bits is
+    /// not between 1 and MAX_INDEX_BITS, or instruction_size is 0.
+    pub fn new(index_bits: usize, instruction_size: u32) -> Result<BranchPredictor, String> {
+        if index_bits == 0 || index_bits > BranchPredictor::MAX_INDEX_BITS {
+            return Err(format!("Branch predictor index bits must be between 1 and {}, was {}",
+                               BranchPredictor::MAX_INDEX_BITS, index_bits));
+        }
+        if instruction_size == 0 {
+            return Err("Branch predictor instruction size cannot be 0".to_string());
+        }
+
+        Ok(BranchPredictor{
+            counters: vec![1; 1 << index_bits],
+            index_bits,
+            instruction_size,
+        })
+    }
+
+    /// Returns the counter of the branch at pc.
+    fn index(&self, pc: u32) -> usize {
+        (pc / self.instruction_size).get_bits(0..self.index_bits) as usize
+    }
+
+    /// Returns true if the branch at pc is predicted to be taken.
+    pub fn predict(&self, pc: u32) -> bool {
+        self.counters[self.index(pc)] >= 2
+    }
+
+    /// Moves the counter of the branch at pc towards the branch's outcome.
+    pub fn update(&mut self, pc: u32, taken: bool) {
+        let i = self.index(pc);
+        self.counters[i] = match taken {
+            true => (self.counters[i] + 1).min(3),
+            false => self.counters[i].saturating_sub(1),
+        };
+    }
+}
+
+/// State of a control unit saved by ControlUnit::save_checkpoint(). Options
+/// which are not part of the program's state are not saved.
+#[derive(Serialize,Deserialize)]
+struct Checkpoint {
+    registers: Vec<u32>,
+    layout: RegisterLayout,
+    interrupt_active: bool,
+    pending_interrupts: Vec<(InterruptSource, u32)>,
+    bit_flips: Vec<(u32, FlipTarget)>,
+    stack_grows_down: bool,
+    cycle_count: u32,
+    instructions_retired: u64,
+    halt_encountered: bool,
+    cache_enabled: bool,
+    instruction_size: u32,
+    stage_latencies: StageLatencies,
+    stack_start: Option<u32>,
+    stack_deepest: u32,
+    dram: MemoryCheckpoint,
+
+    /// None if the control unit's cache is its DRAM.
+    cache: Option<MemoryCheckpoint>,
+}
+
+/// An instruction in a pipeline stage, recorded in ControlUnit::stage_trace.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct StagedInstruction {
+    /// Number of instructions fetched before this one, which tells apart
+    /// instructions fetched from the same address more than once.
+    pub id: u64,
+
+    /// Address the instruction was fetched from.
+    pub pc: u32,
+
+    /// Bits of the instruction.
+    pub instruction: u32,
+}
+
+/// Arrangement of the memories instructions and data are accessed through.
+pub enum MemoryTopology {
+    /// Instructions and data share one memory, the cache or DRAM.
+    Unified,
+
+    /// Instructions are fetched from their own memory, which is loaded
+    /// separately. Data accesses use the cache or DRAM, so stores never change
+    /// instructions.
+    Harvard(Rc<RefCell<dyn Memory<u32, u32>>>),
+}
+
+/// One instruction retired while ControlUnit::tracing is set.
+#[derive(Clone,Debug,PartialEq)]
+pub struct TraceEntry {
+    /// Address the instruction was fetched from.
+    pub pc: u32,
+
+    /// Bits of the instruction.
+    pub instruction: u32,
+
+    /// Registers the instruction's write back changed, as indexes and new
+    /// values in index order.
+    pub changes: Vec<(usize, u32)>,
+
+    /// Disassembly of the instruction, "???" if it can not be disassembled.
+    pub disassembly: String,
+}
+
+impl TraceEntry {
+    /// Creates an entry, finding the changes by comparing the registers
+    /// before and after write back.
+    fn new(pc: u32, instruction: u32, disassembly: String, before: &Registers,
+           after: &Registers) -> TraceEntry {
+        let changes = before.file.iter().zip(after.file.iter())
+            .enumerate()
+            .filter(|(_i, (old, new))| old != new)
+            .map(|(i, (_old, new))| (i, *new))
+            .collect();
+
+        TraceEntry{
+            pc,
+            instruction,
+            changes,
+            disassembly,
+        }
+    }
+
+    /// Parses an entry from a line in the format written by Display. Lines
+    /// without " ; " and the disassembly, as written before traces recorded
+    /// it, are disassembled from the instruction.
+    pub fn parse(line: &str) -> Result<TraceEntry, String> {
+        let parse_hex = |field: Option<&str>, name: &str| -> Result<u32, String> {
+            let field = match field {
+                Some(f) => f,
+                None => return Err(format!("missing {}", name)),
+            };
+
+            u32::from_str_radix(field.trim_start_matches("0x"), 16)
+                .map_err(|e| format!("invalid {} \"{}\": {}", name, field, e))
+        };
+
+        let (line, disassembly) = match line.split_once(" ; ") {
+            Some((line, disassembly)) => (line, Some(disassembly)),
+            None => (line, None),
+        };
+
+        let mut fields = line.split_whitespace();
+        let pc = parse_hex(fields.next(), "PC")?;
+        let instruction = parse_hex(fields.next(), "instruction")?;
+
+        let mut changes: Vec<(usize, u32)> = Vec::new();
+        for field in fields {
+            let parsed = field.strip_prefix('R')
+                .and_then(|f| f.split_once('='))
+                .and_then(|(idx, value)| Some((idx.parse::<usize>().ok()?,
+                                               value.parse::<u32>().ok()?)));
+            match parsed {
+                Some(change) => changes.push(change),
+                None => return Err(format!("invalid register change \"{}\"",
+                                           field)),
+            }
+        }
+
+        Ok(TraceEntry{
+            pc,
+            instruction,
+            changes,
+            disassembly: match disassembly {
+                Some(d) => d.to_string(),
+                None => disassemble(instruction)
+                    .unwrap_or_else(|_e| "???".to_string()),
+            },
+        })
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    /// Formats the entry as one line: the PC and the instruction in hex, each
+    /// change as R<index>=<value>, then " ; " and the disassembly.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x} {:#010x}", self.pc, self.instruction)?;
+
+        for (idx, value) in &self.changes {
+            write!(f, " R{}={}", idx, value)?;
+        }
+
+        write!(f, " ; {}", self.disassembly)
+    }
+}
+
+/// Reason a run did not match a recorded trace.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Divergence {
+    /// The trace file could not be read or parsed.
+    InvalidTrace(String),
+
+    /// The first retired instruction which differs. index is its position in
+    /// the traces. expected is None if the run retired more instructions than
+    /// the trace holds, actual is None if it retired fewer.
+    Mismatch {
+        index: usize,
+        expected: Option<TraceEntry>,
+        actual: Option<TraceEntry>,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |entry: &Option<TraceEntry>| match entry {
+            Some(e) => format!("{}", e),
+            None => "nothing".to_string(),
+        };
+        
+        match self {
+            Divergence::InvalidTrace(e) => write!(f, "invalid trace: {}", e),
+            Divergence::Mismatch{index, expected, actual} => write!(
+                f, "instruction {} differs: expected {}, got {}", index,
+                show(expected), show(actual)),
+        }
+    }
+}
+
+/// Creates an instruction registered with ControlUnit::register_instruction().
+pub type InstructionFactory = Box<dyn Fn() -> Box<dyn Instruction>>;
+
 /// Responsible for running instructions.
 pub struct ControlUnit {
     /// Indicates if a pipeline should be used.
@@ -24,9 +406,202 @@ pub struct ControlUnit {
 
     /// Indicates if the cache should be used.
     pub cache_enabled: bool,
+
+    /// Determines which memory instructions are fetched from.
+    pub topology: MemoryTopology,
     
     /// Processor cycle counter.
     pub cycle_count: u32,
+
+    /// Number of instructions which finished the write back stage.
+    pub instructions_retired: u64,
+
+    /// Number of addresses each instruction occupies. The program counter
+    /// advances by this amount after each fetch. 1 if memory is word
+    /// addressed, 4 if memory is byte addressed.
+    pub instruction_size: u32,
+
+    /// If true jumping to an address which is not a multiple of
+    /// instruction_size is an error.
+    pub strict_jump_alignment: bool,
+
+    /// If true Push decrements the stack pointer and Pop increments it. If false
+    /// the stack grows up and the directions are reversed.
+    pub stack_grows_down: bool,
+
+    /// Interrupts which have not been taken yet, in the order they were queued.
+    pending_interrupts: Vec<(InterruptSource, u32)>,
+
+    /// Bit flips which have not been applied yet, with the cycle they are
+    /// applied at.
+    bit_flips: Vec<(u32, FlipTarget)>,
+
+    /// Addresses of instructions where step() stops before they are fetched.
+    breakpoints: HashSet<u32>,
+
+    /// Address of the breakpoint step() last stopped at. Steps do not stop at
+    /// it again until PC moves off it, so stalls which keep PC on the address
+    /// do not stop again. None once PC has moved on.
+    breakpoint_hit: Option<u32>,
+
+    /// Cycles between checkpoints and the prefix of their paths, see
+    /// enable_checkpointing(). None if checkpoints are not taken.
+    checkpointing: Option<(u32, String)>,
+
+    /// Cycle count at which the next checkpoint is taken.
+    next_checkpoint: u32,
+
+    /// Path of the last checkpoint taken by enable_checkpointing().
+    pub last_checkpoint: Option<String>,
+
+    /// Stack pointer when the first step ran. None before the first step.
+    stack_start: Option<u32>,
+
+    /// Deepest stack pointer reached, the lowest if the stack grows down and
+    /// the highest otherwise.
+    stack_deepest: u32,
+
+    /// Determines what loads do when their memory access fails.
+    pub load_fault_policy: LoadFaultPolicy,
+
+    /// Stage where taken branches are resolved when the pipeline is enabled.
+    pub branch_resolve_stage: BranchResolveStage,
+
+    /// Determines what the pipeline does when an instruction fails to decode.
+    /// Without the pipeline decode errors always stop the program.
+    pub decode_error_policy: DecodeErrorPolicy,
+
+    /// Number of instructions after a taken branch which are executed instead
+    /// of flushed when the pipeline is enabled. Without the pipeline branches
+    /// have no delay slots.
+    pub branch_delay_slots: usize,
+
+    /// Predicts conditional immediate jumps when the pipeline is enabled, so
+    /// the target of a branch predicted taken is fetched right after it. None
+    /// always fetches the next instruction. Predictions are checked at write
+    /// back. Only used without delay slots.
+    pub branch_predictor: Option<BranchPredictor>,
+
+    /// Number of conditional branches the branch predictor got wrong.
+    pub mispredictions: u64,
+
+    /// Numbers of the fetched branches predicted to be taken, which have not
+    /// been resolved yet.
+    predicted_taken: HashSet<u64>,
+
+    /// Number of miss status holding registers, the number of cache misses
+    /// which can be outstanding at once when the pipeline is enabled. While a
+    /// miss is outstanding later accesses which hit continue without waiting
+    /// for it. Instructions wait for an outstanding miss when they read the
+    /// register it loads or access the same address. 0 makes every miss stall
+    /// the pipeline until it is serviced.
+    pub mshr_count: usize,
+
+    /// If true results which are known but not written back yet are forwarded
+    /// to the instruction being decoded, see Instruction::pending_result(). An
+    /// instruction only stalls for a register whose value is not known yet,
+    /// like the destination of a load which has not accessed memory. If false
+    /// it stalls until the register is written back.
+    pub forwarding_enabled: bool,
+
+    /// Misses being serviced without stalling the pipeline, see mshr_count.
+    outstanding_misses: Vec<OutstandingMiss>,
+
+    /// Target of a taken branch and the number of its delay slots which have
+    /// not been fetched yet. PC is set to the target once they are fetched.
+    delayed_branch: Option<(u32, usize)>,
+
+    /// True if the instruction in each stage was resolved as a branch in the
+    /// execute stage.
+    execute_resolved: bool,
+    access_mem_resolved: bool,
+
+    /// Cycles added each time an instruction advances from one pipeline stage
+    /// to the next. Models the cost of the pipeline registers.
+    pub latch_delay: u16,
+
+    /// Cycles each stage takes, see StageLatencies.
+    pub stage_latencies: StageLatencies,
+
+    /// Maximum number of consecutive cycles the pipeline may insert bubbles
+    /// without retiring an instruction. Exceeding this is reported as a
+    /// deadlock error.
+    pub max_consecutive_stalls: u32,
+
+    /// Number of consecutive cycles the pipeline has inserted bubbles without
+    /// retiring an instruction.
+    pub consecutive_stalls: u32,
+
+    /// Maximum number of steps which can be undone by step_back().
+    pub undo_limit: usize,
+
+    /// Information needed to undo recent steps, oldest first.
+    undo_history: VecDeque<UndoRecord>,
+
+    /// Factories for instructions added by register_instruction(), keyed by
+    /// instruction type value and operation code.
+    custom_instructions: HashMap<(u32, u32), InstructionFactory>,
+
+    /// Number of memory accesses which took each number of wait cycles, see
+    /// latency_histogram().
+    latency_counts: HashMap<u16, u64>,
+
+    /// If set reset_registers() fills the general purpose registers with this
+    /// value instead of 0. Only reset_registers() applies it, new() starts
+    /// with every general purpose register 0 since the field is not set
+    /// yet. Set it and call reset_registers() before running to fill them.
+    pub register_fill: Option<u32>,
+
+    /// Where status and debug messages are written. Defaults to the browser
+    /// console.
+    pub output: Rc<RefCell<dyn Output>>,
+
+    /// If true a warning is written to output when a jump to the link
+    /// register goes to address 0, the value LR holds before any subroutine
+    /// call.
+    pub warn_return_before_call: bool,
+
+    /// If true R0 always reads as 0 and writes to it are discarded.
+    pub zero_register_hardwired: bool,
+
+    /// If true an instruction other than a control instruction which writes
+    /// PC, like a Move into PC, is treated as a taken branch to the value
+    /// written. If false such writes are an error.
+    pub pc_write_is_branch: bool,
+
+    /// If true and zero_register_hardwired is set a warning is written to
+    /// output when an instruction writes a value other than 0 to R0.
+    pub warn_zero_register_write: bool,
+
+    /// Number of times an instruction was fetched from each address.
+    pub pc_counts: HashMap<u32, u64>,
+
+    /// Cycles taken by the instructions at each address, summed over every
+    /// time they ran. Without the pipeline each step's cycles are added to the
+    /// instruction it ran. With the pipeline each step's cycles are added to
+    /// the instruction which retired in it, steps which retire nothing are not
+    /// counted.
+    pub pc_cycles: HashMap<u32, u64>,
+
+    /// If true each retired instruction is added to trace.
+    pub tracing: bool,
+
+    /// Instructions retired while tracing was set, oldest first.
+    pub trace: Vec<TraceEntry>,
+
+    /// Instructions in each pipeline stage when each step ended, for every
+    /// step made with the pipeline while tracing was set, oldest first. Stages
+    /// are in the order fetch, decode, execute, access memory, write back. See
+    /// pipeline_diagram().
+    pub stage_trace: Vec<[Option<StagedInstruction>; 5]>,
+
+    /// Disassembly of each instruction word traced so far, so words retired
+    /// repeatedly are only disassembled once.
+    disassembly_cache: HashMap<u32, String>,
+
+    /// Test hook which makes the decode stage stall forever.
+    #[cfg(test)]
+    force_stall: bool,
     
     /// Holds computation registers.
     pub registers: Registers,
@@ -53,6 +628,25 @@ pub struct ControlUnit {
     /// Bits associated with fetch stage of pipeline.
     fetch_instruction_bits: u32,
 
+    /// Address, bits and number of the instruction in the fetch, decode,
+    /// execute and access memory stages, used to trace instructions when they
+    /// retire. Instructions are numbered by fetch_count when fetched.
+    fetch_word: (u32, u32, u64),
+    decode_word: (u32, u32, u64),
+    execute_word: (u32, u32, u64),
+    access_mem_word: (u32, u32, u64),
+
+    /// Address, bits and number of the instruction in the write back stage,
+    /// shown by Display.
+    write_back_word: (u32, u32, u64),
+
+    /// Address and bits of the instruction the last step without the pipeline
+    /// ran, shown by Display.
+    no_pipeline_word: (u32, u32),
+
+    /// Number of instructions fetched with the pipeline.
+    fetch_count: u64,
+
     /// Instruction currently in the decode stage of the pipeline.
     pub decode_instruction: Option<Box<dyn Instruction>>,
 
@@ -66,6 +660,136 @@ pub struct ControlUnit {
     pub write_back_instruction: Option<Box<dyn Instruction>>,
 }
 
+/// State before a step which is needed to undo it.
+struct UndoRecord {
+    registers: Registers,
+    cycle_count: u32,
+    instructions_retired: u64,
+    halt_encountered: bool,
+    trace_len: usize,
+
+    /// Interrupts queued before the step, the step may take one of them.
+    pending_interrupts: Vec<(InterruptSource, u32)>,
+
+    /// Memory writes made by the step in order, as addresses and the values
+    /// they held before the write.
+    writes: Vec<(u32, u32)>,
+}
+
+/// A cache miss serviced in a miss status holding register.
+struct OutstandingMiss {
+    /// Address of the first word of the line being filled, see
+    /// Memory::line_address().
+    line: u32,
+
+    /// Register the access loads into, if any.
+    dest: Option<usize>,
+
+    /// Cycle when the access completes.
+    ready: u32,
+}
+
+/// Passes accesses through to a memory while recording the value each write
+/// overwrites. The old value is read through the memory, so the read may
+/// change the state of a cache.
+struct UndoMemory {
+    writes: Vec<(u32, u32)>,
+    base: Rc<RefCell<dyn Memory<u32, u32>>>,
+}
+
+impl Memory<u32, u32> for UndoMemory {
+    fn get(&mut self, address: u32) -> SimResult<u32, String> {
+        self.base.borrow_mut().get(address)
+    }
+
+    fn set(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        // Peeked so recording the old value does not count as an access
+        let old = match self.base.borrow().peek(address) {
+            SimResult::Err(e) => return SimResult::Err(
+                format!("failed to read value to overwrite for undo: {}", e)),
+            SimResult::Wait(_wait, old) => old,
+        };
+        
+        self.writes.push((address, old));
+        self.base.borrow_mut().set(address, data)
+    }
+
+    fn peek(&self, address: u32) -> SimResult<u32, String> {
+        self.base.borrow().peek(address)
+    }
+
+    fn poke(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        self.base.borrow_mut().poke(address, data)
+    }
+
+    fn flush(&mut self) -> SimResult<(), String> {
+        self.base.borrow_mut().flush()
+    }
+
+    fn invalidate(&mut self) -> SimResult<(), String> {
+        self.base.borrow_mut().invalidate()
+    }
+
+    fn hit_latency(&self) -> Option<u16> {
+        self.base.borrow().hit_latency()
+    }
+
+    fn line_address(&self, address: u32) -> u32 {
+        self.base.borrow().line_address(address)
+    }
+}
+
+/// Passes accesses through to a memory while recording the number of wait
+/// cycles of each access.
+struct LatencyMemory {
+    latencies: Vec<u16>,
+    base: Rc<RefCell<dyn Memory<u32, u32>>>,
+}
+
+impl Memory<u32, u32> for LatencyMemory {
+    fn get(&mut self, address: u32) -> SimResult<u32, String> {
+        let res = self.base.borrow_mut().get(address);
+        if let SimResult::Wait(wait, _v) = res {
+            self.latencies.push(wait);
+        }
+
+        res
+    }
+
+    fn set(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        let res = self.base.borrow_mut().set(address, data);
+        if let SimResult::Wait(wait, _v) = res {
+            self.latencies.push(wait);
+        }
+
+        res
+    }
+
+    fn peek(&self, address: u32) -> SimResult<u32, String> {
+        self.base.borrow().peek(address)
+    }
+
+    fn poke(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        self.base.borrow_mut().poke(address, data)
+    }
+
+    fn flush(&mut self) -> SimResult<(), String> {
+        self.base.borrow_mut().flush()
+    }
+
+    fn invalidate(&mut self) -> SimResult<(), String> {
+        self.base.borrow_mut().invalidate()
+    }
+
+    fn hit_latency(&self) -> Option<u16> {
+        self.base.borrow().hit_latency()
+    }
+
+    fn line_address(&self, address: u32) -> u32 {
+        self.base.borrow().line_address(address)
+    }
+}
+
 /// Prepends 4 spaces to every line.
 fn indent(src: String) -> String {
     let mut out = String::new();
@@ -87,19 +811,31 @@ fn indent(src: String) -> String {
 
 impl fmt::Display for ControlUnit {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Instructions are shown as the address and disassembly of their bits
+        let stage = |instruction: &Option<Box<dyn Instruction>>, (pc, bits): (u32, u32)| {
+            match instruction {
+                None => "None".to_string(),
+                Some(_inst) => format!("{:#06x} {}", pc,
+                                       disassemble(bits).unwrap_or_else(|_e| "???".to_string())),
+            }
+        };
+        let word = |(pc, bits, _id): (u32, u32, u64)| (pc, bits);
+
         let instructions_str =  match self.pipeline_enabled {
             true => format!("\
 Instructions:
-    Fetch        : {:?}
-    Decode       : {:?}
-    Execute      : {:?}
-    Access Memory: {:?}
-    Write Back   : {:?}",
-               self.fetch_instruction, self.decode_instruction,
-               self.execute_instruction, self.access_mem_instruction,
-               self.write_back_instruction),
+    Fetch        : {}
+    Decode       : {}
+    Execute      : {}
+    Access Memory: {}
+    Write Back   : {}",
+               stage(&self.fetch_instruction, word(self.fetch_word)),
+               stage(&self.decode_instruction, word(self.decode_word)),
+               stage(&self.execute_instruction, word(self.execute_word)),
+               stage(&self.access_mem_instruction, word(self.access_mem_word)),
+               stage(&self.write_back_instruction, word(self.write_back_word))),
             false => format!("\
-Instruction : {:?}", self.no_pipeline_instruction),
+Instruction : {}", stage(&self.no_pipeline_instruction, self.no_pipeline_word)),
         };
         
         write!(f, "\
@@ -107,23 +843,77 @@ Pipeline   : {}
 Cache      : {}
 Halted     : {}
 Cycle Count: {}
+Retired    : {}
+IPC        : {:.3}
+Mispredicts: {}
 Registers  :
 {}
 {}",
                self.pipeline_enabled, self.cache_enabled, self.halt_encountered,
-               self.cycle_count, indent(format!("{}", self.registers)),
+               self.cycle_count, self.instructions_retired, self.ipc(),
+               self.mispredictions, indent(format!("{}", self.registers)),
                instructions_str)
     }
 }
 
 impl ControlUnit {
     /// Creates a new ControlUnit.
-    pub fn new(dram: Rc<RefCell<dyn Memory<u32, u32>>>, cache: Rc<RefCell<dyn Memory<u32, u32>>>) -> ControlUnit {
+    pub fn new(dram: Rc<RefCell<dyn Memory<u32, u32>>>, cache: Rc<RefCell<dyn Memory<u32, u32>>>,
+               stage_latencies: StageLatencies) -> ControlUnit {
         ControlUnit{
             pipeline_enabled: true,
             cache_enabled: true,
+            topology: MemoryTopology::Unified,
             cycle_count: 0,
-            registers: Registers::new(),
+            instructions_retired: 0,
+            instruction_size: 1,
+            strict_jump_alignment: false,
+            stack_grows_down: true,
+            pending_interrupts: Vec::new(),
+            bit_flips: Vec::new(),
+            breakpoints: HashSet::new(),
+            breakpoint_hit: None,
+            checkpointing: None,
+            next_checkpoint: 0,
+            last_checkpoint: None,
+            stack_start: None,
+            stack_deepest: 0,
+            load_fault_policy: LoadFaultPolicy::Abort,
+            latch_delay: 0,
+            stage_latencies,
+            max_consecutive_stalls: 100,
+            consecutive_stalls: 0,
+            undo_limit: 100,
+            undo_history: VecDeque::new(),
+            custom_instructions: HashMap::new(),
+            latency_counts: HashMap::new(),
+            output: Rc::new(RefCell::new(WebConsoleOutput{})),
+            register_fill: None,
+            warn_return_before_call: false,
+            zero_register_hardwired: false,
+            pc_write_is_branch: true,
+            warn_zero_register_write: false,
+            pc_counts: HashMap::new(),
+            pc_cycles: HashMap::new(),
+            tracing: false,
+            stage_trace: Vec::new(),
+            trace: Vec::new(),
+            disassembly_cache: HashMap::new(),
+            branch_resolve_stage: BranchResolveStage::WriteBack,
+            branch_delay_slots: 0,
+            branch_predictor: None,
+            mispredictions: 0,
+            predicted_taken: HashSet::new(),
+            mshr_count: 0,
+            forwarding_enabled: true,
+            outstanding_misses: Vec::new(),
+            decode_error_policy: DecodeErrorPolicy::Abort,
+            delayed_branch: None,
+            execute_resolved: false,
+            access_mem_resolved: false,
+            #[cfg(test)]
+            force_stall: false,
+            registers: ControlUnit::initial_registers(Registers::new()),
             dram: dram,
             cache: cache,
             first_instruction_loaded: false,
@@ -131,6 +921,13 @@ impl ControlUnit {
             no_pipeline_instruction: None,
             fetch_instruction: None,
             fetch_instruction_bits: 0,
+            fetch_word: (0, 0, 0),
+            decode_word: (0, 0, 0),
+            execute_word: (0, 0, 0),
+            access_mem_word: (0, 0, 0),
+            write_back_word: (0, 0, 0),
+            no_pipeline_word: (0, 0),
+            fetch_count: 0,
             decode_instruction: None,
             execute_instruction: None,
             access_mem_instruction: None,
@@ -138,65 +935,538 @@ impl ControlUnit {
         }
     }
     
+    /// Sets registers created with every register 0 to their initial values.
+    /// IHDLR is set to InterruptCodes::NOT_SET_INITIAL to indicate no handler
+    /// is set.
+    fn initial_registers(mut registers: Registers) -> Registers {
+        registers[SpecialRegister::IHDLR] = InterruptCodes::NOT_SET_INITIAL.value() as u32;
+
+        registers
+    }
+
+    /// Sets the registers to their initial values, keeping the register
+    /// layout. General purpose registers are set to register_fill if it is
+    /// set.
+    pub fn reset_registers(&mut self) {
+        self.registers = ControlUnit::initial_registers(
+            Registers::new_with_layout(self.registers.layout));
+
+        if let Some(value) = self.register_fill {
+            self.registers.fill_general_purpose(value);
+        }
+
+        if self.zero_register_hardwired {
+            self.registers[0] = 0;
+        }
+    }
+    
     /// Step one instruction through the processor. Stores resulting state in self.
     /// If Result::Ok is returned the value embedded indicates if the program
-    /// should keep running. False indicates it should not.
-    pub fn step(&mut self) -> Result<bool, String> {
+    /// should keep running, or if the step stopped at a breakpoint.
+    pub fn step(&mut self) -> Result<Status, String> {
+        // Stop before fetching from a breakpoint, unless PC has not left the
+        // breakpoint last stopped at
+        let pc = self.registers[SpecialRegister::PC];
+        if self.breakpoint_hit != Some(pc) {
+            self.breakpoint_hit = None;
+        }
+        if self.breakpoint_hit.is_none() && self.breakpoints.contains(&pc) &&
+            !self.halt_encountered && self.program_is_running() {
+            self.breakpoint_hit = Some(pc);
+            return Ok(Status::BreakpointHit(pc));
+        }
+
         self.first_instruction_loaded = true;
 
-        let memory = match self.cache_enabled {
+        if self.stack_start.is_none() {
+            self.stack_start = Some(self.registers[SpecialRegister::SP]);
+            self.stack_deepest = self.registers[SpecialRegister::SP];
+        }
+
+        let mut memory = match self.cache_enabled {
             true => self.cache.clone(),
             false => self.dram.clone(),
         };
 
-        if self.pipeline_enabled {
-            self.step_pipeline(memory)
-        } else {
-            self.step_no_pipeline(memory)
-        }
-    }
+        self.apply_bit_flips(memory.clone())?;
 
-    /// Step one instruction through the processor without a pipeline. See step()
-    /// for return documentation.
-    pub fn step_no_pipeline(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> Result<bool, String> {
-        if self.halt_encountered {
-            return Ok(false);
+        // Record memory writes so steps without the pipeline can be undone
+        let undo_memory = Rc::new(RefCell::new(UndoMemory{
+            writes: vec![],
+            base: memory.clone(),
+        }));
+        if !self.pipeline_enabled && self.undo_limit > 0 {
+            memory = undo_memory.clone();
         }
-        
-        // Fetch instruction
-        let mut ibits: u32 = 0;
 
-        let mut no_pipeline_inst = match memory.clone().borrow_mut().get(self.registers[PC]) {
-            SimResult::Err(e) => return Err(
-                format!("Failed to retrieve instruction from address {}: {}",
-                        self.registers[PC], e)),
-            SimResult::Wait(wait, fetched_bits) => {
-                // Figure out which instruction the bits represent by
-                // looking at the type and operation code.
-                let icreate = self.instruction_factory(fetched_bits);
+        let latency_memory = Rc::new(RefCell::new(LatencyMemory{
+            latencies: vec![],
+            base: memory,
+        }));
 
-                // Set state
-                self.cycle_count += wait as u32;
-                ibits = fetched_bits;
+        let res = if self.pipeline_enabled {
+            self.step_pipeline(latency_memory.clone())
+        } else {
+            let record = UndoRecord{
+                registers: self.registers.clone(),
+                cycle_count: self.cycle_count,
+                instructions_retired: self.instructions_retired,
+                halt_encountered: self.halt_encountered,
+                trace_len: self.trace.len(),
+                pending_interrupts: self.pending_interrupts.clone(),
+                writes: vec![],
+            };
 
-                match icreate {
-                    Err(e) => return Err(format!("Failed to determine type of \
-                                                  instruction for bits {}: {}",
-                                                 fetched_bits, e)),
-                    Ok(v) => v,
+            let res = self.step_no_pipeline(latency_memory.clone());
+
+            if res.is_ok() && self.undo_limit > 0 &&
+                self.cycle_count != record.cycle_count {
+                self.undo_history.push_back(UndoRecord{
+                    writes: undo_memory.borrow_mut().writes.drain(..).collect(),
+                    ..record
+                });
+
+                while self.undo_history.len() > self.undo_limit {
+                    self.undo_history.pop_front();
+                }
+            }
+
+            res
+        };
+
+        for wait in latency_memory.borrow().latencies.iter() {
+            *self.latency_counts.entry(*wait).or_insert(0) += 1;
+        }
+
+        let sp = self.registers[SpecialRegister::SP];
+        self.stack_deepest = match self.stack_grows_down {
+            true => self.stack_deepest.min(sp),
+            false => self.stack_deepest.max(sp),
+        };
+
+        if let Ok(true) = res {
+            self.checkpoint_if_due()?;
+        }
+
+        if let Ok(false) = res {
+            // The program is not done until its outstanding misses are
+            if let Some(ready) = self.outstanding_misses.drain(..).map(|miss| miss.ready).max() {
+                self.cycle_count = self.cycle_count.max(ready);
+            }
+
+            // Dirty lines are only written back when evicted, so write the
+            // rest back for memory to show the program's final state. The
+            // write backs happen after the program and take no cycles.
+            if self.cache_enabled {
+                if let SimResult::Err(e) = self.cache.borrow_mut().flush() {
+                    return Err(format!("Failed to write back cache after \
+                                        program ended: {}", e));
+                }
+            }
+
+            self.output.borrow_mut().write(&format!(
+                "Program ended after {} cycles", self.cycle_count));
+        }
+
+        res.map(|keep_running| match keep_running {
+            true => Status::Continue,
+            false => Status::Halted,
+        })
+    }
+
+    /// Makes step() stop before the instruction at addr is fetched.
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Removes a breakpoint added by add_breakpoint().
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.remove(&addr);
+    }
+
+    /// Reverses the effects of the last step on registers and memory, and
+    /// restores cycle_count and the pending interrupts. Only steps made without the pipeline can be
+    /// undone, at most undo_limit steps are remembered.
+    pub fn step_back(&mut self) -> Result<(), String> {
+        if self.pipeline_enabled {
+            return Err("Cannot step back with the pipeline enabled".to_string());
+        }
+
+        let record = match self.undo_history.pop_back() {
+            Some(r) => r,
+            None => return Err("No steps to undo".to_string()),
+        };
+
+        let memory = match self.cache_enabled {
+            true => self.cache.clone(),
+            false => self.dram.clone(),
+        };
+
+        for (address, old) in record.writes.iter().rev() {
+            if let SimResult::Err(e) = memory.borrow_mut().set(*address, *old) {
+                return Err(format!("Failed to restore memory address {}: {}",
+                                   address, e));
+            }
+        }
+
+        let fetch_pc = record.registers[SpecialRegister::PC];
+        if let Some(count) = self.pc_counts.get_mut(&fetch_pc) {
+            *count -= 1;
+            if *count == 0 {
+                self.pc_counts.remove(&fetch_pc);
+            }
+        }
+        if let Some(cycles) = self.pc_cycles.get_mut(&fetch_pc) {
+            *cycles -= u64::from(self.cycle_count - record.cycle_count);
+            if *cycles == 0 {
+                self.pc_cycles.remove(&fetch_pc);
+            }
+        }
+        
+        self.registers = record.registers;
+        self.cycle_count = record.cycle_count;
+        self.instructions_retired = record.instructions_retired;
+        self.halt_encountered = record.halt_encountered;
+        self.trace.truncate(record.trace_len);
+        self.pending_interrupts = record.pending_interrupts;
+
+        // The program has not finished running after stepping back
+        self.no_pipeline_instruction = None;
+        self.first_instruction_loaded = false;
+
+        Ok(())
+    }
+
+    /// Steps the processor until cycle_count is at least target, the program
+    /// stops running or a breakpoint is hit. A single step can take many
+    /// cycles, so this stops at the first step which reaches or crosses target
+    /// and cycle_count may end up past target. See step() for return
+    /// documentation.
+    pub fn run_to_cycle(&mut self, target: u32) -> Result<Status, String> {
+        let mut status = match self.program_is_running() {
+            true => Status::Continue,
+            false => Status::Halted,
+        };
+
+        while status == Status::Continue && self.cycle_count < target {
+            status = self.step()?;
+        }
+
+        Ok(status)
+    }
+
+    /// Steps the processor until at least cycles more cycles have elapsed or
+    /// the program stops running. Used to advance a display by a number of
+    /// cycles per frame. See run_to_cycle() for how far past the target the
+    /// last step may go.
+    pub fn run_cycles(&mut self, cycles: u32) -> Result<Status, String> {
+        self.run_to_cycle(self.cycle_count.saturating_add(cycles))
+    }
+
+    /// Saves a checkpoint every cycles cycles while the program runs, at the
+    /// end of the first step which reaches each multiple of every. Checkpoints
+    /// are written to path_prefix followed by the cycle count and ".json", and
+    /// can be continued with resume_from(). An every of 0 stops checkpointing.
+    /// See save_checkpoint() for what is saved. Fails if the pipeline is
+    /// enabled, since checkpoints cannot be saved with it.
+    pub fn enable_checkpointing(&mut self, every: u32, path_prefix: &str) -> Result<(), String> {
+        if every > 0 && self.pipeline_enabled {
+            return Err("Failed to enable checkpointing: instructions in the \
+                        pipeline cannot be saved, disable the pipeline".to_string());
+        }
+
+        self.checkpointing = match every {
+            0 => None,
+            _ => Some((every, path_prefix.to_string())),
+        };
+        self.next_checkpoint = (self.cycle_count / every.max(1) + 1) * every;
+
+        Ok(())
+    }
+
+    /// Saves a checkpoint if checkpointing is enabled and one is due.
+    fn checkpoint_if_due(&mut self) -> Result<(), String> {
+        let (every, path_prefix) = match &self.checkpointing {
+            None => return Ok(()),
+            Some(checkpointing) => checkpointing.clone(),
+        };
+        if self.cycle_count < self.next_checkpoint {
+            return Ok(());
+        }
+
+        let path = format!("{}{}.json", path_prefix, self.cycle_count);
+        self.save_checkpoint(&path)?;
+        self.last_checkpoint = Some(path);
+        self.next_checkpoint = (self.cycle_count / every + 1) * every;
+
+        Ok(())
+    }
+
+    /// Writes the registers, cycle and instruction counts, DRAM and cache to a
+    /// file as JSON, so the program can be continued by resume_from(). The
+    /// pipeline holds partly run instructions which cannot be saved, so
+    /// checkpoints can only be taken with the pipeline disabled. Only DRAM and
+    /// DMCache memories can be saved.
+    pub fn save_checkpoint(&self, path: &str) -> Result<(), String> {
+        if self.pipeline_enabled {
+            return Err("Failed to save checkpoint: instructions in the pipeline \
+                        cannot be saved, disable the pipeline".to_string());
+        }
+
+        let dram = self.dram.borrow().checkpoint()
+            .ok_or_else(|| "Failed to save checkpoint: DRAM cannot be saved".to_string())?;
+        let cache = match Rc::ptr_eq(&self.dram, &self.cache) {
+            true => None,
+            false => Some(self.cache.borrow().checkpoint()
+                .ok_or_else(|| "Failed to save checkpoint: cache cannot be saved".to_string())?),
+        };
+
+        let checkpoint = Checkpoint{
+            registers: self.registers.file.to_vec(),
+            layout: self.registers.layout,
+            interrupt_active: self.registers.interrupt_active,
+            pending_interrupts: self.pending_interrupts.clone(),
+            bit_flips: self.bit_flips.clone(),
+            stack_grows_down: self.stack_grows_down,
+            cycle_count: self.cycle_count,
+            instructions_retired: self.instructions_retired,
+            halt_encountered: self.halt_encountered,
+            cache_enabled: self.cache_enabled,
+            instruction_size: self.instruction_size,
+            stage_latencies: self.stage_latencies,
+            stack_start: self.stack_start,
+            stack_deepest: self.stack_deepest,
+            dram,
+            cache,
+        };
+
+        let text = serde_json::to_string(&checkpoint)
+            .map_err(|e| format!("Failed to serialize checkpoint: {}", e))?;
+        fs::write(path, text)
+            .map_err(|e| format!("Failed to write checkpoint file {}: {}", path, e))
+    }
+
+    /// Creates a control unit from a checkpoint written by save_checkpoint().
+    /// Stepping it continues the program exactly where the checkpoint was
+    /// taken. The pipeline is disabled, other options have their defaults and
+    /// checkpointing is not enabled.
+    pub fn resume_from(path: &str) -> Result<ControlUnit, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read checkpoint file {}: {}", path, e))?;
+        let checkpoint: Checkpoint = serde_json::from_str(&text)
+            .map_err(|e| format!("Failed to parse checkpoint file {}: {}", path, e))?;
+
+        let dram = Rc::new(RefCell::new(DRAM::from_checkpoint(&checkpoint.dram)?));
+        let cache: Rc<RefCell<dyn Memory<u32, u32>>> = match &checkpoint.cache {
+            None => dram.clone(),
+            Some(cache) => Rc::new(RefCell::new(DMCache::from_checkpoint(cache, dram.clone())?)),
+        };
+
+        let mut cu = ControlUnit::new(dram, cache, checkpoint.stage_latencies);
+        if checkpoint.registers.len() != cu.registers.file.len() {
+            return Err(format!("Checkpoint file {} holds {} registers instead of {}",
+                               path, checkpoint.registers.len(), cu.registers.file.len()));
+        }
+        cu.registers.file.copy_from_slice(&checkpoint.registers);
+        cu.registers.layout = checkpoint.layout;
+        cu.registers.interrupt_active = checkpoint.interrupt_active;
+        cu.pending_interrupts = checkpoint.pending_interrupts;
+        cu.bit_flips = checkpoint.bit_flips;
+        cu.stack_grows_down = checkpoint.stack_grows_down;
+        cu.pipeline_enabled = false;
+        cu.cache_enabled = checkpoint.cache_enabled;
+        cu.cycle_count = checkpoint.cycle_count;
+        cu.instructions_retired = checkpoint.instructions_retired;
+        cu.halt_encountered = checkpoint.halt_encountered;
+        cu.instruction_size = checkpoint.instruction_size;
+        cu.stack_start = checkpoint.stack_start;
+        cu.stack_deepest = checkpoint.stack_deepest;
+
+        Ok(cu)
+    }
+
+    /// Queues an interrupt with a code. The interrupt is taken at the start of
+    /// a later step, once a handler is set and no interrupt is being handled.
+    /// See InterruptSource for the order pending interrupts are taken in.
+    pub fn queue_interrupt(&mut self, source: InterruptSource, code: u32) {
+        self.pending_interrupts.push((source, code));
+    }
+
+    /// Schedules a bit to be flipped once cycle_count reaches cycle. The bit
+    /// is flipped at the start of the first step which starts at or after
+    /// cycle, before any stage runs. Memory bits are flipped in the memory
+    /// instructions access, in the cache when it is enabled, and the flip
+    /// takes no cycles. Fails if the bit is not in a 32 bit word, or the
+    /// register does not exist.
+    pub fn schedule_bit_flip(&mut self, target: FlipTarget, cycle: u32) -> Result<(), String> {
+        let bit = match target {
+            FlipTarget::Register(reg, bit) => {
+                if reg >= self.registers.file.len() {
+                    return Err(format!("Cannot flip a bit of register {}, there are {} registers",
+                                       reg, self.registers.file.len()));
+                }
+                bit
+            },
+            FlipTarget::Memory(_addr, bit) => bit,
+        };
+        if bit >= u32::BITS as usize {
+            return Err(format!("Cannot flip bit {}, words have {} bits", bit, u32::BITS));
+        }
+
+        self.bit_flips.push((cycle, target));
+
+        Ok(())
+    }
+
+    /// Applies the scheduled bit flips whose cycle has been reached.
+    fn apply_bit_flips(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> Result<(), String> {
+        let cycle_count = self.cycle_count;
+        let (due, later) = self.bit_flips.drain(..)
+            .partition(|(cycle, _target)| *cycle <= cycle_count);
+        self.bit_flips = later;
+
+        for (_cycle, target) in due {
+            match target {
+                FlipTarget::Register(reg, bit) => {
+                    let value = self.registers[reg];
+                    self.registers[reg] = value ^ (1 << bit);
+                },
+                FlipTarget::Memory(addr, bit) => {
+                    let value = match memory.borrow().peek(addr) {
+                        SimResult::Err(e) => return Err(format!(
+                            "Failed to read address {} to flip bit {}: {}", addr, bit, e)),
+                        SimResult::Wait(_wait, value) => value,
+                    };
+                    if let SimResult::Err(e) = memory.borrow_mut().poke(addr, value ^ (1 << bit)) {
+                        return Err(format!("Failed to flip bit {} of address {}: {}",
+                                           bit, addr, e));
+                    }
+                },
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Returns the queued interrupts which have not been taken yet, as sources
+    /// and codes in the order they will be taken. Codes are returned as
+    /// queued, since they are not all InterruptCodes values.
+    pub fn pending_interrupts(&self) -> Vec<(InterruptSource, u32)> {
+        let mut pending = self.pending_interrupts.clone();
+
+        // The sort is stable, so interrupts from a source stay in queue order
+        pending.sort_by_key(|(source, _code)| *source);
+
+        pending
+    }
+
+    /// Takes the pending interrupt which comes first, if a handler is set and
+    /// no interrupt is being handled. Like the INT instruction the code is
+    /// stored at INTERRUPT_CODE_ADDR, PC is saved in INTLR,
+    /// Registers::interrupt_active is set and PC is set to the handler. With the pipeline
+    /// enabled instructions already in the pipeline still complete, step_pipeline()
+    /// only calls this once none of them can set PC.
+    fn take_interrupt(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> Result<(), String> {
+        if !self.interrupt_takeable() {
+            return Ok(());
+        }
+
+        // min_by_key returns the earliest queued of equal sources
+        let next = self.pending_interrupts.iter()
+            .enumerate()
+            .min_by_key(|(_i, (source, _code))| *source)
+            .map(|(i, _interrupt)| i);
+        let (_source, code) = match next {
+            None => return Ok(()),
+            Some(i) => self.pending_interrupts.remove(i),
+        };
+
+        match memory.borrow_mut().set(INTERRUPT_CODE_ADDR, code) {
+            SimResult::Err(e) => return Err(format!(
+                "Failed to store interrupt code {}: {}", code, e)),
+            SimResult::Wait(wait, _v) => self.cycle_count += wait as u32,
+        };
+
+        self.registers.interrupt_active = true;
+        self.registers[SpecialRegister::INTLR] = self.registers[SpecialRegister::PC];
+        self.registers[SpecialRegister::PC] = self.registers[SpecialRegister::IHDLR];
+
+        Ok(())
+    }
+
+    /// Returns true if an interrupt is pending, a handler is set and no
+    /// interrupt is being handled.
+    fn interrupt_takeable(&self) -> bool {
+        let handler_set = self.registers[SpecialRegister::IHDLR] !=
+            InterruptCodes::NOT_SET_INITIAL.value() as u32;
+
+        handler_set && !self.registers.interrupt_active && !self.pending_interrupts.is_empty()
+    }
+
+    /// Returns true if an instruction in the pipeline can still set PC when
+    /// it retires, or a delayed branch has not gone to its target yet.
+    fn control_in_flight(&self) -> bool {
+        let layout = &self.registers.layout;
+        let stages = [
+            (self.fetch_instruction.is_some(), self.fetch_word),
+            (self.decode_instruction.is_some(), self.decode_word),
+            (self.execute_instruction.is_some(), self.execute_word),
+            (self.access_mem_instruction.is_some(), self.access_mem_word),
+        ];
+
+        self.delayed_branch.is_some() || stages.iter()
+            .filter(|(occupied, _word)| *occupied)
+            .any(|(_occupied, (_pc, ibits, _id))| {
+                ibits.get_bits(5..=6) == InstructionT::Control.value() ||
+                    register_defs_uses(*ibits, layout).0.contains(&layout.pc)
+            })
+    }
+
+    /// Step one instruction through the processor without a pipeline. Returns
+    /// true if the program should keep running.
+    pub fn step_no_pipeline(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> Result<bool, String> {
+        if self.halt_encountered {
+            return Ok(false);
+        }
+
+        let start_cycles = self.cycle_count;
+        self.take_interrupt(memory.clone())?;
+        
+        // Fetch instruction
+        let mut ibits: u32 = 0;
+        let fetch_pc = self.registers[SpecialRegister::PC];
+        *self.pc_counts.entry(fetch_pc).or_insert(0) += 1;
+
+        let mut no_pipeline_inst = match self.fetch_memory(&memory).borrow_mut().get(self.registers[SpecialRegister::PC]) {
+            SimResult::Err(e) => return Err(
+                format!("Failed to retrieve instruction from address {}: {}",
+                        self.registers[SpecialRegister::PC], e)),
+            SimResult::Wait(wait, fetched_bits) => {
+                // Figure out which instruction the bits represent by
+                // looking at the type and operation code.
+                let icreate = self.instruction_factory(fetched_bits);
+
+                // Set state
+                self.cycle_count += wait as u32 + self.stage_latencies.fetch as u32;
+                ibits = fetched_bits;
+
+                match icreate {
+                    Err(e) => return Err(format!("Failed to determine type of \
+                                                  instruction for bits {}: {}",
+                                                 fetched_bits, e)),
+                    Ok(v) => v,
                 }
             },
         };
         
         // Decode instruction
-        match no_pipeline_inst.decode(self.fetch_instruction_bits,
-                                &self.registers) {
+        match no_pipeline_inst.decode(ibits, &self.registers) {
             SimResult::Err(e) => return Err(
                 format!("Failed to decode instruction: {}",
                         e)),
             SimResult::Wait(wait, _v) => {
                 // Update state
-                self.cycle_count += wait as u32;
+                self.cycle_count += wait as u32 + self.stage_latencies.decode as u32;
                 
             },
         };
@@ -207,7 +1477,7 @@ impl ControlUnit {
                                                      instruction: {}", e)),
             SimResult::Wait(wait, _v) => {
                 // Update state
-                self.cycle_count += wait as u32;
+                self.cycle_count += wait as u32 + self.stage_latencies.execute as u32;
             },
         };
 
@@ -218,37 +1488,73 @@ impl ControlUnit {
                         e)),
             SimResult::Wait(wait, _v) => {
                 // Update state
-                self.cycle_count += wait as u32;
+                self.cycle_count += wait as u32 + self.stage_latencies.access_mem as u32;
             },
         };
 
         // Write back
+        let pc_before_write_back = self.registers[SpecialRegister::PC];
+        let registers_before = self.registers.clone();
+        let target = no_pipeline_inst.branch_target(&self.registers);
         match no_pipeline_inst.write_back(&mut self.registers) {
             SimResult::Err(e) => return Err(
                 format!("Failed to write back for instruction: {}",
                         e)),
             SimResult::Wait(wait, _v) => {
                 // Update state
-                self.cycle_count += wait as u32;
+                self.cycle_count += wait as u32 + self.stage_latencies.write_back as u32;
+                self.instructions_retired += 1;
             },
         };
+        self.discard_zero_register_write(pc_before_write_back);
+        let pc_written = self.check_pc_write(pc_before_write_back, ibits)?;
+        self.trace_retired(pc_before_write_back, ibits, &registers_before);
+        self.check_return(pc_before_write_back, ibits, target);
 
-        // Update state
+        // Update state. PC is left alone if the instruction set it, was a
+        // taken branch or was a halt, so PC points at the halt once the
+        // program ends. A taken branch can jump to its own address, which
+        // leaves PC unchanged.
         self.no_pipeline_instruction = Some(no_pipeline_inst);
-        self.registers[PC] += 1;
-        self.cycle_count += 5;
+        self.no_pipeline_word = (fetch_pc, ibits);
+        if !self.halt_encountered && !pc_written && target.is_none() &&
+            self.registers[SpecialRegister::PC] == pc_before_write_back {
+            self.registers[SpecialRegister::PC] += self.instruction_size;
+        }
+        *self.pc_cycles.entry(fetch_pc).or_insert(0) += u64::from(self.cycle_count - start_cycles);
 
         // Determine if program should continue running
         Ok(self.program_is_running())
     }
 
-    /// Step one instruction through the processor using the pipeline. See step()
-    /// for return documentation.
+    /// Step one instruction through the processor using the pipeline. Returns
+    /// true if the program should keep running.
     pub fn step_pipeline(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> Result<bool, String> {
+        let start_cycles = self.cycle_count;
+
+        // An older branch would overwrite the handler address in PC when it
+        // retires, so stop fetching until no instruction in the pipeline can
+        // set PC and take the interrupt then
+        let drain_for_interrupt = !self.halt_encountered && self.interrupt_takeable() &&
+            self.control_in_flight();
+        if !self.halt_encountered && !drain_for_interrupt {
+            self.take_interrupt(memory.clone())?;
+        }
+        
+        let retired = self.access_mem_instruction.is_some();
+        let retired_word = self.access_mem_word;
+        let retired_pc = retired_word.0;
+        let stalled = self.decode_stalled();
+
         //  Write back stage
         match &mut self.access_mem_instruction {
             None => self.write_back_instruction = None,
             Some(access_mem_inst) => {
+                let pc_before_write_back = self.registers[SpecialRegister::PC];
+                let target = access_mem_inst.branch_target(&self.registers);
+                let taken = target.is_some();
+                let registers_before = self.registers.clone();
+                
                 match access_mem_inst.write_back(&mut self.registers) {
                     SimResult::Err(e) => return Err(
                         format!("Failed to write back for instruction: {}",
@@ -256,10 +1562,34 @@ impl ControlUnit {
                     SimResult::Wait(wait, _v) => {
                         // Update state
                         self.cycle_count += wait as u32;
+                        self.instructions_retired += 1;
                     },
                 };
 
+                let (pc, ibits, _id) = self.access_mem_word;
+                self.discard_zero_register_write(pc);
+                let pc_written = self.check_pc_write(pc, ibits)?;
+                self.trace_retired(pc, ibits, &registers_before);
+                self.check_return(pc, ibits, target);
+
+                // A branch resolved in the execute stage already set PC
+                if self.access_mem_resolved {
+                    self.registers[SpecialRegister::PC] = pc_before_write_back;
+                } else if let Some(redirect) = self.resolve_prediction(self.access_mem_word, target) {
+                    self.registers[SpecialRegister::PC] = pc_before_write_back;
+                    if let Some(redirect) = redirect {
+                        self.flush_younger(true, redirect);
+                    }
+                } else if taken || pc_written ||
+                    self.registers[SpecialRegister::PC] != pc_before_write_back {
+                    let target = self.registers[SpecialRegister::PC];
+                    self.registers[SpecialRegister::PC] = pc_before_write_back;
+                    self.flush_younger(true, target);
+                }
+
                 self.write_back_instruction = self.access_mem_instruction.take();
+                self.write_back_word = self.access_mem_word;
+                self.cycle_count += self.latch_delay as u32;
             },
         }
         
@@ -267,25 +1597,71 @@ impl ControlUnit {
         match &mut self.execute_instruction {
             None => self.access_mem_instruction = None,
             Some(exec_inst) => {
-                console::log_1(&JsValue::from_serde(
-                    &format!("control unit access memory stage")
-                ).unwrap());
+                self.output.borrow_mut().debug("control unit access memory stage");
                 
-                match exec_inst.access_memory(memory.clone()) {
+                let wait = match exec_inst.access_memory(memory.clone()) {
                     SimResult::Err(e) => return Err(
                         format!("Failed to access memory for instruction: {}",
                                 e)),
-                    SimResult::Wait(wait, _v) => {
-                        // Update state
-                        self.cycle_count += wait as u32;
-                    },
+                    SimResult::Wait(wait, _v) => wait,
                 };
+                let address = exec_inst.effective_address();
 
                 self.access_mem_instruction = self.execute_instruction.take();
+                self.access_mem_word = self.execute_word;
+                self.cycle_count += self.memory_stage_wait(&memory, address, wait);
+                self.cycle_count += self.latch_delay as u32;
             },
         };
+        self.access_mem_resolved = self.execute_resolved;
+
+        // Insert a bubble into the execute stage and hold the decode and fetch
+        // stages. A branch which wrote back above may have flushed the stalled
+        // instruction, then fetching continues at its target.
+        if stalled && self.decode_instruction.is_some() {
+            self.execute_instruction = None;
+            self.execute_resolved = false;
+            self.cycle_count += self.stage_latencies.cycle_time();
+
+            // Read the operands again, so the held instruction sees registers
+            // written back or results forwarded while it waits
+            let decode_registers = self.decode_registers(self.decode_word.0);
+            if let Some(decode_inst) = &mut self.decode_instruction {
+                if let SimResult::Err(e) = decode_inst.decode(self.decode_word.1,
+                                                              &decode_registers) {
+                    return Err(format!("Failed to decode stalled instruction {}: {}",
+                                       decode_inst, e));
+                }
+            }
+
+            if retired {
+                self.consecutive_stalls = 0;
+            } else {
+                self.consecutive_stalls += 1;
+            }
+
+            if self.consecutive_stalls > self.max_consecutive_stalls {
+                return Err(format!("Pipeline deadlock: stalled for {} \
+                                    consecutive cycles without retiring an \
+                                    instruction", self.consecutive_stalls));
+            }
+
+            if retired {
+                *self.pc_cycles.entry(retired_pc).or_insert(0) +=
+                    u64::from(self.cycle_count - start_cycles);
+            }
+            self.trace_stages(retired_word);
+
+            return Ok(self.program_is_running());
+        }
+        self.consecutive_stalls = 0;
         
         // Execute stage
+        self.execute_resolved = false;
+        if self.decode_instruction.is_some() {
+            self.wait_for_loads(self.decode_word.1);
+        }
+        let predicted = self.predictable_target(self.decode_word.1).is_some();
         match &mut self.decode_instruction {
             None => self.execute_instruction = None,
             Some(decode_inst) => {
@@ -297,152 +1673,652 @@ impl ControlUnit {
                     },
                 };
 
+                // Predicted branches are checked at write back
+                let target = match self.branch_resolve_stage {
+                    BranchResolveStage::Execute if !predicted => decode_inst.branch_target(&self.registers),
+                    _ => None,
+                };
+
                 self.execute_instruction = self.decode_instruction.take();
+                self.execute_word = self.decode_word;
+                self.cycle_count += self.latch_delay as u32;
+
+                if let Some(target) = target {
+                    self.flush_younger(false, target);
+                    self.execute_resolved = true;
+                }
             },
         };
 
         // Decode stage
+        let decode_registers = self.decode_registers(self.fetch_word.0);
         match &mut self.fetch_instruction {
             None => self.decode_instruction = None,
             Some(fetch_inst) => {
-                match fetch_inst.decode(self.fetch_instruction_bits,
-                                        &self.registers) {
-                    SimResult::Err(e) => return Err(
-                        format!("Failed to decode instruction {}: {}",
-                                fetch_inst, e)),
+                let decoded = match fetch_inst.decode(self.fetch_instruction_bits,
+                                                      &decode_registers) {
+                    SimResult::Err(e) => match self.decode_error_policy {
+                        DecodeErrorPolicy::Abort => return Err(
+                            format!("Failed to decode instruction {}: {}",
+                                    fetch_inst, e)),
+                        DecodeErrorPolicy::Bubble => {
+                            self.output.borrow_mut().warn(&format!(
+                                "replaced instruction at {:#06x} with a \
+                                 bubble: {}", self.fetch_word.0, e));
+                            false
+                        },
+                    },
                     SimResult::Wait(wait, _v) => {
                         // Update state
                         self.cycle_count += wait as u32;
-                        
+                        true
                     },
                 };
 
-                self.decode_instruction = self.fetch_instruction.take();
+                if decoded {
+                    self.decode_instruction = self.fetch_instruction.take();
+                    self.decode_word = self.fetch_word;
+                } else {
+                    self.decode_instruction = None;
+                    self.fetch_instruction = None;
+                }
+                self.cycle_count += self.latch_delay as u32;
             },
         };
     
         // Fetch stage
-        if !self.halt_encountered {
-            console::log_1(&JsValue::from_serde(
-                &format!("fetching {}", self.registers[PC])
-            ).unwrap());
-            match memory.clone().borrow_mut().get(self.registers[PC]) {
+        if !self.halt_encountered && !drain_for_interrupt {
+            self.output.borrow_mut().debug(&format!("fetching {}", self.registers[SpecialRegister::PC]));
+            *self.pc_counts.entry(self.registers[SpecialRegister::PC]).or_insert(0) += 1;
+            match self.fetch_memory(&memory).borrow_mut().get(self.registers[SpecialRegister::PC]) {
                 SimResult::Err(e) => return Err(
                     format!("Failed to retrieve instruction from address {}: {}",
-                            self.registers[PC], e)),
+                            self.registers[SpecialRegister::PC], e)),
                 SimResult::Wait(wait, ibits) => {
-                    console::log_1(&JsValue::from_serde(
-                        &format!("fetched {} = {}", self.registers[PC], ibits)
-                    ).unwrap());
+                    self.output.borrow_mut().debug(&format!("fetched {} = {}", self.registers[SpecialRegister::PC], ibits));
                     // Figure out which instruction the bits represent by
                     // looking at the type and operation code.
                     let icreate = self.instruction_factory(ibits);
 
                     self.fetch_instruction = match icreate {
-                        Err(e) => return Err(format!("Failed to determine type of \
-                                                      instruction for bits {}: {}",
-                                                     ibits, e)),
+                        Err(e) => {
+                            let error = format!("Failed to determine type of \
+                                                 instruction for bits {}: {}",
+                                                ibits, e);
+
+                            match self.decode_error_policy {
+                                DecodeErrorPolicy::Abort => return Err(error),
+                                DecodeErrorPolicy::Bubble =>
+                                    Some(Box::new(Undecodable{error})),
+                            }
+                        },
                         Ok(v) => Some(v),
                     };
                     self.fetch_instruction_bits = ibits;
+                    self.fetch_word = (self.registers[SpecialRegister::PC], ibits,
+                                       self.fetch_count);
+                    self.fetch_count += 1;
 
                     // Set state
                     self.cycle_count += wait as u32;
                 },
             };
+
+            // Stop advancing once a halt is fetched, so PC points at the halt
+            if !self.halt_encountered {
+                self.registers[SpecialRegister::PC] += self.instruction_size;
+            }
+
+            // Fetch the target of a branch predicted to be taken next
+            if let Some(target) = self.predict_fetched() {
+                self.registers[SpecialRegister::PC] = target;
+            }
+
+            // Go to a branch's target after its last delay slot is fetched
+            if let Some((target, slots)) = self.delayed_branch {
+                if slots <= 1 {
+                    self.registers[SpecialRegister::PC] = target;
+                    self.delayed_branch = None;
+                } else {
+                    self.delayed_branch = Some((target, slots - 1));
+                }
+            }
         } else {
             self.fetch_instruction = None;
         }
 
         // Update state after all stages
-        self.registers[PC] += 1;
-        self.cycle_count += 1;
+        self.cycle_count += self.stage_latencies.cycle_time();
+        if retired {
+            *self.pc_cycles.entry(retired_pc).or_insert(0) +=
+                u64::from(self.cycle_count - start_cycles);
+        }
+        self.trace_stages(retired_word);
 
         // Determine if program should continue running
         Ok(self.program_is_running())
     }
 
-    /// Initializes an instruction data structure based on instruction bits.
-    fn instruction_factory(&mut self, ibits: u32) ->
-        Result<Box<dyn Instruction>, String> {
-            let itype = ibits.get_bits(5..=6) as u32;
-            
-            // Match instruction type
-            match InstructionT::match_val(itype) {
-                Some(InstructionT::Memory) => {
-                    let iop = ibits.get_bits(7..=9) as u32;
+    /// Adds a retired instruction to the trace if tracing is set. before holds
+    /// the registers from before the instruction's write back.
+    fn trace_retired(&mut self, pc: u32, ibits: u32, before: &Registers) {
+        if !self.tracing {
+            return;
+        }
 
-                    match MemoryOp::match_val(iop) {
-                        Some(MemoryOp::LoadRD) => Ok(Box::new(
-                            Load::new(AddrMode::RegisterDirect))),
-                        Some(MemoryOp::LoadI) => Ok(Box::new(
-                            Load::new(AddrMode::Immediate))),
-                        Some(MemoryOp::StoreRD) => Ok(Box::new(
-                            Store::new(AddrMode::RegisterDirect))),
-                        Some(MemoryOp::StoreI) => Ok(Box::new(
-                            Store::new(AddrMode::Immediate))),
-                        Some(MemoryOp::Push) => Ok(Box::new(
-                            Push::new())),
-                        Some(MemoryOp::Pop) => Ok(Box::new(
-                            Pop::new())),
-                        _ => Err(format!("Invalid operation code {} for \
-                                          mememory type instruction", iop)),
-                    }
-                },
+        let disassembly = self.disassembly_cache.entry(ibits)
+            .or_insert_with(|| disassemble(ibits).unwrap_or_else(|_e| "???".to_string()))
+            .clone();
+        self.trace.push(TraceEntry::new(pc, ibits, disassembly, before,
+                                        &self.registers));
+    }
 
-                // Subrouting/notsub
-                // Sub = true
-                // notsub = false
-                Some(InstructionT::Control) => {
-                    let iop = ibits.get_bits(7..=9) as u32;
-                    match ControlOp::match_val(iop) {
-                        Some(ControlOp::Halt) => {
-                            self.halt_encountered = true;
-                            Ok(Box::new(Halt::new()))
-                        },
-                        Some(ControlOp::JmpRD) => Ok(Box::new(
-                            Jump::new(AddrMode::RegisterDirect, false))),
-                        Some(ControlOp::JmpI) => Ok(Box::new(
-                            Jump::new(AddrMode::Immediate, false))),
-                        Some(ControlOp::JmpSRD) => Ok(Box::new(
-                            Jump::new(AddrMode::RegisterDirect, true))),
-                        Some(ControlOp::JmpSI) => Ok(Box::new(
-                            Jump::new(AddrMode::Immediate, true))),
-                        // Some(ControlOp::Sih) => Ok(Box::new(
-                        //     SIH::new())),
-                        // Some(ControlOp::IntRD) => Ok(Box::new(
-                        //     INT::new(AddrMode::RegisterDirect))),
-                        // Some(ControlOp::IntI) => Ok(Box::new(
-                        //     INT::new(AddrMode::Immediate))),
-                        Some(ControlOp::RFI) => Ok(Box::new(
-                            RFI::new())),
-                        Some(ControlOp::Noop) => Ok(Box::new(
-                            Noop::new())),
-                        _ => Err(format!("Invalid operation code {} for \
-                                          Control type instruction", iop)),
-                    }
-                }
+    /// Adds the instructions in each pipeline stage to stage_trace if tracing is
+    /// set. retired is the word of the instruction in the write back stage.
+    fn trace_stages(&mut self, retired: (u32, u32, u64)) {
+        if !self.tracing {
+            return;
+        }
 
-                // sign/unsign:
-                // Unsigned = false
-                // Signed = true
-                Some(InstructionT::ALU) => {
-                    let iop = ibits.get_bits(7..=12) as u32;
+        let staged = |present: bool, (pc, instruction, id): (u32, u32, u64)| match present {
+            true => Some(StagedInstruction{
+                id,
+                pc,
+                instruction,
+            }),
+            false => None,
+        };
 
-                    match ALUOp::match_val(iop) {    // Don't quite know how to add sign/unsign
-                        Some(ALUOp::Move) => Ok(Box::new(
-                            Move::new())),
-                        // ---- Add ----
-                        Some(ALUOp::AddUIRD) => Ok(Box::new(
-                            ArithUnsign::new(AddrMode::RegisterDirect, ArithMode::Add))),
-                        Some(ALUOp::AddUII) => Ok(Box::new(
-                            ArithUnsign::new(AddrMode::Immediate, ArithMode::Add))),
-                        Some(ALUOp::AddSIRD) => Ok(Box::new(
-                            ArithSign::new(AddrMode::RegisterDirect, ArithMode::Add))),
-                        Some(ALUOp::AddSII) => Ok(Box::new(
-                            ArithSign::new(AddrMode::Immediate, ArithMode::Add))),
-                        // ---- Sub ----
-                        Some(ALUOp::SubUIRD) => Ok(Box::new(
+        self.stage_trace.push([
+            staged(self.fetch_instruction.is_some(), self.fetch_word),
+            staged(self.decode_instruction.is_some(), self.decode_word),
+            staged(self.execute_instruction.is_some(), self.execute_word),
+            staged(self.access_mem_instruction.is_some(), self.access_mem_word),
+            staged(self.write_back_instruction.is_some(), retired),
+        ]);
+    }
+
+    /// Warns if warn_return_before_call is set and a retired instruction was a
+    /// taken jump to the link register which went to address 0. target is the
+    /// instruction's branch target before write back.
+    fn check_return(&mut self, pc: u32, ibits: u32, target: Option<u32>) {
+        if !self.warn_return_before_call || target != Some(0) {
+            return;
+        }
+
+        let is_return = ibits.get_bits(5..=6) == InstructionT::Control.value() &&
+            control_op(ibits) == ControlOp::JmpRD.value() &&
+            ibits.get_bits(10..=14) as usize == self.registers.layout.lr;
+
+        if is_return {
+            self.output.borrow_mut().warn(&format!(
+                "return at {:#06x} jumped to LR before it was set by a \
+                 subroutine call", pc));
+        }
+    }
+
+    /// Sets R0 back to 0 if zero_register_hardwired is set and a retired
+    /// instruction wrote it, warning if warn_zero_register_write is set. pc is
+    /// the instruction's address. Writes of 0 leave R0 unchanged, so they are
+    /// not detected.
+    fn discard_zero_register_write(&mut self, pc: u32) {
+        if !self.zero_register_hardwired || self.registers[0] == 0 {
+            return;
+        }
+
+        if self.warn_zero_register_write {
+            self.output.borrow_mut().warn(&format!(
+                "instruction at {:#06x} wrote {} to R0, which is hardwired to 0",
+                pc, self.registers[0]));
+        }
+
+        self.registers[0] = 0;
+    }
+
+    /// Returns true if a retired instruction other than a control instruction
+    /// wrote PC, so it must be treated as a taken branch. Fails if such writes
+    /// are not allowed by pc_write_is_branch. pc is the instruction's address
+    /// and ibits its word.
+    fn check_pc_write(&self, pc: u32, ibits: u32) -> Result<bool, String> {
+        if ibits.get_bits(5..=6) == InstructionT::Control.value() ||
+            !register_defs_uses(ibits, &self.registers.layout).0.contains(&self.registers.layout.pc) {
+            return Ok(false);
+        }
+
+        match self.pc_write_is_branch {
+            true => Ok(true),
+            false => Err(format!("instruction at {:#06x} wrote PC directly, \
+                                  which is not allowed unless pc_write_is_branch \
+                                  is set", pc)),
+        }
+    }
+
+    /// Writes the trace to a file, one entry per line.
+    pub fn record_trace_to(&self, path: &str) -> Result<(), String> {
+        let text: String = self.trace.iter()
+            .map(|entry| format!("{}\n", entry))
+            .collect();
+
+        fs::write(path, text)
+            .map_err(|e| format!("Failed to write trace file {}: {}", path, e))
+    }
+
+    /// Compares the trace to one written by record_trace_to(). Returns the
+    /// first instruction which differs.
+    pub fn verify_against_trace(&self, path: &str) -> Result<(), Divergence> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Divergence::InvalidTrace(
+                format!("failed to read {}: {}", path, e)))?;
+
+        let mut expected: Vec<TraceEntry> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            match TraceEntry::parse(line) {
+                Ok(entry) => expected.push(entry),
+                Err(e) => return Err(Divergence::InvalidTrace(
+                    format!("line {}: {}", i + 1, e))),
+            }
+        }
+
+        for index in 0..expected.len().max(self.trace.len()) {
+            let expected_entry = expected.get(index);
+            let actual_entry = self.trace.get(index);
+
+            if expected_entry != actual_entry {
+                return Err(Divergence::Mismatch{
+                    index,
+                    expected: expected_entry.cloned(),
+                    actual: actual_entry.cloned(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compares DRAM to the values expected at each address, after writing
+    /// back every level of the cache hierarchy if the cache is enabled. Fails with the lowest address which
+    /// holds a different value. Addresses which are not expected are not
+    /// checked.
+    pub fn assert_memory_matches(&self, expected: &HashMap<u32, u32>) -> Result<(), String> {
+        // Flushing the top level cascades to the levels below it
+        if self.cache_enabled {
+            if let SimResult::Err(e) = self.cache.borrow_mut().flush() {
+                return Err(format!("Failed to flush cache: {}", e));
+            }
+        }
+
+        let mut addresses: Vec<&u32> = expected.keys().collect();
+        addresses.sort();
+
+        for addr in addresses {
+            let actual = match self.dram.borrow().peek(*addr) {
+                SimResult::Err(e) => return Err(format!(
+                    "Failed to read address {:#06x}: {}", addr, e)),
+                SimResult::Wait(_wait, value) => value,
+            };
+
+            if actual != expected[addr] {
+                return Err(format!("address {:#06x} holds {:#x}, expected {:#x}",
+                                   addr, actual, expected[addr]));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the memory instructions are fetched from, given the memory data
+    /// is accessed through.
+    fn fetch_memory(&self, data_memory: &Rc<RefCell<dyn Memory<u32, u32>>>) -> Rc<RefCell<dyn Memory<u32, u32>>> {
+        match &self.topology {
+            MemoryTopology::Unified => data_memory.clone(),
+            MemoryTopology::Harvard(instruction_memory) => instruction_memory.clone(),
+        }
+    }
+
+    /// Returns the target of a conditional immediate jump, which the branch
+    /// predictor predicts. Returns None for any other instruction, or when
+    /// the branch predictor is not used.
+    fn predictable_target(&self, ibits: u32) -> Option<u32> {
+        if self.branch_predictor.is_none() || self.branch_delay_slots > 0 ||
+            InstructionT::match_val(ibits.get_bits(5..=6)) != Some(InstructionT::Control) {
+            return None;
+        }
+
+        match ControlOp::match_val(control_op(ibits)) {
+            Some(ControlOp::JmpI) | Some(ControlOp::JmpSI) if ibits.get_bits(0..=4) != 0 =>
+                Some(ibits.get_bits(JUMP_IMMEDIATE)),
+            _ => None,
+        }
+    }
+
+    /// Predicts the branch just fetched. Returns its target if it is predicted
+    /// to be taken.
+    fn predict_fetched(&mut self) -> Option<u32> {
+        let (pc, ibits, id) = self.fetch_word;
+        let target = self.predictable_target(ibits)?;
+
+        match self.branch_predictor.as_ref()?.predict(pc) {
+            true => {
+                self.predicted_taken.insert(id);
+                Some(target)
+            },
+            false => None,
+        }
+    }
+
+    /// Checks the prediction made for a resolved branch, target is where the
+    /// branch goes if it is taken. Returns None for branches which were not
+    /// predicted. Otherwise returns the address younger instructions must be
+    /// flushed to, or None if the prediction was right.
+    fn resolve_prediction(&mut self, word: (u32, u32, u64), target: Option<u32>) -> Option<Option<u32>> {
+        let (pc, ibits, id) = word;
+        self.predictable_target(ibits)?;
+
+        let predicted = self.predicted_taken.contains(&id);
+        self.predicted_taken.retain(|i| *i > id);
+        if let Some(predictor) = self.branch_predictor.as_mut() {
+            predictor.update(pc, target.is_some());
+        }
+
+        if predicted == target.is_some() {
+            return Some(None);
+        }
+
+        self.mispredictions += 1;
+        Some(Some(target.unwrap_or(pc + self.instruction_size)))
+    }
+
+    /// Removes the instructions younger than a taken branch, except the
+    /// oldest branch_delay_slots of them, and sets PC to the branch's target.
+    /// with_execute is true if the execute stage holds a younger instruction,
+    /// which is the case when the branch is resolved at write back. If fewer
+    /// instructions than delay slots were fetched the rest are fetched before
+    /// PC is set to target. A flushed halt no longer stops fetching. Fetching
+    /// stops after a halt, so a halt is always the youngest instruction and is
+    /// flushed whenever any instruction is.
+    fn flush_younger(&mut self, with_execute: bool, target: u32) {
+        let mut slots = self.branch_delay_slots;
+        let mut flushed = false;
+
+        if with_execute && self.execute_instruction.is_some() {
+            match slots {
+                0 => {
+                    self.execute_instruction = None;
+                    self.execute_resolved = false;
+                    flushed = true;
+                },
+                _ => slots -= 1,
+            };
+        }
+
+        if self.decode_instruction.is_some() {
+            match slots {
+                0 => {
+                    self.decode_instruction = None;
+                    flushed = true;
+                },
+                _ => slots -= 1,
+            };
+        }
+
+        if self.fetch_instruction.is_some() {
+            match slots {
+                0 => {
+                    self.fetch_instruction = None;
+                    flushed = true;
+                },
+                _ => slots -= 1,
+            };
+        }
+
+        if flushed {
+            self.halt_encountered = false;
+        }
+
+        match slots {
+            0 => self.registers[SpecialRegister::PC] = target,
+            _ => self.delayed_branch = Some((target, slots)),
+        };
+    }
+
+    /// Returns the cycles the memory stage waits for an access which took wait
+    /// cycles, and starts servicing the access in a miss status holding
+    /// register if it missed and one is free, see mshr_count. memory is the
+    /// memory the access went to and address the address the instruction in
+    /// the stage accessed, if any.
+    fn memory_stage_wait(&mut self, memory: &Rc<RefCell<dyn Memory<u32, u32>>>,
+                         address: Option<u32>, wait: u16) -> u32 {
+        let now = self.cycle_count;
+        self.outstanding_misses.retain(|miss| miss.ready > now);
+
+        let line = match address {
+            Some(address) if self.mshr_count > 0 => memory.borrow().line_address(address),
+            _ => return wait as u32,
+        };
+
+        // The line is not there until the outstanding miss completes, even
+        // though the access to it looked like a hit
+        if let Some(miss) = self.outstanding_misses.iter().find(|miss| miss.line == line) {
+            return (miss.ready - now).max(wait as u32);
+        }
+
+        let hit_latency = match memory.borrow().hit_latency() {
+            Some(latency) => latency,
+            None => return wait as u32,
+        };
+        if wait <= hit_latency || self.outstanding_misses.len() >= self.mshr_count {
+            return wait as u32;
+        }
+
+        let (defs, _uses) = register_defs_uses(self.access_mem_word.1, &self.registers.layout);
+        self.outstanding_misses.push(OutstandingMiss{
+            line,
+            dest: match self.access_mem_word.1.get_bits(5..=6) == InstructionT::Memory.value() {
+                true => defs.first().copied(),
+                false => None,
+            },
+            ready: now + wait as u32,
+        });
+
+        hit_latency as u32
+    }
+
+    /// Waits until the outstanding misses which load registers an instruction
+    /// reads complete. ibits is the instruction's word.
+    fn wait_for_loads(&mut self, ibits: u32) {
+        let (_defs, uses) = register_defs_uses(ibits, &self.registers.layout);
+        let ready = self.outstanding_misses.iter()
+            .filter(|miss| matches!(miss.dest, Some(dest) if uses.contains(&dest)))
+            .map(|miss| miss.ready)
+            .max();
+
+        if let Some(ready) = ready {
+            self.cycle_count = self.cycle_count.max(ready);
+        }
+    }
+
+    /// Returns the registers an instruction at address pc reads in decode.
+    /// PC holds the address being fetched, but instructions read PC as their
+    /// own address like they do without the pipeline. If forwarding_enabled is
+    /// set the pending results of older instructions replace the values in
+    /// registers, younger results replacing older ones.
+    fn decode_registers(&self, pc: u32) -> Registers {
+        let mut registers = self.registers.clone();
+
+        if self.forwarding_enabled {
+            let older = [&self.write_back_instruction, &self.access_mem_instruction,
+                         &self.execute_instruction];
+            for (reg, value) in older.iter().filter_map(|inst| inst.as_ref()?.pending_result()) {
+                if reg != 0 || !self.zero_register_hardwired {
+                    registers[reg] = value;
+                }
+            }
+        }
+
+        registers[SpecialRegister::PC] = pc;
+        registers
+    }
+
+    /// Returns true if the instruction in the decode stage must wait before it
+    /// can move to the execute stage. It waits while an instruction in the
+    /// execute or access memory stage has not written back a register it read
+    /// in decode. If forwarding_enabled is set it does not wait for a register
+    /// whose value is known, see Instruction::pending_result().
+    fn decode_stalled(&self) -> bool {
+        #[cfg(test)]
+        if self.force_stall {
+            return self.decode_instruction.is_some();
+        }
+
+        let src_regs = match &self.decode_instruction {
+            Some(decode_inst) => decode_inst.src_regs(),
+            None => return false,
+        };
+
+        let layout = &self.registers.layout;
+        [&self.execute_instruction, &self.access_mem_instruction].iter()
+            .filter_map(|inst| inst.as_ref())
+            .any(|inst| {
+                let forwarded = match inst.pending_result() {
+                    Some((reg, _value)) if self.forwarding_enabled => Some(reg),
+                    _ => None,
+                };
+                inst.dest_regs(layout).iter()
+                    .any(|dest| src_regs.contains(dest) && forwarded != Some(*dest))
+            })
+    }
+
+    /// Registers a factory which creates the instruction for an instruction
+    /// type and operation code. Registered instructions are used instead of
+    /// built in instructions with the same type and operation code. Control
+    /// operation codes include the extended operation, see ControlOp::value().
+    pub fn register_instruction(&mut self, itype: InstructionT, op: u32,
+                                factory: InstructionFactory) {
+        self.custom_instructions.insert((itype.value(), op), factory);
+    }
+
+    /// Initializes an instruction data structure based on instruction bits.
+    fn instruction_factory(&mut self, ibits: u32) ->
+        Result<Box<dyn Instruction>, String> {
+            let itype = ibits.get_bits(5..=6) as u32;
+
+            // Check registered instructions first
+            let custom_op = match InstructionT::match_val(itype) {
+                Some(InstructionT::Memory) => ibits.get_bits(7..=9),
+                Some(InstructionT::Control) => control_op(ibits),
+                Some(InstructionT::ALU) => ibits.get_bits(7..=12),
+                _ => ibits.get_bits(7..=8),
+            };
+            if let Some(factory) = self.custom_instructions.get(&(itype, custom_op)) {
+                return Ok(factory());
+            }
+            
+            // Match instruction type
+            match InstructionT::match_val(itype) {
+                Some(InstructionT::Memory) => {
+                    let iop = ibits.get_bits(7..=9) as u32;
+
+                    match MemoryOp::match_val(iop) {
+                        Some(MemoryOp::LoadRD) => Ok(Box::new(
+                            Load::new(AddrMode::RegisterDirect,
+                                      self.load_fault_policy))),
+                        Some(MemoryOp::LoadI) => Ok(Box::new(
+                            Load::new(AddrMode::Immediate,
+                                      self.load_fault_policy))),
+                        Some(MemoryOp::StoreRD) => Ok(Box::new(
+                            Store::new(AddrMode::RegisterDirect))),
+                        Some(MemoryOp::StoreI) => Ok(Box::new(
+                            Store::new(AddrMode::Immediate))),
+                        Some(MemoryOp::Push) => Ok(Box::new(
+                            Push::new(self.stack_grows_down))),
+                        Some(MemoryOp::Pop) => Ok(Box::new(
+                            Pop::new(self.stack_grows_down))),
+                        Some(MemoryOp::LoadByteRD) => Ok(Box::new(
+                            LoadByte::new())),
+                        Some(MemoryOp::StoreByteRD) => Ok(Box::new(
+                            StoreByte::new())),
+                        _ => Err(format!("Invalid operation code {} for \
+                                          mememory type instruction {:#010x} \
+                                          (type={}, op={})",
+                                         iop, ibits, itype, iop)),
+                    }
+                },
+
+                // Subrouting/notsub
+                // Sub = true
+                // notsub = false
+                Some(InstructionT::Control) => {
+                    let iop = control_op(ibits);
+                    match ControlOp::match_val(iop) {
+                        Some(ControlOp::Halt) => {
+                            self.halt_encountered = true;
+                            Ok(Box::new(Halt::new()))
+                        },
+                        Some(ControlOp::JmpRD) => Ok(Box::new(
+                            Jump::new(AddrMode::RegisterDirect, false,
+                                      self.instruction_size,
+                                      self.strict_jump_alignment))),
+                        Some(ControlOp::JmpI) => Ok(Box::new(
+                            Jump::new(AddrMode::Immediate, false,
+                                      self.instruction_size,
+                                      self.strict_jump_alignment))),
+                        Some(ControlOp::JmpSRD) => Ok(Box::new(
+                            Jump::new(AddrMode::RegisterDirect, true,
+                                      self.instruction_size,
+                                      self.strict_jump_alignment))),
+                        Some(ControlOp::JmpSI) => Ok(Box::new(
+                            Jump::new(AddrMode::Immediate, true,
+                                      self.instruction_size,
+                                      self.strict_jump_alignment))),
+                        Some(ControlOp::Sih) => Ok(Box::new(
+                            SIH::new())),
+                        Some(ControlOp::IntRD) => Ok(Box::new(
+                            INT::new(AddrMode::RegisterDirect,
+                                     self.instruction_size))),
+                        Some(ControlOp::IntI) => Ok(Box::new(
+                            INT::new(AddrMode::Immediate,
+                                     self.instruction_size))),
+                        Some(ControlOp::RFI) => Ok(Box::new(
+                            RFI::new())),
+                        Some(ControlOp::Noop) => Ok(Box::new(
+                            Noop::new())),
+                        Some(ControlOp::Enter) => Ok(Box::new(
+                            Enter::new(self.stack_grows_down))),
+                        Some(ControlOp::Leave) => Ok(Box::new(
+                            Leave::new(self.stack_grows_down))),
+                        Some(ControlOp::CacheFlush) => Ok(Box::new(
+                            CacheFlush::new())),
+                        Some(ControlOp::CacheInvalidate) => Ok(Box::new(
+                            CacheInvalidate::new())),
+                        _ => Err(format!("Invalid operation code {} for \
+                                          Control type instruction {:#010x} \
+                                          (type={}, op={})",
+                                         iop, ibits, itype, iop)),
+                    }
+                }
+
+                // sign/unsign:
+                // Unsigned = false
+                // Signed = true
+                Some(InstructionT::ALU) => {
+                    let iop = ibits.get_bits(7..=12) as u32;
+
+                    match ALUOp::match_val(iop) {    // Don't quite know how to add sign/unsign
+                        Some(ALUOp::Move) => Ok(Box::new(
+                            Move::new())),
+                        Some(ALUOp::CMov) => Ok(Box::new(
+                            CMov::new())),
+                        // ---- Add ----
+                        Some(ALUOp::AddUIRD) => Ok(Box::new(
+                            ArithUnsign::new(AddrMode::RegisterDirect, ArithMode::Add))),
+                        Some(ALUOp::AddUII) => Ok(Box::new(
+                            ArithUnsign::new(AddrMode::Immediate, ArithMode::Add))),
+                        Some(ALUOp::AddSIRD) => Ok(Box::new(
+                            ArithSign::new(AddrMode::RegisterDirect, ArithMode::Add))),
+                        Some(ALUOp::AddSII) => Ok(Box::new(
+                            ArithSign::new(AddrMode::Immediate, ArithMode::Add))),
+                        // ---- Sub ----
+                        Some(ALUOp::SubUIRD) => Ok(Box::new(
                             ArithUnsign::new(AddrMode::RegisterDirect, ArithMode::Sub))),
                         Some(ALUOp::SubUII) => Ok(Box::new(
                             ArithUnsign::new(AddrMode::Immediate, ArithMode::Sub))),
@@ -468,6 +2344,14 @@ impl ControlUnit {
                             ArithSign::new(AddrMode::RegisterDirect, ArithMode::Div))),
                         Some(ALUOp::DivSII) => Ok(Box::new(
                             ArithSign::new(AddrMode::Immediate, ArithMode::Div))),
+                        Some(ALUOp::ModUIRD) => Ok(Box::new(
+                            ArithUnsign::new(AddrMode::RegisterDirect, ArithMode::Mod))),
+                        Some(ALUOp::ModUII) => Ok(Box::new(
+                            ArithUnsign::new(AddrMode::Immediate, ArithMode::Mod))),
+                        Some(ALUOp::ModSIRD) => Ok(Box::new(
+                            ArithSign::new(AddrMode::RegisterDirect, ArithMode::Mod))),
+                        Some(ALUOp::ModSII) => Ok(Box::new(
+                            ArithSign::new(AddrMode::Immediate, ArithMode::Mod))),
                         // ---- Comp ----
                         Some(ALUOp::Comp) => Ok(Box::new(
                             Comp::new())),
@@ -489,6 +2373,15 @@ impl ControlUnit {
                             LS::new(AddrMode::RegisterDirect, true))),
                         Some(ALUOp::LSRI) => Ok(Box::new(
                             LS::new(AddrMode::Immediate, true))),
+                        // ---- Rotate ----
+                        Some(ALUOp::ROLRD) => Ok(Box::new(
+                            Rotate::new(AddrMode::RegisterDirect, false))),
+                        Some(ALUOp::ROLI) => Ok(Box::new(
+                            Rotate::new(AddrMode::Immediate, false))),
+                        Some(ALUOp::RORRD) => Ok(Box::new(
+                            Rotate::new(AddrMode::RegisterDirect, true))),
+                        Some(ALUOp::RORI) => Ok(Box::new(
+                            Rotate::new(AddrMode::Immediate, true))),
                         // ---- 3 Operation Logic ----
                         Some(ALUOp::AndRD) => Ok(Box::new(
                             ThreeOpLogic::new(AddrMode::RegisterDirect, LogicType::And))),
@@ -507,18 +2400,232 @@ impl ControlUnit {
                             Not::new())),
                         
                         _ => Err(format!("Invalid operation code {} for \
-                                          ALU type instruction", iop)),
+                                          ALU type instruction {:#010x} \
+                                          (type={}, op={})",
+                                         iop, ibits, itype, iop)),
                     }
                 }
-                _ => Err(format!("Invalid type value {} for instruction",
-                                 itype)),
+                // No graphics operations are implemented yet
+                Some(InstructionT::Graphics) => {
+                    let iop = ibits.get_bits(7..=8);
+
+                    Err(format!("Invalid operation code {} for Graphics type \
+                                 instruction {:#010x} (type={}, op={})",
+                                iop, ibits, itype, iop))
+                },
+
+                _ => Err(format!("Invalid type value {} for instruction \
+                                  {:#010x} (type={})", itype, ibits, itype)),
+            }
+        }
+
+    /// Returns the instructions in each pipeline stage, starting with fetch and
+    /// ending with write back. If the pipeline is not enabled holds one element
+    /// which is the last instruction run.
+    pub fn stage_instructions(&self) -> Vec<&Option<Box<dyn Instruction>>> {
+        if self.pipeline_enabled {
+            vec![&self.fetch_instruction, &self.decode_instruction,
+                 &self.execute_instruction, &self.access_mem_instruction,
+                 &self.write_back_instruction]
+        } else {
+            vec![&self.no_pipeline_instruction]
+        }
+    }
+
+    /// Returns the decoded operands of the instruction in each stage. Stages
+    /// are ordered the same as stage_instructions(). The fetch stage
+    /// instruction has not been decoded yet so its operands are not
+    /// meaningful.
+    pub fn stage_operands(&self) -> Vec<Option<Vec<(String, u32)>>> {
+        self.stage_instructions().iter()
+            .map(|inst| inst.as_ref().map(|i| i.operands()))
+            .collect()
+    }
+
+    /// Returns the register file as JSON, with special purpose registers
+    /// identified by name. Useful for comparing the final state of a program
+    /// against a known good result.
+    pub fn registers_json(&self) -> String {
+        self.registers.to_json()
+    }
+
+    /// Returns the number of times each address was fetched, most fetched
+    /// first. Addresses fetched the same number of times are in ascending
+    /// order.
+    pub fn pc_histogram(&self) -> Vec<(u32, u64)> {
+        let mut hist: Vec<(u32, u64)> = self.pc_counts.iter()
+            .map(|(pc, count)| (*pc, *count))
+            .collect();
+        hist.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        hist
+    }
+
+    /// Returns an assembler style listing of count instructions starting at
+    /// address start. Each line holds the address, the instruction word in hex
+    /// and its disassembly, like "0x0004: 0x12345678  ADDU R2, R10, R13". Words
+    /// which are not valid instructions are shown as "???". Memory is peeked
+    /// through the top level, the cache if it is enabled, or the instruction
+    /// memory with the Harvard topology, so values a cache holds are shown and
+    /// caches are not disturbed. The listing stops at the last address.
+    ///
+    /// If timing is true each line ends with the instruction's modeled latency
+    /// and the total cycles it took over every time it ran, like "  ; 25
+    /// cycles modeled, 75 total", see pc_cycles. The modeled latency is the
+    /// fetch, the latency of every stage and, for memory instructions, a data
+    /// access, with accesses taking the top memory level's hit latency. Lines
+    /// which did not run end with "  ; 25 cycles modeled, not run".
+    pub fn listing(&self, start: u32, count: u32, timing: bool) -> String {
+        let data_memory = match self.cache_enabled {
+            true => &self.cache,
+            false => &self.dram,
+        };
+        let memory = self.fetch_memory(data_memory);
+        let mut lines: Vec<String> = Vec::new();
+
+        for i in 0..count {
+            let addr = match i.checked_mul(self.instruction_size)
+                .and_then(|offset| start.checked_add(offset)) {
+                Some(addr) => addr,
+                None => break,
+            };
+            let mut text = match memory.borrow().peek(addr) {
+                SimResult::Err(e) => format!("{:#06x}: failed to read: {}", addr, e),
+                SimResult::Wait(_wait, word) => format!(
+                    "{:#06x}: {:#010x}  {}", addr, word,
+                    disassemble(word).unwrap_or_else(|_e| "???".to_string())),
+            };
+
+            if timing {
+                let modeled = self.modeled_latency(&memory, data_memory, addr);
+                let runs = self.pc_counts.get(&addr).copied().unwrap_or(0);
+                let cycles = self.pc_cycles.get(&addr).copied().unwrap_or(0);
+
+                text.push_str(&match runs {
+                    0 => format!("  ; {} cycles modeled, not run", modeled),
+                    _ => format!("  ; {} cycles modeled, {} total", modeled, cycles),
+                });
+            }
+
+            lines.push(text);
+        }
+
+        lines.join("\n")
+    }
+
+    /// Returns the cycles the instruction at addr is modeled to take without
+    /// the pipeline, see listing(). Instructions are peeked from memory and
+    /// data is accessed through data_memory.
+    fn modeled_latency(&self, memory: &Rc<RefCell<dyn Memory<u32, u32>>>,
+                       data_memory: &Rc<RefCell<dyn Memory<u32, u32>>>, addr: u32) -> u32 {
+        let latencies = &self.stage_latencies;
+        let stages = [latencies.fetch, latencies.decode, latencies.execute,
+                      latencies.access_mem, latencies.write_back];
+        let mut cycles: u32 = stages.iter().map(|latency| *latency as u32).sum();
+        cycles += memory.borrow().hit_latency().unwrap_or(0) as u32;
+
+        if let SimResult::Wait(_wait, ibits) = memory.borrow().peek(addr) {
+            if ibits.get_bits(5..=6) == InstructionT::Memory.value() {
+                cycles += data_memory.borrow().hit_latency().unwrap_or(0) as u32;
+            }
+        }
+
+        cycles
+    }
+
+    /// Renders stage_trace as a pipeline timing diagram. Each instruction is a
+    /// row, in fetch order, labeled with its address and disassembly. Each
+    /// step is a column, numbered from 1. A cell holds the letter of the stage
+    /// the instruction was in at the end of the step: F, D, X, M or W. A cell
+    /// where an instruction stayed in the same stage as the step before, because
+    /// the pipeline stalled, is a bubble shown as "*". Flushed instructions end
+    /// early. Empty if nothing was traced.
+    pub fn pipeline_diagram(&self) -> String {
+        const STAGE_LETTERS: [&str; 5] = ["F", "D", "X", "M", "W"];
+
+        // Stage of each instruction in each step
+        let mut rows: BTreeMap<u64, (StagedInstruction, Vec<Option<usize>>)> = BTreeMap::new();
+        for (step, stages) in self.stage_trace.iter().enumerate() {
+            for (stage, staged) in stages.iter().enumerate() {
+                if let Some(staged) = staged {
+                    let (_staged, cells) = rows.entry(staged.id)
+                        .or_insert_with(|| (*staged, Vec::new()));
+                    cells.resize(step + 1, None);
+                    cells[step] = Some(stage);
+                }
+            }
+        }
+
+        let labels: Vec<String> = rows.values()
+            .map(|(staged, _cells)| format!("{:#06x} {}", staged.pc,
+                disassemble(staged.instruction).unwrap_or_else(|_e| "???".to_string())))
+            .collect();
+        let width = labels.iter().map(|l| l.len()).max().unwrap_or(0);
+
+        let mut lines = vec![];
+        if !rows.is_empty() {
+            let steps: String = (1..=self.stage_trace.len())
+                .map(|step| format!("{:>3}", step))
+                .collect();
+            lines.push(format!("{:width$}{}", "", steps, width = width));
+        }
+
+        for (label, (_staged, cells)) in labels.iter().zip(rows.values()) {
+            let mut line = format!("{:width$}", label, width = width);
+            for (step, cell) in cells.iter().enumerate() {
+                let held = step > 0 && cell.is_some() && cells[step - 1] == *cell;
+                line.push_str(&format!("{:>3}", match cell {
+                    None => "",
+                    Some(_stage) if held => "*",
+                    Some(stage) => STAGE_LETTERS[*stage],
+                }));
             }
+            lines.push(line.trim_end().to_string());
+        }
+
+        lines.join("\n")
+    }
+
+    /// Returns the largest number of words the stack held during the run,
+    /// measured from the stack pointer when the first step ran.
+    pub fn max_stack_depth(&self) -> u32 {
+        match self.stack_start {
+            None => 0,
+            Some(start) => match self.stack_grows_down {
+                true => start.saturating_sub(self.stack_deepest),
+                false => self.stack_deepest.saturating_sub(start),
+            },
+        }
+    }
+
+    /// Returns the instructions retired per cycle. 0 if no cycles have run.
+    /// Bubbles in the pipeline are not instructions, so they are not counted.
+    pub fn ipc(&self) -> f64 {
+        if self.cycle_count == 0 {
+            return 0.0;
         }
 
-    /// Returns if the program should keep running.
+        self.instructions_retired as f64 / self.cycle_count as f64
+    }
+
+    /// Returns the instructions retired per cycle, see ipc().
+    pub fn efficiency(&self) -> f64 {
+        self.ipc()
+    }
+
+    /// Returns the number of memory accesses which took each number of wait
+    /// cycles, keyed by wait cycles. Includes instruction fetches. The wait
+    /// cycles reflect cache hits, misses and evictions.
+    pub fn latency_histogram(&self) -> &HashMap<u16, u64> {
+        &self.latency_counts
+    }
+
+    /// Returns if the program should keep running. The pipeline can be empty
+    /// while fetching waits to take an interrupt, see step_pipeline().
     pub fn program_is_running(&self) -> bool {
         if self.pipeline_enabled {
             !self.first_instruction_loaded ||
+                (!self.halt_encountered && self.interrupt_takeable()) ||
                 self.decode_instruction.is_some() ||
                 self.fetch_instruction.is_some() ||
                 self.execute_instruction.is_some() ||
@@ -528,4 +2635,2599 @@ impl ControlUnit {
                 self.no_pipeline_instruction.is_some()
         }
     }
+
+    /// Returns true if every pipeline stage is empty, including write back.
+    /// After a halt is fetched older instructions keep retiring, and the
+    /// retired halt stays in the write back stage for one more step after
+    /// program_is_running() becomes false. Without the pipeline returns true
+    /// if no instruction is held.
+    pub fn is_drained(&self) -> bool {
+        if self.pipeline_enabled {
+            self.fetch_instruction.is_none() &&
+                self.decode_instruction.is_none() &&
+                self.execute_instruction.is_none() &&
+                self.access_mem_instruction.is_none() &&
+                self.write_back_instruction.is_none()
+        } else {
+            self.no_pipeline_instruction.is_none()
+        }
+    }
+}
+
+/// Options used to set up the control unit for every program run by
+/// run_directory().
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct BatchConfig {
+    /// Indicates if a pipeline should be used.
+    pub pipeline_enabled: bool,
+
+    /// Indicates if a cache should be used.
+    pub cache_enabled: bool,
+
+    /// Initial value of the stack pointer.
+    pub stack_pointer: u32,
+
+    /// Programs which do not halt within this number of cycles fail.
+    pub max_cycles: u32,
+}
+
+/// Final state of a program which ran until it halted.
+#[derive(Clone,Debug,PartialEq)]
+pub struct RunSummary {
+    /// Number of cycles the program took.
+    pub cycles: u32,
+
+    /// Number of instructions fetched.
+    pub instructions_fetched: u64,
+
+    /// Instructions retired per cycle, see ControlUnit::efficiency().
+    pub efficiency: f64,
+
+    /// Instructions per cycle the program could reach, see ideal_ipc().
+    pub ideal_ipc: f64,
+
+    /// Percentage of the ideal instructions per cycle which was reached.
+    pub parallelism_realized: f64,
+
+    /// See ControlUnit::max_stack_depth().
+    pub max_stack_depth: u32,
+
+    /// Average memory access time of the cache in cycles, see DMCache::amat().
+    /// None if the cache was disabled.
+    pub amat: Option<f64>,
+
+    /// Registers after the program halted.
+    pub registers: Registers,
+}
+
+/// Creates a control unit set up by a BatchConfig which runs the program in
+/// dram. Returns the control unit and its cache.
+fn batch_control_unit(dram: DRAM, config: &BatchConfig) -> (ControlUnit, Rc<RefCell<DMCache>>) {
+    let dram = Rc::new(RefCell::new(dram));
+    let cache = Rc::new(RefCell::new(DMCache::new(1, 16, dram.clone())));
+
+    let mut cu = ControlUnit::new(dram, cache.clone(), StageLatencies::default());
+    cu.pipeline_enabled = config.pipeline_enabled;
+    cu.cache_enabled = config.cache_enabled;
+    cu.registers[SpecialRegister::SP] = config.stack_pointer;
+
+    (cu, cache)
+}
+
+/// Steps a control unit until its program halts, failing if it takes more
+/// than the configured maximum number of cycles.
+fn run_batch(cu: &mut ControlUnit, config: &BatchConfig) -> Result<(), String> {
+    while cu.step()? != Status::Halted {
+        if cu.cycle_count > config.max_cycles {
+            return Err(format!("program did not halt within {} cycles",
+                               config.max_cycles));
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads and runs a program file until it halts.
+fn run_file(file_p: &str, config: &BatchConfig) -> Result<RunSummary, String> {
+    let mut dram = DRAM::new(0);
+    dram.load_from_file(file_p)?;
+
+    // Analyze the program words up to the first halt
+    let mut addresses: Vec<u32> = dram.inspect().keys().copied().collect();
+    addresses.sort();
+    let contents = dram.inspect();
+    let words: Vec<u32> = addresses.iter()
+        .map(|a| contents[a])
+        .take_while(|w| *w != 0)
+        .collect();
+    let (mut cu, cache) = batch_control_unit(dram, config);
+    let ideal = ideal_ipc(&words, &cu.registers.layout);
+
+    run_batch(&mut cu, config)?;
+
+    Ok(RunSummary{
+        cycles: cu.cycle_count,
+        instructions_fetched: cu.pc_counts.values().sum(),
+        efficiency: cu.efficiency(),
+        ideal_ipc: ideal,
+        parallelism_realized: match ideal > 0.0 {
+            true => cu.efficiency() / ideal * 100.0,
+            false => 0.0,
+        },
+        max_stack_depth: cu.max_stack_depth(),
+        amat: match config.cache_enabled {
+            true => Some(cache.borrow().amat()),
+            false => None,
+        },
+        registers: cu.registers.clone(),
+    })
+}
+
+/// Runs every .bin file in a directory until it halts. Returns the path of
+/// each program with its summary, or the reason it failed, sorted by path.
+pub fn run_directory(dir: &str, config: &BatchConfig) -> Vec<(String, Result<RunSummary, String>)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return vec![(dir.to_string(), Err(
+            format!("Failed to read directory: {}", e)))],
+    };
+
+    let mut paths: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() &&
+                path.extension().is_some_and(|ext| ext == "bin"))
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    paths.sort();
+
+    paths.into_iter()
+        .map(|path| {
+            let res = run_file(&path, config);
+            (path, res)
+        })
+        .collect()
+}
+
+/// Runs a program of one ALU instruction repeated iterations times followed by
+/// a halt, and returns the cycles it took. Each instruction writes a different
+/// register from the last 15, and every source register is only read, so the
+/// instructions do not depend on each other. Source registers hold non zero
+/// values and immediates are 1, so divisions do not fail.
+pub fn benchmark_instruction(op: ALUOp, iterations: u32, config: &BatchConfig) -> Result<u32, String> {
+    const DEST_REGS: u32 = 15;
+    const SRC_REGS: [usize; 2] = [16, 17];
+
+    let mut base: u32 = 0;
+    base.set_bits(5..=6, InstructionT::ALU.value());
+    base.set_bits(7..=12, op.value());
+    let imm = immediate_field(base);
+
+    let mut dram = DRAM::new(0);
+    for i in 0..iterations {
+        let mut word = base;
+        let mut srcs = SRC_REGS.iter();
+
+        for (name, field) in operand_fields(base) {
+            let value = match name {
+                "dest" => 1 + i % DEST_REGS,
+                "cond" => 0,
+                _ if Some(&field) == imm.as_ref() => 1,
+                _ => *srcs.next().expect("more source fields than registers") as u32,
+            };
+            word.set_bits(field, value);
+        }
+
+        if let SimResult::Err(e) = dram.set(i, word) {
+            return Err(format!("Failed to write benchmark program: {}", e));
+        }
+    }
+    if let SimResult::Err(e) = dram.set(iterations, 0) {
+        return Err(format!("Failed to write benchmark program: {}", e));
+    }
+
+    let (mut cu, _cache) = batch_control_unit(dram, config);
+    for (i, reg) in SRC_REGS.iter().enumerate() {
+        cu.registers[*reg] = 3 + i as u32;
+    }
+    run_batch(&mut cu, config)?;
+
+    Ok(cu.cycle_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{PC,SP,FP,LR,STS,IHDLR,INTLR};
+    use crate::instructions::{ConditionCodes,CompressedOp,expand_halfword};
+    use crate::output::MemoryOutput;
+    use crate::encoding::{program,asm};
+
+    /// Creates a control unit with a DRAM memory loaded from a file. The cache
+    /// is disabled.
+    fn load_control_unit(file_p: &str) -> ControlUnit {
+        load_control_unit_delay(file_p, 0)
+    }
+
+    /// Creates a control unit like load_control_unit() with a DRAM delay.
+    fn load_control_unit_delay(file_p: &str, delay: u16) -> ControlUnit {
+        let mut dram = DRAM::new(delay);
+        dram.load_from_file(file_p).expect("failed to load DRAM file");
+
+        let dram = Rc::new(RefCell::new(dram));
+        let mut cu = ControlUnit::new(dram.clone(), dram, StageLatencies::default());
+        cu.cache_enabled = false;
+        cu.registers[SP] = 1000;
+
+        cu
+    }
+
+    /// Creates a control unit with a DRAM memory which holds words starting at
+    /// address 0. The cache is disabled.
+    fn load_words(words: &[u32]) -> ControlUnit {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        for (i, word) in words.iter().enumerate() {
+            dram.borrow_mut().set(i as u32, *word).unwrap("set word");
+        }
+
+        let mut cu = ControlUnit::new(dram.clone(), dram, StageLatencies::default());
+        cu.cache_enabled = false;
+        cu.registers[SP] = 1000;
+
+        cu
+    }
+
+    /// Memory which fails every access to one address.
+    struct FaultyMemory {
+        fault_addr: u32,
+        base: DRAM,
+    }
+
+    impl Memory<u32, u32> for FaultyMemory {
+        fn get(&mut self, address: u32) -> SimResult<u32, String> {
+            if address == self.fault_addr {
+                return SimResult::Err(format!("injected fault at {}", address));
+            }
+            self.base.get(address)
+        }
+
+        fn set(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+            if address == self.fault_addr {
+                return SimResult::Err(format!("injected fault at {}", address));
+            }
+            self.base.set(address, data)
+        }
+
+        fn peek(&self, address: u32) -> SimResult<u32, String> {
+            self.base.peek(address)
+        }
+
+        fn poke(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+            self.base.poke(address, data)
+        }
+    }
+
+    /// Instruction which sets R7 to 42, used to test registered instructions.
+    #[derive(Debug)]
+    struct SetR7 {}
+
+    impl fmt::Display for SetR7 {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Set R7")
+        }
+    }
+
+    impl Instruction for SetR7 {
+        fn decode(&mut self, _instruction: u32, _registers: &Registers) -> SimResult<(), String> {
+            SimResult::Wait(0, ())
+        }
+
+        fn execute(&mut self) -> SimResult<(), String> {
+            SimResult::Wait(0, ())
+        }
+
+        fn access_memory(&mut self, _memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
+            SimResult::Wait(0, ())
+        }
+
+        fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
+            registers[7] = 42;
+            SimResult::Wait(0, ())
+        }
+    }
+
+    /// Steps a control unit until its program stops running.
+    fn run_to_end(cu: &mut ControlUnit) {
+        while cu.step().expect("failed to step control unit") != Status::Halted {}
+    }
+
+    /// Returns the words of a program file in address order.
+    fn read_words(file_p: &str) -> Vec<u32> {
+        let mut dram = DRAM::new(0);
+        dram.load_from_file(file_p).expect("failed to load DRAM file");
+
+        let mut words: Vec<(u32, u32)> = dram.inspect().into_iter().collect();
+        words.sort();
+
+        words.into_iter().map(|(_addr, word)| word).collect()
+    }
+
+    /// Runs a program in pipeline and no pipeline mode and asserts the final
+    /// registers are identical.
+    pub fn assert_modes_agree(words: &[u32]) {
+        let mut final_regs = vec![];
+
+        for pipeline_enabled in [true, false].iter() {
+            let mut cu = load_words(words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            run_to_end(&mut cu);
+
+            final_regs.push(cu.registers.clone());
+        }
+
+        assert_eq!(final_regs[0], final_regs[1],
+                   "pipeline (left) and no pipeline (right) registers differ");
+    }
+
+    /// Tests that registers_json reports the final register values of the
+    /// example program.
+    #[test]
+    fn test_registers_json() {
+        let mut cu = load_control_unit("./test-data/instructions.bin");
+        run_to_end(&mut cu);
+
+        let json = cu.registers_json();
+        assert!(json.starts_with("{") && json.ends_with("}"),
+                "json is an object: {}", json);
+        assert!(json.contains("\"PC\":4,"), "json has PC: {}", json);
+        assert!(json.contains("\"R1\":45,"), "json has R1: {}", json);
+    }
+
+    /// Tests that run_to_cycle stops on the first step which reaches the
+    /// target cycle.
+    #[test]
+    fn test_run_to_cycle() {
+        const DELAY: u16 = 10;
+        const TARGET: u32 = 25;
+        
+        let mut cu = load_control_unit_delay("./test-data/instructions.bin",
+                                             DELAY);
+
+        assert_eq!(cu.run_to_cycle(TARGET), Ok(Status::Continue));
+        assert!(cu.cycle_count >= TARGET, "cycle_count {} >= target",
+                cu.cycle_count);
+
+        // Each pipeline step fetches once, plus at most one load
+        assert!(cu.cycle_count < TARGET + 2 * (DELAY as u32) + 1,
+                "cycle_count {} only just past target", cu.cycle_count);
+
+        // A target past the end of the program stops at the end
+        assert_eq!(cu.run_to_cycle(10000), Ok(Status::Halted));
+        assert!(cu.cycle_count < 10000);
+    }
+
+    /// Tests that each frame tick advances the pipeline by the cycles per
+    /// frame until the program ends.
+    #[test]
+    fn test_run_cycles() {
+        const CYCLES_PER_FRAME: u32 = 2;
+
+        let mut cu = load_control_unit("./test-data/instructions.bin");
+
+        // Without memory delays each pipeline step takes one cycle
+        assert_eq!(cu.run_cycles(CYCLES_PER_FRAME), Ok(Status::Continue));
+        assert_eq!(cu.cycle_count, CYCLES_PER_FRAME);
+        assert_eq!(cu.run_cycles(CYCLES_PER_FRAME), Ok(Status::Continue));
+        assert_eq!(cu.cycle_count, 2 * CYCLES_PER_FRAME);
+
+        let mut frames = 2;
+        while cu.run_cycles(CYCLES_PER_FRAME).expect("failed to run frame") == Status::Continue {
+            frames += 1;
+            assert_eq!(cu.cycle_count, frames * CYCLES_PER_FRAME);
+        }
+        assert!(!cu.program_is_running());
+        assert_eq!(cu.run_cycles(CYCLES_PER_FRAME), Ok(Status::Halted));
+    }
+
+    /// Tests that the program counter advances by the instruction size when
+    /// memory is byte addressed.
+    #[test]
+    fn test_byte_addressed_fetch() {
+        const NOOP: u32 = 6 << 7;
+        const HALT: u32 = 0;
+
+        for pipeline_enabled in [false, true].iter() {
+            let dram = Rc::new(RefCell::new(DRAM::new(0)));
+            for (i, word) in [NOOP, NOOP, NOOP, HALT].iter().enumerate() {
+                dram.borrow_mut().set((i as u32) * 4, *word).unwrap("set word");
+            }
+            
+            let mut cu = ControlUnit::new(dram.clone(), dram, StageLatencies::default());
+            cu.cache_enabled = false;
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.instruction_size = 4;
+
+            for i in 1..=3 {
+                cu.step().expect("failed to step");
+                assert_eq!(cu.registers[PC], i * 4,
+                           "pipeline={}, PC after step {}", pipeline_enabled, i);
+            }
+
+            // PC stays on the halt
+            cu.step().expect("failed to step halt");
+            assert_eq!(cu.registers[PC], 12,
+                       "pipeline={}, PC after halt", pipeline_enabled);
+
+            assert!(cu.halt_encountered, "pipeline={}, halt fetched",
+                    pipeline_enabled);
+        }
+    }
+
+    /// Tests that a program results in the same registers when run with and
+    /// without the pipeline.
+    #[test]
+    fn test_no_pipeline_decodes_fetched_bits() {
+        let words = program![
+            add_ui_i(1, 3, 45),
+            halt(),
+        ];
+
+        let mut pipeline_cu = load_words(&words);
+        pipeline_cu.registers[3] = 5;
+        run_to_end(&mut pipeline_cu);
+
+        let mut no_pipeline_cu = load_words(&words);
+        no_pipeline_cu.pipeline_enabled = false;
+        no_pipeline_cu.registers[3] = 5;
+        run_to_end(&mut no_pipeline_cu);
+
+        assert_eq!(no_pipeline_cu.registers[1], 50, "no pipeline R1");
+
+        // The pipeline keeps incrementing PC while it empties
+        pipeline_cu.registers[PC] = 0;
+        no_pipeline_cu.registers[PC] = 0;
+        assert_eq!(pipeline_cu.registers, no_pipeline_cu.registers);
+    }
+
+    /// Tests that Enter sets up a stack frame and Leave restores the stack and
+    /// frame pointers, in both stack directions.
+    #[test]
+    fn test_enter_leave() {
+        const LOCALS: u32 = 3;
+        
+        let words = program![
+            enter(LOCALS),
+            leave(),
+            halt(),
+        ];
+
+        for grows_down in &[true, false] {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = false;
+            cu.stack_grows_down = *grows_down;
+            cu.registers[FP] = 77;
+
+            let sp = cu.registers[SP];
+            let (frame, locals_end) = match grows_down {
+                true => (sp - 1, sp - 1 - LOCALS),
+                false => (sp + 1, sp + 1 + LOCALS),
+            };
+
+            cu.step().expect("failed to step enter");
+            assert_eq!(cu.registers[FP], frame, "enter FP, stack_grows_down={}", grows_down);
+            assert_eq!(cu.registers[SP], locals_end, "enter SP, stack_grows_down={}", grows_down);
+            assert_eq!(cu.dram.borrow_mut().get(frame), SimResult::Wait(0, 77),
+                       "enter pushed FP, stack_grows_down={}", grows_down);
+
+            cu.step().expect("failed to step leave");
+            assert_eq!(cu.registers[FP], 77, "leave FP, stack_grows_down={}", grows_down);
+            assert_eq!(cu.registers[SP], sp, "leave SP, stack_grows_down={}", grows_down);
+        }
+    }
+
+    /// Tests that a pipeline which never stops stalling is reported as
+    /// deadlocked.
+    #[test]
+    fn test_deadlock_detection() {
+        let mut cu = load_control_unit("./test-data/instructions.bin");
+        cu.force_stall = true;
+        cu.max_consecutive_stalls = 5;
+
+        let mut steps = 0;
+        let err = loop {
+            match cu.step() {
+                Err(e) => break e,
+                Ok(outcome) => assert_eq!(outcome, Status::Continue,
+                                          "program stopped before deadlock"),
+            }
+
+            steps += 1;
+            assert!(steps < 100, "deadlock never detected");
+        };
+
+        assert!(err.contains("deadlock"), "error reports deadlock: {}", err);
+        assert_eq!(cu.consecutive_stalls, 6);
+    }
+
+    /// Tests that pc_histogram counts each loop body address once per
+    /// iteration.
+    #[test]
+    fn test_pc_histogram() {
+        const ITERATIONS: u32 = 3;
+
+        // Loops while R1 < R2
+        let words = program![
+            noop(),
+            add_ui_i(1, 1, 1),
+            cmp(1, 2),
+            jmp_lt_i(0),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        cu.registers[2] = ITERATIONS;
+        run_to_end(&mut cu);
+
+        assert_eq!(cu.registers[1], ITERATIONS);
+        assert_eq!(cu.pc_histogram(), vec![
+            (0, ITERATIONS as u64),
+            (1, ITERATIONS as u64),
+            (2, ITERATIONS as u64),
+            (3, ITERATIONS as u64),
+            (4, 1),
+        ]);
+    }
+
+    /// Tests that a faulting load with the poison policy loads the poison value,
+    /// sets the fault condition code and lets the program continue.
+    #[test]
+    fn test_load_fault_poison() {
+        const FAULT_ADDR: u32 = 50;
+        const POISON: u32 = 0xDEAD;
+
+        let words = program![
+            load_rd(1, 2),
+            add_ui_i(3, 0, 7),
+            halt(),
+        ];
+
+        let mut base = DRAM::new(0);
+        for (i, word) in words.iter().enumerate() {
+            base.set(i as u32, *word).unwrap("set word");
+        }
+        let memory = Rc::new(RefCell::new(FaultyMemory{
+            fault_addr: FAULT_ADDR,
+            base,
+        }));
+
+        let mut cu = ControlUnit::new(memory.clone(), memory, StageLatencies::default());
+        cu.cache_enabled = false;
+        cu.pipeline_enabled = false;
+        cu.registers[2] = FAULT_ADDR;
+
+        // Default policy aborts
+        assert!(cu.step().is_err(), "abort policy should fail the load");
+
+        // Checked before the add overwrites STS
+        cu.registers[PC] = 0;
+        cu.load_fault_policy = LoadFaultPolicy::Poison(POISON);
+        cu.step().expect("failed to step control unit");
+
+        assert_eq!(cu.registers[1], POISON, "poison value loaded");
+        assert_eq!(cu.registers[STS], ConditionCodes::FAULT.value(),
+                   "fault flag set");
+
+        run_to_end(&mut cu);
+        assert_eq!(cu.registers[3], 7, "program continued after fault");
+    }
+
+    /// Tests that run_directory runs the example program in the test data
+    /// directory to completion.
+    #[test]
+    fn test_run_directory() {
+        let results = run_directory("./test-data", &BatchConfig{
+            pipeline_enabled: true,
+            cache_enabled: false,
+            stack_pointer: 1000,
+            max_cycles: 10000,
+        });
+
+        assert!(results.iter().all(|(path, _res)| path.ends_with(".bin")),
+                "only .bin files are run: {:?}", results);
+
+        let (_path, res) = results.iter()
+            .find(|(path, _res)| path.ends_with("instructions.bin"))
+            .expect("example program was not run");
+        let summary = res.as_ref().expect("example program failed");
+
+        assert_eq!(summary.registers[1], 45);
+        assert_eq!(summary.registers[PC], 4);
+        assert_eq!(summary.instructions_fetched, 5);
+        assert_eq!(summary.amat, None, "cache was disabled");
+    }
+
+    /// Tests that Push and Pop move the stack pointer in the configured
+    /// direction.
+    #[test]
+    fn test_stack_direction() {
+        let words = program![
+            push(1),
+            pop(2),
+            halt(),
+        ];
+
+        for (grows_down, pushed_sp) in [(true, 999), (false, 1001)].iter() {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = false;
+            cu.stack_grows_down = *grows_down;
+            cu.registers[1] = 500;
+
+            cu.step().expect("failed to step push");
+            assert_eq!(cu.registers[SP], *pushed_sp,
+                       "push, stack_grows_down={}", grows_down);
+
+            cu.step().expect("failed to step pop");
+            assert_eq!(cu.registers[SP], 1000,
+                       "pop, stack_grows_down={}", grows_down);
+        }
+    }
+
+    /// Tests that a value pushed on the stack is popped back into a register,
+    /// in both stack directions.
+    #[test]
+    fn test_push_pop_round_trip() {
+        let words = program![
+            push(1),
+            pop(2),
+            halt(),
+        ];
+
+        for (grows_down, pushed_addr) in [(true, 999), (false, 1001)].iter() {
+            for pipeline_enabled in &[false, true] {
+                let mut cu = load_words(&words);
+                cu.pipeline_enabled = *pipeline_enabled;
+                cu.stack_grows_down = *grows_down;
+                cu.registers[1] = 500;
+                run_to_end(&mut cu);
+
+                assert_eq!(cu.registers[2], 500, "stack_grows_down={}, pipeline={}",
+                           grows_down, pipeline_enabled);
+                assert_eq!(cu.registers[SP], 1000, "stack_grows_down={}, pipeline={}",
+                           grows_down, pipeline_enabled);
+                assert_eq!(cu.dram.borrow_mut().get(*pushed_addr), SimResult::Wait(0, 500),
+                           "stack_grows_down={}, pipeline={}", grows_down,
+                           pipeline_enabled);
+            }
+        }
+    }
+
+    /// Tests that programs without hazards end in the same state with and
+    /// without the pipeline.
+    #[test]
+    fn test_modes_agree() {
+        assert_modes_agree(&read_words("./test-data/instructions.bin"));
+
+        // Independent additions and a load
+        let words = program![
+            add_ui_i(1, 0, 10),
+            add_ui_i(2, 0, 20),
+            add_ui_i(3, 0, 30),
+            load_rd(4, 0),
+            halt(),
+        ];
+        assert_modes_agree(&words);
+
+        let words = program![
+            enter(2),
+            halt(),
+        ];
+        assert_modes_agree(&words);
+    }
+
+    /// Tests that PC points at the halt instruction once a program ends.
+    #[test]
+    fn test_pc_after_halt() {
+        for pipeline_enabled in [true, false].iter() {
+            let mut cu = load_control_unit("./test-data/instructions.bin");
+            cu.pipeline_enabled = *pipeline_enabled;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[PC], 4, "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that decode errors include the instruction in hex and its decoded
+    /// fields.
+    #[test]
+    fn test_decode_error_message() {
+        let mut cu = load_words(&[]);
+
+        // Control type with unused operation code 15, every memory operation
+        // code is used
+        let bits = asm::word(InstructionT::Control, 15);
+
+        let err = cu.instruction_factory(bits).expect_err("invalid op decoded");
+        assert!(err.contains("0x00001f80"), "error has hex: {}", err);
+        assert!(err.contains("type=0"), "error has type: {}", err);
+        assert!(err.contains("op=15"), "error has op: {}", err);
+    }
+
+    /// Tests that the latch delay is added for every pipeline stage advance.
+    #[test]
+    fn test_latch_delay() {
+        const LATCH_DELAY: u16 = 3;
+
+        // Each of the 5 instructions advances through 4 stages
+        const ADVANCES: u32 = 5 * 4;
+        
+        let mut no_delay = load_control_unit("./test-data/instructions.bin");
+        run_to_end(&mut no_delay);
+
+        let mut delay = load_control_unit("./test-data/instructions.bin");
+        delay.latch_delay = LATCH_DELAY;
+        run_to_end(&mut delay);
+
+        assert_eq!(delay.cycle_count - no_delay.cycle_count,
+                   LATCH_DELAY as u32 * ADVANCES);
+    }
+
+    /// Tests that a conditional move only writes its destination when the
+    /// condition matches the result of a comparison.
+    #[test]
+    fn test_cmov() {
+        // R4 = R3 if R1 < R2, R5 = R3 if R1 > R2
+        let words = program![
+            cmp(1, 2),
+            cmov_cond(ConditionCodes::LT.value(), 4, 3),
+            cmov_cond(ConditionCodes::GT.value(), 5, 3),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        cu.registers[1] = 1;
+        cu.registers[2] = 2;
+        cu.registers[3] = 33;
+        cu.registers[5] = 55;
+        run_to_end(&mut cu);
+
+        assert_eq!(cu.registers[4], 33, "condition held, moved");
+        assert_eq!(cu.registers[5], 55, "condition did not hold, not moved");
+    }
+
+    /// Tests that a program without a Halt terminates at the Halt appended by
+    /// the loader.
+    #[test]
+    fn test_load_pad_with_halt() {
+        let mut bytes: Vec<u8> = vec![];
+        for _i in 0..3 {
+            bytes.extend_from_slice(&asm::noop().to_be_bytes());
+        }
+
+        let mut dram = DRAM::new(0);
+        dram.set_pad_word(Some(asm::halt()));
+        dram.load_from_reader(&bytes[..]).expect("failed to load program");
+        assert_eq!(dram.inspect().len(), 4, "halt appended");
+
+        let dram = Rc::new(RefCell::new(dram));
+        let mut cu = ControlUnit::new(dram.clone(), dram, StageLatencies::default());
+        cu.cache_enabled = false;
+        run_to_end(&mut cu);
+
+        assert!(cu.halt_encountered);
+        assert_eq!(cu.registers[PC], 3, "halted at appended halt");
+    }
+
+    /// Tests that stepping back restores registers, memory and cycle count.
+    #[test]
+    fn test_step_back() {
+        let words = program![
+            add_ui_i(1, 0, 5),
+            store_rd(2, 1),
+            add_ui_i(3, 1, 1),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        cu.registers[2] = 100;
+
+        cu.step().expect("failed to step 1");
+        let regs_snapshot = cu.registers.clone();
+        let cycle_snapshot = cu.cycle_count;
+
+        cu.step().expect("failed to step 2");
+        cu.step().expect("failed to step 3");
+        assert_eq!(cu.dram.borrow_mut().get(100), SimResult::Wait(0, 5));
+        assert_eq!(cu.registers[3], 6);
+
+        cu.step_back().expect("failed to step back 1");
+        cu.step_back().expect("failed to step back 2");
+
+        assert_eq!(cu.registers, regs_snapshot);
+        assert_eq!(cu.cycle_count, cycle_snapshot);
+        assert_eq!(cu.dram.borrow_mut().get(100), SimResult::Wait(0, 0),
+                   "store undone");
+        assert!(cu.program_is_running());
+
+        // Stepping forward again reaches the same final state
+        run_to_end(&mut cu);
+        assert_eq!(cu.registers[3], 6);
+    }
+
+    /// Tests that stepping back over a step which took an interrupt queues
+    /// the interrupt again.
+    #[test]
+    fn test_step_back_interrupt() {
+        const HANDLER: u32 = 2;
+        const TIMER_CODE: u32 = 9;
+
+        let words = program![
+            noop(),
+            halt(),
+            noop(),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        cu.registers[IHDLR] = HANDLER;
+        cu.queue_interrupt(InterruptSource::Timer, TIMER_CODE);
+        let regs_snapshot = cu.registers.clone();
+
+        cu.step().expect("failed to step");
+        assert!(cu.pending_interrupts().is_empty(), "interrupt taken");
+        assert_eq!(cu.registers[PC], HANDLER + 1);
+
+        cu.step_back().expect("failed to step back");
+        assert_eq!(cu.pending_interrupts(), vec![(InterruptSource::Timer, TIMER_CODE)]);
+        assert_eq!(cu.registers, regs_snapshot);
+    }
+
+    /// Tests that recording a store for undo does not access the cache, so a
+    /// store miss counts and takes as long as with undo off.
+    #[test]
+    fn test_step_back_store_miss() {
+        const ADDR: u32 = 50;
+
+        let words = program![
+            store_rd(2, 1),
+            halt(),
+        ];
+
+        let mut runs = vec![];
+        for undo_limit in &[0, 100] {
+            let dram = Rc::new(RefCell::new(DRAM::new(10)));
+            for (i, word) in words.iter().enumerate() {
+                dram.borrow_mut().set(i as u32, *word).unwrap("set word");
+            }
+            let cache = Rc::new(RefCell::new(DMCache::new(1, 16, dram.clone())));
+
+            let mut cu = ControlUnit::new(dram, cache.clone(), StageLatencies::default());
+            cu.pipeline_enabled = false;
+            cu.undo_limit = *undo_limit;
+            cu.registers[1] = 42;
+            cu.registers[2] = ADDR;
+            cu.step().expect("failed to step store");
+
+            assert_eq!(cu.undo_history.len(), *undo_limit.min(&1), "undo_limit={}", undo_limit);
+            runs.push((cache.borrow().stats(), cu.cycle_count, cu.latency_histogram().clone()));
+        }
+
+        assert_eq!(runs[0], runs[1]);
+        assert_eq!(runs[1].0, crate::memory::CacheStats{hits: 0, misses: 2}, "fetch and store miss");
+    }
+
+    /// Tests that an unknown graphics operation results in an error naming the
+    /// graphics operation.
+    #[test]
+    fn test_unknown_graphics_op() {
+        let mut cu = load_words(&[]);
+
+        let bits = asm::word(InstructionT::Graphics, 2);
+
+        let err = cu.instruction_factory(bits).expect_err("graphics op decoded");
+        assert!(err.contains("Invalid operation code 2 for Graphics type"),
+                "error names graphics op: {}", err);
+    }
+
+    /// Tests that a registered instruction is run for its opcode.
+    #[test]
+    fn test_register_instruction() {
+        let words = vec![asm::word(InstructionT::Graphics, 1), asm::halt()];
+
+        for pipeline_enabled in [true, false].iter() {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.register_instruction(InstructionT::Graphics, 1,
+                                    Box::new(|| Box::new(SetR7{})));
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[7], 42, "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that cache hits and misses show up as separate latency buckets.
+    #[test]
+    fn test_latency_histogram() {
+        let mut dram = DRAM::new(10);
+        dram.load_from_file("./test-data/instructions.bin")
+            .expect("failed to load DRAM file");
+        let dram = Rc::new(RefCell::new(dram));
+        let cache = Rc::new(RefCell::new(DMCache::new(1, 16, dram.clone())));
+
+        let mut cu = ControlUnit::new(dram, cache, StageLatencies::default());
+        cu.registers[SP] = 1000;
+        run_to_end(&mut cu);
+
+        let hist = cu.latency_histogram();
+        let total: u64 = hist.values().sum();
+
+        // 5 fetches, a load and a push
+        assert_eq!(total, 7, "histogram: {:?}", hist);
+        assert!(hist.contains_key(&1), "cache hit bucket: {:?}", hist);
+        assert!(hist.contains_key(&11), "cache miss bucket: {:?}", hist);
+    }
+
+    /// Tests that a jump updates the program counter register given by a non
+    /// default register layout.
+    #[test]
+    fn test_register_layout_jump() {
+        const LAYOUT_PC: usize = 5;
+
+        let words = program![
+            jmp_i(3),
+            noop(),
+            noop(),
+            halt(),
+        ];
+
+        let mut layout = RegisterLayout::new();
+        layout.pc = LAYOUT_PC;
+
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        cu.registers = Registers::new_with_layout(layout);
+        run_to_end(&mut cu);
+
+        assert_eq!(cu.registers[LAYOUT_PC], 3);
+        assert_eq!(cu.registers[PC], 0);
+        assert_eq!(cu.pc_histogram(), vec![(0, 1), (3, 1)]);
+        assert_eq!(cu.registers.special_name(LAYOUT_PC), Some("PC"));
+        assert_eq!(cu.registers.special_name(PC), None);
+    }
+
+    /// Tests that the end of the program is reported to the output.
+    #[test]
+    fn test_output_status() {
+        let output = Rc::new(RefCell::new(MemoryOutput::new()));
+        
+        let mut cu = load_control_unit("./test-data/instructions.bin");
+        cu.output = output.clone();
+        run_to_end(&mut cu);
+
+        assert_eq!(output.borrow().lines, vec![
+            format!("Program ended after {} cycles", cu.cycle_count),
+        ]);
+    }
+
+    /// Tests that a serial program retires at most one instruction per cycle
+    /// and reaches at most the ideal instructions per cycle.
+    #[test]
+    fn test_efficiency() {
+        for pipeline_enabled in &[false, true] {
+            let mut cu = load_control_unit("./test-data/instructions.bin");
+            cu.pipeline_enabled = *pipeline_enabled;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.instructions_retired, 5, "pipeline={}", pipeline_enabled);
+            assert!(cu.efficiency() > 0.0 && cu.efficiency() <= 1.0,
+                    "pipeline={}, efficiency={}", pipeline_enabled, cu.efficiency());
+        }
+
+        let config = BatchConfig{
+            pipeline_enabled: true,
+            cache_enabled: false,
+            stack_pointer: 1000,
+            max_cycles: 1000,
+        };
+        let summary = run_file("./test-data/instructions.bin", &config)
+            .expect("run program");
+        assert!(summary.parallelism_realized > 0.0 &&
+                summary.parallelism_realized <= 100.0,
+                "parallelism realized: {}", summary.parallelism_realized);
+    }
+
+    /// Tests that a halfword packed program loads both instructions of a word
+    /// and runs them.
+    #[test]
+    fn test_halfword_packed_program() {
+        // R4 = R9, then halt
+        let mut mov: u16 = 0;
+        mov.set_bits(0..=2, CompressedOp::Move.value());
+        mov.set_bits(3..=7, 4);
+        mov.set_bits(8..=12, 9);
+        let halt = CompressedOp::Halt.value();
+
+        let bin: [u8; 4] = [(mov >> 8) as u8, mov as u8, (halt >> 8) as u8, halt as u8];
+
+        let mut dram = DRAM::new(0);
+        dram.set_halfword_packed(Some(expand_halfword));
+        dram.load_from_reader(&bin[..]).expect("load packed program");
+        assert_eq!(dram.inspect().len(), 2);
+
+        for pipeline_enabled in &[false, true] {
+            let dram = Rc::new(RefCell::new(DRAM::new(0)));
+            dram.borrow_mut().set_halfword_packed(Some(expand_halfword));
+            dram.borrow_mut().load_from_reader(&bin[..]).expect("load packed program");
+            
+            let mut cu = ControlUnit::new(dram.clone(), dram, StageLatencies::default());
+            cu.cache_enabled = false;
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.registers[9] = 42;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[4], 42, "pipeline={}", pipeline_enabled);
+            assert_eq!(cu.instructions_retired, 2, "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that a register fill sets general purpose registers to the poison
+    /// value and special purpose registers to their initial values.
+    #[test]
+    fn test_register_fill() {
+        const POISON: u32 = 0xDEADBEEF;
+
+        let registers = Registers::new_with_fill(POISON);
+        assert_eq!(registers[0], POISON);
+        assert_eq!(registers[FP], POISON);
+        assert_eq!(registers[PC], 0);
+        assert_eq!(registers[SP], 0);
+        assert_eq!(registers[IHDLR], 0);
+
+        let mut cu = load_words(&[0]);
+        cu.register_fill = Some(POISON);
+        cu.reset_registers();
+
+        for i in 0..=FP {
+            assert_eq!(cu.registers[i], POISON, "R{}", i);
+        }
+        assert_eq!(cu.registers[PC], 0);
+        assert_eq!(cu.registers[SP], 0);
+        assert_eq!(cu.registers[IHDLR], InterruptCodes::NOT_SET_INITIAL.value() as u32);
+    }
+
+    /// Tests that a cache flush instruction writes stored values to DRAM and
+    /// that an invalidate instruction discards cached values.
+    #[test]
+    fn test_cache_flush_instruction() {
+        const ADDR: u32 = 50;
+        
+        // [R2] = R1
+        let store = asm::store_rd(2, 1);
+        let flush = asm::cache_flush();
+        let invalidate = asm::cache_invalidate();
+
+        for (op, dram_value) in &[(flush, 42), (invalidate, 0)] {
+            for pipeline_enabled in &[false, true] {
+                let dram = Rc::new(RefCell::new(DRAM::new(0)));
+                for (i, word) in [store, *op, 0].iter().enumerate() {
+                    dram.borrow_mut().set(i as u32, *word).unwrap("set word");
+                }
+                let cache = Rc::new(RefCell::new(DMCache::new(0, 16, dram.clone())));
+
+                let mut cu = ControlUnit::new(dram.clone(), cache.clone(), StageLatencies::default());
+                cu.pipeline_enabled = *pipeline_enabled;
+                cu.registers[1] = 42;
+                cu.registers[2] = ADDR;
+                run_to_end(&mut cu);
+
+                let (_wait, value) = dram.borrow_mut().get(ADDR).unwrap("get DRAM");
+                assert_eq!(value, *dram_value, "op={}, pipeline={}", op, pipeline_enabled);
+                if *op == invalidate {
+                    assert!(!cache.borrow().inspect_valid().contains_key(&ADDR),
+                            "pipeline={}", pipeline_enabled);
+                }
+            }
+        }
+    }
+
+    /// Tests that the maximum stack depth is the deepest point reached by
+    /// nested pushes, in both stack directions.
+    #[test]
+    fn test_max_stack_depth() {
+        let words = program![
+            push(1),
+            push(1),
+            pop(2),
+            push(1),
+            push(1),
+            pop(2),
+            pop(2),
+            pop(2),
+            halt(),
+        ];
+
+        for grows_down in &[true, false] {
+            for pipeline_enabled in &[false, true] {
+                let mut cu = load_words(&words);
+                cu.pipeline_enabled = *pipeline_enabled;
+                cu.stack_grows_down = *grows_down;
+                cu.registers[1] = 500;
+                run_to_end(&mut cu);
+
+                assert_eq!(cu.max_stack_depth(), 3,
+                           "stack_grows_down={}, pipeline={}", grows_down,
+                           pipeline_enabled);
+                assert_eq!(cu.registers[SP], 1000,
+                           "stack_grows_down={}, pipeline={}", grows_down,
+                           pipeline_enabled);
+            }
+        }
+    }
+
+    /// Tests that when a timer and a keyboard interrupt are queued in the same
+    /// step the timer interrupt is taken first and the keyboard interrupt stays
+    /// pending.
+    #[test]
+    fn test_interrupt_priority() {
+        const HANDLER: u32 = 2;
+        const TIMER_CODE: u32 = 9;
+        let enter = InterruptCodes::ENTER.value() as u32;
+
+        let words = program![
+            noop(),
+            halt(),
+            noop(),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        cu.registers[IHDLR] = HANDLER;
+        cu.queue_interrupt(InterruptSource::Keyboard, enter);
+        cu.queue_interrupt(InterruptSource::Timer, TIMER_CODE);
+        cu.step().expect("failed to step");
+
+        assert_eq!(cu.registers[INTLR], 0);
+        assert_eq!(cu.registers[PC], HANDLER + 1);
+        assert!(cu.registers.interrupt_active);
+        let (_wait, code) = cu.dram.borrow_mut().get(INTERRUPT_CODE_ADDR).unwrap("get code");
+        assert_eq!(code, TIMER_CODE);
+        assert_eq!(cu.pending_interrupts, vec![(InterruptSource::Keyboard, enter)]);
+    }
+
+    /// Tests that resolving branches in the execute stage flushes fewer
+    /// instructions than resolving them at write back, and that flushed
+    /// instructions have no effect.
+    #[test]
+    fn test_branch_resolve_stage() {
+        // Jump over the adds to the halt
+        let words = program![
+            jmp_i(3),
+            add_ui_i(1, 1, 1),
+            add_ui_i(1, 1, 1),
+            halt(),
+        ];
+
+        let mut cycles = vec![];
+        for stage in &[BranchResolveStage::Execute, BranchResolveStage::WriteBack] {
+            let mut cu = load_words(&words);
+            cu.branch_resolve_stage = *stage;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[1], 0, "stage={:?}", stage);
+            assert_eq!(cu.registers[PC], 3, "stage={:?}", stage);
+            assert_eq!(cu.instructions_retired, 2, "stage={:?}", stage);
+            cycles.push(cu.cycle_count);
+        }
+
+        // Write back resolution flushes two more instructions
+        assert_eq!(cycles[0] + 2, cycles[1], "cycles (execute, write back): {:?}",
+                   cycles);
+    }
+
+    /// Tests that a conditional jump waits for the status register written by
+    /// the compare before it, whichever stage resolves branches.
+    #[test]
+    fn test_conditional_jump_status_hazard() {
+        // The add sets POS but the compare replaces it with GT, so the jump
+        // must fall through to the second add
+        let words = program![
+            add_ui_i(1, 0, 1),
+            cmp(1, 2),
+            jmp_cond_i(ConditionCodes::POS.value(), 5),
+            add_ui_i(3, 0, 7),
+            halt(),
+            halt(),
+        ];
+
+        for stage in &[BranchResolveStage::Execute, BranchResolveStage::WriteBack] {
+            let mut cu = load_words(&words);
+            cu.branch_resolve_stage = *stage;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[3], 7, "stage={:?}", stage);
+            assert_eq!(cu.registers[PC], 4, "stage={:?}", stage);
+        }
+    }
+
+    /// Tests that stage_instructions() and stage_operands() list the instruction
+    /// in each pipeline stage, youngest first, with the operands it decoded, and
+    /// the last instruction run without the pipeline.
+    #[test]
+    fn test_stage_instructions() {
+        let words = program![
+            add_ui_i(1, 0, 5),
+            add_ui_i(2, 0, 6),
+            add_ui_i(3, 0, 7),
+            noop(),
+            halt(),
+        ];
+        let operands = |dest: u32, op2: u32| Some(vec![
+            ("dest".to_string(), dest),
+            ("op1".to_string(), 0),
+            ("op2".to_string(), op2),
+        ]);
+
+        let mut cu = load_words(&words);
+        for _i in 0..4 {
+            cu.step().expect("failed to step");
+        }
+
+        let names: Vec<Option<String>> = cu.stage_instructions().iter()
+            .map(|inst| inst.as_ref().map(|i| i.to_string()))
+            .collect();
+        let add = Some("Add unsigned".to_string());
+        assert_eq!(names, vec![Some("Noop".to_string()), add.clone(), add.clone(),
+                               add, None]);
+
+        let stage_operands = cu.stage_operands();
+        assert_eq!(stage_operands.len(), 5);
+        assert_eq!(stage_operands[1], operands(3, 7), "decode");
+        assert_eq!(stage_operands[2], operands(2, 6), "execute");
+        assert_eq!(stage_operands[3], operands(1, 5), "access memory");
+        assert_eq!(stage_operands[4], None, "write back");
+
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        cu.step().expect("failed to step");
+        cu.step().expect("failed to step");
+        assert_eq!(cu.stage_instructions().len(), 1);
+        assert_eq!(cu.stage_operands(), vec![operands(2, 6)]);
+    }
+
+    /// Tests that the listing of the example program shows the address, word
+    /// and disassembly of each instruction, that a word only the cache holds
+    /// is shown without accessing the cache, and that the listing stops at the
+    /// last address.
+    #[test]
+    fn test_listing() {
+        let cu = load_control_unit("./test-data/instructions.bin");
+        let listing = cu.listing(0, 5, false);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "0x0000: 0x168c20a0  ADDUI R1, R3, #45");
+        assert_eq!(lines[4], "0x0004: 0x00000000  HALT");
+
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let cache = Rc::new(RefCell::new(DMCache::new(0, 4, dram.clone())));
+        cache.borrow_mut().set(1, asm::noop()).unwrap("set cached word");
+        let stats = cache.borrow().stats();
+        let cu = ControlUnit::new(dram, cache.clone(), StageLatencies::default());
+
+        let listing = cu.listing(1, 1, false);
+        assert_eq!(listing, format!("0x0001: {:#010x}  NOOP", asm::noop()));
+        assert_eq!(cache.borrow().stats(), stats);
+
+        assert_eq!(cu.listing(u32::MAX, 3, false).lines().count(), 1);
+    }
+
+    /// Tests that with the Harvard topology a store to an instruction's
+    /// address changes data memory but not instruction memory.
+    #[test]
+    fn test_harvard_topology() {
+        // [R2] = R1
+        let store = asm::store_rd(2, 1);
+
+        for pipeline_enabled in &[false, true] {
+            let instruction_memory = Rc::new(RefCell::new(DRAM::new(0)));
+            instruction_memory.borrow_mut().set(0, store).unwrap("set store");
+            instruction_memory.borrow_mut().set(1, 0).unwrap("set halt");
+            let data_memory = Rc::new(RefCell::new(DRAM::new(0)));
+
+            let mut cu = ControlUnit::new(data_memory.clone(), data_memory.clone(), StageLatencies::default());
+            cu.cache_enabled = false;
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.topology = MemoryTopology::Harvard(instruction_memory.clone());
+            cu.registers[1] = 42;
+            run_to_end(&mut cu);
+
+            let (_wait, instruction) = instruction_memory.borrow_mut().get(0).unwrap("get instruction");
+            let (_wait, data) = data_memory.borrow_mut().get(0).unwrap("get data");
+            assert_eq!(instruction, store, "pipeline={}", pipeline_enabled);
+            assert_eq!(data, 42, "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that a run matches the trace recorded from an identical run, and
+    /// that a changed trace reports the first differing instruction.
+    #[test]
+    fn test_trace_record_verify() {
+        let path = std::env::temp_dir().join(format!("leg-trace-{}.txt", std::process::id()));
+        let path = path.to_str().expect("temp path not unicode");
+
+        let mut golden = load_control_unit("./test-data/instructions.bin");
+        golden.pipeline_enabled = false;
+        golden.tracing = true;
+        run_to_end(&mut golden);
+        assert_eq!(golden.trace.len(), 5);
+        assert_eq!(golden.trace[0], TraceEntry{
+            pc: 0,
+            instruction: 0x168c20a0,
+            changes: vec![(1, 45), (STS, ConditionCodes::POS.value())],
+            disassembly: "ADDUI R1, R3, #45".to_string(),
+        });
+        golden.record_trace_to(path).expect("failed to record trace");
+
+        let mut rerun = load_control_unit("./test-data/instructions.bin");
+        rerun.pipeline_enabled = false;
+        rerun.tracing = true;
+        run_to_end(&mut rerun);
+        assert_eq!(rerun.verify_against_trace(path), Ok(()));
+
+        // The changed register value of instruction 0 no longer matches
+        rerun.trace[0].changes = vec![(1, 46)];
+        let res = rerun.verify_against_trace(path);
+        std::fs::remove_file(path).expect("failed to remove trace");
+        
+        assert_eq!(res, Err(Divergence::Mismatch{
+            index: 0,
+            expected: Some(golden.trace[0].clone()),
+            actual: Some(rerun.trace[0].clone()),
+        }));
+    }
+
+    /// Tests that a trace line without the disassembly parses with the
+    /// disassembly of its instruction, and that a run matches a trace written
+    /// in that format.
+    #[test]
+    fn test_trace_without_disassembly() {
+        assert_eq!(TraceEntry::parse("0x0000 0x168c20a0 R1=45"), Ok(TraceEntry{
+            pc: 0,
+            instruction: 0x168c20a0,
+            changes: vec![(1, 45)],
+            disassembly: "ADDUI R1, R3, #45".to_string(),
+        }));
+
+        let path = std::env::temp_dir().join(format!("leg-old-trace-{}.txt", std::process::id()));
+        let path = path.to_str().expect("temp path not unicode");
+
+        let mut cu = load_control_unit("./test-data/instructions.bin");
+        cu.pipeline_enabled = false;
+        cu.tracing = true;
+        run_to_end(&mut cu);
+
+        let text: String = cu.trace.iter()
+            .map(|entry| {
+                let line = entry.to_string();
+                let (old, _disassembly) = line.split_once(" ; ")
+                    .expect("missing disassembly");
+                format!("{}\n", old)
+            })
+            .collect();
+        std::fs::write(path, text).expect("failed to write trace");
+
+        let res = cu.verify_against_trace(path);
+        std::fs::remove_file(path).expect("failed to remove trace");
+        assert_eq!(res, Ok(()));
+    }
+
+    /// Tests that tracing a loop records every iteration while each loop
+    /// word is disassembled into the cache once.
+    #[test]
+    fn test_trace_disassembly_cache() {
+        const ITERATIONS: u32 = 3;
+
+        // Loops while R1 < R2
+        let words = program![
+            add_ui_i(1, 1, 1),
+            cmp(1, 2),
+            jmp_lt_i(0),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        cu.tracing = true;
+        cu.registers[2] = ITERATIONS;
+        run_to_end(&mut cu);
+
+        // Each iteration retires the loop body, then the halt retires
+        assert_eq!(cu.trace.len(), 3 * ITERATIONS as usize + 1);
+        for i in 0..ITERATIONS as usize {
+            assert_eq!(cu.trace[3 * i].changes,
+                       vec![(1, i as u32 + 1), (STS, ConditionCodes::POS.value())]);
+            assert_eq!(cu.trace[3 * i].disassembly, "ADDUI R1, R1, #1");
+            assert_eq!(cu.trace[3 * i + 1].disassembly, "CMP R1, R2");
+            assert_eq!(cu.trace[3 * i + 2].disassembly, "JMPLT #0");
+        }
+        assert_eq!(cu.trace[3 * ITERATIONS as usize].disassembly, "HALT");
+
+        let mut cached: Vec<u32> = cu.disassembly_cache.keys().copied().collect();
+        cached.sort();
+        let mut sorted = words.clone();
+        sorted.sort();
+        assert_eq!(cached, sorted);
+    }
+
+    /// Tests that the pipeline is not drained while instructions older than
+    /// the halt retire, and is drained once the retired halt leaves write back.
+    #[test]
+    fn test_is_drained() {
+        let mut cu = load_control_unit("./test-data/instructions.bin");
+        assert!(cu.is_drained(), "drained before first step");
+
+        while !cu.halt_encountered {
+            cu.step().expect("failed to step control unit");
+        }
+
+        while cu.step().expect("failed to step control unit") != Status::Halted {
+            assert!(!cu.is_drained(), "draining after halt fetched");
+        }
+        assert!(!cu.is_drained(), "halt in write back stage");
+        assert!(cu.write_back_instruction.is_some());
+
+        cu.step().expect("failed to step control unit");
+        assert!(cu.is_drained(), "drained after halt left write back");
+    }
+
+    /// Tests that returning before any subroutine call writes a warning, and
+    /// that no warning is written when the check is off.
+    #[test]
+    fn test_warn_return_before_call() {
+        // The return jumps to the halt at 0
+        let words = program![
+            halt(),
+            jmp_rd(LR),
+        ];
+
+        for (pipeline_enabled, warn) in &[(false, true), (true, true), (false, false)] {
+            let mut cu = load_words(&words);
+            cu.registers[PC] = 1;
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.warn_return_before_call = *warn;
+            let output = Rc::new(RefCell::new(MemoryOutput::new()));
+            cu.output = output.clone();
+
+            run_to_end(&mut cu);
+            assert_eq!(cu.registers[PC], 0, "pipeline={}", pipeline_enabled);
+
+            let warnings: Vec<String> = output.borrow().lines.iter()
+                .filter(|l| l.starts_with("warning: "))
+                .cloned()
+                .collect();
+            match *warn {
+                true => assert_eq!(warnings, vec![
+                    "warning: return at 0x0001 jumped to LR before it was set by a subroutine call".to_string(),
+                ], "pipeline={}", pipeline_enabled),
+                false => assert!(warnings.is_empty(), "warnings={:?}", warnings),
+            };
+        }
+    }
+
+    /// Tests running a program built with program!, which sums 1 to 4 in a
+    /// loop.
+    #[test]
+    fn test_program_macro_run() {
+        let words = program![
+            add_ui_i(2, 0, 5),
+            add_ui_i(1, 0, 1),
+            add_ui_rd(3, 3, 1),
+            add_ui_i(1, 1, 1),
+            cmp(1, 2),
+            jmp_lt_i(2),
+            halt(),
+        ];
+
+        // The pipeline does not handle data hazards, which the loop has
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        run_to_end(&mut cu);
+
+        assert_eq!(cu.registers[3], 1 + 2 + 3 + 4);
+    }
+
+    /// Tests that queued interrupts are listed in the order they will be taken
+    /// until they are taken.
+    #[test]
+    fn test_pending_interrupts() {
+        const HANDLER: u32 = 2;
+        const TIMER_CODE: u32 = 9;
+        let enter = InterruptCodes::ENTER.value() as u32;
+
+        let words = program![
+            noop(),
+            noop(),
+            noop(),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        cu.queue_interrupt(InterruptSource::Keyboard, enter);
+        cu.queue_interrupt(InterruptSource::Timer, TIMER_CODE);
+        let expected = vec![
+            (InterruptSource::Timer, TIMER_CODE),
+            (InterruptSource::Keyboard, enter),
+        ];
+        assert_eq!(cu.pending_interrupts(), expected);
+
+        // Interrupts stay pending while no handler is set
+        cu.step().expect("failed to step");
+        assert_eq!(cu.pending_interrupts(), expected);
+
+        cu.registers[IHDLR] = HANDLER;
+        cu.step().expect("failed to step");
+        assert_eq!(cu.pending_interrupts(), vec![(InterruptSource::Keyboard, enter)]);
+    }
+
+    /// Tests that with the pipeline an interrupt which becomes takeable while a
+    /// taken jump is in flight is taken after the jump, so the handler runs and
+    /// returns to code after the jump's target.
+    #[test]
+    fn test_interrupt_behind_jump() {
+        const HANDLER: u32 = 12;
+        const TIMER_CODE: u32 = 9;
+
+        // The noops keep the halt from being fetched before the interrupt is
+        // taken
+        let mut words = program![
+            sih(HANDLER),
+            jmp_i(5),
+            add_ui_i(3, 3, 1),
+            add_ui_i(3, 3, 1),
+            add_ui_i(3, 3, 1),
+            add_ui_i(1, 1, 1),
+            noop(),
+            noop(),
+            noop(),
+            noop(),
+            halt(),
+        ];
+        words.resize(HANDLER as usize, 0);
+        words.extend(program![
+            add_ui_i(2, 2, 1),
+            rfi(),
+        ]);
+
+        for stage in &[BranchResolveStage::Execute, BranchResolveStage::WriteBack] {
+            let mut cu = load_words(&words);
+            cu.branch_resolve_stage = *stage;
+
+            // Taken as soon as SIH writes back, when the jump is right behind it
+            cu.queue_interrupt(InterruptSource::Timer, TIMER_CODE);
+
+            assert_eq!(cu.run_cycles(200), Ok(Status::Halted), "stage={:?}", stage);
+            assert_eq!(cu.registers[1], 1, "stage={:?}", stage);
+            assert_eq!(cu.registers[2], 1, "stage={:?}", stage);
+            assert_eq!(cu.registers[3], 0, "stage={:?}", stage);
+            assert!((5..10).contains(&cu.registers[INTLR]),
+                    "returned to {}, stage={:?}", cu.registers[INTLR], stage);
+            assert!(!cu.registers.interrupt_active, "stage={:?}", stage);
+        }
+    }
+
+    /// Tests that with one delay slot the instruction after a taken branch
+    /// executes and later ones are flushed, and that delay slots which were
+    /// not fetched yet when the branch resolved are still executed.
+    #[test]
+    fn test_branch_delay_slots() {
+        let words = program![
+            jmp_i(4),
+            add_ui_i(1, 1, 1),
+            add_ui_i(2, 2, 1),
+            add_ui_i(3, 3, 1),
+            halt(),
+        ];
+
+        for stage in &[BranchResolveStage::Execute, BranchResolveStage::WriteBack] {
+            let mut cu = load_words(&words);
+            cu.branch_resolve_stage = *stage;
+            cu.branch_delay_slots = 1;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[1], 1, "stage={:?}", stage);
+            assert_eq!(cu.registers[2], 0, "stage={:?}", stage);
+            assert_eq!(cu.registers[3], 0, "stage={:?}", stage);
+            assert_eq!(cu.registers[PC], 4, "stage={:?}", stage);
+            assert_eq!(cu.instructions_retired, 3, "stage={:?}", stage);
+        }
+
+        // Only one instruction is fetched when the execute stage resolves the
+        // jump
+        let mut cu = load_words(&words);
+        cu.branch_resolve_stage = BranchResolveStage::Execute;
+        cu.branch_delay_slots = 2;
+        run_to_end(&mut cu);
+
+        assert_eq!((cu.registers[1], cu.registers[2], cu.registers[3]), (1, 1, 0));
+        assert_eq!(cu.registers[PC], 4);
+    }
+
+    /// Tests that values stored through the cache are in DRAM once the
+    /// program halts.
+    #[test]
+    fn test_cache_written_back_at_halt() {
+        const ADDR: u32 = 50;
+
+        for pipeline_enabled in &[false, true] {
+            let dram = Rc::new(RefCell::new(DRAM::new(0)));
+            for (i, word) in program![store_rd(2, 1), halt()].iter().enumerate() {
+                dram.borrow_mut().set(i as u32, *word).unwrap("set word");
+            }
+            let cache = Rc::new(RefCell::new(DMCache::new(0, 16, dram.clone())));
+
+            let mut cu = ControlUnit::new(dram.clone(), cache.clone(), StageLatencies::default());
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.registers[1] = 42;
+            cu.registers[2] = ADDR;
+            run_to_end(&mut cu);
+
+            assert_eq!(dram.borrow().inspect().get(&ADDR), Some(&42),
+                       "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that with R0 hardwired a write to it is discarded and warned
+    /// about.
+    #[test]
+    fn test_zero_register_write_warning() {
+        let words = program![
+            add_ui_i(0, 0, 5),
+            add_ui_i(1, 0, 1),
+            halt(),
+        ];
+
+        for pipeline_enabled in &[false, true] {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.zero_register_hardwired = true;
+            cu.warn_zero_register_write = true;
+            let output = Rc::new(RefCell::new(MemoryOutput::new()));
+            cu.output = output.clone();
+
+            run_to_end(&mut cu);
+            assert_eq!(cu.registers[0], 0, "pipeline={}", pipeline_enabled);
+            assert_eq!(cu.registers[1], 1, "pipeline={}", pipeline_enabled);
+
+            let warnings: Vec<String> = output.borrow().lines.iter()
+                .filter(|l| l.starts_with("warning: "))
+                .cloned()
+                .collect();
+            assert_eq!(warnings, vec![
+                "warning: instruction at 0x0000 wrote 5 to R0, which is hardwired to 0".to_string(),
+            ], "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that with the bubble decode error policy an invalid word is
+    /// replaced by a bubble and the program continues.
+    #[test]
+    fn test_decode_error_bubble() {
+        // Control type with unused operation code 15
+        let mut words = program![add_ui_i(1, 0, 1)];
+        words.push(asm::word(InstructionT::Control, 15));
+        words.extend(program![add_ui_i(2, 0, 2), halt()]);
+
+        let mut cu = load_words(&words);
+        assert!(cu.step().and_then(|_r| cu.step()).is_err(),
+                "invalid word aborts by default");
+
+        let mut cu = load_words(&words);
+        cu.decode_error_policy = DecodeErrorPolicy::Bubble;
+        let output = Rc::new(RefCell::new(MemoryOutput::new()));
+        cu.output = output.clone();
+        run_to_end(&mut cu);
+
+        assert_eq!(cu.registers[1], 1);
+        assert_eq!(cu.registers[2], 2);
+        assert_eq!(cu.instructions_retired, 3);
+
+        let warnings: Vec<String> = output.borrow().lines.iter()
+            .filter(|l| l.starts_with("warning: "))
+            .cloned()
+            .collect();
+        assert_eq!(warnings.len(), 1, "warnings={:?}", warnings);
+        assert!(warnings[0].starts_with("warning: replaced instruction at 0x0001 with a bubble"),
+                "warning={}", warnings[0]);
+    }
+
+    /// Tests that the listing timing column shows the modeled latency and the
+    /// cycles instructions took, with a load modeled to take longer than an add
+    /// since it accesses memory.
+    #[test]
+    fn test_listing_timing() {
+        const DELAY: u16 = 10;
+
+        let dram = Rc::new(RefCell::new(DRAM::new(DELAY)));
+        for (i, word) in program![add_ui_i(1, 0, 1), load_rd(2, 3), halt()].iter().enumerate() {
+            dram.borrow_mut().set(i as u32, *word).unwrap("set word");
+        }
+
+        let mut cu = ControlUnit::new(dram.clone(), dram, StageLatencies::default());
+        cu.cache_enabled = false;
+        cu.pipeline_enabled = false;
+        cu.registers[3] = 100;
+        run_to_end(&mut cu);
+
+        let listing = cu.listing(0, 4, true);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        // The fetch and 5 cycles for the stages, the load also waits for its
+        // access
+        assert_eq!(lines[0], "0x0000: 0x008020a0  ADDUI R1, R0, #1  ; 15 cycles modeled, 15 total");
+        assert_eq!(lines[1], "0x0001: 0x00018840  LOAD R2, [R3]  ; 25 cycles modeled, 25 total");
+        assert!(lines[3].ends_with("  ; 15 cycles modeled, not run"), "line={}", lines[3]);
+    }
+
+    /// Tests that moving a value into PC jumps there and flushes the
+    /// instructions fetched after the move, and that the move is an error when
+    /// PC writes are not treated as branches.
+    #[test]
+    fn test_pc_write_is_branch() {
+        let words = program![
+            add_ui_i(1, 0, 6),
+            mov(PC, 1),
+            add_ui_i(2, 0, 1),
+            add_ui_i(3, 0, 1),
+            add_ui_i(4, 0, 1),
+            halt(),
+            add_ui_i(5, 0, 7),
+            halt(),
+        ];
+
+        for pipeline_enabled in &[false, true] {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[PC], 7, "pipeline={}", pipeline_enabled);
+            assert_eq!(cu.registers[5], 7, "pipeline={}", pipeline_enabled);
+            for reg in 2..=4 {
+                assert_eq!(cu.registers[reg], 0, "R{} pipeline={}", reg, pipeline_enabled);
+            }
+
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.pc_write_is_branch = false;
+            let err = loop {
+                match cu.step() {
+                    Ok(Status::Continue) => (),
+                    Ok(_outcome) => panic!("program ended without an error, pipeline={}",
+                                        pipeline_enabled),
+                    Err(e) => break e,
+                }
+            };
+            assert_eq!(err, "instruction at 0x0001 wrote PC directly, which is not \
+                             allowed unless pc_write_is_branch is set");
+        }
+    }
+
+    /// Tests comparing memory after a program which stores to several
+    /// addresses through the cache.
+    #[test]
+    fn test_assert_memory_matches() {
+        let words = program![
+            add_ui_i(1, 0, 100),
+            add_ui_i(2, 0, 5),
+            add_ui_i(3, 0, 101),
+            add_ui_i(4, 0, 6),
+            add_ui_i(5, 0, 200),
+            store_rd(1, 2),
+            store_rd(3, 4),
+            store_rd(5, 4),
+            halt(),
+        ];
+
+        for (pipeline_enabled, levels) in &[(false, 1), (true, 1), (false, 2), (true, 2)] {
+            let dram = Rc::new(RefCell::new(DRAM::new(0)));
+            for (i, word) in words.iter().enumerate() {
+                dram.borrow_mut().set(i as u32, *word).unwrap("set word");
+            }
+            let mut cache: Rc<RefCell<dyn Memory<u32, u32>>> =
+                Rc::new(RefCell::new(DMCache::new(0, 16, dram.clone())));
+            if *levels == 2 {
+                cache = Rc::new(RefCell::new(DMCache::new(0, 8, cache)));
+            }
+
+            let mut cu = ControlUnit::new(dram.clone(), cache.clone(), StageLatencies::default());
+            cu.pipeline_enabled = *pipeline_enabled;
+            run_to_end(&mut cu);
+
+            let mut expected: HashMap<u32, u32> = HashMap::new();
+            expected.insert(100, 5);
+            expected.insert(101, 6);
+            expected.insert(200, 6);
+            assert_eq!(cu.assert_memory_matches(&expected), Ok(()),
+                       "pipeline={}, levels={}", pipeline_enabled, levels);
+
+            expected.insert(200, 7);
+            expected.insert(300, 1);
+            assert_eq!(cu.assert_memory_matches(&expected),
+                       Err("address 0x00c8 holds 0x6, expected 0x7".to_string()),
+                       "pipeline={}, levels={}", pipeline_enabled, levels);
+        }
+    }
+
+    /// Tests that with the pipeline instructions read PC in decode as their
+    /// own address, not the address being fetched, also when decode is
+    /// repeated while the instruction waits for a load.
+    #[test]
+    fn test_decode_reads_own_address() {
+        let words = program![
+            load_rd(3, 0),
+            add_ui_rd(1, PC as u32, 3),
+            halt(),
+        ];
+
+        for pipeline_enabled in &[false, true] {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.forwarding_enabled = false;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[1], words[0] + 1, "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that a halt which moved past fetch before a branch flushed it does
+    /// not stop the program.
+    #[test]
+    fn test_flushed_halt_in_decode() {
+        let words = program![
+            jmp_i(2),
+            halt(),
+            add_ui_i(1, 0, 1),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.branch_resolve_stage = BranchResolveStage::WriteBack;
+        run_to_end(&mut cu);
+
+        assert_eq!(cu.registers[1], 1);
+        assert_eq!(cu.registers[PC], 3);
+    }
+
+    /// Tests a program which sets an interrupt handler with SIH, raises an
+    /// interrupt with INT, and returns from the handler with RFI.
+    #[test]
+    fn test_software_interrupt() {
+        const HANDLER: u32 = 12;
+
+        let mut words = program![
+            sih(HANDLER),
+            add_ui_i(3, 0, 4),
+            int_i(3),
+            add_ui_i(2, 0, 1),
+            int_rd(3),
+            add_ui_i(4, 0, 1),
+            halt(),
+        ];
+        words.resize(HANDLER as usize, 0);
+        words.extend(program![
+            add_ui_rd(1, 1, 3),
+            rfi(),
+        ]);
+
+        for pipeline_enabled in &[false, true] {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.registers[3] = 0;
+            run_to_end(&mut cu);
+
+            // The handler ran once for each interrupt and returned after it
+            assert_eq!(cu.registers[1], 8, "pipeline={}", pipeline_enabled);
+            assert_eq!(cu.registers[2], 1, "pipeline={}", pipeline_enabled);
+            assert_eq!(cu.registers[4], 1, "pipeline={}", pipeline_enabled);
+            assert_eq!(cu.registers[PC], 6, "pipeline={}", pipeline_enabled);
+
+            let (_wait, code) = cu.dram.borrow_mut().get(INTERRUPT_CODE_ADDR).unwrap("get code");
+            assert_eq!(code, 4, "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that a taken jump to its own address keeps jumping to itself
+    /// without the pipeline.
+    #[test]
+    fn test_jump_to_self() {
+        let words = program![
+            noop(),
+            jmp_i(1),
+            halt(),
+        ];
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+
+        let mut pcs = Vec::new();
+        for _i in 0..4 {
+            assert_eq!(cu.step(), Ok(Status::Continue));
+            pcs.push(cu.registers[PC]);
+        }
+        assert_eq!(pcs, vec![1, 1, 1, 1]);
+    }
+
+    /// Tests that an INT in an interrupt handler is dropped after an ALU
+    /// instruction in the handler wrote its condition code to STS, so the
+    /// handler still returns.
+    #[test]
+    fn test_nested_interrupt_dropped() {
+        const HANDLER: u32 = 8;
+
+        let mut words = program![
+            sih(HANDLER),
+            int_i(3),
+            halt(),
+        ];
+        words.resize(HANDLER as usize, 0);
+        words.extend(program![
+            add_ui_i(1, 1, 1),
+            int_i(4),
+            rfi(),
+        ]);
+
+        for pipeline_enabled in &[false, true] {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+
+            assert_eq!(cu.run_cycles(200), Ok(Status::Halted), "pipeline={}", pipeline_enabled);
+            assert_eq!(cu.registers[1], 1, "pipeline={}", pipeline_enabled);
+            assert_eq!(cu.registers[INTLR], 2, "pipeline={}", pipeline_enabled);
+            assert!(!cu.registers.interrupt_active, "pipeline={}", pipeline_enabled);
+
+            let (_wait, code) = cu.dram.borrow_mut().get(INTERRUPT_CODE_ADDR).unwrap("get code");
+            assert_eq!(code, 3, "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that a subtraction with a zero result sets the zero condition
+    /// code, so a jump can test equality without a compare.
+    #[test]
+    fn test_branch_on_zero_result() {
+        let words = program![
+            add_ui_i(1, 0, 5),
+            add_ui_i(2, 0, 5),
+            sub_ui_rd(3, 1, 2),
+            jmp_cond_i(ConditionCodes::Z.value(), 6),
+            add_ui_i(4, 0, 1),
+            halt(),
+            add_ui_i(5, 0, 1),
+            halt(),
+        ];
+
+        for pipeline_enabled in &[false, true] {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[4], 0, "fell through, pipeline={}", pipeline_enabled);
+            assert_eq!(cu.registers[5], 1, "jumped, pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that with a miss status holding register a load which hits
+    /// continues while an earlier miss is serviced, so the program takes fewer
+    /// cycles than when every miss stalls.
+    #[test]
+    fn test_hit_under_miss() {
+        const MISS_ADDR: u32 = 100;
+        const HIT_ADDR: u32 = 101;
+
+        let words = program![
+            load_rd(3, 11),
+            noop(),
+            noop(),
+            noop(),
+            noop(),
+            load_rd(1, 10),
+            load_rd(2, 11),
+            noop(),
+            noop(),
+            halt(),
+        ];
+
+        let run = |mshr_count: usize| -> ControlUnit {
+            let dram = Rc::new(RefCell::new(DRAM::new(10)));
+            for (i, word) in words.iter().enumerate() {
+                dram.borrow_mut().set(i as u32, *word).unwrap("set word");
+            }
+            dram.borrow_mut().set(MISS_ADDR, 7).unwrap("set miss value");
+            dram.borrow_mut().set(HIT_ADDR, 8).unwrap("set hit value");
+            let cache = Rc::new(RefCell::new(DMCache::new(1, 64, dram.clone())));
+
+            let mut cu = ControlUnit::new(dram, cache, StageLatencies::default());
+            cu.mshr_count = mshr_count;
+            cu.registers[10] = MISS_ADDR;
+            cu.registers[11] = HIT_ADDR;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[1], 7, "mshr_count={}", mshr_count);
+            assert_eq!(cu.registers[2], 8, "mshr_count={}", mshr_count);
+            assert_eq!(cu.registers[3], 8, "mshr_count={}", mshr_count);
+
+            cu
+        };
+
+        let blocking = run(0);
+        let non_blocking = run(1);
+        assert!(non_blocking.cycle_count < blocking.cycle_count,
+                "blocking took {} cycles, non-blocking took {}",
+                blocking.cycle_count, non_blocking.cycle_count);
+    }
+
+    /// Tests that with a miss status holding register a load of another word
+    /// in a line whose miss is outstanding waits for the miss, like a load of
+    /// the same word.
+    #[test]
+    fn test_hit_under_miss_same_line() {
+        const MISS_ADDR: u32 = 100;
+
+        let words = program![
+            load_rd(1, 10),
+            load_rd(2, 11),
+            noop(),
+            noop(),
+            halt(),
+        ];
+
+        let run = |second_addr: u32| -> u32 {
+            let dram = Rc::new(RefCell::new(DRAM::new(10)));
+            for (i, word) in words.iter().enumerate() {
+                dram.borrow_mut().set(i as u32, *word).unwrap("set word");
+            }
+            // Lines hold 4 words, so MISS_ADDR and MISS_ADDR + 1 share one
+            let cache = Rc::new(RefCell::new(
+                DMCache::new_with_block_size(1, 16, 4, dram.clone())
+                    .expect("failed to create cache")));
+
+            let mut cu = ControlUnit::new(dram, cache, StageLatencies::default());
+            cu.mshr_count = 1;
+            cu.registers[10] = MISS_ADDR;
+            cu.registers[11] = second_addr;
+            run_to_end(&mut cu);
+
+            cu.cycle_count
+        };
+
+        assert_eq!(run(MISS_ADDR + 1), run(MISS_ADDR));
+    }
+
+    /// Tests that the cycles a benchmark takes grow by the same amount for
+    /// each added batch of iterations.
+    #[test]
+    fn test_benchmark_instruction() {
+        let config = BatchConfig{
+            pipeline_enabled: true,
+            cache_enabled: false,
+            stack_pointer: 1000,
+            max_cycles: 100000,
+        };
+        let cycles: Vec<u32> = [10, 20, 30].iter()
+            .map(|n| benchmark_instruction(ALUOp::AddUIRD, *n, &config)
+                 .expect("benchmark failed"))
+            .collect();
+
+        assert!(cycles[1] > cycles[0], "cycles={:?}", cycles);
+        assert_eq!(cycles[2] - cycles[1], cycles[1] - cycles[0], "cycles={:?}", cycles);
+
+        // Divisions read non zero registers and immediates
+        benchmark_instruction(ALUOp::DivUIRD, 10, &config).expect("register division failed");
+        benchmark_instruction(ALUOp::DivSII, 10, &config).expect("immediate division failed");
+    }
+
+    /// Tests that an add which reads the register loaded by the instruction
+    /// right before it stalls until the load writes back, and sees the loaded
+    /// value.
+    #[test]
+    fn test_load_use_hazard() {
+        const ADDR: u32 = 100;
+
+        let words = program![
+            load_rd(1, 2),
+            add_ui_i(3, 1, 1),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.dram.borrow_mut().set(ADDR, 41).unwrap("set value");
+        cu.registers[2] = ADDR;
+        run_to_end(&mut cu);
+
+        assert_eq!(cu.registers[1], 41);
+        assert_eq!(cu.registers[3], 42);
+    }
+
+    /// Tests that a chain of adds which each read the result of the one before
+    /// gets the same result in fewer cycles when results are forwarded than
+    /// when each add stalls until the one before writes back.
+    #[test]
+    fn test_forwarding() {
+        const CHAIN: usize = 10;
+
+        let mut words = vec![crate::encoding::asm::add_ui_i(1, 1, 1); CHAIN];
+        words.push(crate::encoding::asm::halt());
+
+        let mut cycles = vec![];
+        for forwarding_enabled in &[false, true] {
+            let mut cu = load_words(&words);
+            cu.forwarding_enabled = *forwarding_enabled;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[1], CHAIN as u32, "forwarding={}", forwarding_enabled);
+            cycles.push(cu.cycle_count);
+        }
+
+        assert!(cycles[1] < cycles[0], "cycles (stalling, forwarding): {:?}", cycles);
+    }
+
+    /// Tests that without the pipeline each stage's latency is added to every
+    /// instruction, and that with the pipeline each cycle takes as long as the
+    /// slowest stage.
+    #[test]
+    fn test_stage_latencies() {
+        let words = program![
+            add_ui_i(1, 0, 1),
+            add_ui_i(2, 0, 2),
+            halt(),
+        ];
+        let slow_execute = StageLatencies{
+            execute: 4,
+            ..StageLatencies::default()
+        };
+        assert_eq!(slow_execute.cycle_time(), 4);
+
+        for pipeline_enabled in &[false, true] {
+            let mut cycles = vec![];
+            for latencies in &[StageLatencies::default(), slow_execute] {
+                let mut cu = load_words(&words);
+                cu.pipeline_enabled = *pipeline_enabled;
+                cu.stage_latencies = *latencies;
+                run_to_end(&mut cu);
+
+                assert_eq!(cu.registers[2], 2, "pipeline={}", pipeline_enabled);
+                cycles.push(cu.cycle_count);
+            }
+
+            match pipeline_enabled {
+                false => assert_eq!(cycles[1], cycles[0] + 3 * 3,
+                                    "no pipeline cycles: {:?}", cycles),
+                true => assert_eq!(cycles[1], cycles[0] * 4,
+                                   "pipeline cycles: {:?}", cycles),
+            };
+        }
+    }
+
+    /// Tests that the pipeline diagram of an add which stalls for the load
+    /// before it shows the bubble in the add's row in the step after it was
+    /// decoded.
+    #[test]
+    fn test_pipeline_diagram() {
+        let words = program![
+            load_rd(1, 2),
+            add_ui_i(3, 1, 1),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.registers[2] = 100;
+        cu.tracing = true;
+        run_to_end(&mut cu);
+
+        let expected = [
+            "                         1  2  3  4  5  6  7  8",
+            "0x0000 LOAD R1, [R2]     F  D  X  M  W",
+            "0x0001 ADDUI R3, R1, #1     F  D  *  X  M  W",
+            "0x0002 HALT                    F  *  D  X  M  W",
+        ];
+        assert_eq!(cu.pipeline_diagram(), expected.join("\n"));
+    }
+
+    /// Tests that the branch predictor learns a loop's branch, so a loop which
+    /// iterates 100 times is mispredicted only a few times and runs faster.
+    #[test]
+    fn test_branch_predictor() {
+        let words = program![
+            add_ui_i(1, 1, 1),
+            cmp(1, 2),
+            jmp_lt_i(0),
+            halt(),
+        ];
+
+        let mut cycles = Vec::new();
+        for predict in &[false, true] {
+            for resolve_stage in &[BranchResolveStage::WriteBack, BranchResolveStage::Execute] {
+                let mut cu = load_words(&words);
+                cu.registers[2] = 100;
+                cu.branch_resolve_stage = *resolve_stage;
+                if *predict {
+                    cu.branch_predictor = Some(BranchPredictor::new(4, cu.instruction_size)
+                                               .expect("failed to create predictor"));
+                }
+                run_to_end(&mut cu);
+
+                assert_eq!(cu.registers[1], 100, "predict={} stage={:?}", predict, resolve_stage);
+                match predict {
+                    true => assert!(cu.mispredictions <= 3,
+                                    "{} mispredictions", cu.mispredictions),
+                    false => assert_eq!(cu.mispredictions, 0),
+                };
+                cycles.push(cu.cycle_count);
+            }
+        }
+
+        assert!(cycles[2] < cycles[0], "cycles {:?}", cycles);
+        assert!(cycles[3] < cycles[1], "cycles {:?}", cycles);
+    }
+
+    /// Tests that branch predictor counters are indexed by instruction number,
+    /// so branches in neighbouring words do not share a counter, and that
+    /// invalid sizes are rejected.
+    #[test]
+    fn test_branch_predictor_index() {
+        let mut predictor = BranchPredictor::new(2, 4).expect("failed to create predictor");
+        predictor.update(4, true);
+        predictor.update(4, true);
+
+        assert!(predictor.predict(4));
+        assert!(!predictor.predict(0));
+        assert!(!predictor.predict(8));
+
+        // 4 instructions later the index wraps around
+        assert!(predictor.predict(4 + 4 * 4));
+
+        assert!(BranchPredictor::new(0, 1).is_err());
+        assert!(BranchPredictor::new(BranchPredictor::MAX_INDEX_BITS, 1).is_ok());
+        assert!(BranchPredictor::new(BranchPredictor::MAX_INDEX_BITS + 1, 1).is_err());
+        assert!(BranchPredictor::new(32, 1).is_err());
+        assert!(BranchPredictor::new(2, 0).is_err());
+    }
+
+    /// Tests that the left shift operation codes shift left and the right
+    /// shift operation codes shift right, as decoded by instruction_factory.
+    #[test]
+    fn test_shift_direction() {
+        const VALUE: u32 = 0x80000002;
+
+        // R1 = R1 shifted by 1
+        for (shift, expected) in &[(asm::unsigned(ALUOp::ASLI, 1, 0, 1), 0x4),
+                                   (asm::unsigned(ALUOp::ASRI, 1, 0, 1), 0xC0000001),
+                                   (asm::unsigned(ALUOp::LSLI, 1, 0, 1), 0x4),
+                                   (asm::unsigned(ALUOp::LSRI, 1, 0, 1), 0x40000001)] {
+            let words = [*shift, asm::halt()];
+
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = false;
+            cu.registers[1] = VALUE;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.registers[1], *expected, "{:?} of {:#x}", disassemble(*shift), VALUE);
+        }
+    }
+
+    /// Tests that scheduled bit flips change a register and a memory value at
+    /// the start of the first step which reaches their cycle.
+    #[test]
+    fn test_schedule_bit_flip() {
+        const FLIP_CYCLE: u32 = 6;
+        const ADDR: u32 = 100;
+
+        let words = program![
+            noop(), noop(), noop(), noop(), noop(),
+            noop(), noop(), noop(), noop(), noop(),
+            halt(),
+        ];
+
+        for pipeline_enabled in &[false, true] {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.registers[5] = 1;
+            cu.schedule_bit_flip(FlipTarget::Register(5, 3), FLIP_CYCLE)
+                .expect("failed to schedule register flip");
+            cu.schedule_bit_flip(FlipTarget::Memory(ADDR, 0), FLIP_CYCLE)
+                .expect("failed to schedule memory flip");
+
+            let mut running = true;
+            while running {
+                let flipped = cu.cycle_count >= FLIP_CYCLE;
+                running = cu.step().expect("failed to step") != Status::Halted;
+
+                let expected = match flipped {
+                    true => 9,
+                    false => 1,
+                };
+                assert_eq!(cu.registers[5], expected,
+                           "pipeline={} cycle={}", pipeline_enabled, cu.cycle_count);
+            }
+
+            assert_eq!(cu.registers[5], 9, "pipeline={}", pipeline_enabled);
+            let (_wait, value) = cu.dram.borrow_mut().get(ADDR).unwrap("get flipped value");
+            assert_eq!(value, 1, "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that flipping a bit in memory does not access the cache, so a
+    /// program takes as long and misses as often as without the flip.
+    #[test]
+    fn test_bit_flip_no_access() {
+        const ADDR: u32 = 50;
+
+        let words = program![
+            noop(),
+            noop(),
+            halt(),
+        ];
+
+        let mut runs = vec![];
+        for flip in &[false, true] {
+            let dram = Rc::new(RefCell::new(DRAM::new(10)));
+            for (i, word) in words.iter().enumerate() {
+                dram.borrow_mut().set(i as u32, *word).unwrap("set word");
+            }
+            let cache = Rc::new(RefCell::new(DMCache::new(1, 16, dram.clone())));
+
+            let mut cu = ControlUnit::new(dram.clone(), cache.clone(), StageLatencies::default());
+            cu.pipeline_enabled = false;
+            if *flip {
+                cu.schedule_bit_flip(FlipTarget::Memory(ADDR, 2), 0)
+                    .expect("failed to schedule flip");
+            }
+            run_to_end(&mut cu);
+
+            assert_eq!(dram.borrow().peek(ADDR), SimResult::Wait(0, (*flip as u32) << 2),
+                       "flip={}", flip);
+            runs.push((cache.borrow().stats(), cu.cycle_count, cu.latency_histogram().clone()));
+        }
+
+        assert_eq!(runs[0], runs[1]);
+    }
+
+    /// Tests that bit flips of bits outside a word or of registers which do
+    /// not exist are rejected when they are scheduled.
+    #[test]
+    fn test_schedule_bit_flip_invalid() {
+        let mut cu = load_words(&[]);
+
+        for target in &[FlipTarget::Register(1, 32), FlipTarget::Register(32, 0),
+                        FlipTarget::Memory(0, 40)] {
+            assert!(cu.schedule_bit_flip(*target, 0).is_err(), "target={:?}", target);
+        }
+        assert!(cu.bit_flips.is_empty());
+
+        // The last bit of the last register can be flipped
+        cu.schedule_bit_flip(FlipTarget::Register(31, 31), 0)
+            .expect("failed to schedule flip");
+    }
+
+    /// Tests that stall bubbles are not counted as retired instructions, and
+    /// that ipc() and Display report the retired count.
+    #[test]
+    fn test_ipc() {
+        let words = program![
+            load_rd(1, 2),
+            add_ui_i(3, 1, 1),
+            halt(),
+        ];
+
+        for pipeline_enabled in &[false, true] {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.forwarding_enabled = false;
+            cu.registers[2] = 100;
+            run_to_end(&mut cu);
+
+            assert_eq!(cu.instructions_retired, 3, "pipeline={}", pipeline_enabled);
+            assert!((cu.ipc() - 3.0 / cu.cycle_count as f64).abs() < 1e-9,
+                    "pipeline={}, ipc={}", pipeline_enabled, cu.ipc());
+
+            let status = format!("{}", cu);
+            assert!(status.contains("Retired    : 3"), "status={}", status);
+            assert!(status.contains(&format!("IPC        : {:.3}", cu.ipc())), "status={}", status);
+        }
+    }
+
+    /// Tests that a run resumed from a checkpoint ends in the same state as a
+    /// run which was not interrupted.
+    #[test]
+    fn test_checkpoint_resume() {
+        let words = program![
+            add_ui_i(1, 1, 1),
+            push(3),
+            cmp(1, 2),
+            jmp_lt_i(0),
+            halt(),
+        ];
+
+        let control_unit = || {
+            let dram = Rc::new(RefCell::new(DRAM::new(2)));
+            for (i, word) in words.iter().enumerate() {
+                dram.borrow_mut().set(i as u32, *word).unwrap("set word");
+            }
+            let cache = Rc::new(RefCell::new(DMCache::new(1, 4, dram.clone())));
+
+            let mut cu = ControlUnit::new(dram, cache, StageLatencies::default());
+            cu.pipeline_enabled = false;
+            cu.registers[SP] = 1000;
+            cu.registers[2] = 20;
+            cu.registers[3] = 500;
+            cu
+        };
+
+        let mut uninterrupted = control_unit();
+        run_to_end(&mut uninterrupted);
+
+        let prefix = std::env::temp_dir()
+            .join(format!("leg-checkpoint-{}-", std::process::id()));
+        let mut checkpointed = control_unit();
+        checkpointed.enable_checkpointing(50, prefix.to_str().unwrap())
+            .expect("failed to enable checkpointing");
+        checkpointed.run_to_cycle(uninterrupted.cycle_count / 2).expect("failed to run");
+        let path = checkpointed.last_checkpoint.clone().expect("no checkpoint taken");
+        run_to_end(&mut checkpointed);
+
+        let mut resumed = ControlUnit::resume_from(&path).expect("failed to resume");
+        assert!(resumed.cycle_count > 0 && resumed.cycle_count < uninterrupted.cycle_count);
+        run_to_end(&mut resumed);
+
+        for cu in &[&checkpointed, &resumed] {
+            assert_eq!(cu.registers.file, uninterrupted.registers.file);
+            assert_eq!(cu.cycle_count, uninterrupted.cycle_count);
+            assert_eq!(cu.instructions_retired, uninterrupted.instructions_retired);
+            assert_eq!(cu.dram.borrow().checkpoint(), uninterrupted.dram.borrow().checkpoint());
+            assert_eq!(cu.cache.borrow().checkpoint(), uninterrupted.cache.borrow().checkpoint());
+        }
+
+        for entry in fs::read_dir(std::env::temp_dir()).expect("failed to list temp dir") {
+            let entry_path = entry.expect("failed to read temp dir entry").path();
+            if entry_path.to_str().unwrap().starts_with(prefix.to_str().unwrap()) {
+                fs::remove_file(entry_path).expect("failed to remove checkpoint");
+            }
+        }
+    }
+
+    /// Tests that checkpoints save the register layout, stack direction,
+    /// pending interrupts and bit flips, and that checkpointing can not be
+    /// enabled with the pipeline.
+    #[test]
+    fn test_checkpoint_saves_pending_state() {
+        let words = program![halt()];
+
+        let mut cu = load_words(&words);
+        assert!(cu.enable_checkpointing(10, "unused").is_err());
+
+        cu.pipeline_enabled = false;
+        let mut layout = RegisterLayout::new();
+        layout.fp = 20;
+        cu.registers = Registers::new_with_layout(layout);
+        cu.stack_grows_down = false;
+        cu.queue_interrupt(InterruptSource::Keyboard, 65);
+        cu.schedule_bit_flip(FlipTarget::Register(3, 4), 100)
+            .expect("failed to schedule flip");
+
+        let path = std::env::temp_dir()
+            .join(format!("leg-checkpoint-pending-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        cu.save_checkpoint(path).expect("failed to save checkpoint");
+        let resumed = ControlUnit::resume_from(path).expect("failed to resume");
+        fs::remove_file(path).expect("failed to remove checkpoint");
+
+        assert_eq!(resumed.registers.layout, layout);
+        assert!(!resumed.stack_grows_down);
+        assert_eq!(resumed.pending_interrupts(), vec![(InterruptSource::Keyboard, 65)]);
+        assert_eq!(resumed.bit_flips, vec![(100, FlipTarget::Register(3, 4))]);
+    }
+
+    /// Tests that a breakpoint stops each step which would fetch from it,
+    /// that resuming runs the instruction instead of stopping again, and that
+    /// removed breakpoints no longer stop.
+    #[test]
+    fn test_breakpoints() {
+        let words = program![
+            add_ui_i(1, 1, 1),
+            cmp(1, 2),
+            jmp_lt_i(0),
+            halt(),
+        ];
+
+        for pipeline_enabled in &[false, true] {
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.registers[2] = 3;
+            cu.add_breakpoint(1);
+
+            let mut hits = 0;
+            loop {
+                let cycles_before = cu.cycle_count;
+                match cu.step().expect("failed to step") {
+                    Status::Continue => (),
+                    Status::Halted => break,
+                    Status::BreakpointHit(addr) => {
+                        assert_eq!(addr, 1, "pipeline={}", pipeline_enabled);
+                        assert_eq!(cu.cycle_count, cycles_before, "pipeline={}", pipeline_enabled);
+                        hits += 1;
+                    },
+                };
+                assert!(hits <= 3, "pipeline={}, breakpoint retriggered", pipeline_enabled);
+            }
+
+            assert_eq!(hits, 3, "pipeline={}", pipeline_enabled);
+            assert_eq!(cu.registers[1], 3, "pipeline={}", pipeline_enabled);
+
+            let mut cu = load_words(&words);
+            cu.pipeline_enabled = *pipeline_enabled;
+            cu.registers[2] = 3;
+            cu.add_breakpoint(1);
+            cu.remove_breakpoint(1);
+            assert_eq!(cu.run_to_cycle(10000), Ok(Status::Halted),
+                       "pipeline={}", pipeline_enabled);
+        }
+    }
+
+    /// Tests that a breakpoint does not stop again while a stall keeps PC on
+    /// its address.
+    #[test]
+    fn test_breakpoints_during_stall() {
+        let words = program![
+            add_ui_i(1, 0, 10),
+            load_rd(2, 1),
+            add_ui_i(3, 2, 1),
+            add_ui_i(4, 3, 1),
+            add_ui_i(5, 4, 1),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        for addr in 0..5 {
+            cu.add_breakpoint(addr);
+        }
+
+        let mut hits = Vec::new();
+        loop {
+            match cu.step().expect("failed to step") {
+                Status::Continue => (),
+                Status::Halted => break,
+                Status::BreakpointHit(addr) => hits.push(addr),
+            };
+            assert!(hits.len() <= 5, "breakpoint retriggered: {:?}", hits);
+        }
+
+        assert_eq!(hits, vec![0, 1, 2, 3, 4]);
+    }
+
+    /// Tests that Display shows the address and disassembly of the
+    /// instruction in each stage.
+    #[test]
+    fn test_display_disassembly() {
+        let words = program![
+            add_ui_i(1, 0, 1),
+            load_rd(2, 1),
+            halt(),
+        ];
+
+        let mut cu = load_words(&words);
+        cu.step().expect("failed to step");
+        cu.step().expect("failed to step");
+        let status = format!("{}", cu);
+        assert!(status.contains("Fetch        : 0x0001 LOAD R2, [R1]"), "status={}", status);
+        assert!(status.contains("Decode       : 0x0000 ADDUI R1, R0, #1"), "status={}", status);
+        assert!(status.contains("Execute      : None"), "status={}", status);
+
+        let mut cu = load_words(&words);
+        cu.pipeline_enabled = false;
+        cu.step().expect("failed to step");
+        let status = format!("{}", cu);
+        assert!(status.contains("Instruction : 0x0000 ADDUI R1, R0, #1"), "status={}", status);
+    }
 }
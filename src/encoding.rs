@@ -0,0 +1,569 @@
+use bit_field::BitField;
+
+use std::ops::RangeInclusive;
+
+use crate::instructions::{InstructionT,MemoryOp,ALUOp,ControlOp,disassemble};
+
+/// Bits of the offset added to PC + 1 by immediate loads and stores.
+pub const MEMORY_IMMEDIATE: RangeInclusive<usize> = 15..=31;
+
+/// Bits of the second operand of immediate unsigned arithmetic and logic
+/// instructions.
+pub const UNSIGNED_IMMEDIATE: RangeInclusive<usize> = 23..=31;
+
+/// Bits of the second operand of immediate signed arithmetic instructions.
+pub const SIGNED_IMMEDIATE: RangeInclusive<usize> = 24..=31;
+
+/// Bits of the shift amount of immediate shift instructions.
+pub const SHIFT_IMMEDIATE: RangeInclusive<usize> = 18..=31;
+
+/// Bits of the target address of immediate jumps.
+pub const JUMP_IMMEDIATE: RangeInclusive<usize> = 10..=31;
+
+/// Bits of the size of the locals allocated by enter.
+pub const ENTER_IMMEDIATE: RangeInclusive<usize> = 13..=31;
+
+/// Bits of the interrupt handler address set by SIH.
+pub const HANDLER_IMMEDIATE: RangeInclusive<usize> = 13..=17;
+
+/// Bits of the interrupt code of immediate interrupts.
+pub const INTERRUPT_IMMEDIATE: RangeInclusive<usize> = 13..=16;
+
+/// Bits of the operation code of control instructions.
+pub const CONTROL_OP: RangeInclusive<usize> = 7..=9;
+
+/// CONTROL_OP value of extended control instructions. Their operation is in
+/// EXTENDED_CONTROL_OP and their operands start after it, so the operands of
+/// the other control instructions keep their original bits.
+pub const EXTENDED_CONTROL: u32 = 7;
+
+/// Bits of the operation of extended control instructions.
+pub const EXTENDED_CONTROL_OP: RangeInclusive<usize> = 10..=12;
+
+/// Returns the operation code of a control instruction, see
+/// ControlOp::value().
+pub fn control_op(instruction: u32) -> u32 {
+    match instruction.get_bits(CONTROL_OP) {
+        EXTENDED_CONTROL => EXTENDED_CONTROL + 1 + instruction.get_bits(EXTENDED_CONTROL_OP),
+        op => op,
+    }
+}
+
+/// Sets the operation code of a control instruction, see ControlOp::value().
+pub fn set_control_op(instruction: &mut u32, op: u32) {
+    if op < EXTENDED_CONTROL {
+        instruction.set_bits(CONTROL_OP, op);
+    } else {
+        instruction.set_bits(CONTROL_OP, EXTENDED_CONTROL);
+        instruction.set_bits(EXTENDED_CONTROL_OP, op - EXTENDED_CONTROL - 1);
+    }
+}
+
+/// Returns the bits of an instruction's immediate field. None if the
+/// instruction has no immediate or is not valid.
+pub fn immediate_field(instruction: u32) -> Option<RangeInclusive<usize>> {
+    match InstructionT::match_val(instruction.get_bits(5..=6)) {
+        Some(InstructionT::Memory) => match MemoryOp::match_val(instruction.get_bits(7..=9)) {
+            Some(MemoryOp::LoadI) | Some(MemoryOp::StoreI) => Some(MEMORY_IMMEDIATE),
+            _ => None,
+        },
+        Some(InstructionT::ALU) => match ALUOp::match_val(instruction.get_bits(7..=12)) {
+            Some(ALUOp::AddUII) | Some(ALUOp::SubUII) | Some(ALUOp::MulUII) |
+            Some(ALUOp::DivUII) | Some(ALUOp::ModUII) | Some(ALUOp::AndI) |
+            Some(ALUOp::OrI) | Some(ALUOp::XorI) => Some(UNSIGNED_IMMEDIATE),
+            Some(ALUOp::AddSII) | Some(ALUOp::SubSII) | Some(ALUOp::MulSII) |
+            Some(ALUOp::DivSII) | Some(ALUOp::ModSII) => Some(SIGNED_IMMEDIATE),
+            Some(ALUOp::ASLI) | Some(ALUOp::ASRI) | Some(ALUOp::LSLI) |
+            Some(ALUOp::LSRI) | Some(ALUOp::ROLI) | Some(ALUOp::RORI) => Some(SHIFT_IMMEDIATE),
+            _ => None,
+        },
+        Some(InstructionT::Control) => match ControlOp::match_val(control_op(instruction)) {
+            Some(ControlOp::JmpI) | Some(ControlOp::JmpSI) => Some(JUMP_IMMEDIATE),
+            Some(ControlOp::Enter) => Some(ENTER_IMMEDIATE),
+            Some(ControlOp::Sih) => Some(HANDLER_IMMEDIATE),
+            Some(ControlOp::IntI) => Some(INTERRUPT_IMMEDIATE),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns the names and bits of an instruction's operand fields, in the order
+/// the disassembler shows them. Empty if the instruction has no operands or is
+/// not valid.
+pub fn operand_fields(instruction: u32) -> Vec<(&'static str, RangeInclusive<usize>)> {
+    let imm = immediate_field(instruction);
+
+    match InstructionT::match_val(instruction.get_bits(5..=6)) {
+        Some(InstructionT::Memory) => match MemoryOp::match_val(instruction.get_bits(7..=9)) {
+            Some(MemoryOp::LoadRD) | Some(MemoryOp::LoadByteRD) =>
+                vec![("dest", 10..=14), ("addr", 15..=19)],
+            Some(MemoryOp::LoadI) => vec![("dest", 10..=14), ("offset", MEMORY_IMMEDIATE)],
+            Some(MemoryOp::StoreRD) | Some(MemoryOp::StoreByteRD) =>
+                vec![("addr", 10..=14), ("value", 15..=19)],
+            Some(MemoryOp::StoreI) => vec![("addr", 10..=14), ("offset", MEMORY_IMMEDIATE)],
+            Some(MemoryOp::Push) => vec![("src", 11..=15)],
+            Some(MemoryOp::Pop) => vec![("dest", 11..=15)],
+            None => vec![],
+        },
+        Some(InstructionT::ALU) => match ALUOp::match_val(instruction.get_bits(7..=12)) {
+            Some(ALUOp::Move) | Some(ALUOp::Not) => vec![("dest", 13..=17), ("src", 18..=22)],
+            Some(ALUOp::CMov) =>
+                vec![("cond", 0..=4), ("dest", 13..=17), ("src", 18..=22)],
+            Some(ALUOp::Comp) => vec![("op1", 13..=17), ("op2", 18..=22)],
+            Some(ALUOp::AddSIRD) | Some(ALUOp::SubSIRD) | Some(ALUOp::MulSIRD) |
+            Some(ALUOp::DivSIRD) | Some(ALUOp::ModSIRD) =>
+                vec![("dest", 14..=18), ("op1", 19..=23), ("op2", 24..=28)],
+            Some(ALUOp::ASLRD) | Some(ALUOp::ASRRD) | Some(ALUOp::LSLRD) |
+            Some(ALUOp::LSRRD) | Some(ALUOp::ROLRD) | Some(ALUOp::RORRD) =>
+                vec![("dest", 13..=17), ("amount", 18..=22)],
+            Some(_) => match imm {
+                Some(SIGNED_IMMEDIATE) =>
+                    vec![("dest", 14..=18), ("op1", 19..=23), ("imm", SIGNED_IMMEDIATE)],
+                Some(SHIFT_IMMEDIATE) => vec![("dest", 13..=17), ("amount", SHIFT_IMMEDIATE)],
+                Some(field) => vec![("dest", 13..=17), ("op1", 18..=22), ("imm", field)],
+                None => vec![("dest", 13..=17), ("op1", 18..=22), ("op2", 23..=27)],
+            },
+            None => vec![],
+        },
+        Some(InstructionT::Control) => match ControlOp::match_val(control_op(instruction)) {
+            Some(ControlOp::JmpRD) | Some(ControlOp::JmpSRD) =>
+                vec![("cond", 0..=4), ("target", 10..=14)],
+            Some(ControlOp::JmpI) | Some(ControlOp::JmpSI) =>
+                vec![("cond", 0..=4), ("target", JUMP_IMMEDIATE)],
+            Some(ControlOp::Enter) => vec![("locals", ENTER_IMMEDIATE)],
+            Some(ControlOp::Sih) => vec![("handler", HANDLER_IMMEDIATE)],
+            Some(ControlOp::IntRD) => vec![("code", 13..=17)],
+            Some(ControlOp::IntI) => vec![("code", INTERRUPT_IMMEDIATE)],
+            _ => vec![],
+        },
+        _ => vec![],
+    }
+}
+
+/// Describes how an instruction word is parsed, for debugging the encoding.
+/// The first line is the word and its disassembly, then each field follows on
+/// its own line with its bits and value, like "dest 13..=17: 2". Operation
+/// codes which are not valid are marked "(invalid)" and have no operand
+/// fields.
+pub fn explain_encoding(bits: u32) -> String {
+    let mut lines = vec![format!("{:#010x}: {}", bits,
+                                 disassemble(bits).unwrap_or_else(|_e| "???".to_string()))];
+    let field = |name: &str, range: RangeInclusive<usize>| format!(
+        "{} {}..={}: {}", name, range.start(), range.end(), bits.get_bits(range.clone()));
+
+    let itype = InstructionT::match_val(bits.get_bits(5..=6));
+    lines.push(format!("{} ({:?})", field("type", 5..=6),
+                       itype.as_ref().expect("type field is 2 bits")));
+
+    let op = match itype {
+        Some(InstructionT::Memory) => Some((7..=9, MemoryOp::match_val(bits.get_bits(7..=9)).is_some())),
+        Some(InstructionT::ALU) => Some((7..=12, ALUOp::match_val(bits.get_bits(7..=12)).is_some())),
+        Some(InstructionT::Control) => Some((CONTROL_OP, ControlOp::match_val(control_op(bits)).is_some())),
+        _ => None,
+    };
+    if let Some((range, valid)) = op {
+        let mut fields = vec![field("op", range)];
+        if itype == Some(InstructionT::Control) && bits.get_bits(CONTROL_OP) == EXTENDED_CONTROL {
+            fields.push(field("extended op", EXTENDED_CONTROL_OP));
+        }
+        if !valid {
+            fields.last_mut().expect("op field").push_str(" (invalid)");
+        }
+        lines.extend(fields);
+    }
+
+    for (name, range) in operand_fields(bits) {
+        lines.push(field(name, range));
+    }
+
+    lines.join("\n")
+}
+
+/// Returns the width in bits of an instruction's immediate field. 0 if the
+/// instruction has no immediate.
+pub fn immediate_bits(instruction: u32) -> u32 {
+    match immediate_field(instruction) {
+        Some(field) => (field.end() - field.start() + 1) as u32,
+        None => 0,
+    }
+}
+
+/// Interprets the low bits of value as a two's complement number, copying the
+/// top bit of the field into the bits above it.
+pub fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+/// Builds a vector of instruction words from calls to the encoders in asm,
+/// for writing test programs inline:
+///
+/// ```ignore
+/// let words = program![
+///     add_ui_i(1, 0, 5),
+///     add_ui_rd(2, 1, 1),
+///     halt(),
+/// ];
+/// ```
+#[cfg(test)]
+macro_rules! program {
+    ($($name:ident($($arg:expr),*)),* $(,)?) => {
+        vec![$($crate::encoding::asm::$name($($arg as u32),*)),*]
+    };
+}
+#[cfg(test)]
+pub(crate) use program;
+
+/// Encoders for instructions used by program!. Register arguments are
+/// register indexes. Arguments are named in the order the disassembler shows
+/// them.
+#[cfg(test)]
+pub mod asm {
+    use bit_field::BitField;
+
+    use super::*;
+    use crate::instructions::ConditionCodes;
+
+    /// Returns a word with an instruction type and operation code, and no
+    /// operands. Used for operation codes without an encoder of their own.
+    pub fn word(itype: InstructionT, op: u32) -> u32 {
+        let mut w: u32 = 0;
+        match &itype {
+            InstructionT::Memory => {
+                w.set_bits(7..=9, op);
+            },
+            InstructionT::Control => set_control_op(&mut w, op),
+            _ => {
+                w.set_bits(7..=12, op);
+            },
+        };
+        w.set_bits(5..=6, itype.value());
+
+        w
+    }
+
+    /// Encodes an unsigned arithmetic or logic instruction, dest = op1 op op2.
+    /// The immediate operation codes take an immediate as op2.
+    pub fn unsigned(op: ALUOp, dest: u32, op1: u32, op2: u32) -> u32 {
+        let mut w = word(InstructionT::ALU, op.value());
+        w.set_bits(13..=17, dest);
+        w.set_bits(18..=22, op1);
+        match immediate_field(w) {
+            Some(field) => w.set_bits(field, op2),
+            None => w.set_bits(23..=27, op2),
+        };
+
+        w
+    }
+
+    /// Encodes a signed arithmetic instruction, see unsigned(). A negative
+    /// immediate is truncated to the width of the field.
+    pub fn signed(op: ALUOp, dest: u32, op1: u32, op2: u32) -> u32 {
+        let mut w = word(InstructionT::ALU, op.value());
+        w.set_bits(14..=18, dest);
+        w.set_bits(19..=23, op1);
+        match immediate_field(w) {
+            Some(field) => w.set_bits(field, op2.get_bits(0..immediate_bits(w) as usize)),
+            None => w.set_bits(24..=28, op2),
+        };
+
+        w
+    }
+
+    /// ADDU dest, op1, op2
+    pub fn add_ui_rd(dest: u32, op1: u32, op2: u32) -> u32 {
+        unsigned(ALUOp::AddUIRD, dest, op1, op2)
+    }
+
+    /// ADDUI dest, op1, #imm
+    pub fn add_ui_i(dest: u32, op1: u32, imm: u32) -> u32 {
+        unsigned(ALUOp::AddUII, dest, op1, imm)
+    }
+
+    /// SUBU dest, op1, op2
+    pub fn sub_ui_rd(dest: u32, op1: u32, op2: u32) -> u32 {
+        unsigned(ALUOp::SubUIRD, dest, op1, op2)
+    }
+
+    /// ADDSI dest, op1, #imm
+    pub fn add_si_i(dest: u32, op1: u32, imm: u32) -> u32 {
+        signed(ALUOp::AddSII, dest, op1, imm)
+    }
+
+    /// MOV dest, src
+    pub fn mov(dest: u32, src: u32) -> u32 {
+        let mut w = word(InstructionT::ALU, ALUOp::Move.value());
+        w.set_bits(13..=17, dest);
+        w.set_bits(18..=22, src);
+
+        w
+    }
+
+    /// CMOV{cond} dest, src, cond is a ConditionCodes value
+    pub fn cmov_cond(cond: u32, dest: u32, src: u32) -> u32 {
+        let mut w = word(InstructionT::ALU, ALUOp::CMov.value());
+        w.set_bits(0..=4, cond);
+        w.set_bits(13..=17, dest);
+        w.set_bits(18..=22, src);
+
+        w
+    }
+
+    /// CMP op1, op2
+    pub fn cmp(op1: u32, op2: u32) -> u32 {
+        let mut w = word(InstructionT::ALU, ALUOp::Comp.value());
+        w.set_bits(13..=17, op1);
+        w.set_bits(18..=22, op2);
+
+        w
+    }
+
+    /// LOAD dest, [addr]
+    pub fn load_rd(dest: u32, addr: u32) -> u32 {
+        let mut w = word(InstructionT::Memory, MemoryOp::LoadRD.value());
+        w.set_bits(10..=14, dest);
+        w.set_bits(15..=19, addr);
+
+        w
+    }
+
+    /// STORE [addr], value
+    pub fn store_rd(addr: u32, value: u32) -> u32 {
+        let mut w = word(InstructionT::Memory, MemoryOp::StoreRD.value());
+        w.set_bits(10..=14, addr);
+        w.set_bits(15..=19, value);
+
+        w
+    }
+
+    /// PUSH src
+    pub fn push(src: u32) -> u32 {
+        let mut w = word(InstructionT::Memory, MemoryOp::Push.value());
+        w.set_bits(11..=15, src);
+
+        w
+    }
+
+    /// POP dest
+    pub fn pop(dest: u32) -> u32 {
+        let mut w = word(InstructionT::Memory, MemoryOp::Pop.value());
+        w.set_bits(11..=15, dest);
+
+        w
+    }
+
+    /// JMP #addr, always taken
+    pub fn jmp_i(addr: u32) -> u32 {
+        let mut w = word(InstructionT::Control, ControlOp::JmpI.value());
+        w.set_bits(JUMP_IMMEDIATE, addr);
+
+        w
+    }
+
+    /// JMP{cond} #addr, cond is a ConditionCodes value
+    pub fn jmp_cond_i(cond: u32, addr: u32) -> u32 {
+        let mut w = jmp_i(addr);
+        w.set_bits(0..=4, cond);
+
+        w
+    }
+
+    /// JMPLT #addr
+    pub fn jmp_lt_i(addr: u32) -> u32 {
+        jmp_cond_i(ConditionCodes::LT.value(), addr)
+    }
+
+    /// JMP reg, always taken
+    pub fn jmp_rd(reg: u32) -> u32 {
+        let mut w = word(InstructionT::Control, ControlOp::JmpRD.value());
+        w.set_bits(10..=14, reg);
+
+        w
+    }
+
+    /// SIH #addr
+    pub fn sih(addr: u32) -> u32 {
+        let mut w = word(InstructionT::Control, ControlOp::Sih.value());
+        w.set_bits(HANDLER_IMMEDIATE, addr);
+
+        w
+    }
+
+    /// INT #code
+    pub fn int_i(code: u32) -> u32 {
+        let mut w = word(InstructionT::Control, ControlOp::IntI.value());
+        w.set_bits(INTERRUPT_IMMEDIATE, code);
+
+        w
+    }
+
+    /// INT reg
+    pub fn int_rd(reg: u32) -> u32 {
+        let mut w = word(InstructionT::Control, ControlOp::IntRD.value());
+        w.set_bits(13..=17, reg);
+
+        w
+    }
+
+    /// ENTER #locals
+    pub fn enter(locals: u32) -> u32 {
+        let mut w = word(InstructionT::Control, ControlOp::Enter.value());
+        w.set_bits(ENTER_IMMEDIATE, locals);
+
+        w
+    }
+
+    /// LEAVE
+    pub fn leave() -> u32 {
+        word(InstructionT::Control, ControlOp::Leave.value())
+    }
+
+    /// CFLUSH
+    pub fn cache_flush() -> u32 {
+        word(InstructionT::Control, ControlOp::CacheFlush.value())
+    }
+
+    /// CINVAL
+    pub fn cache_invalidate() -> u32 {
+        word(InstructionT::Control, ControlOp::CacheInvalidate.value())
+    }
+
+    /// RFI
+    pub fn rfi() -> u32 {
+        word(InstructionT::Control, ControlOp::RFI.value())
+    }
+
+    /// NOOP
+    pub fn noop() -> u32 {
+        word(InstructionT::Control, ControlOp::Noop.value())
+    }
+
+    /// HALT
+    pub fn halt() -> u32 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an instruction word with a type and operation code.
+    fn word(itype: InstructionT, op: u32) -> u32 {
+        let mut w: u32 = 0;
+        match &itype {
+            InstructionT::Memory => {
+                w.set_bits(7..=9, op);
+            },
+            InstructionT::Control => set_control_op(&mut w, op),
+            _ => {
+                w.set_bits(7..=12, op);
+            },
+        };
+        w.set_bits(5..=6, itype.value());
+
+        w
+    }
+
+    /// Tests the immediate widths of instructions with and without
+    /// immediates.
+    #[test]
+    fn test_immediate_bits() {
+        assert_eq!(immediate_bits(word(InstructionT::Memory, MemoryOp::LoadI.value())), 17);
+        assert_eq!(immediate_bits(word(InstructionT::Memory, MemoryOp::StoreI.value())), 17);
+        assert_eq!(immediate_bits(word(InstructionT::Memory, MemoryOp::LoadRD.value())), 0);
+        assert_eq!(immediate_bits(word(InstructionT::ALU, ALUOp::AddUII.value())), 9);
+        assert_eq!(immediate_bits(word(InstructionT::ALU, ALUOp::AddSII.value())), 8);
+        assert_eq!(immediate_bits(word(InstructionT::ALU, ALUOp::LSRI.value())), 14);
+        assert_eq!(immediate_bits(word(InstructionT::ALU, ALUOp::Move.value())), 0);
+        assert_eq!(immediate_bits(word(InstructionT::Control, ControlOp::JmpI.value())), 22);
+        assert_eq!(immediate_bits(word(InstructionT::Control, ControlOp::Enter.value())), 19);
+        assert_eq!(immediate_bits(0), 0);
+    }
+
+    /// Tests sign extending fields with the top bit set and clear.
+    #[test]
+    fn test_sign_extend() {
+        assert_eq!(sign_extend(0xFF, 8), -1);
+        assert_eq!(sign_extend(0x80, 8), -128);
+        assert_eq!(sign_extend(0x7F, 8), 127);
+        assert_eq!(sign_extend(0, 8), 0);
+        assert_eq!(sign_extend(0xFFFFFFFF, 32), -1);
+    }
+
+    /// Tests that words built with program! disassemble to the instructions
+    /// named.
+    #[test]
+    fn test_program_macro() {
+        let words = program![
+            add_ui_rd(2, 10, 13),
+            add_ui_i(1, 0, 5),
+            add_si_i(3, 1, 2),
+            add_si_i(3, 1, -1i32),
+            load_rd(4, 5),
+            store_rd(6, 7),
+            jmp_lt_i(3),
+            halt(),
+        ];
+        let text: Vec<String> = words.iter()
+            .map(|w| crate::instructions::disassemble(*w).expect("failed to disassemble"))
+            .collect();
+
+        assert_eq!(text, vec![
+            "ADDU R2, R10, R13",
+            "ADDUI R1, R0, #5",
+            "ADDSI R3, R1, #2",
+            "ADDSI R3, R1, #-1",
+            "LOAD R4, [R5]",
+            "STORE [R6], R7",
+            "JMPLT #3",
+            "HALT",
+        ]);
+    }
+
+    /// Tests that the fields of an add are explained with their bits and
+    /// values.
+    #[test]
+    fn test_explain_encoding() {
+        let add = asm::add_ui_rd(2, 10, 13);
+
+        assert_eq!(explain_encoding(add), format!("\
+{:#010x}: ADDU R2, R10, R13
+type 5..=6: 1 (ALU)
+op 7..=12: 0
+dest 13..=17: 2
+op1 18..=22: 10
+op2 23..=27: 13", add));
+
+        let invalid = word(InstructionT::ALU, 63);
+        assert_eq!(explain_encoding(invalid), "\
+0x00001fa0: ???
+type 5..=6: 1 (ALU)
+op 7..=12: 63 (invalid)");
+    }
+
+    /// Tests that control instructions without an extended operation keep the
+    /// original encoding, and that extended operations are placed after the
+    /// extended operation code.
+    #[test]
+    fn test_control_op_encoding() {
+        // JMP #3 as encoded before extended operations existed
+        let mut jump: u32 = 0;
+        jump.set_bits(5..=6, InstructionT::Control.value());
+        jump.set_bits(7..=9, 2);
+        jump.set_bits(10..=31, 3);
+        assert_eq!(asm::jmp_i(3), jump);
+        assert_eq!(control_op(jump), ControlOp::JmpI.value());
+
+        let sih = asm::sih(8);
+        assert_eq!(sih.get_bits(CONTROL_OP), EXTENDED_CONTROL);
+        assert_eq!(control_op(sih), ControlOp::Sih.value());
+        assert_eq!(explain_encoding(sih), format!("\
+{:#010x}: SIH #8
+type 5..=6: 0 (Control)
+op 7..=9: 7
+extended op 10..=12: 4
+handler 13..=17: 8", sih));
+    }
+}
@@ -1,12 +1,17 @@
 use bit_field::BitField;
 
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Debug,Display};
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::ops::RangeInclusive;
 
 use crate::result::SimResult;
-use crate::memory::{Memory,DRAM,Registers,PC,STS,LR,IHDLR,INTLR,SP};
+use crate::memory::{Memory,Registers,RegisterLayout,SpecialRegister,SP};
+use crate::encoding::{MEMORY_IMMEDIATE,UNSIGNED_IMMEDIATE,SIGNED_IMMEDIATE,
+    SHIFT_IMMEDIATE,JUMP_IMMEDIATE,ENTER_IMMEDIATE,HANDLER_IMMEDIATE,INTERRUPT_IMMEDIATE,
+    sign_extend,control_op,set_control_op};
 
 /// Defines operations which a single instruction must perform while it is in
 /// the pipeline.
@@ -24,6 +29,52 @@ pub trait Instruction: Display + Debug {
 
     /// Write results to registers.
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String>;
+
+    /// Returns the operand values extracted by decode. Each value is labeled
+    /// with a description of what the operand is. Only meaningful after decode
+    /// has been called.
+    fn operands(&self) -> Vec<(String, u32)> {
+        Vec::new()
+    }
+
+    /// Returns the memory address the instruction will access, if it accesses
+    /// memory. Only meaningful after decode has been called.
+    fn effective_address(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns the address the instruction will jump to, if it is a branch
+    /// which will be taken with the current registers. Only meaningful after
+    /// execute has been called.
+    fn branch_target(&self, _registers: &Registers) -> Option<u32> {
+        None
+    }
+
+    /// Returns every register decode reads, including special purpose
+    /// registers. PC is not included, decode always reads the address the
+    /// instruction was fetched from. Only meaningful after decode has been
+    /// called.
+    fn src_regs(&self) -> Vec<usize> {
+        Vec::new()
+    }
+
+    /// Returns every register write back writes, including registers which
+    /// are updated on the side, like STS or the stack pointer of a pop. The
+    /// indexes of special purpose registers are looked up in layout. PC is not
+    /// included, instructions which write it flush younger instructions. Only
+    /// meaningful after decode has been called.
+    fn dest_regs(&self, _layout: &RegisterLayout) -> Vec<usize> {
+        Vec::new()
+    }
+
+    /// Returns the register write back will write and the value it will write,
+    /// once the value is known. Used to forward results to younger
+    /// instructions before they are written back. Only meaningful after execute
+    /// has been called, instructions which load their result return None until
+    /// access_memory has been called.
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        None
+    }
 }
 
 /// An instruction which performs no operations.
@@ -99,26 +150,42 @@ pub enum InterruptCodes {
 }
 
 impl InterruptCodes {
+    /// Returns the value of the interrupt code. Key codes fit in the 4 bit
+    /// immediate of an INT instruction. Always use this instead of casting a
+    /// variant, which yields its position in the enum.
     pub fn value(self) -> usize {
         match self {
-            InterruptCodes::UPARROW => 0000,
-            InterruptCodes::DOWNARROW => 0001,
-            InterruptCodes::LEFTARROW => 0010,
-            InterruptCodes::RIGHTARROW => 0011,
-            InterruptCodes::ENTER => 0100,
-            InterruptCodes::ESCAPE => 0101,
-            InterruptCodes::SPACE => 0110,
-            InterruptCodes::NOT_SET_INITIAL => 111111,
-            InterruptCodes::NOT_SET => 000000,
-            InterruptCodes::SET => 100000,
+            InterruptCodes::UPARROW => 0b0000,
+            InterruptCodes::DOWNARROW => 0b0001,
+            InterruptCodes::LEFTARROW => 0b0010,
+            InterruptCodes::RIGHTARROW => 0b0011,
+            InterruptCodes::ENTER => 0b0100,
+            InterruptCodes::ESCAPE => 0b0101,
+            InterruptCodes::SPACE => 0b0110,
+            InterruptCodes::NOT_SET_INITIAL => 0b111111,
+            InterruptCodes::NOT_SET => 0b000000,
+            InterruptCodes::SET => 0b100000,
         }
     }
 }
 
+/// Memory address where the code of a taken interrupt is stored, so the
+/// handler can read it.
+pub const INTERRUPT_CODE_ADDR: u32 = 1111111111;
+
 pub enum ConditionCodes {
     NS, NE, E, GT, LT,
     GTE, LTE, OF, Z, NZ,
     NEG, POS,
+
+    /// A load faulted, see LoadFaultPolicy::Poison.
+    FAULT,
+
+    /// An unsigned arithmetic result carried out of 32 bits.
+    C,
+
+    /// A signed arithmetic result overflowed.
+    V,
 }
 
 impl ConditionCodes {
@@ -136,8 +203,46 @@ impl ConditionCodes {
             ConditionCodes::NZ => 9,
             ConditionCodes::NEG => 10,
             ConditionCodes::POS => 11,
+            ConditionCodes::FAULT => 12,
+            ConditionCodes::C => 13,
+            ConditionCodes::V => 14,
         }
     }
+
+    /// Returns the assembly suffix of a condition code value, an empty string
+    /// for NS. None if the value is not a condition code.
+    pub fn suffix(val: u32) -> Option<&'static str> {
+        match val {
+            0 => Some(""),
+            1 => Some("NE"),
+            2 => Some("E"),
+            3 => Some("GT"),
+            4 => Some("LT"),
+            5 => Some("GTE"),
+            6 => Some("LTE"),
+            7 => Some("OF"),
+            8 => Some("Z"),
+            9 => Some("NZ"),
+            10 => Some("NEG"),
+            11 => Some("POS"),
+            12 => Some("FAULT"),
+            13 => Some("C"),
+            14 => Some("V"),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the condition code an ALU result sets in STS: Z if the result is
+/// zero, NEG if it is negative when read as signed and POS otherwise.
+pub fn flags_from_result(result: u32) -> u32 {
+    if result == 0 {
+        ConditionCodes::Z.value()
+    } else if (result as i32) < 0 {
+        ConditionCodes::NEG.value()
+    } else {
+        ConditionCodes::POS.value()
+    }
 }
 
 /// Identifies the addressing mode of an instruction operand.
@@ -159,12 +264,26 @@ impl Display for AddrMode {
     }
 }
 
+/// Determines what a load does when its memory access fails.
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum LoadFaultPolicy {
+    /// The memory error is returned, which stops the program.
+    Abort,
+
+    /// The destination register is set to the poison value and the status
+    /// register is set to ConditionCodes::FAULT. The program continues.
+    Poison(u32),
+}
+
 #[derive(PartialEq,Debug)]
 pub enum ArithMode {
     Add,
     Sub,
     Mul,
     Div,
+
+    /// Remainder of division, with the sign of the dividend like Rust's %.
+    Mod,
 }
 
 impl Display for ArithMode {
@@ -174,6 +293,7 @@ impl Display for ArithMode {
             ArithMode::Sub => write!(f, "Sub"),
             ArithMode::Mul => write!(f, "Mult"),
             ArithMode::Div => write!(f, "Div"),
+            ArithMode::Mod => write!(f, "Mod"),
         }
     }
 }
@@ -202,6 +322,8 @@ pub enum MemoryOp {
     StoreRD, StoreI,
     Push,
     Pop,
+    LoadByteRD,
+    StoreByteRD,
 }
 
 impl MemoryOp {
@@ -214,6 +336,8 @@ impl MemoryOp {
             MemoryOp::StoreI => 3,
             MemoryOp::Push => 4,
             MemoryOp::Pop => 5,
+            MemoryOp::LoadByteRD => 6,
+            MemoryOp::StoreByteRD => 7,
         }
     }
 
@@ -226,6 +350,8 @@ impl MemoryOp {
             3 => Some(MemoryOp::StoreI),
             4 => Some(MemoryOp::Push),
             5 => Some(MemoryOp::Pop),
+            6 => Some(MemoryOp::LoadByteRD),
+            7 => Some(MemoryOp::StoreByteRD),
             _ => None,
         }
     }
@@ -243,12 +369,15 @@ pub enum ALUOp {
     DivUIRD, DivUII, DivSIRD, DivSII,
     Move, 
     Comp,
+    CMov,
     ASLRD, ASLI, ASRRD, ASRI,
     LSLRD, LSLI, LSRRD, LSRI,
     AndRD, AndI,
     OrRD, OrI,
     XorRD, XorI,
     Not, 
+    ModUIRD, ModUII, ModSIRD, ModSII,
+    ROLRD, ROLI, RORRD, RORI,
 }
 impl ALUOp {
     /// Returns the value of the operation field for the represented operation.
@@ -272,6 +401,7 @@ impl ALUOp {
             ALUOp::DivSII => 15,
             ALUOp::Move => 16,
             ALUOp::Comp => 17,
+            ALUOp::CMov => 18,
             ALUOp::ASLRD => 19,
             ALUOp::ASLI => 20,
             ALUOp::ASRRD => 21,
@@ -287,6 +417,14 @@ impl ALUOp {
             ALUOp::XorRD => 31,
             ALUOp::XorI => 32,
             ALUOp::Not => 33,
+            ALUOp::ModUIRD => 34,
+            ALUOp::ModUII => 35,
+            ALUOp::ModSIRD => 36,
+            ALUOp::ModSII => 37,
+            ALUOp::ROLRD => 38,
+            ALUOp::ROLI => 39,
+            ALUOp::RORRD => 40,
+            ALUOp::RORI => 41,
         }
     }
 
@@ -311,6 +449,7 @@ impl ALUOp {
             15 => Some(ALUOp::DivSII),
             16 => Some(ALUOp::Move),
             17 => Some(ALUOp::Comp),
+            18 => Some(ALUOp::CMov),
             19 => Some(ALUOp::ASLRD),
             20 => Some(ALUOp::ASLI),
             21 => Some(ALUOp::ASRRD),
@@ -326,24 +465,42 @@ impl ALUOp {
             31 => Some(ALUOp::XorRD),
             32 => Some(ALUOp::XorI),
             33 => Some(ALUOp::Not),
+            34 => Some(ALUOp::ModUIRD),
+            35 => Some(ALUOp::ModUII),
+            36 => Some(ALUOp::ModSIRD),
+            37 => Some(ALUOp::ModSII),
+            38 => Some(ALUOp::ROLRD),
+            39 => Some(ALUOp::ROLI),
+            40 => Some(ALUOp::RORRD),
+            41 => Some(ALUOp::RORI),
             _ => None,
         }
     }
 }
 
+/// Identifies control operations. The operation field of control instructions
+/// is 4 bits wide.
 #[derive(PartialEq,Debug)]
 pub enum ControlOp {
     JmpRD, JmpI,
     JmpSRD, JmpSI,
-    // Sih,
-    // IntRD, IntI, 
+    Sih,
+    IntRD, IntI, 
     RFI,
     Halt,
     Noop,
+    Enter,
+    Leave,
+    CacheFlush,
+    CacheInvalidate,
 }
 
 impl ControlOp {
-    /// Returns the value of the operation field for the represented operation.
+    /// Returns the operation code for the represented operation. Codes below
+    /// EXTENDED_CONTROL are the value of the operation field. The other codes
+    /// are extended operations, their operation field is EXTENDED_CONTROL and
+    /// the extended operation field is the code - EXTENDED_CONTROL - 1. See
+    /// control_op() and set_control_op().
     pub fn value(self) -> u32 {
         match self {
             ControlOp::Halt => 0,
@@ -351,11 +508,15 @@ impl ControlOp {
             ControlOp::JmpI => 2,
             ControlOp::JmpSRD => 3,
             ControlOp::JmpSI => 4,
-            // ControlOp::Sih => 1,
-            // ControlOp::IntRD => 1,
-            // ControlOp::IntI => 1,
             ControlOp::RFI => 5,
             ControlOp::Noop => 6,
+            ControlOp::Enter => 8,
+            ControlOp::Leave => 9,
+            ControlOp::CacheFlush => 10,
+            ControlOp::CacheInvalidate => 11,
+            ControlOp::Sih => 12,
+            ControlOp::IntRD => 13,
+            ControlOp::IntI => 14,
         }
     }
 
@@ -367,11 +528,15 @@ impl ControlOp {
             2 => Some(ControlOp::JmpI),
             3 => Some(ControlOp::JmpSRD),
             4 => Some(ControlOp::JmpSI),
-            // 1 => Some(ControlOp::Sih),
-            // 1 => Some(ControlOp::IntRD),
-            // 1 => Some(ControlOp::IntI),
             5 => Some(ControlOp::RFI),
             6 => Some(ControlOp::Noop),
+            8 => Some(ControlOp::Enter),
+            9 => Some(ControlOp::Leave),
+            10 => Some(ControlOp::CacheFlush),
+            11 => Some(ControlOp::CacheInvalidate),
+            12 => Some(ControlOp::Sih),
+            13 => Some(ControlOp::IntRD),
+            14 => Some(ControlOp::IntI),
             _ => None,
         }
     }
@@ -424,8 +589,20 @@ pub struct Load {
     /// Memory address to load into register.
     mem_addr: u32,
 
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
     /// Value loaded from mememory during access_memory.
     value: u32,
+
+    /// Determines what happens if the memory access fails.
+    fault_policy: LoadFaultPolicy,
+
+    /// Indicates the memory access failed and the poison value was loaded.
+    faulted: bool,
+
+    /// Indicates access_memory ran, so value holds the value to write back.
+    loaded: bool,
 }
 
 impl Display for Load {
@@ -436,12 +613,16 @@ impl Display for Load {
 
 impl Load {
     /// Creates an empty load instruction.
-    pub fn new(mem_addr_mode: AddrMode) -> Load {
+    pub fn new(mem_addr_mode: AddrMode, fault_policy: LoadFaultPolicy) -> Load {
         Load{
             mem_addr_mode: mem_addr_mode,
             dest_reg: 0,
             mem_addr: 0,
+            src_regs: Vec::new(),
             value: 0,
+            fault_policy,
+            faulted: false,
+            loaded: false,
         }
     }
 }
@@ -450,12 +631,14 @@ impl Instruction for Load {
     /// Extract dest_reg and mem_addr operands.
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
         self.dest_reg = instruction.get_bits(10..=14) as usize;
+        self.src_regs = Vec::new();
         
         if self.mem_addr_mode == AddrMode::RegisterDirect {
+            self.src_regs.push(instruction.get_bits(15..=19) as usize);
             self.mem_addr = registers[instruction.get_bits(15..=19) as usize];
         } else if self.mem_addr_mode == AddrMode::Immediate {
             // self.mem_addr = instruction.get_bits(15..=19) as u32;
-            self.mem_addr = (((registers[PC] + 1) as i32) + (instruction.get_bits(15..=31) as i32)) as u32;
+            self.mem_addr = (((registers[SpecialRegister::PC] + 1) as i32) + (instruction.get_bits(MEMORY_IMMEDIATE) as i32)) as u32;
         }
 
         return SimResult::Wait(0, ());
@@ -466,24 +649,69 @@ impl Instruction for Load {
         return SimResult::Wait(0, ());
     }
 
-    /// Load value at mem_addr from memory into value.
+    /// Load value at mem_addr from memory into value. If the access fails the
+    /// fault policy is applied.
     fn access_memory(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
         match memory.borrow_mut().get(self.mem_addr) {
-            SimResult::Err(e) => SimResult::Err(
-                format!("failed to retrieve memory address {}: {}",
-                        self.mem_addr, e)),
+            SimResult::Err(e) => match self.fault_policy {
+                LoadFaultPolicy::Abort => SimResult::Err(
+                    format!("failed to retrieve memory address {}: {}",
+                            self.mem_addr, e)),
+                LoadFaultPolicy::Poison(poison) => {
+                    self.value = poison;
+                    self.faulted = true;
+                    self.loaded = true;
+                    SimResult::Wait(0, ())
+                },
+            },
             SimResult::Wait(wait, val) => {
                 self.value = val;
+                self.loaded = true;
                 SimResult::Wait(wait, ())
             },
         }
     }
 
-    /// Write value from memory into register.
+    /// Write value from memory into register. Sets the fault condition code if
+    /// the memory access faulted.
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
         registers[self.dest_reg] = self.value;
+
+        if self.faulted {
+            registers[SpecialRegister::STS] = ConditionCodes::FAULT.value();
+        }
+        
         SimResult::Wait(0, ())
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest_reg as u32),
+            ("addr".to_string(), self.mem_addr),
+        ]
+    }
+
+    fn effective_address(&self) -> Option<u32> {
+        Some(self.mem_addr)
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        match self.fault_policy {
+            LoadFaultPolicy::Abort => vec![self.dest_reg],
+            LoadFaultPolicy::Poison(_poison) => vec![self.dest_reg, layout.sts],
+        }
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        match self.loaded {
+            true => Some((self.dest_reg, self.value)),
+            false => None,
+        }
+    }
 }
 
 /// Writes a value in memory from a register.
@@ -494,6 +722,9 @@ pub struct Store {
     /// Address in memory to save value.
     dest_addr: u32,
 
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
     /// Value in register to save in memory.
     value: u32,
 }
@@ -504,6 +735,7 @@ impl Store {
         Store{
             mem_addr_mode: mem_addr_mode,
             dest_addr: 0,
+            src_regs: Vec::new(),
             value: 0,
         }
     }
@@ -518,12 +750,14 @@ impl Display for Store {
 impl Instruction for Store {
     /// Extract operands and retrieve value to save in memory from registers.
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
+        self.src_regs = vec![instruction.get_bits(10..=14) as usize];
         self.dest_addr = registers[instruction.get_bits(10..=14) as usize] as u32;
 
         if self.mem_addr_mode == AddrMode::RegisterDirect {
+            self.src_regs.push(instruction.get_bits(15..=19) as usize);
             self.value = registers[instruction.get_bits(15..=19) as usize] as u32;
         } else if self.mem_addr_mode == AddrMode::Immediate {
-            self.value = (((registers[PC] + 1) as i32) + (instruction.get_bits(15..=31) as i32)) as u32;
+            self.value = (((registers[SpecialRegister::PC] + 1) as i32) + (instruction.get_bits(MEMORY_IMMEDIATE) as i32)) as u32;
         }
 
         SimResult::Wait(0, ())
@@ -547,19 +781,54 @@ impl Instruction for Store {
     fn write_back(&mut self, _registers: &mut Registers) -> SimResult<(), String> {
         SimResult::Wait(0, ())
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("addr".to_string(), self.dest_addr),
+            ("value".to_string(), self.value),
+        ]
+    }
+
+    fn effective_address(&self) -> Option<u32> {
+        Some(self.dest_addr)
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
 }
 
 #[derive(Debug)]
 pub struct Push {
     addr: u32,
     value: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
+    /// Index of the stack pointer in the register layout.
+    sp: usize,
+
+    /// If true the stack pointer is decremented, otherwise it is incremented.
+    stack_grows_down: bool,
 }
 
 impl Push {
-    pub fn new() -> Push {
+    pub fn new(stack_grows_down: bool) -> Push {
         Push{
             addr: 0,
             value: 0,
+            src_regs: Vec::new(),
+            sp: SP,
+            stack_grows_down,
+        }
+    }
+
+    /// Returns the stack pointer after a push.
+    fn next_sp(&self, sp: u32) -> u32 {
+        match self.stack_grows_down {
+            true => sp - 1,
+            false => sp + 1,
         }
     }
 }
@@ -573,8 +842,10 @@ impl Display for Push {
 impl Instruction for Push {
     /// Extract operands and retrieve value to save in memory from registers.
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
-        self.addr = registers[instruction.get_bits(11..=15) as usize] as u32;
-        self.value = registers[SP] - 1;
+        self.sp = registers.layout.sp;
+        self.src_regs = vec![instruction.get_bits(11..=15) as usize, self.sp];
+        self.addr = self.next_sp(registers[SpecialRegister::SP]);
+        self.value = registers[instruction.get_bits(11..=15) as usize];
         SimResult::Wait(0, ())
     }
 
@@ -592,11 +863,34 @@ impl Instruction for Push {
         }
     }
 
-    /// No write back stage.
+    /// Move stack pointer.
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
-        registers[SP] -= 1;
+        registers[SpecialRegister::SP] = self.next_sp(registers[SpecialRegister::SP]);
         SimResult::Wait(0, ())
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("addr".to_string(), self.addr),
+            ("value".to_string(), self.value),
+        ]
+    }
+
+    fn effective_address(&self) -> Option<u32> {
+        Some(self.addr)
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        vec![layout.sp]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        Some((self.sp, self.addr))
+    }
 }
 
 #[derive(Debug)]
@@ -604,14 +898,26 @@ pub struct Pop {
     dest: usize,
     addr: u32,
     value: u32,
+
+    /// Index of the stack pointer in the register layout.
+    sp: usize,
+
+    /// If true the stack pointer is incremented, otherwise it is decremented.
+    stack_grows_down: bool,
+
+    /// Indicates access_memory ran, so value holds the value to write back.
+    loaded: bool,
 }
 
 impl Pop {
-    pub fn new() -> Pop {
+    pub fn new(stack_grows_down: bool) -> Pop {
         Pop{
             dest: 0,
             addr: 0,
             value: 0,
+            sp: SP,
+            stack_grows_down,
+            loaded: false,
         }
     }
 }
@@ -625,7 +931,8 @@ impl Display for Pop {
 impl Instruction for Pop {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
         self.dest = instruction.get_bits(11..=15) as usize;
-        self.addr = registers[SP];
+        self.sp = registers.layout.sp;
+        self.addr = registers[SpecialRegister::SP];
         SimResult::Wait(0, ())
     }
 
@@ -641,17 +948,252 @@ impl Instruction for Pop {
                         self.addr, e)),
             SimResult::Wait(wait, val) => {
                 self.value = val;
+                self.loaded = true;
                 SimResult::Wait(wait, ())
             },
         }
     }
 
-    /// No write back stage.
+    /// Write popped value into register and move stack pointer.
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
         registers[self.dest] = self.value;
-        registers[SP] += 1;
+
+        match self.stack_grows_down {
+            true => registers[SpecialRegister::SP] += 1,
+            false => registers[SpecialRegister::SP] -= 1,
+        }
+
+        SimResult::Wait(0, ())
+    }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest as u32),
+            ("addr".to_string(), self.addr),
+        ]
+    }
+
+    fn effective_address(&self) -> Option<u32> {
+        Some(self.addr)
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        vec![self.sp]
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest, layout.sp]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        match self.loaded {
+            true => Some((self.dest, self.value)),
+            false => None,
+        }
+    }
+}
+
+/// Returns the address of the word which holds a byte address and the bits
+/// of the byte in the word. Byte 0 is the least significant byte.
+fn byte_location(byte_addr: u32) -> (u32, RangeInclusive<usize>) {
+    let offset = (byte_addr % 4) as usize;
+
+    (byte_addr / 4, offset * 8..=offset * 8 + 7)
+}
+
+/// Reads one byte from memory and places it zero extended in a register. The
+/// address register holds a byte address, each word holds 4 bytes.
+#[derive(Debug)]
+pub struct LoadByte {
+    /// Register to place the byte in.
+    dest_reg: usize,
+
+    /// Byte address to load.
+    byte_addr: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
+    /// Byte loaded during access_memory, zero extended.
+    value: u32,
+
+    /// Indicates access_memory ran, so value holds the value to write back.
+    loaded: bool,
+}
+
+impl LoadByte {
+    /// Creates an empty load byte instruction.
+    pub fn new() -> LoadByte {
+        LoadByte{
+            dest_reg: 0,
+            byte_addr: 0,
+            src_regs: Vec::new(),
+            value: 0,
+            loaded: false,
+        }
+    }
+}
+
+impl Display for LoadByte {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Load Byte")
+    }
+}
+
+impl Instruction for LoadByte {
+    /// Extract dest_reg and the byte address.
+    fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
+        self.dest_reg = instruction.get_bits(10..=14) as usize;
+        self.src_regs = vec![instruction.get_bits(15..=19) as usize];
+        self.byte_addr = registers[instruction.get_bits(15..=19) as usize];
+
+        SimResult::Wait(0, ())
+    }
+
+    /// No execute step.
+    fn execute(&mut self) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// Load the word holding the byte and extract the byte.
+    fn access_memory(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
+        let (word_addr, bits) = byte_location(self.byte_addr);
+
+        match memory.borrow_mut().get(word_addr) {
+            SimResult::Err(e) => SimResult::Err(
+                format!("failed to retrieve memory address {} for byte {}: {}",
+                        word_addr, self.byte_addr, e)),
+            SimResult::Wait(wait, word) => {
+                self.value = word.get_bits(bits);
+                self.loaded = true;
+                SimResult::Wait(wait, ())
+            },
+        }
+    }
+
+    /// Write the byte into the register.
+    fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
+        registers[self.dest_reg] = self.value;
+
+        SimResult::Wait(0, ())
+    }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest_reg as u32),
+            ("addr".to_string(), self.byte_addr),
+        ]
+    }
+
+    fn effective_address(&self) -> Option<u32> {
+        Some(byte_location(self.byte_addr).0)
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, _layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest_reg]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        match self.loaded {
+            true => Some((self.dest_reg, self.value)),
+            false => None,
+        }
+    }
+}
+
+/// Writes the least significant byte of a register to memory. The address
+/// register holds a byte address, the other 3 bytes of the word holding it are
+/// preserved.
+#[derive(Debug)]
+pub struct StoreByte {
+    /// Byte address to store to.
+    byte_addr: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
+    /// Register value whose least significant byte is stored.
+    value: u32,
+}
+
+impl StoreByte {
+    /// Creates an empty store byte instruction.
+    pub fn new() -> StoreByte {
+        StoreByte{
+            byte_addr: 0,
+            src_regs: Vec::new(),
+            value: 0,
+        }
+    }
+}
+
+impl Display for StoreByte {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Store Byte")
+    }
+}
+
+impl Instruction for StoreByte {
+    /// Extract the byte address and value from registers.
+    fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
+        self.src_regs = vec![instruction.get_bits(10..=14) as usize,
+                             instruction.get_bits(15..=19) as usize];
+        self.byte_addr = registers[instruction.get_bits(10..=14) as usize];
+        self.value = registers[instruction.get_bits(15..=19) as usize];
+
+        SimResult::Wait(0, ())
+    }
+
+    /// No execution stage.
+    fn execute(&mut self) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// Read the word holding the byte, replace the byte and write the word
+    /// back.
+    fn access_memory(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
+        let (word_addr, bits) = byte_location(self.byte_addr);
+
+        let (get_wait, mut word) = match memory.borrow_mut().get(word_addr) {
+            SimResult::Err(e) => return SimResult::Err(
+                format!("failed to retrieve memory address {} for byte {}: {}",
+                        word_addr, self.byte_addr, e)),
+            SimResult::Wait(wait, word) => (wait, word),
+        };
+
+        word.set_bits(bits, self.value.get_bits(0..=7));
+
+        match memory.borrow_mut().set(word_addr, word) {
+            SimResult::Err(e) => SimResult::Err(
+                format!("Failed to store byte {} in {}: {}", self.byte_addr,
+                        word_addr, e)),
+            SimResult::Wait(wait, _res) => SimResult::Wait(get_wait.saturating_add(wait), ()),
+        }
+    }
+
+    /// No write back stage.
+    fn write_back(&mut self, _registers: &mut Registers) -> SimResult<(), String> {
         SimResult::Wait(0, ())
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("addr".to_string(), self.byte_addr),
+            ("value".to_string(), self.value),
+        ]
+    }
+
+    fn effective_address(&self) -> Option<u32> {
+        Some(byte_location(self.byte_addr).0)
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
 }
 
 // ---------------------------------- ALU Instructions ----------------------------------
@@ -660,6 +1202,9 @@ impl Instruction for Pop {
 pub struct Move {
     dest: usize,
     value: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
 }
 
 impl Move {
@@ -667,6 +1212,7 @@ impl Move {
         Move{
             dest: 0,
             value: 0,
+            src_regs: Vec::new(),
         }
     }
 }
@@ -684,6 +1230,7 @@ impl Instruction for Move {
     /// Extract source register that holds the value to move.
     /// Get the value to move and add it to the value field.
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
+        self.src_regs = vec![instruction.get_bits(18..=22) as usize];
         self.value = registers[instruction.get_bits(18..=22) as usize];
 
         self.dest = instruction.get_bits(13..=17) as usize;
@@ -706,6 +1253,103 @@ impl Instruction for Move {
         registers[self.dest] = self.value;
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest as u32),
+            ("value".to_string(), self.value),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, _layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        Some((self.dest, self.value))
+    }
+}
+
+/// Conditional move. Moves a source register into a destination register if
+/// the condition matches the status register, in the same way as a
+/// conditional jump. The condition is evaluated in write back.
+#[derive(Debug)]
+pub struct CMov {
+    condition: u32,
+    dest: usize,
+    value: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+}
+
+impl CMov {
+    pub fn new() -> CMov {
+        CMov{
+            condition: 0,
+            dest: 0,
+            value: 0,
+            src_regs: Vec::new(),
+        }
+    }
+}
+
+impl Display for CMov {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Conditional Move Instruction")
+    }
+}
+
+impl Instruction for CMov {
+    /// Extract condition and destination register, get the value to move.
+    fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
+        self.condition = instruction.get_bits(0..=4);
+        self.dest = instruction.get_bits(13..=17) as usize;
+        self.src_regs = vec![instruction.get_bits(18..=22) as usize];
+        self.value = registers[instruction.get_bits(18..=22) as usize];
+
+        SimResult::Wait(0, ())
+    }
+
+    /// No execution stage.
+    fn execute(&mut self) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// No memory accessing.
+    fn access_memory(&mut self, _memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// Set the destination register if the condition holds.
+    fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
+        if self.condition == ConditionCodes::NS.value() ||
+            self.condition == registers[SpecialRegister::STS] {
+            registers[self.dest] = self.value;
+        }
+
+        SimResult::Wait(0, ())
+    }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("condition".to_string(), self.condition),
+            ("dest".to_string(), self.dest as u32),
+            ("value".to_string(), self.value),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, _layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest]
+    }
 }
 
 #[derive(Debug)]
@@ -716,6 +1360,12 @@ pub struct ArithSign {
     op1: i32,
     op2: i32,
     result: i32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
+    /// Indicates the result did not fit in 32 bits and was wrapped.
+    overflowed: bool,
 }
 
 impl ArithSign {
@@ -727,6 +1377,8 @@ impl ArithSign {
             op1: 0,
             op2: 0,
             result: 0,
+            src_regs: Vec::new(),
+            overflowed: false,
         }
     }
 }
@@ -742,26 +1394,41 @@ impl Instruction for ArithSign {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
 
         self.dest = instruction.get_bits(14..=18) as usize;
+        self.src_regs = vec![instruction.get_bits(19..=23) as usize];
 
         self.op1 = registers[instruction.get_bits(19..=23) as usize] as i32;
 
         if self.mem_addr_mode == AddrMode::RegisterDirect {
+            self.src_regs.push(instruction.get_bits(24..=28) as usize);
             self.op2 = registers[instruction.get_bits(24..=28) as usize] as i32;
         } else if self.mem_addr_mode == AddrMode::Immediate {
-            self.op2 = instruction.get_bits(24..=31) as i32;
+            let bits = SIGNED_IMMEDIATE.end() - SIGNED_IMMEDIATE.start() + 1;
+            self.op2 = sign_extend(instruction.get_bits(SIGNED_IMMEDIATE), bits as u32);
         }
         
         return SimResult::Wait(0, ());
     }
 
+    /// Fails if a division's or remainder's divisor is 0.
     fn execute(&mut self) -> SimResult<(), String> {
-        match self.operation {
-            ArithMode::Add => self.result = self.op1 + self.op2,
-            ArithMode::Sub => self.result = self.op1 - self.op2,
-            ArithMode::Mul => self.result = self.op1 * self.op2,
-            ArithMode::Div => self.result = self.op1 / self.op2,
+        if (self.operation == ArithMode::Div || self.operation == ArithMode::Mod) &&
+            self.op2 == 0 {
+            return SimResult::Err("division by zero".to_string());
         }
-        return SimResult::Wait(0, ());
+
+        // Results wrap, overflow is detected separately
+        let (result, checked) = match self.operation {
+            ArithMode::Add => (self.op1.wrapping_add(self.op2), self.op1.checked_add(self.op2)),
+            ArithMode::Sub => (self.op1.wrapping_sub(self.op2), self.op1.checked_sub(self.op2)),
+            ArithMode::Mul => (self.op1.wrapping_mul(self.op2), self.op1.checked_mul(self.op2)),
+            ArithMode::Div => (self.op1.wrapping_div(self.op2), self.op1.checked_div(self.op2)),
+            // The remainder of i32::MIN / -1 is 0, which does not overflow
+            ArithMode::Mod => (self.op1.wrapping_rem(self.op2), Some(self.op1.wrapping_rem(self.op2))),
+        };
+        self.result = result;
+        self.overflowed = checked.is_none();
+
+        return SimResult::Wait(0, ());
     }
 
     /// Skipped, no memory accessing.
@@ -769,11 +1436,39 @@ impl Instruction for ArithSign {
         return SimResult::Wait(0, ());
     }
 
-    /// Store the value of the result in the destination register.
+    /// Store the value of the result in the destination register and set STS
+    /// from it, see flags_from_result(). Sets the signed overflow condition
+    /// code V instead if the result overflowed.
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
         registers[self.dest] = self.result as u32;
+        registers[SpecialRegister::STS] = flags_from_result(self.result as u32);
+
+        if self.overflowed {
+            registers[SpecialRegister::STS] = ConditionCodes::V.value();
+        }
+
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest as u32),
+            ("op1".to_string(), self.op1 as u32),
+            ("op2".to_string(), self.op2 as u32),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest, layout.sts]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        Some((self.dest, self.result as u32))
+    }
 }
 
 #[derive(Debug)]
@@ -784,6 +1479,12 @@ pub struct ArithUnsign {
     op1: u32,
     op2: u32,
     result: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
+    /// Indicates the result did not fit in 32 bits and was wrapped.
+    overflowed: bool,
 }
 
 impl ArithUnsign {
@@ -795,6 +1496,8 @@ impl ArithUnsign {
             op1: 0,
             op2: 0,
             result: 0,
+            src_regs: Vec::new(),
+            overflowed: false,
         }
     }
 }
@@ -810,29 +1513,39 @@ impl Instruction for ArithUnsign {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
 
         self.dest = instruction.get_bits(13..=17) as usize;
+        self.src_regs = vec![instruction.get_bits(18..=22) as usize];
 
         self.op1 = registers[instruction.get_bits(18..=22) as usize] as u32;
 
         if self.mem_addr_mode == AddrMode::RegisterDirect {
+            self.src_regs.push(instruction.get_bits(23..=27) as usize);
             self.op2 = registers[instruction.get_bits(23..=27) as usize] as u32;
         } else if self.mem_addr_mode == AddrMode::Immediate {
-            self.op2 = instruction.get_bits(23..=31) as u32;
+            self.op2 = instruction.get_bits(UNSIGNED_IMMEDIATE);
         }
         
         return SimResult::Wait(0, ());
         // return SimResult::Err(format!("Instruction details: dest: {}, op1: {}, op2: {}",self.dest, self.op1, self.op2));
     }
 
+    /// Fails if a division's or remainder's divisor is 0.
     fn execute(&mut self) -> SimResult<(), String> {
-        match self.operation {
-            ArithMode::Add => {
-                self.result = self.op1 + self.op2;
-                // return SimResult::Err(format!("Instruction details: result: {}, op1: {}, op2: {}",self.result, self.op1, self.op2));
-            },
-            ArithMode::Sub => self.result = self.op1 - self.op2,
-            ArithMode::Mul => self.result = self.op1 * self.op2,
-            ArithMode::Div => self.result = self.op1 / self.op2,
+        if (self.operation == ArithMode::Div || self.operation == ArithMode::Mod) &&
+            self.op2 == 0 {
+            return SimResult::Err("division by zero".to_string());
         }
+
+        // Results wrap, overflow is detected separately
+        let (result, checked) = match self.operation {
+            ArithMode::Add => (self.op1.wrapping_add(self.op2), self.op1.checked_add(self.op2)),
+            ArithMode::Sub => (self.op1.wrapping_sub(self.op2), self.op1.checked_sub(self.op2)),
+            ArithMode::Mul => (self.op1.wrapping_mul(self.op2), self.op1.checked_mul(self.op2)),
+            ArithMode::Div => (self.op1.wrapping_div(self.op2), self.op1.checked_div(self.op2)),
+            ArithMode::Mod => (self.op1.wrapping_rem(self.op2), self.op1.checked_rem(self.op2)),
+        };
+        self.result = result;
+        self.overflowed = checked.is_none();
+
         return SimResult::Wait(0, ());
         // return SimResult::Err(format!("Instruction details: result: {}, op1: {}, op2: {}",self.result, self.op1, self.op2));
     }
@@ -842,17 +1555,48 @@ impl Instruction for ArithUnsign {
         return SimResult::Wait(0, ());
     }
 
-    /// Store the value of the result in the destination register.
+    /// Store the value of the result in the destination register and set STS
+    /// from it, see flags_from_result(). Sets the carry condition code C
+    /// instead if the result did not fit in 32 bits.
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
         registers[self.dest] = self.result as u32;
+        registers[SpecialRegister::STS] = flags_from_result(self.result as u32);
+
+        if self.overflowed {
+            registers[SpecialRegister::STS] = ConditionCodes::C.value();
+        }
+
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest as u32),
+            ("op1".to_string(), self.op1),
+            ("op2".to_string(), self.op2),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest, layout.sts]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        Some((self.dest, self.result))
+    }
 }
 
 #[derive(Debug)]
 pub struct Comp {
     op1: u32,
     op2: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
 }
 
 impl Comp {
@@ -860,6 +1604,7 @@ impl Comp {
         Comp{
             op1: 0,
             op2: 0,
+            src_regs: Vec::new(),
         }
     }
 }
@@ -872,6 +1617,8 @@ impl Display for Comp {
 
 impl Instruction for Comp {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
+        self.src_regs = vec![instruction.get_bits(13..=17) as usize,
+                             instruction.get_bits(18..=22) as usize];
 
         self.op1 = registers[instruction.get_bits(13..=17) as usize] as u32;
 
@@ -893,15 +1640,30 @@ impl Instruction for Comp {
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
         
         if self.op1 < self.op2 {
-            registers[STS] = ConditionCodes::LT.value();
+            registers[SpecialRegister::STS] = ConditionCodes::LT.value();
         } else if self.op1 > self.op2 {
-            registers[STS] = ConditionCodes::GT.value();
+            registers[SpecialRegister::STS] = ConditionCodes::GT.value();
         } else {
-            registers[STS] = ConditionCodes::E.value();
+            registers[SpecialRegister::STS] = ConditionCodes::E.value();
         }
         
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("op1".to_string(), self.op1),
+            ("op2".to_string(), self.op2),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        vec![layout.sts]
+    }
 }
 
 
@@ -910,9 +1672,14 @@ pub struct AS {
     mem_addr_mode: AddrMode,
     direction: bool,
     dest: usize,
-    op: u32,
+
+    /// Shifted as signed so right shifts copy the sign bit.
+    op: i32,
     amount: u32,
-    result: u32,
+    result: i32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
 }
 
 impl AS {
@@ -925,6 +1692,7 @@ impl AS {
             op: 0,
             amount: 0,
             result: 0,
+            src_regs: Vec::new(),
         }
     }
 }
@@ -939,23 +1707,25 @@ impl Instruction for AS {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
 
         self.dest = instruction.get_bits(13..=17) as usize;
+        self.src_regs = vec![self.dest];
 
         if self.mem_addr_mode == AddrMode::RegisterDirect {
+            self.src_regs.push(instruction.get_bits(18..=22) as usize);
             self.amount = registers[instruction.get_bits(18..=22) as usize] as u32;
         } else if self.mem_addr_mode == AddrMode::Immediate {
-            self.amount = instruction.get_bits(18..=31) as u32;
+            self.amount = instruction.get_bits(SHIFT_IMMEDIATE);
         }
         
-        self.op = registers[self.dest] as u32;
+        self.op = registers[self.dest] as i32;
 
         return SimResult::Wait(0, ());
     }
 
     fn execute(&mut self) -> SimResult<(), String> {
         if self.direction {
-            self.result = self.op << self.amount;
-        } else {
             self.result = self.op >> self.amount;
+        } else {
+            self.result = self.op << self.amount;
         }
 
         return SimResult::Wait(0, ());
@@ -968,10 +1738,30 @@ impl Instruction for AS {
 
     /// Store the value of the result in the destination register.
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
-        registers[self.dest] = self.result;
+        registers[self.dest] = self.result as u32;
         
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest as u32),
+            ("value".to_string(), self.op as u32),
+            ("amount".to_string(), self.amount),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, _layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        Some((self.dest, self.result as u32))
+    }
 }
 
 
@@ -980,9 +1770,14 @@ pub struct LS {
     mem_addr_mode: AddrMode,
     direction: bool,
     dest: usize,
-    op: i32,
-    amount: i32,
-    result: i32,
+
+    /// Shifted as unsigned so right shifts fill with zeros.
+    op: u32,
+    amount: u32,
+    result: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
 }
 
 impl LS {
@@ -995,6 +1790,7 @@ impl LS {
             op: 0,
             amount: 0,
             result: 0,
+            src_regs: Vec::new(),
         }
     }
 }
@@ -1009,23 +1805,25 @@ impl Instruction for LS {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
 
         self.dest = instruction.get_bits(13..=17) as usize;
+        self.src_regs = vec![self.dest];
 
         if self.mem_addr_mode == AddrMode::RegisterDirect {
-            self.amount = registers[instruction.get_bits(18..=22) as usize] as i32;
+            self.src_regs.push(instruction.get_bits(18..=22) as usize);
+            self.amount = registers[instruction.get_bits(18..=22) as usize];
         } else if self.mem_addr_mode == AddrMode::Immediate {
-            self.amount = instruction.get_bits(18..=31) as i32;
+            self.amount = instruction.get_bits(SHIFT_IMMEDIATE);
         }
         
-        self.op = registers[self.dest] as i32;
+        self.op = registers[self.dest];
 
         return SimResult::Wait(0, ());
     }
 
     fn execute(&mut self) -> SimResult<(), String> {
         if self.direction {
-            self.result = self.op << self.amount;
-        } else {
             self.result = self.op >> self.amount;
+        } else {
+            self.result = self.op << self.amount;
         }
 
         return SimResult::Wait(0, ());
@@ -1038,10 +1836,127 @@ impl Instruction for LS {
 
     /// Store the value of the result in the destination register.
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
-        registers[self.dest] = self.result as u32;
+        registers[self.dest] = self.result;
         
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest as u32),
+            ("value".to_string(), self.op),
+            ("amount".to_string(), self.amount),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, _layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        Some((self.dest, self.result))
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Rotate {
+    mem_addr_mode: AddrMode,
+    direction: bool,
+    dest: usize,
+    op: u32,
+
+    /// Bits to rotate by, modulo 32 so multiples of 32 leave the value alone.
+    amount: u32,
+    result: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+}
+
+impl Rotate {
+    // direction: Left = false, right = true
+    pub fn new(mem_addr_mode: AddrMode, d: bool) -> Rotate {
+        Rotate{
+            mem_addr_mode,
+            direction: d,
+            dest: 0,
+            op: 0,
+            amount: 0,
+            result: 0,
+            src_regs: Vec::new(),
+        }
+    }
+}
+
+impl Display for Rotate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Rotate")
+    }
+}
+
+impl Instruction for Rotate {
+    fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
+        self.dest = instruction.get_bits(13..=17) as usize;
+        self.src_regs = vec![self.dest];
+
+        if self.mem_addr_mode == AddrMode::RegisterDirect {
+            self.src_regs.push(instruction.get_bits(18..=22) as usize);
+            self.amount = registers[instruction.get_bits(18..=22) as usize] % 32;
+        } else if self.mem_addr_mode == AddrMode::Immediate {
+            self.amount = instruction.get_bits(SHIFT_IMMEDIATE) % 32;
+        }
+
+        self.op = registers[self.dest];
+
+        SimResult::Wait(0, ())
+    }
+
+    fn execute(&mut self) -> SimResult<(), String> {
+        if self.direction {
+            self.result = self.op.rotate_right(self.amount);
+        } else {
+            self.result = self.op.rotate_left(self.amount);
+        }
+
+        SimResult::Wait(0, ())
+    }
+
+    /// Skipped, no memory accessing.
+    fn access_memory(&mut self, _memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// Store the value of the result in the destination register.
+    fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
+        registers[self.dest] = self.result;
+
+        SimResult::Wait(0, ())
+    }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest as u32),
+            ("value".to_string(), self.op),
+            ("amount".to_string(), self.amount),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, _layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        Some((self.dest, self.result))
+    }
 }
 
 
@@ -1053,6 +1968,9 @@ pub struct ThreeOpLogic {
     op1: u32,
     op2: u32,
     result: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
 }
 
 impl ThreeOpLogic {
@@ -1064,6 +1982,7 @@ impl ThreeOpLogic {
             op1: 0,
             op2: 0,
             result: 0,
+            src_regs: Vec::new(),
         }
     }
 }
@@ -1078,13 +1997,15 @@ impl Instruction for ThreeOpLogic {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
 
         self.dest = instruction.get_bits(13..=17) as usize;
+        self.src_regs = vec![instruction.get_bits(18..=22) as usize];
 
         self.op1 = registers[instruction.get_bits(18..=22) as usize] as u32;
 
         if self.mem_addr_mode == AddrMode::RegisterDirect {
+            self.src_regs.push(instruction.get_bits(23..=27) as usize);
             self.op2 = registers[instruction.get_bits(23..=27) as usize] as u32;
         } else if self.mem_addr_mode == AddrMode::Immediate {
-            self.op2 = instruction.get_bits(23..=31) as u32;
+            self.op2 = instruction.get_bits(UNSIGNED_IMMEDIATE);
         }
 
         return SimResult::Wait(0, ());
@@ -1105,12 +2026,34 @@ impl Instruction for ThreeOpLogic {
         return SimResult::Wait(0, ());
     }
 
-    /// Store the value of the result in the destination register.
+    /// Store the value of the result in the destination register and set STS
+    /// from it, see flags_from_result().
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
         registers[self.dest] = self.result;
+        registers[SpecialRegister::STS] = flags_from_result(self.result);
         
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest as u32),
+            ("op1".to_string(), self.op1),
+            ("op2".to_string(), self.op2),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest, layout.sts]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        Some((self.dest, self.result))
+    }
 }
 
 #[derive(Debug)]
@@ -1118,6 +2061,9 @@ pub struct Not {
     dest: usize,
     op: u32,
     result: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
 }
 
 impl Not {
@@ -1126,6 +2072,7 @@ impl Not {
             dest: 0,
             op: 0,
             result: 0,
+            src_regs: Vec::new(),
         }
     }
 }
@@ -1139,6 +2086,7 @@ impl Display for Not {
 impl Instruction for Not {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
         self.dest = instruction.get_bits(13..=17) as usize;
+        self.src_regs = vec![instruction.get_bits(18..=22) as usize];
 
         self.op = registers[instruction.get_bits(18..=22) as usize] as u32;
 
@@ -1155,11 +2103,32 @@ impl Instruction for Not {
     }
 
     /// Store the value of the result in the destination register and invert it.
+    /// Sets STS from the result, see flags_from_result().
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
         registers[self.dest] = !self.op;
+        registers[SpecialRegister::STS] = flags_from_result(!self.op);
         
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("dest".to_string(), self.dest as u32),
+            ("value".to_string(), self.op),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        vec![self.dest, layout.sts]
+    }
+
+    fn pending_result(&self) -> Option<(usize, u32)> {
+        Some((self.dest, !self.op))
+    }
 }
 
 // ---------------------------------- Control Instructions ----------------------------------
@@ -1170,15 +2139,34 @@ pub struct Jump {
     is_sub: bool,
     condition: u32,
     addr: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
+    /// Number of addresses an instruction occupies in memory.
+    instruction_size: u32,
+
+    /// If true taking a jump to an address which is not a multiple of
+    /// instruction_size fails.
+    check_alignment: bool,
+
+    /// Address of the instruction after the jump, saved in the link register
+    /// for subroutine jumps.
+    return_addr: u32,
 }
 
 impl Jump {
-    pub fn new(mem_addr_mode: AddrMode, is_sub: bool) -> Jump {
+    pub fn new(mem_addr_mode: AddrMode, is_sub: bool,
+               instruction_size: u32, check_alignment: bool) -> Jump {
         Jump{
             mem_addr_mode: mem_addr_mode,
             is_sub: is_sub,
             condition: 0,
             addr: 0,
+            src_regs: Vec::new(),
+            instruction_size,
+            check_alignment,
+            return_addr: 0,
         }
     }
 }
@@ -1191,14 +2179,23 @@ impl Display for Jump {
 
 impl Instruction for Jump {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
-        self.condition = instruction.get_bits(0..=4) as u32;
+        self.src_regs = Vec::new();
+        self.condition = instruction.get_bits(0..=4);
+
+        // Conditional jumps read the status register
+        if self.condition != 0 {
+            self.src_regs.push(registers.layout.sts);
+        }
 
         if self.mem_addr_mode == AddrMode::RegisterDirect {
-            self.addr = registers[instruction.get_bits(10..=14) as usize] as u32;
+            self.src_regs.push(instruction.get_bits(10..=14) as usize);
+            self.addr = registers[instruction.get_bits(10..=14) as usize];
         } else if self.mem_addr_mode == AddrMode::Immediate {
-            self.addr = instruction.get_bits(10..=31) as u32;
+            self.addr = instruction.get_bits(JUMP_IMMEDIATE);
         }
 
+        self.return_addr = registers[SpecialRegister::PC] + self.instruction_size;
+
         return SimResult::Wait(0, ());
     }
 
@@ -1212,34 +2209,55 @@ impl Instruction for Jump {
     }
 
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
+        let taken = self.condition == 0 || self.condition == registers[SpecialRegister::STS];
+        
+        if taken && self.check_alignment &&
+            self.addr % self.instruction_size != 0 {
+            return SimResult::Err(format!("jump target {} is not aligned to \
+                                           instruction size {}",
+                                          self.addr, self.instruction_size));
+        }
         
         if self.condition != 0 {
-            if self.condition == registers[STS] {
+            if self.condition == registers[SpecialRegister::STS] {
                 if self.is_sub {
-                    registers[LR] = (PC + 1) as u32;
+                    registers[SpecialRegister::LR] = self.return_addr;
                 } 
-                registers[PC] = self.addr;
-                // else if self.mem_addr_mode == AddrMode::RegisterDirect {
-                //     registers[PC] = self.addr;
-                // }
-                // else if self.mem_addr_mode == AddrMode::Immediate {
-                //     registers[PC] += self.addr;
-                // }
+                registers[SpecialRegister::PC] = self.addr;
             }
 
         } else {
-            registers[PC] = self.addr;
-            // if self.mem_addr_mode == AddrMode::RegisterDirect {
-            //     registers[PC] = self.addr;
-            // }
-            // else if self.mem_addr_mode == AddrMode::Immediate {
-            //     registers[PC] += self.addr;
-            // }
+            registers[SpecialRegister::PC] = self.addr;
         }
         
         
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("condition".to_string(), self.condition),
+            ("addr".to_string(), self.addr),
+        ]
+    }
+
+    fn branch_target(&self, registers: &Registers) -> Option<u32> {
+        match self.condition == 0 || self.condition == registers[SpecialRegister::STS] {
+            true => Some(self.addr),
+            false => None,
+        }
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        match self.is_sub {
+            true => vec![layout.lr],
+            false => vec![],
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1263,7 +2281,7 @@ impl Display for SIH {
 
 impl Instruction for SIH {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
-        self.addr = instruction.get_bits(10..=14) as u32;
+        self.addr = instruction.get_bits(HANDLER_IMMEDIATE);
 
         return SimResult::Wait(0, ());
     }
@@ -1278,27 +2296,54 @@ impl Instruction for SIH {
     }
 
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
-        registers[IHDLR] = self.addr;
+        registers[SpecialRegister::IHDLR] = self.addr;
         
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("addr".to_string(), self.addr),
+        ]
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        vec![layout.ihdlr]
+    }
 }
 
+/// Performs an interrupt. The interrupt is taken if a handler has been set with
+/// SIH and an interrupt is not already being handled. A taken interrupt saves
+/// the address of the instruction after the INT in INTLR, sets
+/// Registers::interrupt_active and jumps to the handler. An interrupt which is not
+/// taken is dropped and has no effect.
 #[derive(Debug)]
 pub struct INT {
     mem_addr_mode: AddrMode,
     proceed: bool,
     code: u32,
     addr: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
+    /// Number of addresses an instruction occupies in memory.
+    instruction_size: u32,
+
+    /// Address of the instruction after the interrupt, which RFI returns to.
+    return_addr: u32,
 }
 
 impl INT {
-    pub fn new(mem_addr_mode: AddrMode) -> INT {
+    pub fn new(mem_addr_mode: AddrMode, instruction_size: u32) -> INT {
         INT{
             mem_addr_mode: mem_addr_mode,
             proceed: false,
             code: 0,
             addr: 0,
+            src_regs: Vec::new(),
+            instruction_size,
+            return_addr: 0,
         }
     }
 }
@@ -1311,15 +2356,23 @@ impl Display for INT {
 
 impl Instruction for INT {
     fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
+        self.src_regs = Vec::new();
         if self.mem_addr_mode == AddrMode::RegisterDirect {
-            self.code = registers[instruction.get_bits(10..=14) as usize] as u32;
+            self.src_regs.push(instruction.get_bits(13..=17) as usize);
+            self.code = registers[instruction.get_bits(13..=17) as usize];
         } else if self.mem_addr_mode == AddrMode::Immediate {
-            self.code = instruction.get_bits(10..=13) as u32;
+            self.code = instruction.get_bits(INTERRUPT_IMMEDIATE);
         }
 
-        if registers[STS] != InterruptCodes::NOT_SET as u32 && registers[IHDLR] != InterruptCodes::NOT_SET_INITIAL as u32 {
-            self.proceed = true;
-        }
+        // Taken if a handler was set by SIH and an interrupt is not already
+        // being handled, dropped otherwise. Registers::interrupt_active is
+        // only changed by interrupts and RFI, which jump and so flush younger
+        // instructions, so it is never stale here.
+        self.src_regs.push(registers.layout.ihdlr);
+        let handler_set = registers[SpecialRegister::IHDLR] != InterruptCodes::NOT_SET_INITIAL.value() as u32;
+        self.proceed = handler_set && !registers.interrupt_active;
+
+        self.return_addr = registers[SpecialRegister::PC] + self.instruction_size;
 
         return SimResult::Wait(0, ());
     }
@@ -1333,7 +2386,7 @@ impl Instruction for INT {
     /// Skipped, no memory accessing.
     fn access_memory(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
         if self.proceed {
-            match memory.borrow_mut().set(1111111111, self.code) {
+            match memory.borrow_mut().set(INTERRUPT_CODE_ADDR, self.code) {
                 SimResult::Err(e) => SimResult::Err(format!("Failed to store interrupt code, value in {}: {}", self.code, e)),
                 SimResult::Wait(wait, _res) => SimResult::Wait(wait, ()),
             }
@@ -1345,14 +2398,30 @@ impl Instruction for INT {
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
 
         if self.proceed {
-            self.proceed = true;
-            registers[STS] = InterruptCodes::SET as u32;
-            registers[INTLR] = registers[PC];
-            registers[PC] = registers[IHDLR];
+            registers.interrupt_active = true;
+            registers[SpecialRegister::INTLR] = self.return_addr;
+            registers[SpecialRegister::PC] = registers[SpecialRegister::IHDLR];
         }
 
         return SimResult::Wait(0, ());
     }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("code".to_string(), self.code),
+        ]
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        match self.proceed {
+            true => vec![layout.intlr],
+            false => vec![],
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1385,20 +2454,586 @@ impl Instruction for RFI {
     }
 
     fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
-        if registers[STS] != InterruptCodes::NOT_SET_INITIAL as u32 {
-            registers[STS] = InterruptCodes::NOT_SET as u32;
-            registers[PC] = registers[INTLR];
+        if registers.interrupt_active {
+            registers.interrupt_active = false;
+            registers[SpecialRegister::PC] = registers[SpecialRegister::INTLR];
         }
         
         return SimResult::Wait(0, ());
     }
 }
 
+/// Sets up a stack frame. Pushes the frame pointer, points the frame pointer
+/// at the pushed value, then allocates space on the stack for locals.
+#[derive(Debug)]
+pub struct Enter {
+    /// Number of words to allocate for locals.
+    locals: u32,
+
+    /// Address the old frame pointer is pushed to.
+    addr: u32,
+
+    /// Frame pointer value to push.
+    value: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
+    /// If true the frame pointer is pushed below the stack pointer and locals
+    /// are allocated downwards, otherwise upwards.
+    stack_grows_down: bool,
+}
+
+impl Enter {
+    pub fn new(stack_grows_down: bool) -> Enter {
+        Enter{
+            locals: 0,
+            addr: 0,
+            value: 0,
+            src_regs: Vec::new(),
+            stack_grows_down,
+        }
+    }
+}
+
+impl Display for Enter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Enter")
+    }
+}
+
+impl Instruction for Enter {
+    /// Extract locals size and retrieve stack and frame pointers.
+    fn decode(&mut self, instruction: u32, registers: &Registers) -> SimResult<(), String> {
+        self.locals = instruction.get_bits(ENTER_IMMEDIATE);
+        self.src_regs = vec![registers.layout.sp, registers.layout.fp];
+        self.addr = match self.stack_grows_down {
+            true => registers[SpecialRegister::SP] - 1,
+            false => registers[SpecialRegister::SP] + 1,
+        };
+        self.value = registers[SpecialRegister::FP];
+        SimResult::Wait(0, ())
+    }
+
+    /// No execution stage.
+    fn execute(&mut self) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// Push the old frame pointer.
+    fn access_memory(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
+        match memory.borrow_mut().set(self.addr, self.value) {
+            SimResult::Err(e) => SimResult::Err(
+                format!("Failed to push frame pointer to {}: {}", self.addr, e)),
+            SimResult::Wait(wait, _res) => SimResult::Wait(wait, ()),
+        }
+    }
+
+    /// Point frame pointer at old frame pointer and allocate locals.
+    fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
+        registers[SpecialRegister::FP] = self.addr;
+        registers[SpecialRegister::SP] = match self.stack_grows_down {
+            true => self.addr - self.locals,
+            false => self.addr + self.locals,
+        };
+        SimResult::Wait(0, ())
+    }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("locals".to_string(), self.locals),
+            ("addr".to_string(), self.addr),
+        ]
+    }
+
+    fn effective_address(&self) -> Option<u32> {
+        Some(self.addr)
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        vec![layout.sp, layout.fp]
+    }
+}
+
+/// Tears down a stack frame set up by Enter. Frees locals by pointing the stack
+/// pointer at the frame pointer, then pops the old frame pointer.
+#[derive(Debug)]
+pub struct Leave {
+    /// Address of the old frame pointer.
+    addr: u32,
+
+    /// Old frame pointer value.
+    value: u32,
+
+    /// Registers read by decode.
+    src_regs: Vec<usize>,
+
+    /// If true the stack pointer is moved up past the old frame pointer,
+    /// otherwise down.
+    stack_grows_down: bool,
+}
+
+impl Leave {
+    pub fn new(stack_grows_down: bool) -> Leave {
+        Leave{
+            addr: 0,
+            value: 0,
+            src_regs: Vec::new(),
+            stack_grows_down,
+        }
+    }
+}
+
+impl Display for Leave {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Leave")
+    }
+}
+
+impl Instruction for Leave {
+    /// Retrieve frame pointer.
+    fn decode(&mut self, _instruction: u32, registers: &Registers) -> SimResult<(), String> {
+        self.src_regs = vec![registers.layout.fp];
+        self.addr = registers[SpecialRegister::FP];
+        SimResult::Wait(0, ())
+    }
+
+    /// No execution stage.
+    fn execute(&mut self) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// Pop the old frame pointer.
+    fn access_memory(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
+        match memory.borrow_mut().get(self.addr) {
+            SimResult::Err(e) => SimResult::Err(
+                format!("failed to pop frame pointer from {}: {}", self.addr, e)),
+            SimResult::Wait(wait, val) => {
+                self.value = val;
+                SimResult::Wait(wait, ())
+            },
+        }
+    }
+
+    /// Restore stack and frame pointers.
+    fn write_back(&mut self, registers: &mut Registers) -> SimResult<(), String> {
+        registers[SpecialRegister::SP] = match self.stack_grows_down {
+            true => self.addr + 1,
+            false => self.addr - 1,
+        };
+        registers[SpecialRegister::FP] = self.value;
+        SimResult::Wait(0, ())
+    }
+
+    fn operands(&self) -> Vec<(String, u32)> {
+        vec![
+            ("addr".to_string(), self.addr),
+        ]
+    }
+
+    fn effective_address(&self) -> Option<u32> {
+        Some(self.addr)
+    }
+
+    fn src_regs(&self) -> Vec<usize> {
+        self.src_regs.clone()
+    }
+
+    fn dest_regs(&self, layout: &RegisterLayout) -> Vec<usize> {
+        vec![layout.sp, layout.fp]
+    }
+}
+
+/// Writes every modified value held by the cache to the memory below it, see
+/// Memory::flush().
+#[derive(Debug)]
+pub struct CacheFlush {}
+
+impl CacheFlush {
+    pub fn new() -> CacheFlush {
+        CacheFlush{}
+    }
+}
+
+impl Display for CacheFlush {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CacheFlush")
+    }
+}
+
+impl Instruction for CacheFlush {
+    fn decode(&mut self, _instruction: u32, _registers: &Registers) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    fn execute(&mut self) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// Flush the cache.
+    fn access_memory(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
+        match memory.borrow_mut().flush() {
+            SimResult::Err(e) => SimResult::Err(
+                format!("failed to flush cache: {}", e)),
+            SimResult::Wait(wait, _v) => SimResult::Wait(wait, ()),
+        }
+    }
+
+    fn write_back(&mut self, _registers: &mut Registers) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+}
+
+/// Discards every value held by the cache, see Memory::invalidate(). Modified
+/// values which were not flushed are lost.
+#[derive(Debug)]
+pub struct CacheInvalidate {}
+
+impl CacheInvalidate {
+    pub fn new() -> CacheInvalidate {
+        CacheInvalidate{}
+    }
+}
+
+impl Display for CacheInvalidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CacheInvalidate")
+    }
+}
+
+impl Instruction for CacheInvalidate {
+    fn decode(&mut self, _instruction: u32, _registers: &Registers) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    fn execute(&mut self) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// Invalidate the cache.
+    fn access_memory(&mut self, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> SimResult<(), String> {
+        match memory.borrow_mut().invalidate() {
+            SimResult::Err(e) => SimResult::Err(
+                format!("failed to invalidate cache: {}", e)),
+            SimResult::Wait(wait, _v) => SimResult::Wait(wait, ()),
+        }
+    }
+
+    fn write_back(&mut self, _registers: &mut Registers) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+}
+
+// ---------------------------------- Compressed Instructions ----------------------------------
+
+/// Operation codes of 16-bit compressed instructions. Compressed instructions
+/// have the following format:
+///
+/// - bits 0..=2: Operation code
+/// - Move: 3..=7 destination register, 8..=12 source register
+/// - JmpI: 3..=15 address, the jump is unconditional
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum CompressedOp {
+    Halt,
+    Noop,
+    Move,
+    JmpI,
+}
+
+impl CompressedOp {
+    pub fn value(self) -> u16 {
+        match self {
+            CompressedOp::Halt => 0,
+            CompressedOp::Noop => 1,
+            CompressedOp::Move => 2,
+            CompressedOp::JmpI => 3,
+        }
+    }
+
+    pub fn match_val(val: u16) -> Option<CompressedOp> {
+        match val {
+            0 => Some(CompressedOp::Halt),
+            1 => Some(CompressedOp::Noop),
+            2 => Some(CompressedOp::Move),
+            3 => Some(CompressedOp::JmpI),
+            _ => None,
+        }
+    }
+}
+
+/// Converts a 16-bit compressed instruction into the 32-bit instruction which
+/// does the same thing.
+pub fn expand_halfword(half: u16) -> Result<u32, String> {
+    let mut word: u32 = 0;
+
+    match CompressedOp::match_val(half.get_bits(0..=2)) {
+        Some(CompressedOp::Halt) => {
+            word.set_bits(5..=6, InstructionT::Control.value());
+            set_control_op(&mut word, ControlOp::Halt.value());
+        },
+        Some(CompressedOp::Noop) => {
+            word.set_bits(5..=6, InstructionT::Control.value());
+            set_control_op(&mut word, ControlOp::Noop.value());
+        },
+        Some(CompressedOp::Move) => {
+            word.set_bits(5..=6, InstructionT::ALU.value());
+            word.set_bits(7..=12, ALUOp::Move.value());
+            word.set_bits(13..=17, half.get_bits(3..=7) as u32);
+            word.set_bits(18..=22, half.get_bits(8..=12) as u32);
+        },
+        Some(CompressedOp::JmpI) => {
+            word.set_bits(5..=6, InstructionT::Control.value());
+            set_control_op(&mut word, ControlOp::JmpI.value());
+            word.set_bits(JUMP_IMMEDIATE, half.get_bits(3..=15) as u32);
+        },
+        None => return Err(format!("Invalid compressed instruction operation \
+                                    code {} in {:#06x}", half.get_bits(0..=2),
+                                   half)),
+    };
+
+    Ok(word)
+}
+
+// ---------------------------------- Disassembly ----------------------------------
+
+/// Returns the assembly text of an instruction word, like "LOAD R20, [R6]" or
+/// "ADDUI R2, R10, #2". Fields are read from the same bits as the decode
+/// methods. Fails if the word is not a valid instruction.
+pub fn disassemble(bits: u32) -> Result<String, String> {
+    let reg = |range: std::ops::RangeInclusive<usize>| format!("R{}", bits.get_bits(range));
+    let imm = |range: std::ops::RangeInclusive<usize>| format!("#{}", bits.get_bits(range));
+    let cond = match ConditionCodes::suffix(bits.get_bits(0..=4)) {
+        Some(c) => c,
+        None => return Err(format!("Invalid condition code {} in instruction {:#010x}",
+                                   bits.get_bits(0..=4), bits)),
+    };
+    let itype = bits.get_bits(5..=6);
+
+    match InstructionT::match_val(itype) {
+        Some(InstructionT::Memory) => {
+            let op = bits.get_bits(7..=9);
+
+            match MemoryOp::match_val(op) {
+                Some(MemoryOp::LoadRD) => Ok(format!("LOAD {}, [{}]", reg(10..=14), reg(15..=19))),
+                Some(MemoryOp::LoadI) => Ok(format!("LOAD {}, [PC + {}]", reg(10..=14), imm(MEMORY_IMMEDIATE))),
+                Some(MemoryOp::StoreRD) => Ok(format!("STORE [{}], {}", reg(10..=14), reg(15..=19))),
+                Some(MemoryOp::StoreI) => Ok(format!("STORE [{}], PC + {}", reg(10..=14), imm(MEMORY_IMMEDIATE))),
+                Some(MemoryOp::Push) => Ok(format!("PUSH {}", reg(11..=15))),
+                Some(MemoryOp::Pop) => Ok(format!("POP {}", reg(11..=15))),
+                Some(MemoryOp::LoadByteRD) => Ok(format!("LOADB {}, [{}]", reg(10..=14), reg(15..=19))),
+                Some(MemoryOp::StoreByteRD) => Ok(format!("STOREB [{}], {}", reg(10..=14), reg(15..=19))),
+                None => Err(format!("Invalid operation code {} for Memory type \
+                                     instruction {:#010x}", op, bits)),
+            }
+        },
+        Some(InstructionT::ALU) => {
+            let op = bits.get_bits(7..=12);
+            let unsigned_rd = |name: &str| format!("{} {}, {}, {}", name, reg(13..=17), reg(18..=22), reg(23..=27));
+            let unsigned_i = |name: &str| format!("{} {}, {}, {}", name, reg(13..=17), reg(18..=22), imm(UNSIGNED_IMMEDIATE));
+            let signed_rd = |name: &str| format!("{} {}, {}, {}", name, reg(14..=18), reg(19..=23), reg(24..=28));
+            let signed_imm = sign_extend(bits.get_bits(SIGNED_IMMEDIATE),
+                                         (SIGNED_IMMEDIATE.end() - SIGNED_IMMEDIATE.start() + 1) as u32);
+            let signed_i = |name: &str| format!("{} {}, {}, #{}", name, reg(14..=18), reg(19..=23), signed_imm);
+            let shift_rd = |name: &str| format!("{} {}, {}", name, reg(13..=17), reg(18..=22));
+            let shift_i = |name: &str| format!("{} {}, {}", name, reg(13..=17), imm(SHIFT_IMMEDIATE));
+
+            match ALUOp::match_val(op) {
+                Some(ALUOp::AddUIRD) => Ok(unsigned_rd("ADDU")),
+                Some(ALUOp::AddUII) => Ok(unsigned_i("ADDUI")),
+                Some(ALUOp::AddSIRD) => Ok(signed_rd("ADDS")),
+                Some(ALUOp::AddSII) => Ok(signed_i("ADDSI")),
+                Some(ALUOp::SubUIRD) => Ok(unsigned_rd("SUBU")),
+                Some(ALUOp::SubUII) => Ok(unsigned_i("SUBUI")),
+                Some(ALUOp::SubSIRD) => Ok(signed_rd("SUBS")),
+                Some(ALUOp::SubSII) => Ok(signed_i("SUBSI")),
+                Some(ALUOp::MulUIRD) => Ok(unsigned_rd("MULU")),
+                Some(ALUOp::MulUII) => Ok(unsigned_i("MULUI")),
+                Some(ALUOp::MulSIRD) => Ok(signed_rd("MULS")),
+                Some(ALUOp::MulSII) => Ok(signed_i("MULSI")),
+                Some(ALUOp::DivUIRD) => Ok(unsigned_rd("DIVU")),
+                Some(ALUOp::DivUII) => Ok(unsigned_i("DIVUI")),
+                Some(ALUOp::DivSIRD) => Ok(signed_rd("DIVS")),
+                Some(ALUOp::DivSII) => Ok(signed_i("DIVSI")),
+                Some(ALUOp::Move) => Ok(format!("MOV {}, {}", reg(13..=17), reg(18..=22))),
+                Some(ALUOp::Comp) => Ok(format!("CMP {}, {}", reg(13..=17), reg(18..=22))),
+                Some(ALUOp::CMov) => Ok(format!("CMOV{} {}, {}", cond, reg(13..=17), reg(18..=22))),
+                Some(ALUOp::ASLRD) => Ok(shift_rd("ASL")),
+                Some(ALUOp::ASLI) => Ok(shift_i("ASLI")),
+                Some(ALUOp::ASRRD) => Ok(shift_rd("ASR")),
+                Some(ALUOp::ASRI) => Ok(shift_i("ASRI")),
+                Some(ALUOp::LSLRD) => Ok(shift_rd("LSL")),
+                Some(ALUOp::LSLI) => Ok(shift_i("LSLI")),
+                Some(ALUOp::LSRRD) => Ok(shift_rd("LSR")),
+                Some(ALUOp::LSRI) => Ok(shift_i("LSRI")),
+                Some(ALUOp::ROLRD) => Ok(shift_rd("ROL")),
+                Some(ALUOp::ROLI) => Ok(shift_i("ROLI")),
+                Some(ALUOp::RORRD) => Ok(shift_rd("ROR")),
+                Some(ALUOp::RORI) => Ok(shift_i("RORI")),
+                Some(ALUOp::AndRD) => Ok(unsigned_rd("AND")),
+                Some(ALUOp::AndI) => Ok(unsigned_i("ANDI")),
+                Some(ALUOp::OrRD) => Ok(unsigned_rd("OR")),
+                Some(ALUOp::OrI) => Ok(unsigned_i("ORI")),
+                Some(ALUOp::XorRD) => Ok(unsigned_rd("XOR")),
+                Some(ALUOp::XorI) => Ok(unsigned_i("XORI")),
+                Some(ALUOp::Not) => Ok(format!("NOT {}, {}", reg(13..=17), reg(18..=22))),
+                Some(ALUOp::ModUIRD) => Ok(unsigned_rd("MODU")),
+                Some(ALUOp::ModUII) => Ok(unsigned_i("MODUI")),
+                Some(ALUOp::ModSIRD) => Ok(signed_rd("MODS")),
+                Some(ALUOp::ModSII) => Ok(signed_i("MODSI")),
+                None => Err(format!("Invalid operation code {} for ALU type \
+                                     instruction {:#010x}", op, bits)),
+            }
+        },
+        Some(InstructionT::Control) => {
+            let op = control_op(bits);
+
+            match ControlOp::match_val(op) {
+                Some(ControlOp::Halt) => Ok("HALT".to_string()),
+                Some(ControlOp::JmpRD) => Ok(format!("JMP{} {}", cond, reg(10..=14))),
+                Some(ControlOp::JmpI) => Ok(format!("JMP{} {}", cond, imm(JUMP_IMMEDIATE))),
+                Some(ControlOp::JmpSRD) => Ok(format!("JMPS{} {}", cond, reg(10..=14))),
+                Some(ControlOp::JmpSI) => Ok(format!("JMPS{} {}", cond, imm(JUMP_IMMEDIATE))),
+                Some(ControlOp::RFI) => Ok("RFI".to_string()),
+                Some(ControlOp::Noop) => Ok("NOOP".to_string()),
+                Some(ControlOp::Enter) => Ok(format!("ENTER {}", imm(ENTER_IMMEDIATE))),
+                Some(ControlOp::Leave) => Ok("LEAVE".to_string()),
+                Some(ControlOp::CacheFlush) => Ok("CFLUSH".to_string()),
+                Some(ControlOp::CacheInvalidate) => Ok("CINVAL".to_string()),
+                Some(ControlOp::Sih) => Ok(format!("SIH {}", imm(HANDLER_IMMEDIATE))),
+                Some(ControlOp::IntRD) => Ok(format!("INT {}", reg(13..=17))),
+                Some(ControlOp::IntI) => Ok(format!("INT {}", imm(INTERRUPT_IMMEDIATE))),
+                None => Err(format!("Invalid operation code {} for Control type \
+                                     instruction {:#010x}", op, bits)),
+            }
+        },
+        Some(InstructionT::Graphics) => Err(format!(
+            "Graphics instruction {:#010x} can not be disassembled", bits)),
+        None => Err(format!("Invalid type {} for instruction {:#010x}", itype, bits)),
+    }
+}
+
+// ---------------------------------- Analysis ----------------------------------
+
+/// Returns the registers an instruction writes and the registers it reads, in
+/// that order. Special purpose registers are indexed by layout. Words which
+/// are not valid instructions use and define nothing.
+pub(crate) fn register_defs_uses(word: u32, layout: &RegisterLayout) -> (Vec<usize>, Vec<usize>) {
+    let (sts, sp, lr, fp) = (layout.sts, layout.sp, layout.lr, layout.fp);
+
+    // Jumps with a condition code read it from the status register
+    let jump_uses = |mut uses: Vec<usize>| {
+        if word.get_bits(0..=4) != 0 {
+            uses.push(sts);
+        }
+        uses
+    };
+    let reg = |bits: std::ops::RangeInclusive<usize>| word.get_bits(bits) as usize;
+
+    match InstructionT::match_val(word.get_bits(5..=6)) {
+        Some(InstructionT::Memory) => match MemoryOp::match_val(word.get_bits(7..=9)) {
+            Some(MemoryOp::LoadRD) => (vec![reg(10..=14)], vec![reg(15..=19)]),
+            Some(MemoryOp::LoadI) => (vec![reg(10..=14)], vec![]),
+            Some(MemoryOp::StoreRD) => (vec![], vec![reg(10..=14), reg(15..=19)]),
+            Some(MemoryOp::StoreI) => (vec![], vec![reg(10..=14)]),
+            Some(MemoryOp::Push) => (vec![sp], vec![reg(11..=15), sp]),
+            Some(MemoryOp::Pop) => (vec![reg(11..=15), sp], vec![sp]),
+            Some(MemoryOp::LoadByteRD) => (vec![reg(10..=14)], vec![reg(15..=19)]),
+            Some(MemoryOp::StoreByteRD) => (vec![], vec![reg(10..=14), reg(15..=19)]),
+            None => (vec![], vec![]),
+        },
+        Some(InstructionT::ALU) => match ALUOp::match_val(word.get_bits(7..=12)) {
+            Some(ALUOp::Move) => (vec![reg(13..=17)], vec![reg(18..=22)]),
+            Some(ALUOp::Not) => (vec![reg(13..=17), sts], vec![reg(18..=22)]),
+            Some(ALUOp::CMov) =>
+                (vec![reg(13..=17)], vec![reg(18..=22), reg(13..=17), sts]),
+            Some(ALUOp::Comp) => (vec![sts], vec![reg(13..=17), reg(18..=22)]),
+            Some(ALUOp::AddSIRD) | Some(ALUOp::SubSIRD) |
+            Some(ALUOp::MulSIRD) | Some(ALUOp::DivSIRD) | Some(ALUOp::ModSIRD) =>
+                (vec![reg(14..=18), sts], vec![reg(19..=23), reg(24..=28)]),
+            Some(ALUOp::AddSII) | Some(ALUOp::SubSII) |
+            Some(ALUOp::MulSII) | Some(ALUOp::DivSII) | Some(ALUOp::ModSII) =>
+                (vec![reg(14..=18), sts], vec![reg(19..=23)]),
+            Some(ALUOp::ASLRD) | Some(ALUOp::ASRRD) |
+            Some(ALUOp::LSLRD) | Some(ALUOp::LSRRD) |
+            Some(ALUOp::ROLRD) | Some(ALUOp::RORRD) =>
+                (vec![reg(13..=17)], vec![reg(13..=17), reg(18..=22)]),
+            Some(ALUOp::ASLI) | Some(ALUOp::ASRI) |
+            Some(ALUOp::LSLI) | Some(ALUOp::LSRI) |
+            Some(ALUOp::ROLI) | Some(ALUOp::RORI) =>
+                (vec![reg(13..=17)], vec![reg(13..=17)]),
+            Some(ALUOp::AddUIRD) | Some(ALUOp::SubUIRD) |
+            Some(ALUOp::MulUIRD) | Some(ALUOp::DivUIRD) | Some(ALUOp::ModUIRD) |
+            Some(ALUOp::AndRD) | Some(ALUOp::OrRD) | Some(ALUOp::XorRD) =>
+                (vec![reg(13..=17), sts], vec![reg(18..=22), reg(23..=27)]),
+            Some(ALUOp::AddUII) | Some(ALUOp::SubUII) |
+            Some(ALUOp::MulUII) | Some(ALUOp::DivUII) | Some(ALUOp::ModUII) |
+            Some(ALUOp::AndI) | Some(ALUOp::OrI) | Some(ALUOp::XorI) =>
+                (vec![reg(13..=17), sts], vec![reg(18..=22)]),
+            None => (vec![], vec![]),
+        },
+        Some(InstructionT::Control) => match ControlOp::match_val(control_op(word)) {
+            Some(ControlOp::JmpRD) => (vec![], jump_uses(vec![reg(10..=14)])),
+            Some(ControlOp::JmpI) => (vec![], jump_uses(vec![])),
+            Some(ControlOp::JmpSRD) => (vec![lr], jump_uses(vec![reg(10..=14)])),
+            Some(ControlOp::JmpSI) => (vec![lr], jump_uses(vec![])),
+            Some(ControlOp::Enter) => (vec![sp, fp], vec![sp, fp]),
+            Some(ControlOp::Leave) => (vec![sp, fp], vec![fp]),
+            Some(ControlOp::Sih) => (vec![layout.ihdlr], vec![]),
+            Some(ControlOp::IntRD) =>
+                (vec![layout.intlr], vec![reg(13..=17), layout.ihdlr]),
+            Some(ControlOp::IntI) => (vec![layout.intlr], vec![layout.ihdlr]),
+            Some(ControlOp::RFI) => (vec![], vec![layout.intlr]),
+            _ => (vec![], vec![]),
+        },
+        _ => (vec![], vec![]),
+    }
+}
+
+/// Computes the instructions per cycle a program could reach with infinite
+/// resources and perfect branch prediction. Each instruction can only run after
+/// the instructions which define the registers it reads, so this is the number
+/// of instructions divided by the length of the longest chain of register
+/// dependencies. Control flow is not followed, words are analyzed in order.
+/// Special purpose registers are indexed by layout.
+pub fn ideal_ipc(words: &[u32], layout: &RegisterLayout) -> f64 {
+    // Length of the dependency chain ending at the last writer of each register
+    let mut reg_depth: HashMap<usize, u64> = HashMap::new();
+    let mut critical_path: u64 = 0;
+
+    for word in words {
+        let (defs, uses) = register_defs_uses(*word, layout);
+
+        let depth = 1 + uses.iter()
+            .filter_map(|r| reg_depth.get(r))
+            .max()
+            .copied()
+            .unwrap_or(0);
+
+        for r in defs {
+            reg_depth.insert(r, depth);
+        }
+
+        critical_path = critical_path.max(depth);
+    }
+
+    if critical_path == 0 {
+        return 0.0;
+    }
+
+    words.len() as f64 / critical_path as f64
+}
+
 // ------------------------------------ Tests ---------------------------------------
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::memory::{DRAM,PC,IHDLR,INTLR,STS};
+    use crate::encoding::{immediate_field,immediate_bits,asm};
     use mockers::Scenario;
     
     /// Ensures that the load instruction functions correctly.
@@ -1425,7 +3060,7 @@ mod tests {
         const MEM_VALUE: u32 = 567;
         
         // Test decode with register direct
-        let mut load_instruction = Load::new(AddrMode::RegisterDirect);
+        let mut load_instruction = Load::new(AddrMode::RegisterDirect, LoadFaultPolicy::Abort);
         let mut INSTRUCTION_RD: u32 = 0;
         INSTRUCTION_RD.set_bits(10..=14, (DEST_REG_IDX as u32).get_bits(0..=4));
         INSTRUCTION_RD.set_bits(15..=19, (ADDR_REG_IDX as u32).get_bits(0..=4));
@@ -1444,7 +3079,7 @@ mod tests {
         INSTRUCTION_I.set_bits(15..=31, ADDR_VAL.get_bits(0..=16)
                                - (regs[PC] + 1));
         
-        load_instruction = Load::new(AddrMode::Immediate);
+        load_instruction = Load::new(AddrMode::Immediate, LoadFaultPolicy::Abort);
 
         assert_eq!(load_instruction.decode(INSTRUCTION_I, &regs),
                    SimResult::Wait(0, ()),
@@ -1454,6 +3089,11 @@ mod tests {
         assert_eq!(load_instruction.mem_addr, ADDR_VAL,
                    "immediate, .mem_addr == expected");
 
+        assert_eq!(load_instruction.operands(),
+                   vec![("dest".to_string(), DEST_REG_IDX as u32),
+                        ("addr".to_string(), ADDR_VAL)],
+                   "immediate, operands() == expected");
+
         // Test execute
         assert_eq!(load_instruction.execute(), SimResult::Wait(0, ()),
                    "execute() == expected");
@@ -1559,8 +3199,28 @@ mod tests {
         assert_eq!(move_instruction.write_back(&mut regs), SimResult::Wait(0, ()), "write_back() == expected");
 
         assert_eq!(regs[DEST], VAL);
+    }
+
+    /// Ensures that a register diff after a move only lists the destination
+    /// register.
+    #[test]
+    fn test_move_register_diff() {
+        let mut regs = Registers::new();
+        regs[4] = 69;
+        regs[PC] = 12;
 
+        let earlier = regs.clone();
+        
+        let mut move_instruction = Move::new();
+        let mut instruction: u32 = 0;
+        instruction.set_bits(18..=22, 4);
+        instruction.set_bits(13..=17, 5);
+
+        move_instruction.decode(instruction, &regs).unwrap("decode");
+        move_instruction.write_back(&mut regs).unwrap("write back");
 
+        assert_eq!(regs.register_diff(&earlier), "    5: 0 -> 69");
+        assert_eq!(earlier.register_diff(&earlier), "");
     }
 
     #[test]
@@ -1673,4 +3333,657 @@ mod tests {
 
         assert_eq!(regs[STS], RESULT);
     }
+
+    /// Ensures jumps to targets which are not aligned to the instruction size
+    /// fail only when alignment is checked.
+    #[test]
+    fn test_jump_alignment() {
+        let mut regs = Registers::new();
+
+        const ADDR_REG: usize = 3;
+        let mut instruction: u32 = 0;
+        instruction.set_bits(10..=14, ADDR_REG as u32);
+
+        for (target, check_alignment, ok) in [(8, true, true), (6, true, false),
+                                              (6, false, true)].iter() {
+            regs[ADDR_REG] = *target;
+            
+            let mut jump = Jump::new(AddrMode::RegisterDirect, false, 4,
+                                     *check_alignment);
+            assert_eq!(jump.decode(instruction, &regs), SimResult::Wait(0, ()),
+                       "decode() == expected");
+            
+            match jump.write_back(&mut regs) {
+                SimResult::Wait(_w, ()) => {
+                    assert!(*ok, "jump to {} succeeded", target);
+                    assert_eq!(regs[PC], *target);
+                },
+                SimResult::Err(_e) => assert!(!*ok, "jump to {} failed", target),
+            }
+        }
+    }
+
+    /// Tests that compressed instructions expand to the equivalent 32-bit
+    /// instructions.
+    #[test]
+    fn test_expand_halfword() {
+        assert_eq!(expand_halfword(CompressedOp::Halt.value()), Ok(0));
+
+        let mut mov: u16 = 0;
+        mov.set_bits(0..=2, CompressedOp::Move.value());
+        mov.set_bits(3..=7, 4);
+        mov.set_bits(8..=12, 9);
+
+        let word = expand_halfword(mov).expect("expand move");
+        assert_eq!(word.get_bits(5..=6), InstructionT::ALU.value());
+        assert_eq!(word.get_bits(7..=12), ALUOp::Move.value());
+        assert_eq!(word.get_bits(13..=17), 4);
+        assert_eq!(word.get_bits(18..=22), 9);
+
+        assert!(expand_halfword(7).is_err());
+    }
+
+    /// Tests that disassemble formats each instruction family and rejects
+    /// invalid words.
+    #[test]
+    fn test_disassemble() {
+        let mut load: u32 = 0;
+        load.set_bits(5..=6, InstructionT::Memory.value());
+        load.set_bits(7..=9, MemoryOp::LoadRD.value());
+        load.set_bits(10..=14, 20);
+        load.set_bits(15..=19, 6);
+        assert_eq!(disassemble(load), Ok("LOAD R20, [R6]".to_string()));
+
+        let mut add: u32 = 0;
+        add.set_bits(5..=6, InstructionT::ALU.value());
+        add.set_bits(7..=12, ALUOp::AddUII.value());
+        add.set_bits(13..=17, 2);
+        add.set_bits(18..=22, 10);
+        add.set_bits(23..=31, 2);
+        assert_eq!(disassemble(add), Ok("ADDUI R2, R10, #2".to_string()));
+
+        let mut jump: u32 = 0;
+        jump.set_bits(0..=4, ConditionCodes::LT.value());
+        jump.set_bits(5..=6, InstructionT::Control.value());
+        set_control_op(&mut jump, ControlOp::JmpRD.value());
+        jump.set_bits(10..=14, 3);
+        assert_eq!(disassemble(jump), Ok("JMPLT R3".to_string()));
+
+        assert_eq!(disassemble(0), Ok("HALT".to_string()));
+
+        let mut invalid: u32 = 0;
+        invalid.set_bits(5..=6, InstructionT::Control.value());
+        set_control_op(&mut invalid, 15);
+        assert!(disassemble(invalid).is_err());
+    }
+
+    /// Tests that ideal_ipc is 1 for a chain of dependent instructions and
+    /// higher for independent instructions.
+    #[test]
+    fn test_ideal_ipc() {
+        /// Encodes R[dest] = R[src] + 1.
+        fn add_imm(dest: u32, src: u32) -> u32 {
+            let mut instruction: u32 = 0;
+            instruction.set_bits(5..=6, InstructionT::ALU.value());
+            instruction.set_bits(7..=12, ALUOp::AddUII.value());
+            instruction.set_bits(13..=17, dest);
+            instruction.set_bits(18..=22, src);
+            instruction.set_bits(23..=31, 1);
+            instruction
+        }
+
+        let layout = RegisterLayout::new();
+        let chain = vec![add_imm(1, 1), add_imm(1, 1), add_imm(1, 1),
+                         add_imm(1, 1)];
+        assert!((ideal_ipc(&chain, &layout) - 1.0).abs() < 1e-9,
+                "dependent chain ideal ipc: {}", ideal_ipc(&chain, &layout));
+
+        let independent = vec![add_imm(1, 0), add_imm(2, 0), add_imm(3, 0),
+                               add_imm(4, 0)];
+        assert!((ideal_ipc(&independent, &layout) - 4.0).abs() < 1e-9,
+                "independent ideal ipc: {}", ideal_ipc(&independent, &layout));
+
+        // Two independent chains of 2
+        let two_chains = vec![add_imm(1, 0), add_imm(2, 0), add_imm(1, 1),
+                              add_imm(2, 2)];
+        assert!((ideal_ipc(&two_chains, &layout) - 2.0).abs() < 1e-9,
+                "two chains ideal ipc: {}", ideal_ipc(&two_chains, &layout));
+    }
+
+    /// Tests that register_defs_uses and the stack instructions take the stack
+    /// pointer's index from the register layout.
+    #[test]
+    fn test_stack_registers_follow_layout() {
+        const LAYOUT_SP: usize = 20;
+
+        let mut layout = RegisterLayout::new();
+        layout.sp = LAYOUT_SP;
+
+        assert_eq!(register_defs_uses(asm::push(1), &layout),
+                   (vec![LAYOUT_SP], vec![1, LAYOUT_SP]));
+        assert_eq!(register_defs_uses(asm::pop(2), &layout),
+                   (vec![2, LAYOUT_SP], vec![LAYOUT_SP]));
+
+        let mut regs = Registers::new_with_layout(layout);
+        regs[LAYOUT_SP] = 1000;
+
+        let mut push = Push::new(true);
+        assert_eq!(push.decode(asm::push(1), &regs), SimResult::Wait(0, ()));
+        assert_eq!(push.src_regs(), vec![1, LAYOUT_SP]);
+        assert_eq!(push.pending_result(), Some((LAYOUT_SP, 999)));
+
+        let mut pop = Pop::new(true);
+        assert_eq!(pop.decode(asm::pop(2), &regs), SimResult::Wait(0, ()));
+        assert_eq!(pop.src_regs(), vec![LAYOUT_SP]);
+    }
+
+    /// Tests that register_defs_uses lists the special purpose registers
+    /// flag setting, conditional and interrupt instructions define and use.
+    #[test]
+    fn test_register_defs_uses_special_registers() {
+        let layout = RegisterLayout::new();
+
+        assert_eq!(register_defs_uses(asm::cmp(1, 2), &layout),
+                   (vec![STS], vec![1, 2]));
+        assert_eq!(register_defs_uses(asm::add_ui_i(1, 2, 3), &layout),
+                   (vec![1, STS], vec![2]));
+        assert_eq!(register_defs_uses(asm::mov(1, 2), &layout),
+                   (vec![1], vec![2]));
+        assert_eq!(register_defs_uses(asm::jmp_i(8), &layout),
+                   (vec![], vec![]));
+        assert_eq!(register_defs_uses(asm::jmp_lt_i(8), &layout),
+                   (vec![], vec![STS]));
+        assert_eq!(register_defs_uses(asm::sih(8), &layout),
+                   (vec![IHDLR], vec![]));
+        assert_eq!(register_defs_uses(asm::int_rd(4), &layout),
+                   (vec![INTLR], vec![4, IHDLR]));
+        assert_eq!(register_defs_uses(asm::int_i(3), &layout),
+                   (vec![INTLR], vec![IHDLR]));
+        assert_eq!(register_defs_uses(asm::rfi(), &layout),
+                   (vec![], vec![INTLR]));
+
+        // A conditional jump depends on the compare before it
+        let words = vec![asm::cmp(1, 2), asm::jmp_lt_i(8)];
+        assert!((ideal_ipc(&words, &layout) - 1.0).abs() < 1e-9,
+                "compare and jump ideal ipc: {}", ideal_ipc(&words, &layout));
+    }
+
+    /// Tests that INT is dropped when no handler is set or an interrupt is
+    /// already being handled, and taken otherwise.
+    #[test]
+    fn test_int_proceed() {
+        const HANDLER: u32 = 40;
+        const INT_PC: u32 = 6;
+        const RET_PC: u32 = INT_PC + 1;
+        
+        let mut instruction: u32 = 0;
+        instruction.set_bits(INTERRUPT_IMMEDIATE, InterruptCodes::ENTER.value() as u32);
+
+        // Handler unset
+        let mut regs = Registers::new();
+        regs[IHDLR] = InterruptCodes::NOT_SET_INITIAL.value() as u32;
+        regs[PC] = INT_PC;
+
+        let mut int = INT::new(AddrMode::Immediate, 1);
+        int.decode(instruction, &regs).unwrap("decode unset");
+        int.write_back(&mut regs).unwrap("write back unset");
+        assert_eq!(regs[PC], INT_PC, "unset handler, PC unchanged");
+        assert!(!regs.interrupt_active, "unset handler, not handling");
+
+        // Handler set
+        regs[IHDLR] = HANDLER;
+
+        let mut int = INT::new(AddrMode::Immediate, 1);
+        int.decode(instruction, &regs).unwrap("decode set");
+        int.write_back(&mut regs).unwrap("write back set");
+        assert_eq!(regs[PC], HANDLER, "set handler, jumped to handler");
+        assert_eq!(regs[INTLR], RET_PC, "set handler, return address saved");
+        assert!(regs.interrupt_active, "set handler, handling");
+
+        // Already handling an interrupt
+        regs[PC] = RET_PC + 1;
+        
+        let mut int = INT::new(AddrMode::Immediate, 1);
+        int.decode(instruction, &regs).unwrap("decode nested");
+        int.write_back(&mut regs).unwrap("write back nested");
+        assert_eq!(regs[PC], RET_PC + 1, "nested interrupt, PC unchanged");
+        assert_eq!(regs[INTLR], RET_PC, "nested interrupt, INTLR unchanged");
+    }
+
+    /// Tests that a decoded PC relative load reports its effective address.
+    #[test]
+    fn test_effective_address() {
+        const DEST: u32 = 4;
+        const OFFSET: u32 = 20;
+        
+        let mut regs = Registers::new();
+        regs[PC] = 100;
+
+        let mut instruction: u32 = 0;
+        instruction.set_bits(10..=14, DEST);
+        instruction.set_bits(15..=31, OFFSET);
+
+        let mut load = Load::new(AddrMode::Immediate, LoadFaultPolicy::Abort);
+        load.decode(instruction, &regs).unwrap("decode load");
+        assert_eq!(load.effective_address(), Some(100 + 1 + OFFSET));
+
+        assert_eq!(Noop::new().effective_address(), None);
+    }
+
+    /// Tests that each instruction with an immediate decodes every bit of the
+    /// field declared for it, and no more.
+    #[test]
+    fn test_immediate_decode_width() {
+        let mut regs = Registers::new();
+        regs[SP] = 1000;
+
+        // Returns a word with the immediate field set to all ones and the
+        // largest value the field holds
+        let all_ones = |itype: InstructionT, op: u32| -> (u32, u32) {
+            let mut word: u32 = 0;
+            match &itype {
+                InstructionT::Memory => {
+                    word.set_bits(7..=9, op);
+                },
+                InstructionT::Control => set_control_op(&mut word, op),
+                _ => {
+                    word.set_bits(7..=12, op);
+                },
+            };
+            word.set_bits(5..=6, itype.value());
+            
+            let field = immediate_field(word).expect("instruction has no immediate");
+            let max = ((1u64 << immediate_bits(word)) - 1) as u32;
+            word.set_bits(field, max);
+
+            (word, max)
+        };
+
+        let (word, max) = all_ones(InstructionT::Memory, MemoryOp::LoadI.value());
+        let mut load = Load::new(AddrMode::Immediate, LoadFaultPolicy::Abort);
+        load.decode(word, &regs).unwrap("decode load");
+        assert_eq!(load.mem_addr, max + 1, "load");
+
+        let (word, max) = all_ones(InstructionT::Memory, MemoryOp::StoreI.value());
+        let mut store = Store::new(AddrMode::Immediate);
+        store.decode(word, &regs).unwrap("decode store");
+        assert_eq!(store.value, max + 1, "store");
+
+        let (word, max) = all_ones(InstructionT::ALU, ALUOp::AddUII.value());
+        let mut add_u = ArithUnsign::new(AddrMode::Immediate, ArithMode::Add);
+        add_u.decode(word, &regs).unwrap("decode unsigned add");
+        assert_eq!(add_u.op2, max, "unsigned add");
+
+        let (word, _max) = all_ones(InstructionT::ALU, ALUOp::AddSII.value());
+        let mut add_s = ArithSign::new(AddrMode::Immediate, ArithMode::Add);
+        add_s.decode(word, &regs).unwrap("decode signed add");
+        assert_eq!(add_s.op2, -1, "signed add, all ones sign extended");
+
+        let (word, max) = all_ones(InstructionT::ALU, ALUOp::ASLI.value());
+        let mut asl = AS::new(AddrMode::Immediate, false);
+        asl.decode(word, &regs).unwrap("decode arithmetic shift");
+        assert_eq!(asl.amount, max, "arithmetic shift");
+
+        let (word, max) = all_ones(InstructionT::ALU, ALUOp::LSLI.value());
+        let mut lsl = LS::new(AddrMode::Immediate, false);
+        lsl.decode(word, &regs).unwrap("decode logical shift");
+        assert_eq!(lsl.amount, max, "logical shift");
+
+        let (word, max) = all_ones(InstructionT::ALU, ALUOp::AndI.value());
+        let mut and = ThreeOpLogic::new(AddrMode::Immediate, LogicType::And);
+        and.decode(word, &regs).unwrap("decode and");
+        assert_eq!(and.op2, max, "and");
+
+        let (word, max) = all_ones(InstructionT::Control, ControlOp::JmpI.value());
+        let mut jump = Jump::new(AddrMode::Immediate, false, 1, false);
+        jump.decode(word, &regs).unwrap("decode jump");
+        assert_eq!(jump.addr, max, "jump");
+
+        let (word, max) = all_ones(InstructionT::Control, ControlOp::Enter.value());
+        let mut enter = Enter::new(true);
+        enter.decode(word, &regs).unwrap("decode enter");
+        assert_eq!(enter.locals, max, "enter");
+    }
+
+    /// Tests that a byte load zero extends the addressed byte and that a byte
+    /// store only changes the addressed byte of its word.
+    #[test]
+    fn test_load_store_byte() {
+        let memory: Rc<RefCell<dyn Memory<u32, u32>>> = Rc::new(RefCell::new(DRAM::new(1)));
+        memory.borrow_mut().set(10, 0x44332211).unwrap("set word");
+
+        let mut regs = Registers::new();
+        regs[1] = 0xFFFFFFAB;
+        regs[2] = 10 * 4 + 2;
+
+        // LOADB R3, [R2]
+        let mut load_bits: u32 = 0;
+        load_bits.set_bits(10..=14, 3);
+        load_bits.set_bits(15..=19, 2);
+
+        let mut load = LoadByte::new();
+        load.decode(load_bits, &regs).unwrap("decode load");
+        assert_eq!(load.effective_address(), Some(10));
+        assert_eq!(load.access_memory(memory.clone()), SimResult::Wait(1, ()));
+        load.write_back(&mut regs).unwrap("write back load");
+        assert_eq!(regs[3], 0x33);
+
+        // STOREB [R2], R1
+        let mut store_bits: u32 = 0;
+        store_bits.set_bits(10..=14, 2);
+        store_bits.set_bits(15..=19, 1);
+
+        let mut store = StoreByte::new();
+        store.decode(store_bits, &regs).unwrap("decode store");
+        assert_eq!(store.access_memory(memory.clone()), SimResult::Wait(2, ()));
+        assert_eq!(memory.borrow_mut().get(10), SimResult::Wait(1, 0x44AB2211));
+
+        // The most significant byte
+        regs[2] = 10 * 4 + 3;
+        store.decode(store_bits, &regs).unwrap("decode store");
+        store.access_memory(memory.clone()).unwrap("access memory");
+        assert_eq!(memory.borrow_mut().get(10), SimResult::Wait(1, 0xABAB2211));
+
+        assert_eq!(disassemble(MemoryOp::LoadByteRD.value() << 7 |
+                               InstructionT::Memory.value() << 5 | load_bits),
+                   Ok("LOADB R3, [R2]".to_string()));
+    }
+
+    /// Tests that signed and unsigned division by zero return an error.
+    #[test]
+    fn test_divide_by_zero() {
+        let mut regs = Registers::new();
+        regs[1] = 7;
+
+        // R3 = R1 / R2, R2 is 0
+        let mut signed_bits: u32 = 0;
+        signed_bits.set_bits(14..=18, 3);
+        signed_bits.set_bits(19..=23, 1);
+        signed_bits.set_bits(24..=28, 2);
+
+        let mut signed = ArithSign::new(AddrMode::RegisterDirect, ArithMode::Div);
+        signed.decode(signed_bits, &regs).unwrap("decode signed");
+        assert_eq!(signed.execute(), SimResult::Err("division by zero".to_string()));
+
+        let mut unsigned_bits: u32 = 0;
+        unsigned_bits.set_bits(13..=17, 3);
+        unsigned_bits.set_bits(18..=22, 1);
+        unsigned_bits.set_bits(23..=27, 2);
+
+        let mut unsigned = ArithUnsign::new(AddrMode::RegisterDirect, ArithMode::Div);
+        unsigned.decode(unsigned_bits, &regs).unwrap("decode unsigned");
+        assert_eq!(unsigned.execute(), SimResult::Err("division by zero".to_string()));
+    }
+
+    /// Tests that results which overflow wrap and set the carry or signed
+    /// overflow condition code, and that other results do not.
+    #[test]
+    fn test_arith_overflow() {
+        let mut regs = Registers::new();
+        regs[STS] = ConditionCodes::NS.value();
+
+        // R3 = R1 + R2
+        let mut unsigned_bits: u32 = 0;
+        unsigned_bits.set_bits(13..=17, 3);
+        unsigned_bits.set_bits(18..=22, 1);
+        unsigned_bits.set_bits(23..=27, 2);
+
+        let mut signed_bits: u32 = 0;
+        signed_bits.set_bits(14..=18, 3);
+        signed_bits.set_bits(19..=23, 1);
+        signed_bits.set_bits(24..=28, 2);
+
+        let cases: Vec<(bool, ArithMode, u32, u32, u32, bool)> = vec![
+            (false, ArithMode::Add, 0xFFFFFFFF, 1, 0, true),
+            (false, ArithMode::Sub, 0, 1, 0xFFFFFFFF, true),
+            (false, ArithMode::Mul, 0x10000, 0x10000, 0, true),
+            (false, ArithMode::Add, 2, 3, 5, false),
+            (true, ArithMode::Add, i32::MAX as u32, 1, i32::MIN as u32, true),
+            (true, ArithMode::Sub, i32::MIN as u32, 1, i32::MAX as u32, true),
+            (true, ArithMode::Div, i32::MIN as u32, -1i32 as u32, i32::MIN as u32, true),
+            (true, ArithMode::Mod, i32::MIN as u32, -1i32 as u32, 0, false),
+            (true, ArithMode::Add, -2i32 as u32, 1, -1i32 as u32, false),
+        ];
+
+        for (signed, mode, op1, op2, result, overflowed) in cases {
+            let desc = format!("signed={} {:?} {:#x} {:#x}", signed, mode, op1, op2);
+            let mut regs = regs.clone();
+            regs[1] = op1;
+            regs[2] = op2;
+
+            let mut inst: Box<dyn Instruction> = match signed {
+                true => Box::new(ArithSign::new(AddrMode::RegisterDirect, mode)),
+                false => Box::new(ArithUnsign::new(AddrMode::RegisterDirect, mode)),
+            };
+            let bits = match signed {
+                true => signed_bits,
+                false => unsigned_bits,
+            };
+            inst.decode(bits, &regs).unwrap("decode");
+            inst.execute().unwrap("execute");
+            inst.write_back(&mut regs).unwrap("write back");
+
+            let overflow_code = match signed {
+                true => ConditionCodes::V.value(),
+                false => ConditionCodes::C.value(),
+            };
+            assert_eq!(regs[3], result, "{}", desc);
+            assert_eq!(regs[STS] == overflow_code, overflowed, "{}", desc);
+        }
+    }
+
+    /// Tests that arithmetic right shifts copy the sign bit, logical right
+    /// shifts fill with zeros and both left shifts fill with zeros.
+    #[test]
+    fn test_shift_sign() {
+        let mut regs = Registers::new();
+
+        // R1 = R1 shifted by 1
+        let mut bits: u32 = 0;
+        bits.set_bits(13..=17, 1);
+        bits.set_bits(SHIFT_IMMEDIATE, 1);
+
+        let cases: Vec<(Box<dyn Instruction>, u32, u32)> = vec![
+            (Box::new(AS::new(AddrMode::Immediate, true)), 0x80000000, 0xC0000000),
+            (Box::new(LS::new(AddrMode::Immediate, true)), 0x80000000, 0x40000000),
+            (Box::new(AS::new(AddrMode::Immediate, true)), 0x40000000, 0x20000000),
+            (Box::new(AS::new(AddrMode::Immediate, false)), 0xC0000001, 0x80000002),
+            (Box::new(LS::new(AddrMode::Immediate, false)), 0xC0000001, 0x80000002),
+        ];
+
+        for (mut inst, value, expected) in cases {
+            regs[1] = value;
+            inst.decode(bits, &regs).unwrap("decode");
+            inst.execute().unwrap("execute");
+            inst.write_back(&mut regs).unwrap("write back");
+
+            assert_eq!(regs[1], expected, "{} of {:#x}", inst, value);
+        }
+    }
+
+    /// Tests the remainder instructions. Signed remainders truncate like
+    /// Rust's %, so the result has the sign of the dividend.
+    #[test]
+    fn test_mod() {
+        // R3 = R1 mod R2
+        let mut unsigned_bits: u32 = 0;
+        unsigned_bits.set_bits(13..=17, 3);
+        unsigned_bits.set_bits(18..=22, 1);
+        unsigned_bits.set_bits(23..=27, 2);
+
+        let mut signed_bits: u32 = 0;
+        signed_bits.set_bits(14..=18, 3);
+        signed_bits.set_bits(19..=23, 1);
+        signed_bits.set_bits(24..=28, 2);
+
+        let cases: Vec<(bool, i32, i32, i32)> = vec![
+            (false, 7, 3, 1),
+            (false, -1, 10, 5),
+            (true, 7, 3, 1),
+            (true, -7, 3, -1),
+            (true, 7, -3, 1),
+            (true, -7, -3, -1),
+        ];
+
+        for (signed, op1, op2, result) in cases {
+            let mut regs = Registers::new();
+            regs[1] = op1 as u32;
+            regs[2] = op2 as u32;
+
+            let (mut inst, bits): (Box<dyn Instruction>, u32) = match signed {
+                true => (Box::new(ArithSign::new(AddrMode::RegisterDirect, ArithMode::Mod)), signed_bits),
+                false => (Box::new(ArithUnsign::new(AddrMode::RegisterDirect, ArithMode::Mod)), unsigned_bits),
+            };
+            inst.decode(bits, &regs).unwrap("decode");
+            inst.execute().unwrap("execute");
+            inst.write_back(&mut regs).unwrap("write back");
+
+            assert_eq!(regs[3] as i32, result, "signed={} {} mod {}", signed, op1, op2);
+        }
+
+        let mut regs = Registers::new();
+        regs[1] = 7;
+        let mut inst = ArithSign::new(AddrMode::RegisterDirect, ArithMode::Mod);
+        inst.decode(signed_bits, &regs).unwrap("decode");
+        assert_eq!(inst.execute(), SimResult::Err("division by zero".to_string()));
+    }
+
+    /// Tests that rotates wrap bits around, and that the amount is taken
+    /// modulo 32 so 0 and 32 leave the value alone.
+    #[test]
+    fn test_rotate() {
+        // R1 = R1 rotated by R2
+        let mut bits: u32 = 0;
+        bits.set_bits(13..=17, 1);
+        bits.set_bits(18..=22, 2);
+
+        let cases: Vec<(bool, u32, u32, u32)> = vec![
+            (false, 0x80000001, 1, 0x00000003),
+            (true, 0x80000001, 1, 0xC0000000),
+            (false, 0x12345678, 0, 0x12345678),
+            (true, 0x12345678, 0, 0x12345678),
+            (false, 0x12345678, 32, 0x12345678),
+            (true, 0x12345678, 32, 0x12345678),
+            (false, 0x12345678, 36, 0x23456781),
+            (true, 0x12345678, 68, 0x81234567),
+        ];
+
+        for (direction, value, amount, expected) in cases {
+            let mut regs = Registers::new();
+            regs[1] = value;
+            regs[2] = amount;
+
+            let mut inst = Rotate::new(AddrMode::RegisterDirect, direction);
+            inst.decode(bits, &regs).unwrap("decode");
+            inst.execute().unwrap("execute");
+            inst.write_back(&mut regs).unwrap("write back");
+
+            assert_eq!(regs[1], expected, "right={} {:#x} by {}", direction, value, amount);
+        }
+    }
+
+    /// Tests the condition codes ALU results set in STS.
+    #[test]
+    fn test_result_flags() {
+        assert_eq!(flags_from_result(0), ConditionCodes::Z.value());
+        assert_eq!(flags_from_result(1), ConditionCodes::POS.value());
+        assert_eq!(flags_from_result(0x7FFFFFFF), ConditionCodes::POS.value());
+        assert_eq!(flags_from_result(0x80000000), ConditionCodes::NEG.value());
+        assert_eq!(flags_from_result(-1i32 as u32), ConditionCodes::NEG.value());
+
+        // R3 = R1 op R2
+        let mut bits: u32 = 0;
+        bits.set_bits(13..=17, 3);
+        bits.set_bits(18..=22, 1);
+        bits.set_bits(23..=27, 2);
+
+        let cases: Vec<(Box<dyn Instruction>, u32, u32, ConditionCodes)> = vec![
+            (Box::new(ArithUnsign::new(AddrMode::RegisterDirect, ArithMode::Sub)), 5, 5, ConditionCodes::Z),
+            (Box::new(ArithUnsign::new(AddrMode::RegisterDirect, ArithMode::Add)), 5, 5, ConditionCodes::POS),
+            (Box::new(ThreeOpLogic::new(AddrMode::RegisterDirect, LogicType::And)), 0xF0, 0x0F, ConditionCodes::Z),
+            (Box::new(ThreeOpLogic::new(AddrMode::RegisterDirect, LogicType::Or)), 0x80000000, 1, ConditionCodes::NEG),
+            (Box::new(Not::new()), 0, 0, ConditionCodes::NEG),
+            (Box::new(Not::new()), 0xFFFFFFFF, 0, ConditionCodes::Z),
+        ];
+
+        for (mut inst, op1, op2, flag) in cases {
+            let mut regs = Registers::new();
+            regs[1] = op1;
+            regs[2] = op2;
+
+            inst.decode(bits, &regs).unwrap("decode");
+            inst.execute().unwrap("execute");
+            inst.write_back(&mut regs).unwrap("write back");
+
+            assert_eq!(regs[STS], flag.value(), "{} of {:#x} and {:#x}", inst, op1, op2);
+        }
+    }
+
+    /// Tests that signed immediates are sign extended, so adding a negative
+    /// immediate subtracts, and that unsigned immediates are not.
+    #[test]
+    fn test_signed_immediate_sign_extend() {
+        let mut regs = Registers::new();
+        regs[1] = 10;
+
+        // R3 = R1 + 0xFF
+        let mut add_s_bits: u32 = 0;
+        add_s_bits.set_bits(14..=18, 3);
+        add_s_bits.set_bits(19..=23, 1);
+        add_s_bits.set_bits(SIGNED_IMMEDIATE, 0xFF);
+
+        let mut add_s = ArithSign::new(AddrMode::Immediate, ArithMode::Add);
+        add_s.decode(add_s_bits, &regs).unwrap("decode signed add");
+        assert_eq!(add_s.op2, -1);
+        add_s.execute().unwrap("execute signed add");
+        add_s.write_back(&mut regs).unwrap("write back signed add");
+        assert_eq!(regs[3], 9);
+
+        let mut add_u_bits: u32 = 0;
+        add_u_bits.set_bits(13..=17, 3);
+        add_u_bits.set_bits(18..=22, 1);
+        add_u_bits.set_bits(UNSIGNED_IMMEDIATE, 0xFF);
+
+        let mut add_u = ArithUnsign::new(AddrMode::Immediate, ArithMode::Add);
+        add_u.decode(add_u_bits, &regs).unwrap("decode unsigned add");
+        assert_eq!(add_u.op2, 0xFF);
+    }
+
+    /// Tests that an unsigned add which carries out of 32 bits sets C, and
+    /// that the same add read as signed, -1 + 1, sets neither C nor V.
+    #[test]
+    fn test_carry_without_signed_overflow() {
+        let mut regs = Registers::new();
+        regs[1] = 0xFFFFFFFF;
+        regs[2] = 1;
+
+        // R3 = R1 + R2
+        let mut unsigned_bits: u32 = 0;
+        unsigned_bits.set_bits(13..=17, 3);
+        unsigned_bits.set_bits(18..=22, 1);
+        unsigned_bits.set_bits(23..=27, 2);
+
+        let mut signed_bits: u32 = 0;
+        signed_bits.set_bits(14..=18, 3);
+        signed_bits.set_bits(19..=23, 1);
+        signed_bits.set_bits(24..=28, 2);
+
+        let mut unsigned_regs = regs.clone();
+        let mut add = ArithUnsign::new(AddrMode::RegisterDirect, ArithMode::Add);
+        add.decode(unsigned_bits, &unsigned_regs).unwrap("decode unsigned");
+        add.execute().unwrap("execute unsigned");
+        add.write_back(&mut unsigned_regs).unwrap("write back unsigned");
+
+        assert_eq!(unsigned_regs[3], 0);
+        assert_eq!(unsigned_regs[STS], ConditionCodes::C.value());
+
+        let mut signed_regs = regs.clone();
+        let mut add = ArithSign::new(AddrMode::RegisterDirect, ArithMode::Add);
+        add.decode(signed_bits, &signed_regs).unwrap("decode signed");
+        add.execute().unwrap("execute signed");
+        add.write_back(&mut signed_regs).unwrap("write back signed");
+
+        assert_eq!(signed_regs[3], 0);
+        assert_eq!(signed_regs[STS], ConditionCodes::Z.value());
+    }
 }
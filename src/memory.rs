@@ -2,14 +2,14 @@
 
 use web_sys::console;
 use wasm_bindgen::JsValue;
-use bit_field::BitField;
+use serde_derive::{Serialize,Deserialize};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap,HashMap,VecDeque};
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::rc::{Rc,Weak};
 use std::ops::{Index,IndexMut};
 use std::io::{Read,BufReader};
-use std::fs::File;
+use std::fs::{self,File};
 use std::fmt;
 
 use crate::result::SimResult;
@@ -26,10 +26,20 @@ const REGISTERS_SIZE: usize = 32;
 /// - 29: Status
 /// - 30: Stack pointer
 /// - 31: Subroutine link return address
+///
+/// The special purpose register indexes can be changed with a RegisterLayout.
 #[derive(Clone,Debug,PartialEq)]
 pub struct Registers {
     /// Holds register values
     pub file: [u32; REGISTERS_SIZE],
+
+    /// Indexes of the special purpose registers
+    pub layout: RegisterLayout,
+
+    /// True while an interrupt is being handled, from taking the interrupt
+    /// until RFI. Kept out of STS, which ALU instructions overwrite with
+    /// condition codes.
+    pub interrupt_active: bool,
 }
 
 /// Interupt link register index
@@ -50,6 +60,78 @@ pub const SP: usize = 30;
 /// Link register index
 pub const LR: usize = 31;
 
+/// Frame pointer register index. This is a general purpose register which is
+/// used as the frame pointer by convention, see the Enter and Leave
+/// instructions.
+pub const FP: usize = 25;
+
+
+/// Special purpose registers. Indexing Registers with one of these looks up
+/// the register's index in the register file's layout.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum SpecialRegister {
+    INTLR,
+    IHDLR,
+    PC,
+    STS,
+    SP,
+    LR,
+    FP,
+}
+
+/// Indexes of the special purpose registers in the register file.
+#[derive(Serialize,Deserialize,Copy,Clone,Debug,PartialEq)]
+pub struct RegisterLayout {
+    /// Interrupt link register index
+    pub intlr: usize,
+
+    /// Interrupt handler register index
+    pub ihdlr: usize,
+
+    /// Program counter register index
+    pub pc: usize,
+
+    /// Status register index
+    pub sts: usize,
+
+    /// Stack pointer register index
+    pub sp: usize,
+
+    /// Link register index
+    pub lr: usize,
+
+    /// Frame pointer register index
+    pub fp: usize,
+}
+
+impl RegisterLayout {
+    /// Creates the default layout, which uses the INTLR, IHDLR, PC, STS, SP,
+    /// LR and FP constants.
+    pub fn new() -> RegisterLayout {
+        RegisterLayout{
+            intlr: INTLR,
+            ihdlr: IHDLR,
+            pc: PC,
+            sts: STS,
+            sp: SP,
+            lr: LR,
+            fp: FP,
+        }
+    }
+
+    /// Returns the index of a special purpose register.
+    pub fn index(&self, reg: SpecialRegister) -> usize {
+        match reg {
+            SpecialRegister::INTLR => self.intlr,
+            SpecialRegister::IHDLR => self.ihdlr,
+            SpecialRegister::PC => self.pc,
+            SpecialRegister::STS => self.sts,
+            SpecialRegister::SP => self.sp,
+            SpecialRegister::LR => self.lr,
+            SpecialRegister::FP => self.fp,
+        }
+    }
+}
 
 /// Start of the program memory
 // pub struct Memory_Start {
@@ -66,27 +148,139 @@ pub const LR: usize = 31;
 // }
 
 impl Registers {
+    /// Creates a register file with every register set to 0.
     pub fn new() -> Registers {
+        Registers::new_with_layout(RegisterLayout::new())
+    }
+
+    /// Creates a register file like Registers::new() where the special purpose
+    /// registers are at the indexes given by layout.
+    pub fn new_with_layout(layout: RegisterLayout) -> Registers {
         Registers{
             file: [0; REGISTERS_SIZE],
+            layout,
+            interrupt_active: false,
+        }
+    }
+
+    /// Creates a register file like Registers::new() where every general
+    /// purpose register holds value. Useful to make reads of registers which
+    /// were never written stand out.
+    pub fn new_with_fill(value: u32) -> Registers {
+        let mut registers = Registers::new();
+        registers.fill_general_purpose(value);
+
+        registers
+    }
+
+    /// Sets every general purpose register to value. Special purpose registers
+    /// are not changed.
+    pub fn fill_general_purpose(&mut self, value: u32) {
+        for i in 0..REGISTERS_SIZE {
+            if self.special_name(i).is_none() {
+                self.file[i] = value;
+            }
+        }
+    }
+
+    /// Returns the name of a special purpose register. None if the index is a
+    /// general purpose register. The frame pointer is general purpose, so it
+    /// has no name.
+    pub fn special_name(&self, idx: usize) -> Option<&'static str> {
+        let l = &self.layout;
+        
+        if idx == l.intlr {
+            Some("INTLR")
+        } else if idx == l.ihdlr {
+            Some("IHDLR")
+        } else if idx == l.pc {
+            Some("PC")
+        } else if idx == l.sts {
+            Some("STS")
+        } else if idx == l.sp {
+            Some("SP")
+        } else if idx == l.lr {
+            Some("LR")
+        } else {
+            None
+        }
+    }
+
+    /// Returns a table of the registers which have a different value than in
+    /// an earlier copy of the registers. Each line is formatted as
+    /// "<register>: <earlier value> -> <current value>".
+    pub fn register_diff(&self, earlier: &Registers) -> String {
+        let mut lines: Vec<String> = Vec::new();
+
+        for i in 0..REGISTERS_SIZE {
+            if self.file[i] == earlier.file[i] {
+                continue;
+            }
+            
+            let key = match self.special_name(i) {
+                Some(n) => format!("{:5}", n),
+                None => format!("{:5}", i),
+            };
+
+            lines.push(format!("{}: {} -> {}", key, earlier.file[i],
+                               self.file[i]));
         }
+
+        lines.join("\n")
+    }
+
+    /// Returns the register file as a JSON object. Keys are register names,
+    /// general purpose registers are named R<index>.
+    pub fn to_json(&self) -> String {
+        let named = NamedRegisters{
+            intlr: self[SpecialRegister::INTLR],
+            ihdlr: self[SpecialRegister::IHDLR],
+            pc: self[SpecialRegister::PC],
+            sts: self[SpecialRegister::STS],
+            sp: self[SpecialRegister::SP],
+            lr: self[SpecialRegister::LR],
+            general: (0..REGISTERS_SIZE)
+                .filter(|i| self.special_name(*i).is_none())
+                .map(|i| (format!("R{}", i), self.file[i]))
+                .collect(),
+        };
+
+        serde_json::to_string(&named).expect("registers always serialize")
     }
 }
 
+/// Register file as serialized by Registers::to_json().
+#[derive(Serialize)]
+struct NamedRegisters {
+    #[serde(rename = "INTLR")]
+    intlr: u32,
+
+    #[serde(rename = "IHDLR")]
+    ihdlr: u32,
+
+    #[serde(rename = "PC")]
+    pc: u32,
+
+    #[serde(rename = "STS")]
+    sts: u32,
+
+    #[serde(rename = "SP")]
+    sp: u32,
+
+    #[serde(rename = "LR")]
+    lr: u32,
+
+    /// General purpose registers by name.
+    #[serde(flatten)]
+    general: BTreeMap<String, u32>,
+}
+
 impl fmt::Display for Registers {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut out = String::new();
         
         for i in 0..REGISTERS_SIZE {
-            let key = match i {
-                INTLR => "INTLR",
-                IHDLR => "IHDLR",
-                PC => "PC",
-                STS => "STS",
-                SP => "SP",
-                LR => "LR",
-                _ => "",
-            };
+            let key = self.special_name(i).unwrap_or("");
             if key.len() == 0 {
                 out.push_str(format!("{:5}", i).as_str());
             } else {
@@ -118,6 +312,20 @@ impl IndexMut<usize> for Registers {
     }
 }
 
+impl Index<SpecialRegister> for Registers {
+    type Output = u32;
+    
+    fn index(&self, reg: SpecialRegister) -> &u32 {
+        &self.file[self.layout.index(reg)]
+    }
+}
+
+impl IndexMut<SpecialRegister> for Registers {
+    fn index_mut(&mut self, reg: SpecialRegister) -> &mut u32 {
+        &mut self.file[self.layout.index(reg)]
+    }
+}
+
 /// Memory provides an interface to access a memory struct, A is the address type,
 /// D is the data type.
 #[cfg_attr(test, mocked)]
@@ -127,6 +335,80 @@ pub trait Memory<A, D> {
 
     /// Place data at a memory address.
     fn set(&mut self, address: A, data: D) -> SimResult<(), String>;
+
+    /// Returns the value get() would return for an address, without taking
+    /// cycles or changing any state, like hit and miss counts, replacement
+    /// order or coherence state. Caches which do not hold the address look in
+    /// the memory below.
+    fn peek(&self, address: A) -> SimResult<D, String>;
+
+    /// Changes the value get() would return for an address, without taking
+    /// cycles or changing any other state. A cache which holds the address
+    /// changes its copy and leaves the line's flags as they are, otherwise the
+    /// memory below is changed. Read only ranges do not apply.
+    fn poke(&mut self, address: A, data: D) -> SimResult<(), String>;
+
+    /// Write every modified value held by the memory to the memory below it.
+    /// Memories which do not hold copies of another memory do nothing.
+    fn flush(&mut self) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// Discard every value held by the memory, without writing modified values
+    /// to the memory below it. Memories which do not hold copies of another
+    /// memory do nothing.
+    fn invalidate(&mut self) -> SimResult<(), String> {
+        SimResult::Wait(0, ())
+    }
+
+    /// Returns the number of cycles an access which hits takes. Accesses which
+    /// take longer missed. Memories where every access takes the same time
+    /// return that time. None if the latency is not known.
+    fn hit_latency(&self) -> Option<u16> {
+        None
+    }
+
+    /// Returns the address of the first word of the line which holds an
+    /// address, for memories which move several words at once. Other memories
+    /// return the address.
+    fn line_address(&self, address: A) -> A {
+        address
+    }
+
+    /// Returns the memory's state so it can be saved in a checkpoint. Caches
+    /// only return their own lines, the memory below them is checkpointed on
+    /// its own. None if the memory cannot be checkpointed.
+    fn checkpoint(&self) -> Option<MemoryCheckpoint> {
+        None
+    }
+}
+
+/// State of a memory saved in a checkpoint, see Memory::checkpoint(). Holds
+/// the configuration the memory was created with, so it can be created again
+/// when a checkpoint is resumed.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub enum MemoryCheckpoint {
+    DRAM{
+        delay: u16,
+        data: HashMap<u32, u32>,
+        readonly: Vec<(u32, u32)>,
+    },
+
+    DMCache{
+        delay: u16,
+        num_lines: usize,
+        block_size: usize,
+        write_through: bool,
+
+        /// Tag, valid and dirty flags of each line, in line order.
+        lines: Vec<(u32, bool, bool)>,
+
+        /// Words held by the lines, block_size words per line in line order.
+        data: Vec<u32>,
+
+        stats: CacheStats,
+        miss_cycles: u64,
+    },
 }
 
 /// InspectableMemory allows a memory unit to be insepcted for user
@@ -140,10 +422,30 @@ pub trait InspectableMemory<A, D> {
     fn inspect_address_txt(&self, address: A) -> String;
 }
 
+/// Byte order of the words in a program file.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Endianness {
+    /// Most significant byte first.
+    Big,
+
+    /// Least significant byte first.
+    Little,
+}
+
 /// Simulates the slow DRAM memory.
 pub struct DRAM {
     delay: u16,
     data: HashMap<u32, u32>,
+
+    /// Inclusive address ranges which cannot be written by set().
+    readonly: Vec<(u32, u32)>,
+
+    /// Word appended after a loaded program, if any.
+    pad_word: Option<u32>,
+
+    /// If set loaded words hold two 16-bit halves, which this expands into
+    /// words.
+    expand_halfword: Option<fn(u16) -> Result<u32, String>>,
 }
 
 impl DRAM {
@@ -152,9 +454,68 @@ impl DRAM {
         DRAM{
             delay: delay,
             data: HashMap::new(),
+            readonly: Vec::new(),
+            pad_word: None,
+            expand_halfword: None,
+        }
+    }
+
+    /// Creates a DRAM from its checkpoint. Returns an error if the checkpoint
+    /// is not of a DRAM.
+    pub fn from_checkpoint(checkpoint: &MemoryCheckpoint) -> Result<DRAM, String> {
+        match checkpoint {
+            MemoryCheckpoint::DRAM{delay, data, readonly} => {
+                let mut dram = DRAM::new(*delay);
+                dram.data = data.clone();
+                dram.readonly = readonly.clone();
+                Ok(dram)
+            },
+            _ => Err(format!("checkpoint is not of a DRAM: {:?}", checkpoint)),
+        }
+    }
+
+    /// Sets a word loading a program appends after the last loaded word, or
+    /// None to append nothing. Padding with a Halt instruction makes every
+    /// program terminate. This makes the loaded program one word longer.
+    pub fn set_pad_word(&mut self, word: Option<u32>) {
+        self.pad_word = word;
+    }
+
+    /// Sets if loading a program treats every 32 bits as two 16-bit compressed
+    /// instructions, the most significant half first. Each compressed
+    /// instruction is expanded into a 32-bit instruction word by expand, for
+    /// example instructions::expand_halfword(). None loads words unchanged.
+    pub fn set_halfword_packed(&mut self, expand: Option<fn(u16) -> Result<u32, String>>) {
+        self.expand_halfword = expand;
+    }
+
+    /// Marks the addresses from start to end (inclusive) as read only. Any
+    /// set() on these addresses will fail. Loading a program does not check
+    /// read only ranges.
+    pub fn mark_readonly(&mut self, start: u32, end: u32) {
+        self.readonly.push((start, end));
+    }
+
+    /// Sets count consecutive addresses starting at start to the value
+    /// pattern returns for each address. Like loading a program, read only
+    /// ranges are not checked and no cycles are taken. Addresses past the last
+    /// address are not filled.
+    pub fn fill_pattern(&mut self, start: u32, count: u32, pattern: impl Fn(u32) -> u32) {
+        if count == 0 {
+            return;
+        }
+
+        for address in start..=start.saturating_add(count - 1) {
+            self.data.insert(address, pattern(address));
         }
     }
 
+    /// Returns true if the address is in a read only range.
+    fn is_readonly(&self, address: u32) -> bool {
+        self.readonly.iter()
+            .any(|(start, end)| *start <= address && address <= *end)
+    }
+
     /// Loads contents of a file into DRAM.
     /// See load_from_reader() for details about the required format of
     /// this file.
@@ -173,9 +534,17 @@ impl DRAM {
     }
 
     /// Loads contents of a reader into DRAM.
-    /// The buffer should be binary. Every 32 bits will be loaded in as a word
-    /// in memory. The address in memory will increment by 1 for word loaded.
+    /// The buffer should be binary. Every 32 bits will be loaded in as a big
+    /// endian word in memory. The address in memory will increment by 1 for
+    /// word loaded.
     pub fn load_from_reader(&mut self, src: impl Read) -> Result<(), String> {
+        self.load_from_reader_with(src, Endianness::Big)
+    }
+
+    /// Loads contents of a reader into DRAM like load_from_reader(), with
+    /// words in the given byte order.
+    pub fn load_from_reader_with(&mut self, src: impl Read,
+                                 endian: Endianness) -> Result<(), String> {
         let mut reader = BufReader::new(src);
         let mut addr: u32 = 0;
         let mut buf: [u8; 4] = [0; 4];
@@ -184,6 +553,10 @@ impl DRAM {
             match reader.read(&mut buf) {
                 Ok(bytes_read) => {
                     if bytes_read == 0 { // End of file
+                        if let Some(word) = self.pad_word {
+                            self.data.insert(addr, word);
+                        }
+                        
                         return Ok(());
                     } else if bytes_read != 4 { // Incorrect number of bytes read
                         let mut read_as: Vec<String> = Vec::new();
@@ -196,13 +569,26 @@ impl DRAM {
                                            bytes_read, read_as, self.data.len()));
                     }
 
-                    let value: u32 = (buf[3] as u32) |
-                        (buf[2] as u32) << 8 |
-                        (buf[1] as u32) << 16 |
-                        (buf[0] as u32) << 24;
+                    let value: u32 = match endian {
+                        Endianness::Big => u32::from_be_bytes(buf),
+                        Endianness::Little => u32::from_le_bytes(buf),
+                    };
                     
-                    self.data.insert(addr, value);
-                    addr += 1;
+                    if let Some(expand_halfword) = self.expand_halfword {
+                        for half in &[(value >> 16) as u16, value as u16] {
+                            let word = match expand_halfword(*half) {
+                                Ok(w) => w,
+                                Err(e) => return Err(format!(
+                                    "Failed to expand word {}: {}", addr, e)),
+                            };
+                            
+                            self.data.insert(addr, word);
+                            addr += 1;
+                        }
+                    } else {
+                        self.data.insert(addr, value);
+                        addr += 1;
+                    }
                 },
                 Err(e) => {
                     return Err(format!("Failed to read buffer: {}", e));
@@ -210,6 +596,27 @@ impl DRAM {
             }
         }
     }
+
+    /// Saves the contents of DRAM to a file in the format load_from_reader()
+    /// reads. Every address from 0 up to the highest set address is written,
+    /// addresses which were never set are written as 0. Loading the file into
+    /// a DRAM without a pad word or halfword expansion gives back the contents,
+    /// with those options set the loaded contents are padded or expanded.
+    pub fn save_to_file(&self, path: &str) -> Result<(), String> {
+        let len = match self.data.keys().max() {
+            Some(max) => *max as usize + 1,
+            None => 0,
+        };
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(len * 4);
+        for addr in 0..len {
+            let word = self.data.get(&(addr as u32)).copied().unwrap_or(0);
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+
+        fs::write(path, bytes)
+            .map_err(|e| format!("Failed to write DRAM file \"{}\": {}", path, e))
+    }
 }
 
 impl InspectableMemory<u32, u32> for DRAM {
@@ -258,9 +665,34 @@ impl Memory<u32, u32> for DRAM {
     }
     
     fn set(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        if self.is_readonly(address) {
+            return SimResult::Err(format!("address {} is read only", address));
+        }
+        
         self.data.insert(address, data);
         SimResult::Wait(self.delay, ())
     }
+
+    fn peek(&self, address: u32) -> SimResult<u32, String> {
+        SimResult::Wait(0, *self.data.get(&address).unwrap_or(&0))
+    }
+
+    fn poke(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        self.data.insert(address, data);
+        SimResult::Wait(0, ())
+    }
+
+    fn hit_latency(&self) -> Option<u16> {
+        Some(self.delay)
+    }
+
+    fn checkpoint(&self) -> Option<MemoryCheckpoint> {
+        Some(MemoryCheckpoint::DRAM{
+            delay: self.delay,
+            data: self.data.clone(),
+            readonly: self.readonly.clone(),
+        })
+    }
 }
 
 /// Direct mapped cache.
@@ -271,18 +703,149 @@ pub struct DMCache {
     /// Number of lines in the cache.
     num_lines: usize,
 
-    /// Number of least significant bits used for an address's cache line index.
+    /// Number of bits above the offset bits used for an address's cache line
+    /// index.
     idx_bits: usize,
 
-    /// Number of most significant bits used for an address's cache line tag.
-    tag_bits: usize,
+    /// Number of words in each line, always a power of two.
+    block_size: usize,
+
+    /// Number of least significant bits used for an address's offset in its
+    /// line.
+    offset_bits: usize,
 
     /// Cache lines.
     lines: Vec<DMCacheLine>,
 
+    /// Words held by the lines, block_size words per line in line order.
+    data: Vec<u32>,
+
     /// Underlying memory which will be used to populate the cache on the event
     /// of a cache miss.
     base: Rc<RefCell<dyn Memory<u32, u32>>>,
+
+    /// Hit and miss counts.
+    stats: CacheStats,
+
+    /// Cycles misses took beyond the cache's own delay, summed over every
+    /// miss.
+    miss_cycles: u64,
+
+    /// If true hits take no cycles, see set_count_only().
+    count_only: bool,
+
+    /// Bus the cache uses to keep coherent with other caches, and the cache's
+    /// position on the bus. None if the cache is not connected.
+    bus: Option<(Rc<RefCell<CoherenceBus>>, usize)>,
+
+    /// Number of misses on lines another cache's write invalidated.
+    coherence_misses: u64,
+
+    /// If true every set() is also written to base and lines are never dirty.
+    /// Otherwise lines are written to base when they are evicted or flushed.
+    write_through: bool,
+}
+
+/// MSI coherence state of a cache line. Lines store the state as their valid
+/// and dirty flags.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum CoherenceState {
+    /// The only valid copy, which differs from the memory below. Valid and
+    /// dirty.
+    Modified,
+
+    /// A copy which matches the memory below, other caches may hold copies
+    /// too. Valid and not dirty.
+    Shared,
+
+    /// Not valid.
+    Invalid,
+}
+
+/// Messages broadcast on a CoherenceBus. Fields are addresses.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum BusMessage {
+    /// A cache is reading the address. Caches holding it Modified write it
+    /// back and move to Shared.
+    Read(u32),
+
+    /// A cache is writing the address. Caches holding it move to Invalid.
+    Invalidate(u32),
+}
+
+/// Connects the private caches of cores so they follow the MSI coherence
+/// protocol. Each cache broadcasts a Read before filling a line after a miss
+/// and an Invalidate before writing a line it does not hold Modified. Caches
+/// must share the memory below them.
+pub struct CoherenceBus {
+    /// Connected caches, in the order they were connected.
+    caches: Vec<Weak<RefCell<DMCache>>>,
+}
+
+impl CoherenceBus {
+    /// Creates a bus with no caches connected.
+    pub fn new() -> CoherenceBus {
+        CoherenceBus{
+            caches: Vec::new(),
+        }
+    }
+
+    /// Connects a cache to a bus. The bus only keeps a weak reference to the
+    /// cache.
+    pub fn connect(bus: &Rc<RefCell<CoherenceBus>>, cache: &Rc<RefCell<DMCache>>) {
+        let id = bus.borrow().caches.len();
+        bus.borrow_mut().caches.push(Rc::downgrade(cache));
+        cache.borrow_mut().bus = Some((bus.clone(), id));
+    }
+
+    /// Delivers a message to every connected cache except the sender, in the
+    /// order the caches were connected. Returns the cycles write backs took.
+    fn broadcast(&self, sender: usize, msg: BusMessage) -> SimResult<(), String> {
+        let mut total_wait: u16 = 0;
+
+        for (id, cache) in self.caches.iter().enumerate() {
+            if id == sender {
+                continue;
+            }
+
+            let cache = match cache.upgrade() {
+                Some(c) => c,
+                None => continue,
+            };
+
+            match cache.borrow_mut().snoop(msg) {
+                SimResult::Err(e) => return SimResult::Err(
+                    format!("cache {} failed to handle {:?}: {}", id, msg, e)),
+                SimResult::Wait(w, _v) => total_wait = total_wait.saturating_add(w),
+            };
+        }
+
+        SimResult::Wait(total_wait, ())
+    }
+}
+
+/// State of one cache line in a CacheSnapshot.
+#[derive(Serialize,Clone,Debug,PartialEq)]
+pub struct CacheLineSnapshot {
+    /// Index of the line's set.
+    pub index: usize,
+
+    /// Position of the line in its set, always 0 for direct mapped caches.
+    pub way: usize,
+
+    pub tag: u32,
+    pub data: u32,
+    pub valid: bool,
+    pub dirty: bool,
+
+    /// Address reconstructed from the index and tag.
+    pub address: u32,
+}
+
+/// State of every line in a cache, for user interfaces.
+#[derive(Serialize,Clone,Debug,PartialEq)]
+pub struct CacheSnapshot {
+    pub lines: Vec<CacheLineSnapshot>,
 }
 
 #[derive(Copy,Clone,Debug)]
@@ -291,6 +854,10 @@ struct DMCacheLine {
     data: u32,
     valid: bool,
     dirty: bool,
+
+    /// True if the line was invalidated by another cache's write. The tag is
+    /// kept so the next miss on it is counted as a coherence miss.
+    snooped: bool,
 }
 
 impl DMCacheLine {
@@ -300,48 +867,295 @@ impl DMCacheLine {
             data: 0,
             valid: false,
             dirty: false,
+            snooped: false,
+        }
+    }
+
+    /// Returns the line's MSI coherence state.
+    fn state(&self) -> CoherenceState {
+        match (self.valid, self.dirty) {
+            (false, _) => CoherenceState::Invalid,
+            (true, true) => CoherenceState::Modified,
+            (true, false) => CoherenceState::Shared,
         }
     }
 }
 
 impl DMCache {
+    /// Creates a write back cache.
     pub fn new(delay: u16,
                num_lines: usize,
                base: Rc<RefCell<dyn Memory<u32, u32>>>) -> DMCache {
+        DMCache::new_with_policy(delay, num_lines, base, false)
+    }
+
+    /// Creates a cache which is write through if write_through is true and
+    /// write back otherwise.
+    pub fn new_with_policy(delay: u16,
+                           num_lines: usize,
+                           base: Rc<RefCell<dyn Memory<u32, u32>>>,
+                           write_through: bool) -> DMCache {
+        DMCache::build(delay, num_lines, 1, base, write_through)
+    }
+
+    /// Creates a write back cache whose lines each hold block_size words with
+    /// consecutive addresses. Returns an error if block_size is not a power
+    /// of two.
+    pub fn new_with_block_size(delay: u16,
+                               num_lines: usize,
+                               block_size: usize,
+                               base: Rc<RefCell<dyn Memory<u32, u32>>>) -> Result<DMCache, String> {
+        if !block_size.is_power_of_two() {
+            return Err(format!("block size {} is not a power of two", block_size));
+        }
+
+        Ok(DMCache::build(delay, num_lines, block_size, base, false))
+    }
+
+    /// Creates a cache, block_size must be a power of two.
+    fn build(delay: u16,
+             num_lines: usize,
+             block_size: usize,
+             base: Rc<RefCell<dyn Memory<u32, u32>>>,
+             write_through: bool) -> DMCache {
         let mut lines: Vec<DMCacheLine> = vec![];
         for i in 0..num_lines {
             lines.push(DMCacheLine::new());
         }
 
         let idx_bits = (num_lines as f32).log(2.0).ceil();
-        let tag_bits = 32.0 - idx_bits;
 
         DMCache{
             delay: delay,
             num_lines: num_lines,
             idx_bits: idx_bits as usize,
-            tag_bits: tag_bits as usize,
+            block_size,
+            offset_bits: block_size.trailing_zeros() as usize,
             lines: lines,
+            data: vec![0; num_lines * block_size],
             base: base,
+            stats: CacheStats{
+                hits: 0,
+                misses: 0,
+            },
+            miss_cycles: 0,
+            count_only: false,
+            bus: None,
+            coherence_misses: 0,
+            write_through,
+        }
+    }
+
+    /// Creates a cache in front of base from its checkpoint. Returns an error
+    /// if the checkpoint is not of a DMCache.
+    pub fn from_checkpoint(checkpoint: &MemoryCheckpoint,
+                           base: Rc<RefCell<dyn Memory<u32, u32>>>) -> Result<DMCache, String> {
+        match checkpoint {
+            MemoryCheckpoint::DMCache{delay, num_lines, block_size, write_through,
+                                      lines, data, stats, miss_cycles} => {
+                if lines.len() != *num_lines || data.len() != num_lines * block_size ||
+                    !block_size.is_power_of_two() {
+                    return Err(format!("checkpoint of a cache with {} lines of {} words \
+                                        holds {} lines and {} words",
+                                       num_lines, block_size, lines.len(), data.len()));
+                }
+
+                let mut cache = DMCache::build(*delay, *num_lines, *block_size, base,
+                                               *write_through);
+                for (line, (tag, valid, dirty)) in cache.lines.iter_mut().zip(lines.iter()) {
+                    line.tag = *tag;
+                    line.valid = *valid;
+                    line.dirty = *dirty;
+                }
+                cache.data = data.clone();
+                cache.stats = *stats;
+                cache.miss_cycles = *miss_cycles;
+                Ok(cache)
+            },
+            _ => Err(format!("checkpoint is not of a DMCache: {:?}", checkpoint)),
+        }
+    }
+
+    /// Returns the hit and miss counts of the cache.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Returns the average number of cycles a miss took beyond the cache's own
+    /// delay, fetching from and writing back to base. 0 if there were no
+    /// misses.
+    pub fn miss_penalty(&self) -> f64 {
+        match self.stats.misses {
+            0 => 0.0,
+            misses => self.miss_cycles as f64 / misses as f64,
+        }
+    }
+
+    /// Returns the average memory access time in cycles, hit time + miss rate
+    /// * miss penalty. The miss penalty is measured, see miss_penalty().
+    pub fn amat(&self) -> f64 {
+        let accesses = self.stats.hits + self.stats.misses;
+        let miss_rate = match accesses {
+            0 => 0.0,
+            _ => self.stats.misses as f64 / accesses as f64,
+        };
+
+        self.hit_delay() as f64 + miss_rate * self.miss_penalty()
+    }
+
+    /// Returns the number of misses caused by another cache on the coherence
+    /// bus invalidating a line. These are also counted as misses in stats().
+    pub fn coherence_misses(&self) -> u64 {
+        self.coherence_misses
+    }
+
+    /// Returns the MSI coherence state of the line which holds an address.
+    pub fn coherence_state(&self, address: u32) -> CoherenceState {
+        let line = self.lines[self.get_address_index(address)];
+
+        match line.tag == self.get_address_tag(address) {
+            true => line.state(),
+            false => CoherenceState::Invalid,
+        }
+    }
+
+    /// Broadcasts a message on the coherence bus if the cache is connected to
+    /// one.
+    fn broadcast(&self, msg: BusMessage) -> SimResult<(), String> {
+        match &self.bus {
+            None => SimResult::Wait(0, ()),
+            Some((bus, id)) => bus.borrow().broadcast(*id, msg),
+        }
+    }
+
+    /// Handles a message another cache broadcast on the coherence bus.
+    fn snoop(&mut self, msg: BusMessage) -> SimResult<(), String> {
+        let address = match msg {
+            BusMessage::Read(a) => a,
+            BusMessage::Invalidate(a) => a,
+        };
+        let idx = self.get_address_index(address);
+        let line = self.lines[idx];
+
+        if !line.valid || line.tag != self.get_address_tag(address) {
+            return SimResult::Wait(0, ());
+        }
+
+        match msg {
+            BusMessage::Read(_a) => {
+                if !line.dirty {
+                    return SimResult::Wait(0, ());
+                }
+
+                match self.write_back_line(idx) {
+                    SimResult::Err(e) => SimResult::Err(
+                        format!("failed to write back modified line: {}", e)),
+                    SimResult::Wait(w, _v) => SimResult::Wait(w, ()),
+                }
+            },
+            BusMessage::Invalidate(_a) => {
+                // The writer only replaces one word and loads the rest of the
+                // line from base, so modified words are written back first
+                let wait = match line.dirty {
+                    true => match self.write_back_line(idx) {
+                        SimResult::Err(e) => return SimResult::Err(
+                            format!("failed to write back modified line: {}", e)),
+                        SimResult::Wait(w, _v) => w,
+                    },
+                    false => 0,
+                };
+
+                self.lines[idx].valid = false;
+                self.lines[idx].snooped = true;
+                SimResult::Wait(wait, ())
+            },
+        }
+    }
+
+    /// Sets if hits take no cycles. Hits are still counted in stats(), so an
+    /// idealized runtime can be computed separately. Misses are not affected.
+    pub fn set_count_only(&mut self, count_only: bool) {
+        self.count_only = count_only;
+    }
+
+    /// Returns the number of cycles a hit takes.
+    fn hit_delay(&self) -> u16 {
+        match self.count_only {
+            true => 0,
+            false => self.delay,
         }
     }
 
+    /// Returns the position of an address's word in its line.
+    fn get_address_offset(&self, address: u32) -> usize {
+        (address as usize) & (self.block_size - 1)
+    }
+
     fn get_address_index(&self, address: u32) -> usize {
-        address.get_bits(0..=self.idx_bits-1) as usize
-        //((address << 22) >> 22) as usize
+        ((u64::from(address) >> self.offset_bits) & ((1 << self.idx_bits) - 1)) as usize
     }
 
     fn get_address_tag(&self, address: u32) -> u32 {
-        address >> self.idx_bits
-        //address >> 10
+        (u64::from(address) >> (self.offset_bits + self.idx_bits)) as u32
     }
 
+    /// Returns the address of the first word of a line.
     fn get_idx_address(&self, idx: usize, tag: u32) -> u32 {
-        let mut addr: u32 = 0;
-        addr.set_bits(0..=self.tag_bits-1, idx as u32);
-        addr.set_bits(self.tag_bits..=31, tag);
+        ((u64::from(tag) << (self.offset_bits + self.idx_bits)) |
+         ((idx as u64) << self.offset_bits)) as u32
+    }
+
+    /// Returns the words held by a line.
+    fn line_data(&self, idx: usize) -> &[u32] {
+        &self.data[idx * self.block_size..(idx + 1) * self.block_size]
+    }
+
+    /// Writes every word of a line to base and marks the line clean.
+    fn write_back_line(&mut self, idx: usize) -> SimResult<(), String> {
+        let addr = self.get_idx_address(idx, self.lines[idx].tag);
+        let mut total_wait: u16 = 0;
+
+        for offset in 0..self.block_size {
+            let word = self.data[idx * self.block_size + offset];
+            match self.base.borrow_mut().set(addr + offset as u32, word) {
+                SimResult::Err(e) => return SimResult::Err(e),
+                SimResult::Wait(w, _v) => total_wait = total_wait.saturating_add(w),
+            };
+        }
+
+        self.lines[idx].dirty = false;
+
+        SimResult::Wait(total_wait, ())
+    }
+
+    /// Reads every word of the block with a tag from base into a line, except
+    /// the word at skip_offset which is about to be overwritten. The line is
+    /// left valid and clean.
+    fn fill_line(&mut self, idx: usize, tag: u32,
+                 skip_offset: Option<usize>) -> SimResult<(), String> {
+        let addr = self.get_idx_address(idx, tag);
+        let mut total_wait: u16 = 0;
+
+        for offset in 0..self.block_size {
+            if Some(offset) == skip_offset {
+                continue;
+            }
+
+            match self.base.borrow_mut().get(addr + offset as u32) {
+                SimResult::Err(e) => return SimResult::Err(e),
+                SimResult::Wait(w, d) => {
+                    total_wait = total_wait.saturating_add(w);
+                    self.data[idx * self.block_size + offset] = d;
+                },
+            };
+        }
+
+        self.lines[idx].valid = true;
+        self.lines[idx].dirty = false;
+        self.lines[idx].snooped = false;
+        self.lines[idx].tag = tag;
 
-        addr
+        SimResult::Wait(total_wait, ())
     }
 
     pub fn inspect_valid(&self) -> HashMap<u32, u32> {
@@ -356,12 +1170,56 @@ impl DMCache {
             
             let addr: u32 = self.get_idx_address(i, line.tag);
 
-            map.insert(addr, line.data);
+            for (offset, word) in self.line_data(i).iter().enumerate() {
+                map.insert(addr + offset as u32, *word);
+            }
         }
 
         map
     }
 
+    /// Returns the address and value of every valid line, sorted by address.
+    pub fn valid_addresses_sorted(&self) -> Vec<(u32, u32)> {
+        let mut valid: Vec<(u32, u32)> = self.inspect_valid().into_iter()
+            .collect();
+        valid.sort();
+
+        valid
+    }
+
+    /// Returns the state of every line.
+    pub fn snapshot(&self) -> CacheSnapshot {
+        CacheSnapshot{
+            lines: self.lines.iter().enumerate()
+                .map(|(i, line)| CacheLineSnapshot{
+                    index: i,
+                    way: 0,
+                    tag: line.tag,
+                    data: self.line_data(i)[0],
+                    valid: line.valid,
+                    dirty: line.dirty,
+                    address: self.get_idx_address(i, line.tag),
+                })
+                .collect(),
+        }
+    }
+
+    /// Sets the exact state of a cache line. Used by tests to set up eviction
+    /// scenarios without a sequence of accesses. data is the line's first
+    /// word.
+    #[cfg(test)]
+    pub fn force_line(&mut self, idx: usize, tag: u32, data: u32, valid: bool,
+                      dirty: bool) {
+        self.lines[idx] = DMCacheLine{
+            tag,
+            data: 0,
+            valid,
+            dirty,
+            snooped: false,
+        };
+        self.data[idx * self.block_size] = data;
+    }
+
     /// Keys are addresses, values are descriptions of the line.
     pub fn inspect_valid_aliases(&self) -> HashMap<u32, String> {
         let mut map: HashMap<u32, String> = HashMap::new();
@@ -380,7 +1238,10 @@ impl DMCache {
                 false => "",
             };
 
-            map.insert(addr, format!("#{} [{}]{}", i, line.tag, dirty_str));
+            for offset in 0..self.block_size {
+                map.insert(addr + offset as u32,
+                           format!("#{} [{}]{}", i, line.tag, dirty_str));
+            }
         }
 
         map
@@ -396,7 +1257,9 @@ impl InspectableMemory<u32, u32> for DMCache {
             
             let addr: u32 = self.get_idx_address(i, line.tag);
 
-            map.insert(addr, line.data);
+            for (offset, word) in self.line_data(i).iter().enumerate() {
+                map.insert(addr + offset as u32, *word);
+            }
         }
 
         map
@@ -406,6 +1269,7 @@ impl InspectableMemory<u32, u32> for DMCache {
         let idx = self.get_address_index(address);
 
         let line = self.lines[idx];
+        let data = self.line_data(idx)[self.get_address_offset(address)];
 
         format!("\
 Index: {}
@@ -413,7 +1277,7 @@ Tag  : {}
 Data : {}
 Valid: {}
 Dirty: {}", idx,
-                   line.tag, line.data, line.valid, line.dirty)
+                   line.tag, data, line.valid, line.dirty)
     }
 }
 
@@ -423,8 +1287,11 @@ impl fmt::Display for DMCache {
 
         let mut i = 0;
         for line in self.lines.iter() {
+            let words: Vec<String> = self.line_data(i).iter()
+                .map(|w| w.to_string())
+                .collect();
             out.push_str(format!("{} = {} [valid={}, dirty={}]",
-                                 line.tag, line.data, line.valid,
+                                 line.tag, words.join(", "), line.valid,
                                  line.dirty).as_str());
 
             if i + 1 != self.lines.len() {
@@ -443,93 +1310,1044 @@ impl Memory<u32, u32> for DMCache {
         // Get line
         let idx = self.get_address_index(address);
         let tag = self.get_address_tag(address);
+        let offset = self.get_address_offset(address);
 
         let line = self.lines[idx];
 
         // Check if address in cache
         if line.valid && line.tag == tag {
-            SimResult::Wait(self.delay, line.data)
+            self.stats.hits += 1;
+            SimResult::Wait(self.hit_delay(), self.line_data(idx)[offset])
         } else {
-            let mut total_wait: u16 = self.delay;
+            self.stats.misses += 1;
+            if line.snooped && line.tag == tag {
+                self.coherence_misses += 1;
+            }
+            
+            // Other caches write back a modified copy before the line is read
+            let mut total_wait: u16 = match self.broadcast(BusMessage::Read(address)) {
+                SimResult::Err(e) => return SimResult::Err(
+                    format!("failed to broadcast read: {}", e)),
+                SimResult::Wait(w, _v) => self.delay.saturating_add(w),
+            };
             
             // Evict current line if dirty and there is a conflict
             if line.valid && line.tag != tag && line.dirty {
                 // Write to cache layer below
-                let evict_res = self.base.borrow_mut().set(address, line.data);
+                match self.write_back_line(idx) {
+                    SimResult::Err(e) => return SimResult::Err(
+                        format!("failed to write out old line value when evicting: {}", e)),
+                    SimResult::Wait(c, _r) => total_wait = total_wait.saturating_add(c),
+                };
+            }
 
-                if let SimResult::Err(e) = evict_res {
-                    return SimResult::Err(format!("failed to write out old line value when evicting: {}", e));
-                }
+            // Get block from cache layer below
+            match self.fill_line(idx, tag, None) {
+                SimResult::Err(e) => return SimResult::Err(
+                    format!("failed to get line value from base cache: {}", e)),
+                SimResult::Wait(w, _v) => total_wait = total_wait.saturating_add(w),
+            };
 
-                if let SimResult::Wait(c, _r) = evict_res {
-                    total_wait += c;
-                }
-            }
+            self.miss_cycles += u64::from(total_wait - self.delay);
 
-            // Get value from cache layer below
-            let get_res = self.base.borrow_mut().get(address);
+            SimResult::Wait(total_wait, self.line_data(idx)[offset])
+        }
+    }
 
-            let data = match get_res {
-                SimResult::Wait(w, d) => {
-                    total_wait += w;
-                    
-                    d
-                },
-                SimResult::Err(e) => {
-                    return SimResult::Err(format!("failed to get line value from base cache: {}", e));
-                },
-            };
+    fn peek(&self, address: u32) -> SimResult<u32, String> {
+        let idx = self.get_address_index(address);
+        let line = self.lines[idx];
 
-            // Save in cache
-            self.lines[idx].valid = true;
-            self.lines[idx].dirty = false;
-            self.lines[idx].tag = tag;
-            self.lines[idx].data = data;
+        match line.valid && line.tag == self.get_address_tag(address) {
+            true => SimResult::Wait(0, self.line_data(idx)[self.get_address_offset(address)]),
+            false => self.base.borrow().peek(address),
+        }
+    }
+
+    fn poke(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        let idx = self.get_address_index(address);
+        let line = self.lines[idx];
+
+        let offset = self.get_address_offset(address);
 
-            SimResult::Wait(total_wait, data)
+        match line.valid && line.tag == self.get_address_tag(address) {
+            true => {
+                self.data[idx * self.block_size + offset] = data;
+                SimResult::Wait(0, ())
+            },
+            false => self.base.borrow_mut().poke(address, data),
         }
     }
+
+    fn hit_latency(&self) -> Option<u16> {
+        Some(self.hit_delay())
+    }
+
+    fn line_address(&self, address: u32) -> u32 {
+        address & !(self.block_size as u32 - 1)
+    }
+
+    fn checkpoint(&self) -> Option<MemoryCheckpoint> {
+        Some(MemoryCheckpoint::DMCache{
+            delay: self.delay,
+            num_lines: self.num_lines,
+            block_size: self.block_size,
+            write_through: self.write_through,
+            lines: self.lines.iter().map(|l| (l.tag, l.valid, l.dirty)).collect(),
+            data: self.data.clone(),
+            stats: self.stats,
+            miss_cycles: self.miss_cycles,
+        })
+    }
     
     fn set(&mut self, address: u32, data: u32) -> SimResult<(), String> {
         // Get line
         let idx = self.get_address_index(address);
         let tag = self.get_address_tag(address);
+        let offset = self.get_address_offset(address);
 
         let line = self.lines[idx];
 
+        // Other caches drop their copies unless this cache already holds the
+        // only copy
+        let mut bus_wait: u16 = 0;
+        if !(line.tag == tag && line.state() == CoherenceState::Modified) {
+            bus_wait = match self.broadcast(BusMessage::Invalidate(address)) {
+                SimResult::Err(e) => return SimResult::Err(
+                    format!("failed to broadcast invalidate: {}", e)),
+                SimResult::Wait(w, _v) => w,
+            };
+        }
+
         // If line matches address
-        if line.valid && line.tag == tag {
-            self.lines[idx].dirty = true;
-            self.lines[idx].data = data;
+        let wait = if line.valid && line.tag == tag {
+            self.stats.hits += 1;
 
-            SimResult::Wait(self.delay, ())
+            self.hit_delay().saturating_add(bus_wait)
         } else {
-            let mut total_wait: u16 = self.delay;
+            self.stats.misses += 1;
+            if line.snooped && line.tag == tag {
+                self.coherence_misses += 1;
+            }
+            let mut total_wait: u16 = self.delay.saturating_add(bus_wait);
             
             // Evict current line if dirty and there is a conflict
             if line.valid && line.tag != tag && line.dirty {
                 // Write to cache layer below
-                let old_addr = self.get_idx_address(idx, line.tag);//(u32::from(line.tag) << 10) | (idx as u32);
-                let evict_res = self.base.borrow_mut().set(old_addr, line.data);
+                match self.write_back_line(idx) {
+                    SimResult::Err(e) => return SimResult::Err(
+                        format!("failed to write out old line value when evicting: {}", e)),
+                    SimResult::Wait(c, _r) => total_wait = total_wait.saturating_add(c),
+                };
+            }
+
+            // The rest of the block is loaded so the whole line is valid
+            match self.fill_line(idx, tag, Some(offset)) {
+                SimResult::Err(e) => return SimResult::Err(
+                    format!("failed to get line value from base cache: {}", e)),
+                SimResult::Wait(w, _v) => total_wait = total_wait.saturating_add(w),
+            };
+            self.miss_cycles += u64::from(total_wait - self.delay);
+
+            total_wait
+        };
+
+        // Save in cache
+        self.lines[idx].dirty = true;
+        self.data[idx * self.block_size + offset] = data;
+
+        if !self.write_through {
+            return SimResult::Wait(wait, ());
+        }
+
+        // The line matches base, so it is never written out again
+        self.lines[idx].dirty = false;
+        match self.base.borrow_mut().set(address, data) {
+            SimResult::Err(e) => SimResult::Err(
+                format!("failed to write through to base: {}", e)),
+            SimResult::Wait(w, _v) => SimResult::Wait(wait.saturating_add(w), ()),
+        }
+    }
+
+    fn flush(&mut self) -> SimResult<(), String> {
+        let mut total_wait: u16 = self.delay;
+
+        for idx in 0..self.num_lines {
+            let line = self.lines[idx];
+            if !line.valid || !line.dirty {
+                continue;
+            }
+
+            match self.write_back_line(idx) {
+                SimResult::Err(e) => return SimResult::Err(
+                    format!("failed to write out line {} when flushing: {}", idx, e)),
+                SimResult::Wait(w, _v) => total_wait = total_wait.saturating_add(w),
+            };
+        }
+
+        // Lower levels may hold lines written by this flush or earlier
+        match self.base.borrow_mut().flush() {
+            SimResult::Err(e) => return SimResult::Err(
+                format!("failed to flush the memory below: {}", e)),
+            SimResult::Wait(w, _v) => total_wait = total_wait.saturating_add(w),
+        };
+
+        SimResult::Wait(total_wait, ())
+    }
+
+    fn invalidate(&mut self) -> SimResult<(), String> {
+        for line in self.lines.iter_mut() {
+            line.valid = false;
+            line.dirty = false;
+            line.snooped = false;
+        }
+
+        SimResult::Wait(self.delay, ())
+    }
+}
+
+/// Selects which line in a set is evicted when a set associative cache misses
+/// and every line in the set is valid.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ReplacementPolicy {
+    /// Evict lines in order using a per set counter.
+    RoundRobin,
+
+    /// Evict the line which was least recently accessed.
+    Lru,
+
+    /// Evict a pseudo random line. The field seeds the random number generator
+    /// so runs are reproducible.
+    Random(u64),
+
+    /// Evict the line which was placed in the cache first.
+    Fifo,
+}
+
+/// Hit and miss counts for a cache.
+#[derive(Serialize,Deserialize,Copy,Clone,Debug,PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Set associative cache.
+pub struct NWayCache {
+    /// Number of cycles it takes to access this cache.
+    delay: u16,
+
+    /// Number of lines in each set.
+    ways: usize,
+
+    /// Number of least significant bits used for an address's set index.
+    idx_bits: usize,
+
+    /// Cache lines. The lines of set i are stored at [i * ways, (i+1) * ways).
+    lines: Vec<DMCacheLine>,
+
+    /// Determines which line in a set is evicted.
+    policy: ReplacementPolicy,
+
+    /// Incremented on every access, used to order line accesses and fills.
+    clock: u64,
+
+    /// Value of clock when each line was last accessed.
+    last_used: Vec<u64>,
+
+    /// Value of clock when each line was filled.
+    filled: Vec<u64>,
+
+    /// Next way to evict in each set for the round robin policy.
+    next_victim: Vec<usize>,
+
+    /// State of the random number generator for the random policy.
+    rng: u64,
+
+    /// Hit and miss counts.
+    stats: CacheStats,
+
+    /// Underlying memory which will be used to populate the cache on the event
+    /// of a cache miss.
+    base: Rc<RefCell<dyn Memory<u32, u32>>>,
+}
+
+impl NWayCache {
+    /// Creates a set associative cache which uses the round robin replacement
+    /// policy. num_sets should be a power of 2. Returns an error if num_sets or
+    /// ways is 0.
+    pub fn new(delay: u16, num_sets: usize, ways: usize,
+               base: Rc<RefCell<dyn Memory<u32, u32>>>) -> Result<NWayCache, String> {
+        NWayCache::new_with_policy(delay, num_sets, ways, base,
+                                   ReplacementPolicy::RoundRobin)
+    }
+
+    /// Creates a set associative cache which uses the specified replacement
+    /// policy. num_sets should be a power of 2. Returns an error if num_sets or
+    /// ways is 0.
+    pub fn new_with_policy(delay: u16, num_sets: usize, ways: usize,
+                           base: Rc<RefCell<dyn Memory<u32, u32>>>,
+                           policy: ReplacementPolicy) -> Result<NWayCache, String> {
+        if num_sets == 0 {
+            return Err("set associative cache must have at least 1 set".to_string());
+        }
+        if ways == 0 {
+            return Err("set associative cache must have at least 1 way".to_string());
+        }
+
+        let idx_bits = (num_sets as f32).log(2.0).ceil();
+
+        let rng = match policy {
+            ReplacementPolicy::Random(0) => 0x9E37_79B9_7F4A_7C15,
+            ReplacementPolicy::Random(seed) => seed,
+            _ => 0,
+        };
+
+        Ok(NWayCache{
+            delay,
+            ways,
+            idx_bits: idx_bits as usize,
+            lines: vec![DMCacheLine::new(); num_sets * ways],
+            policy,
+            clock: 0,
+            last_used: vec![0; num_sets * ways],
+            filled: vec![0; num_sets * ways],
+            next_victim: vec![0; num_sets],
+            rng,
+            stats: CacheStats{
+                hits: 0,
+                misses: 0,
+            },
+            base,
+        })
+    }
+
+    /// Returns the hit and miss counts of the cache.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    fn get_address_index(&self, address: u32) -> usize {
+        (address & ((1 << self.idx_bits) - 1)) as usize
+    }
+
+    fn get_address_tag(&self, address: u32) -> u32 {
+        address >> self.idx_bits
+    }
+
+    fn get_idx_address(&self, idx: usize, tag: u32) -> u32 {
+        (tag << self.idx_bits) | (idx as u32)
+    }
+
+    /// Returns the index in lines of the line in a set which holds a tag.
+    fn find_line(&self, set: usize, tag: u32) -> Option<usize> {
+        (set * self.ways..(set + 1) * self.ways)
+            .find(|i| self.lines[*i].valid && self.lines[*i].tag == tag)
+    }
+
+    /// Returns the index in lines of the line in a set which should be
+    /// replaced. Invalid lines are always used first, otherwise the replacement
+    /// policy decides.
+    fn select_victim(&mut self, set: usize) -> usize {
+        let start = set * self.ways;
+        let end = start + self.ways;
+
+        if let Some(i) = (start..end).find(|i| !self.lines[*i].valid) {
+            return i;
+        }
+
+        match self.policy {
+            ReplacementPolicy::RoundRobin => {
+                let way = self.next_victim[set];
+                self.next_victim[set] = (way + 1) % self.ways;
+
+                start + way
+            },
+            ReplacementPolicy::Lru => (start..end)
+                .min_by_key(|i| self.last_used[*i]).unwrap(),
+            ReplacementPolicy::Fifo => (start..end)
+                .min_by_key(|i| self.filled[*i]).unwrap(),
+            ReplacementPolicy::Random(_seed) => {
+                // Xorshift
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+
+                start + (self.rng % (self.ways as u64)) as usize
+            },
+        }
+    }
+
+    /// Finds a line for an address which missed the cache. Writes out the
+    /// line's old value if it is dirty. Returns the index in lines of the line
+    /// and the number of cycles evicting took.
+    fn replace_line(&mut self, address: u32) -> SimResult<usize, String> {
+        let set = self.get_address_index(address);
+        let i = self.select_victim(set);
+        let line = self.lines[i];
+
+        let mut wait: u16 = 0;
 
-                if let SimResult::Err(e) = evict_res {
-                    return SimResult::Err(format!("failed to write out old line value when evicting: {}", e));
+        if line.valid && line.dirty {
+            // Write to cache layer below
+            let old_addr = self.get_idx_address(set, line.tag);
+            
+            match self.base.borrow_mut().set(old_addr, line.data) {
+                SimResult::Err(e) => return SimResult::Err(
+                    format!("failed to write out old line value when evicting: {}", e)),
+                SimResult::Wait(c, _r) => wait += c,
+            }
+        }
+
+        self.filled[i] = self.clock;
+
+        SimResult::Wait(wait, i)
+    }
+
+    /// Returns the state of every line.
+    pub fn snapshot(&self) -> CacheSnapshot {
+        CacheSnapshot{
+            lines: self.lines.iter().enumerate()
+                .map(|(i, line)| CacheLineSnapshot{
+                    index: i / self.ways,
+                    way: i % self.ways,
+                    tag: line.tag,
+                    data: line.data,
+                    valid: line.valid,
+                    dirty: line.dirty,
+                    address: self.get_idx_address(i / self.ways, line.tag),
+                })
+                .collect(),
+        }
+    }
+
+    /// Keys are addresses, values are descriptions of the line which include
+    /// its set and way.
+    pub fn inspect_valid_aliases(&self) -> HashMap<u32, String> {
+        self.aliases(false)
+    }
+
+    /// Like inspect_valid_aliases() but each description ends with the line's
+    /// recency in its set, "r0" for the most recently accessed valid line,
+    /// "r1" for the next and so on. Lets the replacement policy be checked.
+    pub fn inspect_valid_aliases_with_recency(&self) -> HashMap<u32, String> {
+        self.aliases(true)
+    }
+
+    /// Returns the position of a line when the valid lines in its set are
+    /// ordered from most to least recently accessed.
+    fn recency(&self, i: usize) -> usize {
+        let start = (i / self.ways) * self.ways;
+
+        (start..start + self.ways)
+            .filter(|j| self.lines[*j].valid && self.last_used[*j] > self.last_used[i])
+            .count()
+    }
+
+    /// See inspect_valid_aliases() and inspect_valid_aliases_with_recency().
+    fn aliases(&self, show_recency: bool) -> HashMap<u32, String> {
+        let mut map: HashMap<u32, String> = HashMap::new();
+
+        for i in 0..self.lines.len() {
+            let line = self.lines[i];
+
+            if !line.valid {
+                continue
+            }
+
+            let addr: u32 = self.get_idx_address(i / self.ways, line.tag);
+
+            let dirty_str = match line.dirty {
+                true => " d",
+                false => "",
+            };
+
+            let mut alias = format!("#{}.{} [{}]{}", i / self.ways,
+                                    i % self.ways, line.tag, dirty_str);
+            if show_recency {
+                alias.push_str(&format!(" r{}", self.recency(i)));
+            }
+
+            map.insert(addr, alias);
+        }
+
+        map
+    }
+}
+
+impl InspectableMemory<u32, u32> for NWayCache {
+    fn inspect(&self) -> HashMap<u32, u32> {
+        let mut map: HashMap<u32, u32> = HashMap::new();
+
+        for i in 0..self.lines.len() {
+            let line = self.lines[i];
+
+            if !line.valid {
+                continue
+            }
+
+            let addr: u32 = self.get_idx_address(i / self.ways, line.tag);
+
+            map.insert(addr, line.data);
+        }
+
+        map
+    }
+
+    /// Describes every way of the set the address maps to.
+    fn inspect_address_txt(&self, address: u32) -> String {
+        let set = self.get_address_index(address);
+
+        let mut out = format!("Set  : {}", set);
+        for way in 0..self.ways {
+            let line = self.lines[set * self.ways + way];
+
+            out.push_str(&format!("\nWay {}: tag={} data={} valid={} dirty={}",
+                                  way, line.tag, line.data, line.valid,
+                                  line.dirty));
+        }
+
+        out
+    }
+}
+
+impl Memory<u32, u32> for NWayCache {
+    fn hit_latency(&self) -> Option<u16> {
+        Some(self.delay)
+    }
+
+    fn peek(&self, address: u32) -> SimResult<u32, String> {
+        let set = self.get_address_index(address);
+
+        match self.find_line(set, self.get_address_tag(address)) {
+            Some(i) => SimResult::Wait(0, self.lines[i].data),
+            None => self.base.borrow().peek(address),
+        }
+    }
+
+    fn poke(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        let set = self.get_address_index(address);
+
+        match self.find_line(set, self.get_address_tag(address)) {
+            Some(i) => {
+                self.lines[i].data = data;
+                SimResult::Wait(0, ())
+            },
+            None => self.base.borrow_mut().poke(address, data),
+        }
+    }
+
+    fn get(&mut self, address: u32) -> SimResult<u32, String> {
+        self.clock += 1;
+        
+        let set = self.get_address_index(address);
+        let tag = self.get_address_tag(address);
+
+        // Check if address in cache
+        if let Some(i) = self.find_line(set, tag) {
+            self.stats.hits += 1;
+            self.last_used[i] = self.clock;
+            
+            return SimResult::Wait(self.delay, self.lines[i].data);
+        }
+
+        self.stats.misses += 1;
+        let mut total_wait: u16 = self.delay;
+
+        let i = match self.replace_line(address) {
+            SimResult::Err(e) => return SimResult::Err(e),
+            SimResult::Wait(w, i) => {
+                total_wait += w;
+                i
+            },
+        };
+
+        // Get value from cache layer below
+        let data = match self.base.borrow_mut().get(address) {
+            SimResult::Wait(w, d) => {
+                total_wait += w;
+                d
+            },
+            SimResult::Err(e) => {
+                return SimResult::Err(format!("failed to get line value from base cache: {}", e));
+            },
+        };
+
+        // Save in cache
+        self.lines[i].valid = true;
+        self.lines[i].dirty = false;
+        self.lines[i].tag = tag;
+        self.lines[i].data = data;
+        self.last_used[i] = self.clock;
+
+        SimResult::Wait(total_wait, data)
+    }
+
+    fn set(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        self.clock += 1;
+        
+        let set = self.get_address_index(address);
+        let tag = self.get_address_tag(address);
+
+        let mut total_wait: u16 = self.delay;
+
+        let i = match self.find_line(set, tag) {
+            Some(i) => {
+                self.stats.hits += 1;
+                i
+            },
+            None => {
+                self.stats.misses += 1;
+
+                match self.replace_line(address) {
+                    SimResult::Err(e) => return SimResult::Err(e),
+                    SimResult::Wait(w, i) => {
+                        total_wait += w;
+                        i
+                    },
                 }
+            },
+        };
+
+        // Save in cache
+        self.lines[i].valid = true;
+        self.lines[i].dirty = true;
+        self.lines[i].tag = tag;
+        self.lines[i].data = data;
+        self.last_used[i] = self.clock;
+
+        SimResult::Wait(total_wait, ())
+    }
+
+    fn flush(&mut self) -> SimResult<(), String> {
+        let mut total_wait: u16 = self.delay;
+
+        for i in 0..self.lines.len() {
+            let line = self.lines[i];
+            if !line.valid || !line.dirty {
+                continue;
+            }
+
+            let addr = self.get_idx_address(i / self.ways, line.tag);
+            match self.base.borrow_mut().set(addr, line.data) {
+                SimResult::Err(e) => return SimResult::Err(
+                    format!("failed to write out line {} when flushing: {}", i, e)),
+                SimResult::Wait(w, _v) => total_wait = total_wait.saturating_add(w),
+            };
+
+            self.lines[i].dirty = false;
+        }
+
+        // Lower levels may hold lines written by this flush or earlier
+        match self.base.borrow_mut().flush() {
+            SimResult::Err(e) => return SimResult::Err(
+                format!("failed to flush the memory below: {}", e)),
+            SimResult::Wait(w, _v) => total_wait = total_wait.saturating_add(w),
+        };
+
+        SimResult::Wait(total_wait, ())
+    }
+
+    fn invalidate(&mut self) -> SimResult<(), String> {
+        for line in self.lines.iter_mut() {
+            line.valid = false;
+            line.dirty = false;
+        }
+
+        SimResult::Wait(self.delay, ())
+    }
+}
 
-                if let SimResult::Wait(c, _r) = evict_res {
-                    total_wait += c;
+/// Identifies the kind of a memory access.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// Passes accesses through to a memory while recording the address and kind
+/// of every access. The recorded trace can be replayed with replay_trace().
+pub struct AccessRecorder {
+    /// Accesses in the order they were made.
+    pub trace: Vec<(AccessKind, u32)>,
+
+    /// Memory which accesses are passed to.
+    base: Rc<RefCell<dyn Memory<u32, u32>>>,
+}
+
+impl AccessRecorder {
+    pub fn new(base: Rc<RefCell<dyn Memory<u32, u32>>>) -> AccessRecorder {
+        AccessRecorder{
+            trace: Vec::new(),
+            base,
+        }
+    }
+}
+
+impl Memory<u32, u32> for AccessRecorder {
+    fn get(&mut self, address: u32) -> SimResult<u32, String> {
+        self.trace.push((AccessKind::Read, address));
+        self.base.borrow_mut().get(address)
+    }
+
+    fn set(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        self.trace.push((AccessKind::Write, address));
+        self.base.borrow_mut().set(address, data)
+    }
+
+    fn peek(&self, address: u32) -> SimResult<u32, String> {
+        self.base.borrow().peek(address)
+    }
+
+    fn poke(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        self.base.borrow_mut().poke(address, data)
+    }
+
+    fn flush(&mut self) -> SimResult<(), String> {
+        self.base.borrow_mut().flush()
+    }
+
+    fn invalidate(&mut self) -> SimResult<(), String> {
+        self.base.borrow_mut().invalidate()
+    }
+}
+
+/// Translates virtual addresses to physical addresses with a page table before
+/// passing accesses to a memory. Recently used translations are kept in a
+/// TLB, a translation missing from the TLB adds a penalty. Accessing a page
+/// which is not in the page table is an error.
+pub struct TranslatingMemory {
+    /// Number of least significant address bits which are the offset in a
+    /// page. Pages hold 2^page_bits words.
+    page_bits: usize,
+
+    /// Physical page of each mapped virtual page.
+    page_table: HashMap<u32, u32>,
+
+    /// Virtual pages whose translations are in the TLB, least recently used
+    /// first.
+    tlb: VecDeque<u32>,
+
+    /// Number of translations the TLB holds.
+    tlb_entries: usize,
+
+    /// Cycles added when a translation is not in the TLB.
+    miss_penalty: u16,
+
+    /// TLB hit and miss counts.
+    stats: CacheStats,
+
+    /// Memory physical addresses are accessed in.
+    base: Rc<RefCell<dyn Memory<u32, u32>>>,
+}
+
+impl TranslatingMemory {
+    /// Creates a translating memory with an empty page table and TLB.
+    pub fn new(page_bits: usize, tlb_entries: usize, miss_penalty: u16,
+               base: Rc<RefCell<dyn Memory<u32, u32>>>) -> TranslatingMemory {
+        TranslatingMemory{
+            page_bits,
+            page_table: HashMap::new(),
+            tlb: VecDeque::new(),
+            tlb_entries,
+            miss_penalty,
+            stats: CacheStats{
+                hits: 0,
+                misses: 0,
+            },
+            base,
+        }
+    }
+
+    /// Maps a virtual page to a physical page. Removes any old translation
+    /// of the virtual page from the TLB.
+    pub fn map_page(&mut self, virtual_page: u32, physical_page: u32) {
+        self.page_table.insert(virtual_page, physical_page);
+        self.tlb.retain(|page| *page != virtual_page);
+    }
+
+    /// Returns the TLB hit and miss counts.
+    pub fn tlb_stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Returns the physical address of a virtual address from the page table,
+    /// without using the TLB.
+    fn physical_address(&self, address: u32) -> Result<u32, String> {
+        let virtual_page = address >> self.page_bits;
+        let offset = address & ((1 << self.page_bits) - 1);
+
+        match self.page_table.get(&virtual_page) {
+            Some(p) => Ok((*p << self.page_bits) | offset),
+            None => Err(format!("page fault: virtual address {} is in unmapped page {}",
+                                address, virtual_page)),
+        }
+    }
+
+    /// Returns the physical address of a virtual address and the cycles the
+    /// translation took, updating the TLB.
+    pub fn translate(&mut self, address: u32) -> SimResult<u32, String> {
+        let virtual_page = address >> self.page_bits;
+        let physical = match self.physical_address(address) {
+            Ok(p) => p,
+            Err(e) => return SimResult::Err(e),
+        };
+
+        let wait = match self.tlb.iter().position(|page| *page == virtual_page) {
+            Some(i) => {
+                self.stats.hits += 1;
+                self.tlb.remove(i);
+                0
+            },
+            None => {
+                self.stats.misses += 1;
+                if self.tlb.len() >= self.tlb_entries {
+                    self.tlb.pop_front();
                 }
+                self.miss_penalty
+            },
+        };
+        if self.tlb_entries > 0 {
+            self.tlb.push_back(virtual_page);
+        }
+
+        SimResult::Wait(wait, physical)
+    }
+}
+
+impl Memory<u32, u32> for TranslatingMemory {
+    fn get(&mut self, address: u32) -> SimResult<u32, String> {
+        let (wait, physical) = match self.translate(address) {
+            SimResult::Err(e) => return SimResult::Err(e),
+            SimResult::Wait(w, p) => (w, p),
+        };
+
+        SimResult::Wait(wait, ()).and(self.base.borrow_mut().get(physical))
+    }
+
+    fn set(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        let (wait, physical) = match self.translate(address) {
+            SimResult::Err(e) => return SimResult::Err(e),
+            SimResult::Wait(w, p) => (w, p),
+        };
+
+        SimResult::Wait(wait, ()).and(self.base.borrow_mut().set(physical, data))
+    }
+
+    fn peek(&self, address: u32) -> SimResult<u32, String> {
+        match self.physical_address(address) {
+            Ok(physical) => self.base.borrow().peek(physical),
+            Err(e) => SimResult::Err(e),
+        }
+    }
+
+    fn poke(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        match self.physical_address(address) {
+            Ok(physical) => self.base.borrow_mut().poke(physical, data),
+            Err(e) => SimResult::Err(e),
+        }
+    }
+
+    fn flush(&mut self) -> SimResult<(), String> {
+        self.base.borrow_mut().flush()
+    }
+
+    fn invalidate(&mut self) -> SimResult<(), String> {
+        self.base.borrow_mut().invalidate()
+    }
+}
+
+/// Order in which an access is translated by a TLB and looked up in a cache,
+/// see translated_cache().
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum TranslationOrder {
+    /// Addresses are translated before the cache is looked up, so the cache
+    /// holds physical addresses. Every access is translated before the cache
+    /// lookup, so every TLB miss adds its penalty, and virtual addresses which
+    /// alias one physical address share a cache line.
+    PhysicallyIndexed,
+
+    /// The cache is looked up with virtual addresses and only misses are
+    /// translated, so hits never pay for a TLB miss. Aliases of one physical
+    /// address occupy separate cache lines and each miss on their own. A write
+    /// through one alias is not seen through another alias whose line is
+    /// already cached.
+    VirtuallyIndexed,
+}
+
+/// Configuration of a TLB and a direct mapped cache stacked over a memory.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct TranslationConfig {
+    pub order: TranslationOrder,
+
+    /// Number of least significant address bits which are the offset in a
+    /// page, see TranslatingMemory.
+    pub page_bits: usize,
+
+    /// Number of translations the TLB holds.
+    pub tlb_entries: usize,
+
+    /// Cycles added when a translation is not in the TLB.
+    pub tlb_miss_penalty: u16,
+
+    /// Delay and number of lines of the cache.
+    pub cache_delay: u16,
+    pub cache_lines: usize,
+}
+
+/// A TLB and a cache stacked over a memory by translated_cache().
+pub struct TranslatedCache {
+    /// Memory accesses should be made to, the top of the stack.
+    pub memory: Rc<RefCell<dyn Memory<u32, u32>>>,
+
+    /// The cache, holding physical or virtual addresses depending on the
+    /// translation order.
+    pub cache: Rc<RefCell<DMCache>>,
+
+    /// The translating memory, pages are mapped with map_page().
+    pub tlb: Rc<RefCell<TranslatingMemory>>,
+}
+
+/// Stacks a TLB and a cache over base in the configured order. Physically
+/// indexed stacks put the translating memory in front of the cache, virtually
+/// indexed stacks put the cache in front of the translating memory.
+pub fn translated_cache(config: &TranslationConfig,
+                        base: Rc<RefCell<dyn Memory<u32, u32>>>) -> TranslatedCache {
+    match config.order {
+        TranslationOrder::PhysicallyIndexed => {
+            let cache = Rc::new(RefCell::new(DMCache::new(
+                config.cache_delay, config.cache_lines, base)));
+            let tlb = Rc::new(RefCell::new(TranslatingMemory::new(
+                config.page_bits, config.tlb_entries, config.tlb_miss_penalty,
+                cache.clone())));
+
+            TranslatedCache{
+                memory: tlb.clone(),
+                cache,
+                tlb,
             }
+        },
+        TranslationOrder::VirtuallyIndexed => {
+            let tlb = Rc::new(RefCell::new(TranslatingMemory::new(
+                config.page_bits, config.tlb_entries, config.tlb_miss_penalty,
+                base)));
+            let cache = Rc::new(RefCell::new(DMCache::new(
+                config.cache_delay, config.cache_lines, tlb.clone())));
+
+            TranslatedCache{
+                memory: cache.clone(),
+                cache,
+                tlb,
+            }
+        },
+    }
+}
+
+/// Address of the memory mapped input status port. Reading it returns the
+/// number of key codes waiting in the input queue.
+pub const INPUT_STATUS_ADDR: u32 = 0xFF00;
+
+/// Address of the memory mapped input data port. Reading it removes and
+/// returns the next key code in the input queue, 0 if the queue is empty.
+pub const INPUT_DATA_ADDR: u32 = 0xFF01;
+
+/// Memory mapped input device. The user interface queues key codes which
+/// programs read through the input ports. Accesses to all other addresses are
+/// passed through to a memory.
+pub struct InputPort {
+    /// Key codes which have not been read yet, oldest first.
+    queue: VecDeque<u32>,
+
+    /// Memory which accesses to non port addresses are passed to.
+    base: Rc<RefCell<dyn Memory<u32, u32>>>,
+}
+
+impl InputPort {
+    pub fn new(base: Rc<RefCell<dyn Memory<u32, u32>>>) -> InputPort {
+        InputPort{
+            queue: VecDeque::new(),
+            base,
+        }
+    }
+
+    /// Adds a key code to the end of the input queue.
+    pub fn push_key(&mut self, code: u32) {
+        self.queue.push_back(code);
+    }
+}
+
+impl Memory<u32, u32> for InputPort {
+    fn get(&mut self, address: u32) -> SimResult<u32, String> {
+        match address {
+            INPUT_STATUS_ADDR => SimResult::Wait(0, self.queue.len() as u32),
+            INPUT_DATA_ADDR => SimResult::Wait(0, self.queue.pop_front().unwrap_or(0)),
+            _ => self.base.borrow_mut().get(address),
+        }
+    }
+
+    fn set(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        match address {
+            INPUT_STATUS_ADDR | INPUT_DATA_ADDR => SimResult::Err(
+                format!("input port {} is read only", address)),
+            _ => self.base.borrow_mut().set(address, data),
+        }
+    }
+
+    /// Peeking the data port returns the next key code without removing it.
+    fn peek(&self, address: u32) -> SimResult<u32, String> {
+        match address {
+            INPUT_STATUS_ADDR => SimResult::Wait(0, self.queue.len() as u32),
+            INPUT_DATA_ADDR => SimResult::Wait(0, *self.queue.front().unwrap_or(&0)),
+            _ => self.base.borrow().peek(address),
+        }
+    }
+
+    fn poke(&mut self, address: u32, data: u32) -> SimResult<(), String> {
+        match address {
+            INPUT_STATUS_ADDR | INPUT_DATA_ADDR => SimResult::Err(
+                format!("input port {} is read only", address)),
+            _ => self.base.borrow_mut().poke(address, data),
+        }
+    }
+
+    fn flush(&mut self) -> SimResult<(), String> {
+        self.base.borrow_mut().flush()
+    }
+
+    fn invalidate(&mut self) -> SimResult<(), String> {
+        self.base.borrow_mut().invalidate()
+    }
+}
 
-            // Save in cache
-            self.lines[idx].valid = true;
-            self.lines[idx].dirty = true;
-            self.lines[idx].tag = tag;
-            self.lines[idx].data = data;
+/// Configuration of a cache used to replay traces. A direct mapped cache has
+/// 1 way.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct CacheConfig {
+    pub num_sets: usize,
+    pub ways: usize,
+    pub policy: ReplacementPolicy,
+}
 
-            SimResult::Wait(total_wait, ())
+/// Runs a memory access trace through a cache and returns the cache's hit and
+/// miss counts. Values written are not recorded in traces so the values in
+/// memory are meaningless, only the hit and miss counts are significant.
+/// Returns an error if config does not describe a valid cache.
+pub fn replay_trace(trace: &[(AccessKind, u32)], config: &CacheConfig) -> Result<CacheStats, String> {
+    let dram = Rc::new(RefCell::new(DRAM::new(0)));
+    let mut cache = NWayCache::new_with_policy(0, config.num_sets, config.ways,
+                                               dram, config.policy)?;
+
+    for (kind, address) in trace {
+        match kind {
+            AccessKind::Read => {
+                cache.get(*address);
+            },
+            AccessKind::Write => {
+                cache.set(*address, 0);
+            },
         }
     }
+
+    Ok(cache.stats())
 }
 
 #[cfg(test)]
@@ -562,4 +2380,646 @@ mod tests {
 
         assert_eq!(dram.inspect(), expected);
     }
+
+    /// Tests that loading the same bytes in each byte order gives byte swapped
+    /// words.
+    #[test]
+    fn test_dram_load_endianness() {
+        let bytes: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+        let mut big = DRAM::new(0);
+        big.load_from_reader_with(&bytes[..], Endianness::Big).expect("failed to load big endian");
+        let mut little = DRAM::new(0);
+        little.load_from_reader_with(&bytes[..], Endianness::Little).expect("failed to load little endian");
+
+        let (_wait, big_word) = big.get(0).unwrap("get big endian word");
+        let (_wait, little_word) = little.get(0).unwrap("get little endian word");
+        assert_eq!(big_word, 0x12345678);
+        assert_eq!(little_word, big_word.swap_bytes());
+    }
+
+    /// Tests that saving DRAM and loading the file gives the same contents,
+    /// with addresses which were never set loaded as 0, and that loading the
+    /// file with a pad word appends it.
+    #[test]
+    fn test_dram_save_to_file() {
+        let mut dram = DRAM::new(0);
+        dram.fill_pattern(0, 4, |addr| addr * 3 + 1);
+        dram.set(10, 0xdeadbeef).unwrap("set word");
+
+        // Unique across processes and runs which reuse a process id
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .expect("clock before epoch").as_nanos();
+        let path = std::env::temp_dir().join(format!("leg-dram-save-{}-{}.bin",
+                                                     std::process::id(), nanos));
+        let path = path.to_str().unwrap();
+        dram.save_to_file(path).expect("failed to save DRAM");
+
+        let mut loaded = DRAM::new(0);
+        loaded.load_from_file(path).expect("failed to load DRAM");
+
+        // A pad word is appended after the saved contents
+        let mut padded = DRAM::new(0);
+        padded.set_pad_word(Some(7));
+        padded.load_from_file(path).expect("failed to load padded DRAM");
+        fs::remove_file(path).expect("failed to remove DRAM file");
+
+        let mut expected = dram.inspect();
+        for addr in 4..10 {
+            expected.insert(addr, 0);
+        }
+        assert_eq!(loaded.inspect(), expected);
+
+        expected.insert(11, 7);
+        assert_eq!(padded.inspect(), expected);
+    }
+
+    /// Tests that DRAM.set fails for addresses marked read only.
+    #[test]
+    fn test_dram_mark_readonly() {
+        let mut dram = DRAM::new(0);
+
+        assert_eq!(dram.load_from_file("./test-data/instructions.bin"), Ok(()));
+        let code_len = dram.inspect().len() as u32;
+
+        dram.mark_readonly(0, code_len - 1);
+
+        assert!(matches!(dram.set(2, 1234), SimResult::Err(_)),
+                "store into code region should fail");
+        assert_eq!(dram.get(2).unwrap("get code word").1, 0x00041040,
+                   "code word should be unchanged");
+        
+        assert_eq!(dram.set(code_len + 10, 1234), SimResult::Wait(0, ()),
+                   "store into data region should succeed");
+        assert_eq!(dram.get(code_len + 10), SimResult::Wait(0, 1234));
+    }
+
+    /// Tests that hits in count only mode take no cycles but are counted.
+    #[test]
+    fn test_dmcache_count_only() {
+        let dram = Rc::new(RefCell::new(DRAM::new(10)));
+        let mut cache = DMCache::new(2, 4, dram);
+        cache.set_count_only(true);
+
+        assert_eq!(cache.get(1), SimResult::Wait(12, 0));
+        assert_eq!(cache.get(1), SimResult::Wait(0, 0));
+        assert_eq!(cache.set(1, 5), SimResult::Wait(0, ()));
+        assert_eq!(cache.stats(), CacheStats{ hits: 2, misses: 1 });
+
+        cache.set_count_only(false);
+        assert_eq!(cache.get(1), SimResult::Wait(2, 5));
+        assert_eq!(cache.stats(), CacheStats{ hits: 3, misses: 1 });
+    }
+
+    /// Tests that InputPort returns queued key codes in order and passes other
+    /// addresses through.
+    #[test]
+    fn test_input_port() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let mut port = InputPort::new(dram.clone());
+
+        assert_eq!(port.get(INPUT_STATUS_ADDR), SimResult::Wait(0, 0));
+        assert_eq!(port.get(INPUT_DATA_ADDR), SimResult::Wait(0, 0));
+
+        port.push_key(97);
+        port.push_key(98);
+        assert_eq!(port.get(INPUT_STATUS_ADDR), SimResult::Wait(0, 2));
+        assert_eq!(port.get(INPUT_DATA_ADDR), SimResult::Wait(0, 97));
+        assert_eq!(port.get(INPUT_DATA_ADDR), SimResult::Wait(0, 98));
+        assert_eq!(port.get(INPUT_STATUS_ADDR), SimResult::Wait(0, 0));
+
+        assert!(matches!(port.set(INPUT_DATA_ADDR, 1), SimResult::Err(_)),
+                "input port should be read only");
+        assert_eq!(port.set(5, 1234), SimResult::Wait(0, ()));
+        assert_eq!(dram.borrow_mut().get(5), SimResult::Wait(0, 1234));
+    }
+
+    /// Tests that each NWayCache replacement policy evicts its documented
+    /// victims. All addresses map to a single 2 way set and the cache is
+    /// accessed in the order A B A C repeated 3 times, then invalidated and
+    /// accessed in the order A B C B.
+    /// - Round robin and FIFO evict lines in the order they were filled until
+    ///   the invalidate. Afterwards round robin's counter still points at the
+    ///   second way, so it evicts B, which was filled last, while FIFO evicts
+    ///   A. Round robin misses B again.
+    /// - LRU keeps A cached since it is used every other access.
+    /// - Random evicts based on its seed.
+    #[test]
+    fn test_nway_cache_replacement_policies() {
+        // None invalidates the cache
+        let pattern = [
+            Some(0), Some(1), Some(0), Some(2), Some(0), Some(1),
+            Some(0), Some(2), Some(0), Some(1), Some(0), Some(2),
+            None, Some(0), Some(1), Some(2), Some(1),
+        ];
+        let accesses = pattern.iter().filter(|addr| addr.is_some()).count() as u64;
+
+        let expected = [
+            (ReplacementPolicy::RoundRobin, 13),
+            (ReplacementPolicy::Fifo, 12),
+            (ReplacementPolicy::Lru, 10),
+            (ReplacementPolicy::Random(2), 12),
+        ];
+
+        for (policy, misses) in expected.iter() {
+            let dram = Rc::new(RefCell::new(DRAM::new(0)));
+            let mut cache = NWayCache::new_with_policy(0, 1, 2, dram, *policy)
+                .expect("failed to create cache");
+
+            for addr in pattern.iter() {
+                match addr {
+                    Some(addr) => { cache.get(*addr).unwrap("cache get"); },
+                    None => { cache.invalidate().unwrap("cache invalidate"); },
+                };
+            }
+
+            assert_eq!(cache.stats().misses, *misses,
+                       "{:?} misses == expected", policy);
+            assert_eq!(cache.stats().hits, accesses - *misses,
+                       "{:?} hits == expected", policy);
+        }
+    }
+
+    /// Tests that a set associative cache without sets or ways is rejected.
+    #[test]
+    fn test_nway_cache_empty() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+
+        assert!(NWayCache::new(0, 0, 2, dram.clone()).is_err(), "no sets");
+        assert!(NWayCache::new(0, 2, 0, dram.clone()).is_err(), "no ways");
+        assert!(NWayCache::new(0, 1, 1, dram).is_ok());
+    }
+
+    /// Tests that DMCache.valid_addresses_sorted returns valid lines in
+    /// ascending address order.
+    #[test]
+    fn test_dmcache_valid_addresses_sorted() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let mut cache = DMCache::new(0, 16, dram);
+
+        for addr in [37, 2, 20, 9, 1].iter() {
+            cache.set(*addr, addr * 10).unwrap("cache set");
+        }
+
+        assert_eq!(cache.valid_addresses_sorted(),
+                   vec![(1, 10), (2, 20), (9, 90), (20, 200), (37, 370)]);
+    }
+
+    /// Tests that a forced dirty line is written back to its own address when a
+    /// conflicting address is accessed.
+    #[test]
+    fn test_dmcache_force_line_eviction() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let mut cache = DMCache::new(0, 16, dram.clone());
+
+        // Line 3 holds address 5 * 16 + 3 = 83
+        cache.force_line(3, 5, 1234, true, true);
+        assert_eq!(cache.get(83), SimResult::Wait(0, 1234));
+        assert_eq!(dram.borrow_mut().get(83), SimResult::Wait(0, 0),
+                   "forced line should not be in DRAM yet");
+
+        // Address 99 = 6 * 16 + 3 conflicts with line 3
+        cache.set(99, 42).unwrap("cache set");
+        assert_eq!(dram.borrow_mut().get(83), SimResult::Wait(0, 1234),
+                   "forced line should be written back");
+        assert_eq!(dram.borrow_mut().get(99), SimResult::Wait(0, 0),
+                   "new line should only be in the cache");
+    }
+
+    /// Tests that a cache snapshot reflects a written line.
+    #[test]
+    fn test_cache_snapshot() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let mut cache = DMCache::new(0, 16, dram.clone());
+
+        // 37 = 2 * 16 + 5
+        cache.set(37, 1234).unwrap("cache set");
+
+        let snapshot = cache.snapshot();
+        assert_eq!(snapshot.lines.len(), 16);
+        assert_eq!(snapshot.lines[5], CacheLineSnapshot{
+            index: 5,
+            way: 0,
+            tag: 2,
+            data: 1234,
+            valid: true,
+            dirty: true,
+            address: 37,
+        });
+        assert_eq!(snapshot.lines.iter().filter(|l| l.valid).count(), 1);
+
+        let mut nway = NWayCache::new(0, 4, 2, dram).expect("failed to create cache");
+        nway.set(37, 1234).unwrap("nway cache set");
+
+        // 37 = 9 * 4 + 1
+        let valid: Vec<CacheLineSnapshot> = nway.snapshot().lines.into_iter()
+            .filter(|l| l.valid)
+            .collect();
+        assert_eq!(valid.len(), 1);
+        assert_eq!((valid[0].index, valid[0].tag, valid[0].address),
+                   (1, 9, 37));
+    }
+
+    /// Tests that replaying a recorded trace through a larger cache results in
+    /// fewer misses.
+    #[test]
+    fn test_replay_trace() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let mut recorder = AccessRecorder::new(dram);
+
+        // Loop over 8 addresses 4 times
+        for _i in 0..4 {
+            for addr in 0..8 {
+                recorder.get(addr).unwrap("recorder get");
+            }
+        }
+        recorder.set(3, 1).unwrap("recorder set");
+
+        assert_eq!(recorder.trace.len(), 33);
+        assert_eq!(recorder.trace[32], (AccessKind::Write, 3));
+
+        let small = replay_trace(&recorder.trace, &CacheConfig{
+            num_sets: 4,
+            ways: 1,
+            policy: ReplacementPolicy::RoundRobin,
+        }).expect("failed to replay small");
+        let large = replay_trace(&recorder.trace, &CacheConfig{
+            num_sets: 8,
+            ways: 1,
+            policy: ReplacementPolicy::RoundRobin,
+        }).expect("failed to replay large");
+
+        assert_eq!(small, CacheStats{ hits: 0, misses: 33 });
+        assert_eq!(large, CacheStats{ hits: 25, misses: 8 });
+        assert!(large.misses < small.misses);
+    }
+
+    /// Tests that the set associative cache annotates valid lines with their
+    /// set and way, and describes every way of an address's set.
+    #[test]
+    fn test_nway_cache_inspect() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let mut cache = NWayCache::new(0, 2, 2, dram).expect("failed to create cache");
+
+        // Addresses 0 and 2 share set 0, 3 is in set 1
+        cache.get(0).unwrap("get 0");
+        cache.set(2, 7).unwrap("set 2");
+        cache.get(3).unwrap("get 3");
+
+        let aliases = cache.inspect_valid_aliases();
+        assert_eq!(aliases.len(), 3);
+        assert_eq!(aliases[&0], "#0.0 [0]");
+        assert_eq!(aliases[&2], "#0.1 [1] d");
+        assert_eq!(aliases[&3], "#1.0 [1]");
+
+        assert_eq!(cache.inspect_address_txt(2), "Set  : 0
+Way 0: tag=0 data=0 valid=true dirty=false
+Way 1: tag=1 data=7 valid=true dirty=true");
+        assert_eq!(cache.inspect()[&2], 7);
+    }
+
+    /// Tests that NWayCache.inspect skips invalid ways, which would otherwise
+    /// replace the valid line cached for address 0.
+    #[test]
+    fn test_nway_cache_inspect_skips_invalid() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        dram.borrow_mut().set(0, 5).unwrap("dram set 0");
+        let mut cache = NWayCache::new(0, 2, 2, dram).expect("failed to create cache");
+
+        cache.get(0).unwrap("get 0");
+
+        let contents = cache.inspect();
+        assert_eq!(contents.len(), 1);
+        assert_eq!(contents[&0], 5);
+    }
+
+    /// Tests that peek and poke read and change the cached copy of an address
+    /// when the cache holds it and the memory below otherwise, without
+    /// counting accesses or changing line flags.
+    #[test]
+    fn test_dmcache_peek_poke() {
+        let dram = Rc::new(RefCell::new(DRAM::new(10)));
+        dram.borrow_mut().set(0, 5).unwrap("dram set 0");
+        dram.borrow_mut().set(1, 6).unwrap("dram set 1");
+        let mut cache = DMCache::new(1, 2, dram.clone());
+        cache.get(0).unwrap("get 0");
+        let stats = cache.stats();
+
+        assert_eq!(cache.peek(0), SimResult::Wait(0, 5));
+        assert_eq!(cache.peek(1), SimResult::Wait(0, 6));
+
+        cache.poke(0, 7).unwrap("poke cached");
+        cache.poke(1, 8).unwrap("poke not cached");
+
+        assert_eq!(cache.stats(), stats, "no accesses counted");
+        assert_eq!(cache.coherence_state(0), CoherenceState::Shared, "line still clean");
+        assert_eq!(cache.peek(0), SimResult::Wait(0, 7));
+        assert_eq!(dram.borrow().peek(0), SimResult::Wait(0, 5), "DRAM copy unchanged");
+        assert_eq!(dram.borrow().peek(1), SimResult::Wait(0, 8));
+    }
+
+    /// Tests that flushing the top cache of a two level hierarchy writes
+    /// modified values all the way down to DRAM.
+    #[test]
+    fn test_cache_flush_cascades() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let l2 = Rc::new(RefCell::new(DMCache::new(0, 4, dram.clone())));
+        let mut l1 = DMCache::new(0, 2, l2.clone());
+
+        l1.set(1, 42).unwrap("set l1");
+        l1.flush().unwrap("flush l1");
+        assert_eq!(dram.borrow().peek(1), SimResult::Wait(0, 42));
+
+        let l2 = Rc::new(RefCell::new(DMCache::new(0, 4, dram.clone())));
+        let mut l1 = NWayCache::new(0, 1, 2, l2.clone()).expect("failed to create cache");
+
+        l1.set(2, 43).unwrap("set nway l1");
+        l1.flush().unwrap("flush nway l1");
+        assert_eq!(dram.borrow().peek(2), SimResult::Wait(0, 43));
+    }
+
+    /// Tests that with the LRU policy a hit makes a line the most recently
+    /// used, so the other line in the set is evicted next.
+    #[test]
+    fn test_lru_hit_updates_recency() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let mut cache = NWayCache::new_with_policy(0, 1, 2, dram,
+                                                   ReplacementPolicy::Lru)
+            .expect("failed to create cache");
+
+        cache.get(0).unwrap("get 0");
+        cache.get(1).unwrap("get 1");
+        let aliases = cache.inspect_valid_aliases_with_recency();
+        assert_eq!(aliases[&0], "#0.0 [0] r1");
+        assert_eq!(aliases[&1], "#0.1 [1] r0");
+
+        // The hit makes 0 the most recently used line
+        cache.get(0).unwrap("get 0 again");
+        let aliases = cache.inspect_valid_aliases_with_recency();
+        assert_eq!(aliases[&0], "#0.0 [0] r0");
+        assert_eq!(aliases[&1], "#0.1 [1] r1");
+
+        // 1 is evicted instead of 0
+        cache.set(2, 5).unwrap("set 2");
+        let aliases = cache.inspect_valid_aliases_with_recency();
+        assert_eq!(aliases.len(), 2);
+        assert_eq!(aliases[&0], "#0.0 [0] r1");
+        assert_eq!(aliases[&2], "#0.1 [2] d r0");
+        assert_eq!(cache.stats(), CacheStats{ hits: 1, misses: 3 });
+    }
+
+    /// Tests that a write through store reaches base immediately, adds base's
+    /// delay, and leaves the line clean so eviction does not write it again.
+    #[test]
+    fn test_dmcache_write_through() {
+        let dram = Rc::new(RefCell::new(DRAM::new(5)));
+        let mut cache = DMCache::new_with_policy(1, 4, dram.clone(), true);
+
+        assert_eq!(cache.set(2, 42), SimResult::Wait(1 + 5, ()));
+        let (_wait, value) = dram.borrow_mut().get(2).unwrap("get base value");
+        assert_eq!(value, 42);
+        assert_eq!(cache.coherence_state(2), CoherenceState::Shared);
+
+        // A hit is written through too
+        assert_eq!(cache.set(2, 43), SimResult::Wait(1 + 5, ()));
+        let (_wait, value) = dram.borrow_mut().get(2).unwrap("get base value");
+        assert_eq!(value, 43);
+
+        // Evicting the clean line only reads the new line
+        assert_eq!(cache.get(6), SimResult::Wait(1 + 5, 0));
+
+        let mut write_back = DMCache::new(1, 4, dram.clone());
+        assert_eq!(write_back.set(3, 7), SimResult::Wait(1, ()));
+        let (_wait, value) = dram.borrow_mut().get(3).unwrap("get base value");
+        assert_eq!(value, 0);
+    }
+
+    /// Tests that a translated access reaches the mapped physical address,
+    /// that a TLB miss adds the penalty and a hit does not, and that an
+    /// unmapped page faults.
+    #[test]
+    fn test_translating_memory() {
+        const PENALTY: u16 = 20;
+        
+        let dram = Rc::new(RefCell::new(DRAM::new(3)));
+        // Pages of 16 words
+        let mut tm = TranslatingMemory::new(4, 2, PENALTY, dram.clone());
+        tm.map_page(1, 5);
+
+        // Virtual 0x13 is offset 3 in virtual page 1, physical page 5
+        assert_eq!(tm.set(0x13, 42), SimResult::Wait(PENALTY + 3, ()));
+        let (_wait, value) = dram.borrow_mut().get(0x53).unwrap("get physical");
+        assert_eq!(value, 42);
+
+        assert_eq!(tm.get(0x1F), SimResult::Wait(3, 0));
+        assert_eq!(tm.get(0x13), SimResult::Wait(3, 42));
+        assert_eq!(tm.tlb_stats(), CacheStats{ hits: 2, misses: 1 });
+
+        match tm.get(0x23) {
+            SimResult::Err(e) => assert!(e.contains("page fault"), "error: {}", e),
+            SimResult::Wait(_w, v) => panic!("unmapped access returned {}", v),
+        };
+    }
+
+    /// Tests that alternating reads of two virtual addresses which alias one
+    /// physical address hit in a physically indexed cache after the first
+    /// read, but keep missing in a virtually indexed cache where the aliases
+    /// map to the same line with different tags. The virtually indexed stack
+    /// only translates misses.
+    #[test]
+    fn test_translation_order_aliasing() {
+        let mut latencies = vec![];
+        for order in &[TranslationOrder::PhysicallyIndexed, TranslationOrder::VirtuallyIndexed] {
+            let dram = Rc::new(RefCell::new(DRAM::new(3)));
+            dram.borrow_mut().set(0x53, 42).unwrap("set physical");
+
+            // Pages of 16 words, virtual pages 1 and 2 both map to page 5
+            let stack = translated_cache(&TranslationConfig{
+                order: *order,
+                page_bits: 4,
+                tlb_entries: 4,
+                tlb_miss_penalty: 20,
+                cache_delay: 1,
+                cache_lines: 16,
+            }, dram);
+            stack.tlb.borrow_mut().map_page(1, 5);
+            stack.tlb.borrow_mut().map_page(2, 5);
+
+            let mut latency: u32 = 0;
+            for address in &[0x13, 0x23, 0x13, 0x23] {
+                let (wait, value) = stack.memory.borrow_mut().get(*address)
+                    .unwrap("get alias");
+                assert_eq!(value, 42, "{:?} address={:#x}", order, address);
+                latency += wait as u32;
+            }
+            latencies.push(latency);
+
+            let (cache_stats, tlb_stats) = (stack.cache.borrow().stats(),
+                                            stack.tlb.borrow().tlb_stats());
+            match order {
+                TranslationOrder::PhysicallyIndexed => {
+                    assert_eq!(cache_stats, CacheStats{ hits: 3, misses: 1 });
+                    assert_eq!(tlb_stats, CacheStats{ hits: 2, misses: 2 });
+                },
+                TranslationOrder::VirtuallyIndexed => {
+                    assert_eq!(cache_stats, CacheStats{ hits: 0, misses: 4 });
+                    assert_eq!(tlb_stats, CacheStats{ hits: 2, misses: 2 });
+                },
+            };
+        }
+
+        assert!(latencies[0] < latencies[1],
+                "latencies (physically, virtually indexed): {:?}", latencies);
+    }
+
+    /// Tests that get_idx_address rebuilds an address from its index and tag.
+    /// The index holds the low idx_bits of the line number and the tag the
+    /// rest, so with 16 lines address 37 is index 5 and tag 2.
+    #[test]
+    fn test_dmcache_idx_address() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let cache = DMCache::new(0, 16, dram.clone());
+
+        assert_eq!(cache.get_address_index(37), 5);
+        assert_eq!(cache.get_address_tag(37), 2);
+        assert_eq!(cache.get_idx_address(5, 2), 37);
+
+        let cache = DMCache::new_with_block_size(0, 4, 2, dram)
+            .expect("failed to create cache");
+        for addr in [0, 6, 37, 1000].iter() {
+            let line_start = addr - addr % 2;
+            assert_eq!(cache.get_idx_address(cache.get_address_index(*addr),
+                                             cache.get_address_tag(*addr)),
+                       line_start, "address {}", addr);
+        }
+    }
+
+    /// Tests that a read which evicts a dirty line writes the line to its own
+    /// address in base, not the address being read, also when the index and
+    /// tag bits of the old address differ from the read address.
+    #[test]
+    fn test_dmcache_get_evicts_to_old_address() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        dram.borrow_mut().set(6, 9).unwrap("set conflicting value");
+        let mut cache = DMCache::new(0, 4, dram.clone());
+
+        // 2 and 6 share line 2
+        cache.set(2, 42).unwrap("set dirty line");
+        assert_eq!(cache.get(6), SimResult::Wait(0, 9));
+
+        let (_wait, old) = dram.borrow_mut().get(2).unwrap("get evicted value");
+        let (_wait, read) = dram.borrow_mut().get(6).unwrap("get read value");
+        assert_eq!(old, 42);
+        assert_eq!(read, 9);
+
+        // The old address is rebuilt from index 5 and tag 2, see
+        // test_dmcache_idx_address
+        let mut cache = DMCache::new(0, 16, dram.clone());
+        cache.set(37, 43).unwrap("set dirty line");
+        cache.get(53).unwrap("get conflicting address");
+        assert_eq!(dram.borrow().peek(37), SimResult::Wait(0, 43));
+    }
+
+    /// Tests that a miss fills a whole block, so neighbouring addresses hit,
+    /// and that evicting a dirty block writes every word back.
+    #[test]
+    fn test_dmcache_block_size() {
+        let dram = Rc::new(RefCell::new(DRAM::new(2)));
+        for addr in 8..12 {
+            dram.borrow_mut().set(addr, addr * 10).unwrap("dram set");
+        }
+
+        assert!(DMCache::new_with_block_size(1, 4, 3, dram.clone()).is_err());
+        assert!(DMCache::new_with_block_size(1, 4, 0, dram.clone()).is_err());
+
+        let mut cache = DMCache::new_with_block_size(1, 4, 4, dram.clone())
+            .expect("failed to create cache");
+
+        // 1 cycle for the cache and 2 for each of the 4 words
+        assert_eq!(cache.get(9), SimResult::Wait(9, 90));
+        for addr in 8..12 {
+            assert_eq!(cache.get(addr), SimResult::Wait(1, addr * 10));
+        }
+        assert_eq!(cache.stats().misses, 1);
+        assert_eq!(cache.stats().hits, 4);
+
+        let inspect = cache.inspect();
+        assert_eq!(inspect.len(), 16);
+        assert_eq!(inspect.get(&11), Some(&110));
+
+        // 8 = 0 * 16 + 2 * 4 and 40 = 2 * 16 + 2 * 4 share line 2
+        cache.set(10, 7).unwrap("cache set");
+        cache.get(40).unwrap("cache get");
+        assert_eq!(dram.borrow_mut().get(10), SimResult::Wait(2, 7));
+        assert_eq!(dram.borrow_mut().get(11), SimResult::Wait(2, 110));
+    }
+
+    /// Tests that a cache writing one word of a line another cache modified
+    /// keeps the other cache's modified words.
+    #[test]
+    fn test_coherence_invalidate_keeps_modified_words() {
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let bus = Rc::new(RefCell::new(CoherenceBus::new()));
+        let caches: Vec<Rc<RefCell<DMCache>>> = (0..2)
+            .map(|_i| {
+                let cache = DMCache::new_with_block_size(0, 4, 2, dram.clone())
+                    .expect("failed to create cache");
+                let cache = Rc::new(RefCell::new(cache));
+                CoherenceBus::connect(&bus, &cache);
+                cache
+            })
+            .collect();
+
+        caches[1].borrow_mut().set(1, 77).unwrap("cache 1 set");
+        caches[0].borrow_mut().set(0, 5).unwrap("cache 0 set");
+
+        assert_eq!(caches[1].borrow().coherence_state(1), CoherenceState::Invalid);
+        for cache in caches.iter() {
+            let (_wait, value) = cache.borrow_mut().get(1).unwrap("get modified word");
+            assert_eq!(value, 77);
+        }
+        let (_wait, value) = caches[0].borrow_mut().get(0).unwrap("get written word");
+        assert_eq!(value, 5);
+    }
+
+    /// Tests that the average memory access time is the hit time plus the
+    /// miss rate times the miss penalty.
+    #[test]
+    fn test_dmcache_amat() {
+        const HIT_TIME: u16 = 2;
+        const MISS_PENALTY: u16 = 10;
+
+        let dram = Rc::new(RefCell::new(DRAM::new(MISS_PENALTY)));
+        let mut cache = DMCache::new(HIT_TIME, 16, dram);
+        assert_eq!(cache.amat(), HIT_TIME as f64);
+
+        // 1 miss and 3 hits
+        for _i in 0..4 {
+            cache.get(5).unwrap("cache get");
+        }
+        assert_eq!(cache.stats().misses, 1);
+        assert_eq!(cache.miss_penalty(), MISS_PENALTY as f64);
+        assert_eq!(cache.amat(), HIT_TIME as f64 + 0.25 * MISS_PENALTY as f64);
+    }
+
+    /// Tests that fill_pattern sets each address in the region to the value
+    /// the pattern gives it, stopping at the last address.
+    #[test]
+    fn test_dram_fill_pattern() {
+        let mut dram = DRAM::new(0);
+        dram.fill_pattern(10, 4, |addr| addr * 2);
+
+        let mut contents: Vec<(u32, u32)> = dram.inspect().into_iter().collect();
+        contents.sort();
+        assert_eq!(contents, vec![(10, 20), (11, 22), (12, 24), (13, 26)]);
+
+        // Stops at the last address
+        let mut dram = DRAM::new(0);
+        dram.fill_pattern(u32::MAX - 1, 4, |_addr| 1);
+        assert_eq!(dram.inspect().len(), 2);
+        assert_eq!(dram.peek(u32::MAX), SimResult::Wait(0, 1));
+
+        dram.fill_pattern(0, 0, |_addr| 1);
+        assert_eq!(dram.inspect().len(), 2);
+    }
 }
@@ -0,0 +1,245 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::memory::{Memory,DMCache,CoherenceBus};
+use crate::control_unit::{ControlUnit,StageLatencies,Status};
+
+/// Control units which share one memory and are stepped together, one step of
+/// each core at a time. The shared memory is usually a last level cache in
+/// front of DRAM. Cores either access the shared memory directly or through
+/// private caches kept coherent by a CoherenceBus.
+///
+/// Cores are only in cycle lockstep with the pipeline enabled, where a step
+/// is one cycle. Without the pipeline a step runs a whole instruction, so
+/// cores advance by an instruction each and their cycle counts drift apart
+/// when their instructions take different numbers of cycles.
+pub struct MultiCore {
+    /// Cores in the order they are stepped.
+    pub cores: Vec<ControlUnit>,
+
+    /// Private cache of each core, in core order. Empty if cores access the
+    /// shared memory directly.
+    pub caches: Vec<Rc<RefCell<DMCache>>>,
+
+    /// Indicates which cores have not finished running their program.
+    running: Vec<bool>,
+}
+
+impl MultiCore {
+    /// Creates num_cores control units which access memory directly, with their
+    /// own caches disabled.
+    pub fn new(num_cores: usize, memory: Rc<RefCell<dyn Memory<u32, u32>>>) -> MultiCore {
+        let cores = (0..num_cores)
+            .map(|_i| {
+                let mut cu = ControlUnit::new(memory.clone(), memory.clone(), StageLatencies::default());
+                cu.cache_enabled = false;
+                cu
+            })
+            .collect();
+
+        MultiCore{
+            cores,
+            caches: Vec::new(),
+            running: vec![true; num_cores],
+        }
+    }
+
+    /// Creates num_cores control units which each access memory through a
+    /// private direct mapped cache. The caches are connected to one coherence
+    /// bus.
+    pub fn new_coherent(num_cores: usize, memory: Rc<RefCell<dyn Memory<u32, u32>>>,
+                        cache_delay: u16, cache_lines: usize) -> MultiCore {
+        let bus = Rc::new(RefCell::new(CoherenceBus::new()));
+        let mut mc = MultiCore::new(0, memory.clone());
+
+        for _i in 0..num_cores {
+            let cache = Rc::new(RefCell::new(DMCache::new(cache_delay, cache_lines,
+                                                          memory.clone())));
+            CoherenceBus::connect(&bus, &cache);
+
+            let mut cu = ControlUnit::new(memory.clone(), cache.clone(), StageLatencies::default());
+            cu.cache_enabled = true;
+
+            mc.cores.push(cu);
+            mc.caches.push(cache);
+            mc.running.push(true);
+        }
+
+        mc
+    }
+
+    /// Steps each core which is still running once, see ControlUnit::step().
+    /// Returns true if any core is still running afterwards. Stops at the
+    /// first core which fails.
+    ///
+    /// Cores are always stepped in index order, and each core finishes its
+    /// step before the next core starts. So memory accesses made in the same
+    /// step happen in core order: when several cores write an address in one
+    /// step the core with the highest index wins, and a core reading an
+    /// address sees the writes lower indexed cores made that step. A step is a
+    /// cycle with the pipeline and a whole instruction without it.
+    pub fn step_all(&mut self) -> Result<bool, String> {
+        for (i, cu) in self.cores.iter_mut().enumerate() {
+            if !self.running[i] {
+                continue;
+            }
+
+            self.running[i] = match cu.step() {
+                Ok(outcome) => outcome != Status::Halted,
+                Err(e) => return Err(format!("Core {} failed to step: {}", i, e)),
+            };
+        }
+
+        Ok(self.running.iter().any(|r| *r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bit_field::BitField;
+    use crate::memory::{DRAM,PC,CoherenceState};
+    use crate::instructions::{InstructionT,MemoryOp,ControlOp};
+    use crate::encoding::set_control_op;
+
+    /// Returns a memory instruction which accesses the address in a register.
+    fn memory_op(op: MemoryOp, addr_reg: u32, value_reg: u32) -> u32 {
+        let mut word: u32 = 0;
+        word.set_bits(5..=6, InstructionT::Memory.value());
+
+        match &op {
+            MemoryOp::LoadRD => {
+                word.set_bits(10..=14, value_reg);
+                word.set_bits(15..=19, addr_reg);
+            },
+            _ => {
+                word.set_bits(10..=14, addr_reg);
+                word.set_bits(15..=19, value_reg);
+            },
+        };
+        word.set_bits(7..=9, op.value());
+
+        word
+    }
+
+    /// Returns a no operation instruction.
+    fn noop() -> u32 {
+        let mut word: u32 = 0;
+        set_control_op(&mut word, ControlOp::Noop.value());
+        word
+    }
+
+    /// Tests that a value stored by one core is loaded by another core
+    /// through the shared cache.
+    #[test]
+    fn test_shared_cache() {
+        const ADDR: u32 = 100;
+        const CORE1_START: u32 = 10;
+
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let program = [
+            (0, memory_op(MemoryOp::StoreRD, 2, 1)),
+            (1, 0),
+            (CORE1_START, noop()),
+            (CORE1_START + 1, noop()),
+            (CORE1_START + 2, memory_op(MemoryOp::LoadRD, 2, 3)),
+            (CORE1_START + 3, 0),
+        ];
+        for (addr, word) in program.iter() {
+            dram.borrow_mut().set(*addr, *word).unwrap("set program");
+        }
+        let cache = Rc::new(RefCell::new(DMCache::new(1, 16, dram.clone())));
+
+        let mut mc = MultiCore::new(2, cache);
+        for cu in mc.cores.iter_mut() {
+            cu.pipeline_enabled = false;
+            cu.registers[2] = ADDR;
+        }
+        mc.cores[0].registers[1] = 42;
+        mc.cores[1].registers[PC] = CORE1_START;
+
+        while mc.step_all().expect("failed to step cores") {}
+
+        assert_eq!(mc.cores[1].registers[3], 42);
+    }
+
+    /// Tests that after one core writes a line another core holds, the other
+    /// core's next read misses and loads the written value.
+    #[test]
+    fn test_coherence_miss() {
+        const ADDR: u32 = 100;
+        const CORE1_START: u32 = 10;
+
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let program = [
+            (0, noop()),
+            (1, memory_op(MemoryOp::StoreRD, 2, 1)),
+            (2, 0),
+            (CORE1_START, memory_op(MemoryOp::LoadRD, 2, 3)),
+            (CORE1_START + 1, noop()),
+            (CORE1_START + 2, noop()),
+            (CORE1_START + 3, memory_op(MemoryOp::LoadRD, 2, 4)),
+            (CORE1_START + 4, 0),
+        ];
+        for (addr, word) in program.iter() {
+            dram.borrow_mut().set(*addr, *word).unwrap("set program");
+        }
+
+        let mut mc = MultiCore::new_coherent(2, dram.clone(), 1, 16);
+        for cu in mc.cores.iter_mut() {
+            cu.pipeline_enabled = false;
+            cu.registers[2] = ADDR;
+        }
+        mc.cores[0].registers[1] = 42;
+        mc.cores[1].registers[PC] = CORE1_START;
+
+        // Core 1 reads the line, then core 0 writes it
+        mc.step_all().expect("failed to step cores");
+        assert_eq!(mc.caches[1].borrow().coherence_state(ADDR), CoherenceState::Shared);
+        mc.step_all().expect("failed to step cores");
+        assert_eq!(mc.caches[0].borrow().coherence_state(ADDR), CoherenceState::Modified);
+        assert_eq!(mc.caches[1].borrow().coherence_state(ADDR), CoherenceState::Invalid);
+
+        while mc.step_all().expect("failed to step cores") {}
+
+        assert_eq!(mc.cores[1].registers[3], 0);
+        assert_eq!(mc.cores[1].registers[4], 42);
+        assert_eq!(mc.caches[1].borrow().coherence_misses(), 1);
+        assert_eq!(mc.caches[0].borrow().coherence_misses(), 0);
+        assert_eq!(mc.caches[0].borrow().coherence_state(ADDR), CoherenceState::Shared);
+    }
+
+    /// Tests that when both cores write an address in the same step the
+    /// write of the core with the highest index is kept.
+    #[test]
+    fn test_same_step_write_order() {
+        const ADDR: u32 = 100;
+        const CORE1_START: u32 = 10;
+
+        let dram = Rc::new(RefCell::new(DRAM::new(0)));
+        let program = [
+            (0, memory_op(MemoryOp::StoreRD, 2, 1)),
+            (1, 0),
+            (CORE1_START, memory_op(MemoryOp::StoreRD, 2, 1)),
+            (CORE1_START + 1, 0),
+        ];
+        for (addr, word) in program.iter() {
+            dram.borrow_mut().set(*addr, *word).unwrap("set program");
+        }
+
+        for pipeline_enabled in &[false, true] {
+            let mut mc = MultiCore::new(2, dram.clone());
+            for (i, cu) in mc.cores.iter_mut().enumerate() {
+                cu.pipeline_enabled = *pipeline_enabled;
+                cu.registers[1] = 1 + i as u32;
+                cu.registers[2] = ADDR;
+            }
+            mc.cores[1].registers[PC] = CORE1_START;
+
+            while mc.step_all().expect("failed to step cores") {}
+
+            let (_wait, value) = dram.borrow_mut().get(ADDR).unwrap("get value");
+            assert_eq!(value, 2, "pipeline={}", pipeline_enabled);
+        }
+    }
+}
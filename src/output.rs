@@ -0,0 +1,74 @@
+#[cfg(target_arch = "wasm32")] use web_sys::console;
+#[cfg(target_arch = "wasm32")] use wasm_bindgen::JsValue;
+
+/// Output is where the simulator writes text meant for the user. Status messages
+/// are always shown, debug messages are only shown by outputs which want them.
+pub trait Output {
+    /// Write a status message.
+    fn write(&mut self, msg: &str);
+
+    /// Write a debug message. Ignored by default.
+    fn debug(&mut self, _msg: &str) {}
+
+    /// Write a warning about a likely bug in the running program. Written as a
+    /// status message prefixed with "warning: " by default.
+    fn warn(&mut self, msg: &str) {
+        self.write(&format!("warning: {}", msg));
+    }
+}
+
+/// Writes status messages to stdout. Debug messages are ignored.
+pub struct StdoutOutput {}
+
+impl Output for StdoutOutput {
+    fn write(&mut self, msg: &str) {
+        println!("{}", msg);
+    }
+}
+
+/// Writes status and debug messages to the browser console. The console only
+/// exists when compiled to web assembly, so nothing is written otherwise.
+pub struct WebConsoleOutput {}
+
+impl WebConsoleOutput {
+    /// Logs a message to the browser console.
+    fn log(&self, msg: &str) {
+        #[cfg(target_arch = "wasm32")]
+        console::log_1(&JsValue::from_serde(&msg).unwrap());
+
+        #[cfg(not(target_arch = "wasm32"))]
+        let _ = msg;
+    }
+}
+
+impl Output for WebConsoleOutput {
+    fn write(&mut self, msg: &str) {
+        self.log(msg);
+    }
+
+    fn debug(&mut self, msg: &str) {
+        self.log(msg);
+    }
+}
+
+/// Keeps status messages in memory so they can be inspected later. Debug
+/// messages are ignored.
+pub struct MemoryOutput {
+    /// Status messages in the order they were written
+    pub lines: Vec<String>,
+}
+
+impl MemoryOutput {
+    /// Creates an empty MemoryOutput.
+    pub fn new() -> MemoryOutput {
+        MemoryOutput{
+            lines: Vec::new(),
+        }
+    }
+}
+
+impl Output for MemoryOutput {
+    fn write(&mut self, msg: &str) {
+        self.lines.push(msg.to_string());
+    }
+}
@@ -30,4 +30,51 @@ impl<D, E: Display> SimResult<D, E> {
             SimResult::Wait(wait, _v) => wait == 0,
         }
     }
+
+    /// Sequences two operations. Returns the value of next with the wait cycles
+    /// of both added. If self is an error it is returned, otherwise if next is
+    /// an error it is returned.
+    pub fn and<U>(self, next: SimResult<U, E>) -> SimResult<U, E> {
+        match self {
+            SimResult::Err(e) => SimResult::Err(e),
+            SimResult::Wait(wait, _v) => match next {
+                SimResult::Err(e) => SimResult::Err(e),
+                SimResult::Wait(next_wait, v) =>
+                    SimResult::Wait(wait.saturating_add(next_wait), v),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that and() adds wait cycles and returns the second value.
+    #[test]
+    fn test_and_wait() {
+        let first: SimResult<u32, String> = SimResult::Wait(3, 1);
+        assert_eq!(first.and(SimResult::Wait(4, "second")),
+                   SimResult::Wait(7, "second"));
+
+        let first: SimResult<u32, String> = SimResult::Wait(u16::MAX, 1);
+        assert_eq!(first.and(SimResult::Wait(1, ())),
+                   SimResult::Wait(u16::MAX, ()));
+    }
+
+    /// Tests that and() returns the first error.
+    #[test]
+    fn test_and_error() {
+        let first: SimResult<u32, String> = SimResult::Err("first".to_string());
+        assert_eq!(first.and(SimResult::<(), String>::Err("second".to_string())),
+                   SimResult::Err("first".to_string()));
+
+        let first: SimResult<u32, String> = SimResult::Err("first".to_string());
+        assert_eq!(first.and(SimResult::Wait(1, ())),
+                   SimResult::Err("first".to_string()));
+
+        let first: SimResult<u32, String> = SimResult::Wait(1, 1);
+        assert_eq!(first.and(SimResult::<(), String>::Err("second".to_string())),
+                   SimResult::Err("second".to_string()));
+    }
 }
@@ -1,48 +1,159 @@
 use std::env;
+use std::process;
+use std::io::{self,BufRead};
+use std::sync::mpsc::{self,Receiver,TryRecvError};
+use std::thread;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 mod result;
 mod memory;
 mod instructions;
+mod encoding;
 mod control_unit;
+mod multicore;
+mod output;
 pub use crate::result::SimResult;
-pub use crate::memory::{Registers,Memory,InspectableMemory,DRAM,DMCache};
-pub use crate::instructions::Instruction;
-pub use crate::control_unit::ControlUnit;
+pub use crate::memory::{Registers,Memory,InspectableMemory,DRAM,DMCache,InputPort};
+pub use crate::instructions::{Instruction,InterruptCodes,expand_halfword};
+pub use crate::control_unit::{ControlUnit,StageLatencies,Status};
+pub use crate::output::{Output,StdoutOutput};
+
+/// Reads stdin one line at a time on a separate thread. Each line is sent as
+/// the key codes of its characters. The newline is sent as the ENTER interrupt
+/// code. The thread stops at the end of stdin, which disconnects the channel,
+/// or after sending a read error.
+fn read_stdin_keys() -> Receiver<Result<Vec<u32>, String>> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let keys = match line {
+                Ok(line) => {
+                    let mut keys: Vec<u32> = line.chars().map(|c| c as u32).collect();
+                    keys.push(InterruptCodes::ENTER.value() as u32);
+                    Ok(keys)
+                },
+                Err(e) => Err(format!("Failed to read stdin: {}", e)),
+            };
+            let failed = keys.is_err();
+
+            if tx.send(keys).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    rx
+}
+
+/// Prints an error and exits.
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+/// Prints how to run the simulator and exits. Each --break prints the
+/// processor status when execution reaches ADDR, then waits for a line on
+/// stdin before execution continues.
+/// --halfword-packed loads each word as two compressed instructions.
+fn exit_with_usage(program: &str) -> ! {
+    exit_with_error(&format!(
+        "Usage: {} PROGRAM [--no-pipeline] [--halfword-packed] [--break ADDR]...",
+        program));
+}
+
+/// Parses a decimal address, or a hexadecimal address prefixed with 0x.
+fn parse_address(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
 
 fn main() {
-    /*
     let args: Vec<_> = env::args().collect();
-    if args.len() > 2 {
-        panic!("Usage: {} [gui]", args[0]);
+    if args.len() < 2 {
+        exit_with_usage(&args[0]);
     }
 
-    // Run GUI
-    if args.len() == 2 && args[1] == "gui" {
-        panic!("No GUI implemented at the moment");
-    } else {
-        // Run text interface
-        let mut cu = ControlUnit::new();
-
-        match cu.load_memory_from_file("test-data/example-prog.bin") {
-            Err(e) => panic!("Failed to load initial control unit memory \
-                              from file: {}", e),
-            Ok(_v) => (),
-        }
-      
-        let mut program_running = true;
-
-        while program_running {
-            println!("====================");
-            match cu.step() {
-                Err(e) => panic!("Failed to run processor cycle: {}", e),
-                Ok(keep_running) => program_running = keep_running,
-            };
+    let mut pipeline_enabled = true;
+    let mut halfword_packed = false;
+    let mut breakpoints = Vec::new();
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--no-pipeline" => pipeline_enabled = false,
+            "--halfword-packed" => halfword_packed = true,
+            "--break" => match options.next().and_then(|addr| parse_address(addr)) {
+                Some(addr) => breakpoints.push(addr),
+                None => exit_with_usage(&args[0]),
+            },
+            _ => exit_with_usage(&args[0]),
+        };
+    }
 
-            println!("{}", cu);
-            if !program_running {
-                println!("Program ended");
-            }
+    let mut dram = DRAM::new(0);
+    if halfword_packed {
+        dram.set_halfword_packed(Some(expand_halfword));
+    }
+    if let Err(e) = dram.load_from_file(&args[1]) {
+        exit_with_error(&format!("Failed to load program into memory: {}", e));
+    }
+
+    let input = Rc::new(RefCell::new(InputPort::new(
+        Rc::new(RefCell::new(dram)))));
+
+    let mut cu = ControlUnit::new(input.clone(), input.clone(), StageLatencies::default());
+    cu.cache_enabled = false;
+    cu.pipeline_enabled = pipeline_enabled;
+    for addr in breakpoints {
+        cu.add_breakpoint(addr);
+    }
+    cu.output = Rc::new(RefCell::new(StdoutOutput{}));
+
+    let keys = read_stdin_keys();
+    let mut stdin_open = true;
+    let mut program_running = true;
+
+    while program_running {
+        // Feed any input typed since the last step to the program, until
+        // stdin ends
+        while stdin_open {
+            match keys.try_recv() {
+                Ok(Ok(line_keys)) => for key in line_keys {
+                    input.borrow_mut().push_key(key);
+                },
+                Ok(Err(e)) => exit_with_error(&e),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => stdin_open = false,
+            };
         }
+
+        match cu.step() {
+            Err(e) => exit_with_error(&format!("Failed to run processor cycle: {}", e)),
+            Ok(Status::Continue) => (),
+            Ok(Status::Halted) => program_running = false,
+            Ok(Status::BreakpointHit(addr)) => {
+                // Print the status and pause until a line is entered, the
+                // next step runs the instruction at the breakpoint. The line
+                // is not passed to the program. Once stdin has ended there is
+                // nothing to wait for.
+                let status = format!("Breakpoint hit at {:#06x}\n{}\n\
+                                      Press enter to continue", addr, cu);
+                cu.output.borrow_mut().write(&status);
+
+                if stdin_open {
+                    match keys.recv() {
+                        Ok(Ok(_line_keys)) => (),
+                        Ok(Err(e)) => exit_with_error(&e),
+                        Err(_e) => stdin_open = false,
+                    };
+                }
+            },
+        };
     }
-*/
+
+    let status = format!("{}", cu);
+    cu.output.borrow_mut().write(&status);
 }
@@ -19,13 +19,19 @@ use std::rc::Rc;
 mod result;
 mod memory;
 mod instructions;
+mod encoding;
 mod control_unit;
+mod multicore;
+mod output;
 mod assembler;
-use crate::control_unit::ControlUnit;
+use crate::control_unit::{ControlUnit,StageLatencies,Status};
 use crate::result::SimResult;
 use crate::memory::{DMCache,DRAM,Memory,InspectableMemory};
 use crate::assembler::Assembler;
 
+/// Disassembles instruction words, exported for the integration tests.
+pub use crate::instructions::disassemble;
+
 /// Run configuration which determines how programs run in the simulator.
 #[derive(Serialize,Deserialize)]
 pub struct RunConfig {
@@ -68,6 +74,9 @@ pub struct Simulator {
     /// at index 4. If the pipeline is not enabled the inner vector holds one
     /// element which represents the last instruction run.
     pipeline_statuses: Vec<Vec<Option<String>>>,
+
+    /// Number of cycles step_frame() advances the control unit.
+    cycles_per_frame: u32,
 }
 
 #[wasm_bindgen]
@@ -94,9 +103,10 @@ impl Simulator {
             l2_cache: l2_cache.clone(),
             l3_cache: l3_cache.clone(),
             dram: dram.clone(),
-            control_unit: ControlUnit::new(dram.clone(), l1_cache.clone()),
+            control_unit: ControlUnit::new(dram.clone(), l1_cache.clone(), StageLatencies::default()),
             assembler: Assembler::new(),
             pipeline_statuses: vec![],
+            cycles_per_frame: 1,
         }
     }
 
@@ -178,6 +188,24 @@ impl Simulator {
         self.control_unit.cache_enabled = run_config.cache_enabled;
     }
 
+    /// Returns the cycles each pipeline stage takes.
+    pub fn get_stage_latencies(&self) -> JsValue {
+        JsValue::from_serde(&self.control_unit.stage_latencies).unwrap()
+    }
+
+    /// Sets the cycles each pipeline stage takes. Fails if raw_latencies does
+    /// not hold a latency for every stage.
+    pub fn set_stage_latencies(&mut self, raw_latencies: JsValue) -> Result<(), JsValue> {
+        match raw_latencies.into_serde::<StageLatencies>() {
+            Err(e) => Err(JsValue::from_serde(
+                &format!("Invalid stage latencies: {}", e)).unwrap()),
+            Ok(latencies) => {
+                self.control_unit.stage_latencies = latencies;
+                Ok(())
+            },
+        }
+    }
+
     /// Returns the address and values in the cache.
     pub fn get_cache(&self) -> JsValue {
         JsValue::from_serde(&CachesInfo{
@@ -196,6 +224,15 @@ impl Simulator {
         }).unwrap()
     }
 
+    /// Returns the state of every line in each cache, L1 first.
+    pub fn get_cache_snapshots(&self) -> JsValue {
+        JsValue::from_serde(&vec![
+            self.l1_cache.borrow().snapshot(),
+            self.l2_cache.borrow().snapshot(),
+            self.l3_cache.borrow().snapshot(),
+        ]).unwrap()
+    }
+
     /// Returns addresses and values in DRAM. 
     pub fn get_dram(&self) -> JsValue {
         JsValue::from_serde(&self.dram.borrow().inspect()).unwrap()
@@ -227,31 +264,84 @@ impl Simulator {
         self.control_unit.cycle_count
     }
 
+    /// Returns the number of branches the branch predictor got wrong.
+    pub fn get_mispredictions(&self) -> u64 {
+        self.control_unit.mispredictions
+    }
+
     /// Step through one cycle of processor.
-    /// See return value of ControlUnit::step() for details on this methods
-    /// return value.
+    /// Returns true if the program is still running, see ControlUnit::step().
+    /// A step which stops at a breakpoint returns true.
     pub fn step(&mut self) -> Result<JsValue, JsValue> {
         match self.control_unit.step() {
             Err(e) => Err(JsValue::from_serde(&e).unwrap()),
-            Ok(done) => {
+            Ok(outcome) => {
                 self.pipeline_statuses.insert(0, self.mk_pipeline_statuses());
 
-                Ok(JsValue::from_serde(&done).unwrap())
+                Ok(JsValue::from_serde(&(outcome != Status::Halted)).unwrap())
             }
         }
     }
 
-    /// Steps through processor cycles until the program completes.
+    /// Sets the number of cycles each call to step_frame() advances the
+    /// control unit, which controls how fast an animated program runs.
+    pub fn set_cycles_per_frame(&mut self, n: u32) {
+        self.cycles_per_frame = n;
+    }
+
+    /// Steps through the number of cycles set by set_cycles_per_frame(), for
+    /// one rendered frame. Only the pipeline status after the last step is
+    /// recorded. Stops early at a breakpoint. Returns true if the program is
+    /// still running and did not stop at a breakpoint, so an animation pauses
+    /// at breakpoints. The next call continues past the breakpoint. See
+    /// ControlUnit::run_cycles().
+    pub fn step_frame(&mut self) -> Result<JsValue, JsValue> {
+        match self.control_unit.run_cycles(self.cycles_per_frame) {
+            Err(e) => Err(JsValue::from_serde(&e).unwrap()),
+            Ok(outcome) => {
+                self.pipeline_statuses.insert(0, self.mk_pipeline_statuses());
+
+                Ok(JsValue::from_serde(&(outcome == Status::Continue)).unwrap())
+            }
+        }
+    }
+
+    /// Undoes the last step. See ControlUnit::step_back() for details.
+    pub fn step_back(&mut self) -> Result<(), JsValue> {
+        match self.control_unit.step_back() {
+            Err(e) => Err(JsValue::from_serde(&e).unwrap()),
+            Ok(_v) => {
+                if !self.pipeline_statuses.is_empty() {
+                    self.pipeline_statuses.remove(0);
+                }
+
+                Ok(())
+            },
+        }
+    }
+
+    /// Makes stepping stop before the instruction at addr is fetched.
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        self.control_unit.add_breakpoint(addr);
+    }
+
+    /// Removes a breakpoint added by add_breakpoint().
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.control_unit.remove_breakpoint(addr);
+    }
+
+    /// Steps through processor cycles until the program completes or a
+    /// breakpoint is hit.
     pub fn finish_program(&mut self) -> Result<(), JsValue> {
         let mut program_running = self.control_unit.program_is_running();
 
         while (program_running) {
             match self.control_unit.step() {
                 Err(e) => return Err(JsValue::from_serde(&e).unwrap()),
-                Ok(done) => {
+                Ok(outcome) => {
                     self.pipeline_statuses.insert(0, self.mk_pipeline_statuses());
 
-                    program_running = done;
+                    program_running = outcome == Status::Continue;
                 }
             }
         }
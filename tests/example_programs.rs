@@ -0,0 +1,46 @@
+use std::fs;
+
+use wasmsimulator::disassemble;
+
+/// Most words read from an example program before giving up on finding the
+/// halt.
+const MAX_WORDS: usize = 1024;
+
+/// Halt instruction which ends every example program.
+const HALT: u32 = 0;
+
+/// Reads a program file into big endian words, the same way DRAM loads it.
+fn read_words(file_p: &str) -> Vec<u32> {
+    let bytes = fs::read(file_p)
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", file_p, e));
+
+    bytes.chunks(4)
+        .map(|chunk| {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u32::from_be_bytes(word)
+        })
+        .collect()
+}
+
+/// Tests that every word of the bundled example programs, up to the first
+/// halt, disassembles without a decode error. There is no example-prog.bin in
+/// test-data, instructions.bin is the bundled example program.
+#[test]
+fn test_example_programs_decode() {
+    for file_p in &["./test-data/instructions.bin",
+                    "./test-data/assembly.bin",
+                    "./test-data/simple-load.bin"] {
+        let words = read_words(file_p);
+
+        for (addr, word) in words.iter().take(MAX_WORDS).enumerate() {
+            if *word == HALT {
+                break;
+            }
+
+            if let Err(e) = disassemble(*word) {
+                panic!("{} word {} failed to decode: {}", file_p, addr, e);
+            }
+        }
+    }
+}
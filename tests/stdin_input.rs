@@ -0,0 +1,118 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead,BufReader,Write};
+use std::path::PathBuf;
+use std::process::{Command,Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Address of the memory mapped input status port.
+const INPUT_STATUS_ADDR: u32 = 0xFF00;
+
+/// Address of the memory mapped input data port.
+const INPUT_DATA_ADDR: u32 = 0xFF01;
+
+/// Key code for ENTER.
+const ENTER: u32 = 4;
+
+/// Encodes a LoadI instruction which loads the absolute address addr into
+/// dest from an instruction at address pc.
+fn load_immediate(pc: u32, dest: u32, addr: u32) -> u32 {
+    (2 << 5) | (1 << 7) | (dest << 10) | ((addr - pc - 1) << 15)
+}
+
+/// Encoding of a Noop instruction.
+const NOOP: u32 = 6 << 7;
+
+/// Writes program words to a temporary file named after name and returns
+/// its path.
+fn write_program(name: &str, program: &[u32]) -> PathBuf {
+    let program_p = env::temp_dir().join(
+        format!("{}-{}.bin", name, std::process::id()));
+    let bytes: Vec<u8> = program.iter()
+        .flat_map(|w| w.to_be_bytes().to_vec())
+        .collect();
+    fs::write(&program_p, bytes).expect("failed to write program");
+
+    program_p
+}
+
+/// Tests that the text simulator feeds lines from stdin to the input port.
+#[test]
+fn test_stdin_input_port() {
+    let program: Vec<u32> = vec![
+        // Noop, loop jumps here so the status load runs next
+        NOOP,
+        // R1 = [INPUT_STATUS_ADDR]
+        load_immediate(1, 1, INPUT_STATUS_ADDR),
+        // Comp R1 R0
+        (1 << 5) | (17 << 7) | (1 << 13),
+        // Jump to 0 if no input is waiting
+        2 | (2 << 7),
+        // R3 = [INPUT_DATA_ADDR]
+        load_immediate(4, 3, INPUT_DATA_ADDR),
+        // R4 = [INPUT_DATA_ADDR]
+        load_immediate(5, 4, INPUT_DATA_ADDR),
+        // Halt
+        0,
+    ];
+
+    let program_p = write_program("stdin-input", &program);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_textsimulator"))
+        .arg(&program_p)
+        .arg("--no-pipeline")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start text simulator");
+
+    child.stdin.take().unwrap().write_all(b"a\n")
+        .expect("failed to write stdin");
+
+    let output = child.wait_with_output().expect("failed to wait for simulator");
+    fs::remove_file(&program_p).ok();
+    assert!(output.status.success(), "simulator failed: {:?}", output);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().map(|l| l.trim()).collect();
+    assert!(lines.contains(&"3: 97"), "R3 should hold 'a': {}", stdout);
+    assert!(lines.contains(&format!("4: {}", ENTER).as_str()),
+            "R4 should hold ENTER: {}", stdout);
+}
+
+/// Tests that the text simulator pauses at a breakpoint until a line is
+/// entered on stdin, then runs the program to the end.
+#[test]
+fn test_breakpoint_pauses() {
+    let program_p = write_program("breakpoint", &[NOOP, NOOP, 0]);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_textsimulator"))
+        .arg(&program_p)
+        .arg("--no-pipeline")
+        .arg("--break")
+        .arg("1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start text simulator");
+
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut output = String::new();
+    while !output.contains("Press enter to continue") {
+        let read = stdout.read_line(&mut output).expect("failed to read stdout");
+        assert_ne!(read, 0, "simulator ended before the breakpoint: {}", output);
+    }
+    assert!(output.contains("Breakpoint hit at 0x0001"), "{}", output);
+
+    // Still paused a while later
+    thread::sleep(Duration::from_millis(100));
+    assert!(child.try_wait().expect("failed to check simulator").is_none(),
+            "simulator did not pause: {}", output);
+
+    child.stdin.take().unwrap().write_all(b"\n")
+        .expect("failed to write stdin");
+    let status = child.wait().expect("failed to wait for simulator");
+    fs::remove_file(&program_p).ok();
+    assert!(status.success(), "simulator failed: {:?}", status);
+}